@@ -0,0 +1,264 @@
+//! Live progress of a running commit, for `0s top`.
+//!
+//! Every worker reports the file it's processing and the bytes it has
+//! read, and the chunk deduplication hits are counted, so another
+//! process can take a [`Snapshot`] over the control socket.
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+/// Shared counters of an operation and its workers
+#[derive(Clone, Default)]
+pub struct Activity(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    workers: RwLock<Vec<Arc<Worker>>>,
+    queued: AtomicU64,
+    chunks: AtomicU64,
+    chunks_stored: AtomicU64,
+    bytes_stored: AtomicU64,
+    added: AtomicU64,
+    changed: AtomicU64,
+    files_found: AtomicU64,
+    bytes_found: AtomicU64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    listed: AtomicBool,
+}
+
+impl fmt::Debug for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Activity").finish()
+    }
+}
+
+impl Activity {
+    /// Replace the workers with `count` idle ones
+    pub fn start_workers(&self, count: usize) -> Vec<Arc<Worker>> {
+        let workers = (0..count)
+            .map(|_| Arc::new(Worker::default()))
+            .collect::<Vec<_>>();
+        *self.0.workers.write().unwrap() = workers.clone();
+        workers
+    }
+
+    pub fn queued(&self) {
+        self.0.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dequeued(&self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A file of `bytes` needs to be processed
+    pub fn found(&self, bytes: u64) {
+        self.0.files_found.fetch_add(1, Ordering::Relaxed);
+        self.0.bytes_found.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// All the files to process are found, so the totals are final
+    pub fn listed(&self) {
+        self.0.listed.store(true, Ordering::Relaxed);
+    }
+
+    /// A file of `bytes` is processed, or skipped
+    pub fn done(&self, bytes: u64) {
+        self.0.files_done.fetch_add(1, Ordering::Relaxed);
+        self.0.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A chunk was added to a file, whether it's stored or not
+    pub fn chunk(&self) {
+        self.0.chunks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A chunk of `bytes` wasn't found in the stash, and had to be
+    /// stored
+    pub fn chunk_stored(&self, bytes: u64) {
+        self.0.chunks_stored.fetch_add(1, Ordering::Relaxed);
+        self.0.bytes_stored.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A file that wasn't in the stash is being stored
+    pub fn added(&self) {
+        self.0.added.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A file in the stash has changed, and is being stored again
+    pub fn changed(&self) {
+        self.0.changed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            queued: self.0.queued.load(Ordering::Relaxed),
+            chunks: self.0.chunks.load(Ordering::Relaxed),
+            chunks_stored: self.0.chunks_stored.load(Ordering::Relaxed),
+            bytes_stored: self.0.bytes_stored.load(Ordering::Relaxed),
+            added: self.0.added.load(Ordering::Relaxed),
+            changed: self.0.changed.load(Ordering::Relaxed),
+            files_found: self.0.files_found.load(Ordering::Relaxed),
+            bytes_found: self.0.bytes_found.load(Ordering::Relaxed),
+            files_done: self.0.files_done.load(Ordering::Relaxed),
+            bytes_done: self.0.bytes_done.load(Ordering::Relaxed),
+            listed: self.0.listed.load(Ordering::Relaxed),
+            workers: self
+                .0
+                .workers
+                .read()
+                .unwrap()
+                .iter()
+                .map(|w| w.snapshot())
+                .collect(),
+        }
+    }
+}
+
+/// Progress of a single worker
+#[derive(Default)]
+pub struct Worker {
+    current: Mutex<Option<String>>,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Worker {
+    pub fn start(&self, path: &str) {
+        *self.current.lock().unwrap() = Some(path.to_string());
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn read(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn idle(&self) {
+        *self.current.lock().unwrap() = None;
+    }
+
+    fn snapshot(&self) -> WorkerSnapshot {
+        WorkerSnapshot {
+            current: self.current.lock().unwrap().clone(),
+            files: self.files.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of an [`Activity`]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Files waiting for a worker
+    pub queued: u64,
+    pub chunks: u64,
+    pub chunks_stored: u64,
+    #[serde(default)]
+    pub bytes_stored: u64,
+    /// Files new to the stash
+    #[serde(default)]
+    pub added: u64,
+    /// Files that changed since the last commit
+    #[serde(default)]
+    pub changed: u64,
+    #[serde(default)]
+    pub files_found: u64,
+    #[serde(default)]
+    pub bytes_found: u64,
+    #[serde(default)]
+    pub files_done: u64,
+    #[serde(default)]
+    pub bytes_done: u64,
+    /// Whether all files are found, and the totals are final
+    #[serde(default)]
+    pub listed: bool,
+    pub workers: Vec<WorkerSnapshot>,
+}
+
+impl Snapshot {
+    /// Share of the chunks that were already in the stash
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.chunks == 0 {
+            return 0.0;
+        }
+        1.0 - self.chunks_stored as f64 / self.chunks as f64
+    }
+
+    /// Time left at the average rate since the start, once all files
+    /// are found
+    pub fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        if !self.listed || self.bytes_done == 0 {
+            return None;
+        }
+
+        let rate = self.bytes_done as f64 / elapsed.as_secs_f64();
+        let left = self.bytes_found.saturating_sub(self.bytes_done);
+        Some(Duration::from_secs_f64(left as f64 / rate))
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WorkerSnapshot {
+    /// The file being processed, if any
+    pub current: Option<String>,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::Activity;
+    use std::time::Duration;
+
+    #[test]
+    fn snapshot_follows_workers() {
+        let activity = Activity::default();
+        let workers = activity.start_workers(2);
+
+        activity.queued();
+        activity.queued();
+        activity.dequeued();
+        workers[0].start("a/file");
+        workers[0].read(100);
+        for _ in 0..4 {
+            activity.chunk();
+        }
+        activity.chunk_stored(10);
+        activity.added();
+
+        let snapshot = activity.snapshot();
+        assert_eq!(snapshot.queued, 1);
+        assert_eq!(snapshot.workers.len(), 2);
+        assert_eq!(snapshot.workers[0].current.as_deref(), Some("a/file"));
+        assert_eq!(snapshot.workers[0].bytes, 100);
+        assert_eq!(snapshot.workers[1].files, 0);
+        assert_eq!(snapshot.dedup_ratio(), 0.75);
+        assert_eq!(snapshot.bytes_stored, 10);
+        assert_eq!(snapshot.added, 1);
+
+        workers[0].idle();
+        assert_eq!(activity.snapshot().workers[0].current, None);
+    }
+
+    #[test]
+    fn eta_once_listed() {
+        let activity = Activity::default();
+        activity.found(300);
+        activity.found(100);
+        activity.done(100);
+
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(activity.snapshot().eta(elapsed), None);
+
+        activity.listed();
+        let snapshot = activity.snapshot();
+        assert_eq!(snapshot.files_found, 2);
+        assert_eq!(snapshot.eta(elapsed), Some(Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,27 @@
+//! Adapters that add behaviour on top of any `infinitree` backend
+mod append_only;
+pub use append_only::AppendOnly;
+mod durable;
+pub use durable::{Durability, DurableDirectory};
+mod observed;
+pub use observed::{BackendObserver, Event, Observed, Operation, TransferStats};
+mod read_only;
+pub use read_only::ReadOnly;
+mod relocated;
+pub use relocated::{manifest_id, Moved, Relocated, Relocation, Relocations};
+mod request_log;
+pub use request_log::{Logged, RequestLog};
+mod s3;
+pub use s3::{RetainedObjects, S3Backend, S3Options};
+mod spill_cache;
+pub use spill_cache::{CacheDir, SpillCache};
+mod throttled;
+pub use throttled::{BandwidthSchedule, BandwidthWindow, Throttled, TimeOfDay};
+mod traced;
+pub use traced::Traced;
+mod verify;
+pub use verify::VerifyWrites;
+#[cfg(unix)]
+pub mod socket;
+#[cfg(unix)]
+pub use socket::SocketBackend;
@@ -0,0 +1,54 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{io, sync::Arc};
+use tracing::warn;
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// Refuse to delete objects from the upstream.
+///
+/// Used with credentials that may only append to a stash, so they
+/// can't delete history by mistake. See the README on limiting the
+/// storage credentials as well.
+pub struct AppendOnly {
+    upstream: Arc<dyn Backend>,
+}
+
+impl AppendOnly {
+    pub fn new(upstream: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self { upstream })
+    }
+}
+
+impl Backend for AppendOnly {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.upstream.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        warn!(count = objects.len(), "refusing to delete objects");
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the stash is append-only with these credentials",
+        )
+        .into())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
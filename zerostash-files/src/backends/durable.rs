@@ -0,0 +1,109 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// How carefully objects are written to a local directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Durability {
+    /// Flush every object to the disk before the write returns
+    pub fsync: bool,
+    /// Write objects to a temporary file first, then rename them into
+    /// place, so a crash never leaves a truncated object behind
+    pub tmpfile_rename: bool,
+}
+
+/// Write objects into the directory of an upstream filesystem
+/// backend with the requested durability guarantees.
+///
+/// Everything except writes is handled by the upstream, which must be
+/// a directory backend rooted at `path`.
+pub struct DurableDirectory {
+    path: PathBuf,
+    upstream: Arc<dyn Backend>,
+    durability: Durability,
+}
+
+impl DurableDirectory {
+    pub fn new(
+        path: impl AsRef<Path>,
+        upstream: Arc<dyn Backend>,
+        durability: Durability,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            path: path.as_ref().to_owned(),
+            upstream,
+            durability,
+        })
+    }
+
+    fn write_file(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(data)?;
+
+        if self.durability.fsync {
+            file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, object: &WriteObject) -> io::Result<()> {
+        let target = self.path.join(object.id().to_string());
+
+        if !self.durability.tmpfile_rename {
+            return self.write_file(&target, object.as_inner());
+        }
+
+        let tmp = self.path.join(format!(".{}.tmp", object.id()));
+        if let Err(error) = self
+            .write_file(&tmp, object.as_inner())
+            .and_then(|_| fs::rename(&tmp, &target))
+        {
+            _ = fs::remove_file(&tmp);
+            return Err(error);
+        }
+
+        // make the rename itself durable
+        #[cfg(unix)]
+        if self.durability.fsync {
+            fs::File::open(&self.path)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for DurableDirectory {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        Ok(self.write(object)?)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
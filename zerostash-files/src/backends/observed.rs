@@ -0,0 +1,204 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Preload,
+    Delete,
+    Sync,
+}
+
+/// A single completed backend operation
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub operation: Operation,
+    /// Number of objects involved
+    pub objects: usize,
+    /// Bytes transferred, only known for reads and writes
+    pub bytes: usize,
+    pub duration: Duration,
+    pub success: bool,
+}
+
+/// Receive an [`Event`] for every operation on an [`Observed`] backend.
+///
+/// Observers are called synchronously from the storage threads, so
+/// they should return quickly.
+pub trait BackendObserver: Send + Sync {
+    fn observe(&self, event: &Event);
+}
+
+impl<F> BackendObserver for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn observe(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Report every operation on the upstream backend to an observer.
+pub struct Observed {
+    upstream: Arc<dyn Backend>,
+    observer: Arc<dyn BackendObserver>,
+}
+
+impl Observed {
+    pub fn new(upstream: Arc<dyn Backend>, observer: Arc<dyn BackendObserver>) -> Arc<Self> {
+        Arc::new(Self { upstream, observer })
+    }
+
+    fn observe<T>(
+        &self,
+        operation: Operation,
+        objects: usize,
+        bytes: impl FnOnce(&T) -> usize,
+        op: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = op();
+
+        self.observer.observe(&Event {
+            operation,
+            objects,
+            bytes: result.as_ref().map(bytes).unwrap_or_default(),
+            duration: start.elapsed(),
+            success: result.is_ok(),
+        });
+
+        result
+    }
+}
+
+impl Backend for Observed {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let size = object.as_inner().len();
+        self.observe(
+            Operation::Write,
+            1,
+            |_| size,
+            || self.upstream.write_object(object),
+        )
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.observe(
+            Operation::Read,
+            1,
+            |object: &Arc<ReadObject>| object.as_inner().len(),
+            || self.upstream.read_object(id),
+        )
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.observe(
+            Operation::Preload,
+            objects.len(),
+            |_| 0,
+            || self.upstream.preload(objects),
+        )
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.observe(
+            Operation::Delete,
+            objects.len(),
+            |_| 0,
+            || self.upstream.delete(objects),
+        )
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.observe(Operation::Sync, 0, |_| 0, || self.upstream.sync())
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
+
+/// Running totals of the traffic through a backend
+#[derive(Default, Debug)]
+pub struct TransferStats {
+    pub objects_read: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub objects_written: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub errors: AtomicU64,
+    /// Time spent waiting on the backend, in microseconds
+    pub busy_micros: AtomicU64,
+}
+
+impl BackendObserver for TransferStats {
+    fn observe(&self, event: &Event) {
+        let (objects, bytes) = match event.operation {
+            Operation::Read => (&self.objects_read, &self.bytes_read),
+            Operation::Write => (&self.objects_written, &self.bytes_written),
+            _ => {
+                if !event.success {
+                    self.errors.fetch_add(1, Ordering::Relaxed);
+                }
+                return;
+            }
+        };
+
+        if event.success {
+            objects.fetch_add(event.objects as u64, Ordering::Relaxed);
+            bytes.fetch_add(event.bytes as u64, Ordering::Relaxed);
+        } else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.busy_micros
+            .fetch_add(event.duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Observed, TransferStats};
+    use crate::{Entry, Files};
+    use infinitree::{crypto::UsernamePassword, Infinitree};
+    use std::sync::{atomic::Ordering, Arc};
+
+    #[test]
+    fn commit_traffic_is_counted() {
+        let stats = Arc::new(TransferStats::default());
+        let backend = Observed::new(
+            infinitree::backends::test::InMemoryBackend::shared(),
+            stats.clone(),
+        );
+
+        let stash = Infinitree::<Files>::empty(
+            backend,
+            UsernamePassword::with_credentials("observed".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap();
+
+        stash
+            .index()
+            .tree
+            .insert_file("test/file.rs", Entry::default())
+            .unwrap();
+        stash.commit(None).unwrap();
+        stash.backend().sync().unwrap();
+
+        assert!(stats.objects_written.load(Ordering::Relaxed) > 0);
+        assert!(stats.bytes_written.load(Ordering::Relaxed) > 0);
+        assert_eq!(stats.errors.load(Ordering::Relaxed), 0);
+    }
+}
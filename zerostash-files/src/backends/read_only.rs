@@ -0,0 +1,59 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{io, sync::Arc};
+use tracing::warn;
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// Refuse to write or delete objects in the upstream.
+///
+/// Used with credentials that should only be able to restore from a
+/// stash, like the ones handed to auditors. See the README on
+/// limiting the storage credentials as well.
+pub struct ReadOnly {
+    upstream: Arc<dyn Backend>,
+}
+
+impl ReadOnly {
+    pub fn new(upstream: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self { upstream })
+    }
+}
+
+impl Backend for ReadOnly {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        warn!(id = ?object.id(), "refusing to write object");
+        Err(denied())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        warn!(count = objects.len(), "refusing to delete objects");
+        Err(denied())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
+
+fn denied() -> BackendError {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "the stash is read-only with these credentials",
+    )
+    .into()
+}
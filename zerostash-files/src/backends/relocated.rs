@@ -0,0 +1,367 @@
+//! Read objects whose live chunks were repacked into other objects.
+//!
+//! Chunk pointers in older commits can't be rewritten, so when `prune`
+//! packs the live chunks of partially dead objects together, it
+//! records where every byte range went in a manifest instead. Reading
+//! a relocated object puts its ranges back at their original offsets,
+//! so every pointer into it keeps working, and the original object can
+//! be deleted.
+//!
+//! The ranges hold encrypted chunks, which are copied as they are. The
+//! manifest is not encrypted, so the storage provider can see which
+//! ranges of the objects are still in use, but not what's in them.
+
+use crate::OBJECT_SIZE;
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+    Hasher,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, RwLock},
+};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+const MANIFEST_MAGIC: &[u8] = b"0s-relocations\x01";
+
+/// Bytes in the manifest for a relocation without ranges
+const RELOCATION_LEN: usize = 32 + 32 + 4;
+/// Bytes in the manifest for each moved range
+const RANGE_LEN: usize = 3 * 4;
+
+/// The object that holds the manifest
+pub fn manifest_id() -> ObjectId {
+    let hash = Hasher::new().update(b"zerostash-relocations").finalize();
+    ObjectId::from_bytes(hash.as_bytes())
+}
+
+/// A byte range copied from one object to another
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Moved {
+    /// Offset in the original object
+    pub from: u32,
+    /// Offset in the object that holds it now
+    pub to: u32,
+    pub len: u32,
+}
+
+/// Where the live ranges of an object went
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// The object that was repacked
+    pub source: ObjectId,
+    /// The object that holds its live ranges now
+    pub target: ObjectId,
+    pub ranges: Vec<Moved>,
+}
+
+/// Every object that was repacked, by its id
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Relocations(HashMap<ObjectId, Relocation>);
+
+impl Relocations {
+    /// Read the manifest from `backend`. A missing manifest means no
+    /// objects were relocated.
+    pub fn load(backend: &dyn Backend) -> Result<Self> {
+        match backend.read_object(&manifest_id()) {
+            Ok(object) => Self::decode(object.as_inner()).map_err(Into::into),
+            Err(error) if is_not_found(&error) => Ok(Self::default()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Replace the manifest on `backend`
+    pub fn store(&self, backend: &dyn Backend) -> Result<()> {
+        let mut object = WriteObject::default();
+        object.set_id(manifest_id());
+        self.encode(object.as_inner_mut())?;
+
+        backend.write_object(&object)?;
+        backend.sync()
+    }
+
+    pub fn get(&self, id: &ObjectId) -> Option<&Relocation> {
+        self.0.get(id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn insert(&mut self, relocation: Relocation) -> Option<Relocation> {
+        self.0.insert(relocation.source, relocation)
+    }
+
+    pub fn remove(&mut self, id: &ObjectId) -> Option<Relocation> {
+        self.0.remove(id)
+    }
+
+    pub fn extend(&mut self, other: Relocations) {
+        self.0.extend(other.0);
+    }
+
+    /// The objects that hold relocated ranges
+    pub fn targets(&self) -> HashSet<ObjectId> {
+        self.0.values().map(|r| r.target).collect()
+    }
+
+    /// Whether the manifest still fits in an object with `more` added
+    pub fn fits(&self, more: &[Relocation]) -> bool {
+        let len = self.0.values().chain(more).map(encoded_len).sum::<usize>();
+        MANIFEST_MAGIC.len() + 4 + len <= OBJECT_SIZE as usize
+    }
+
+    fn encode(&self, buf: &mut [u8]) -> io::Result<()> {
+        if !self.fits(&[]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many relocated objects for the manifest",
+            ));
+        }
+
+        let mut out = Writer(buf);
+        out.put(MANIFEST_MAGIC);
+        out.put(&(self.0.len() as u32).to_le_bytes());
+        for relocation in self.0.values() {
+            out.put(relocation.source.as_ref());
+            out.put(relocation.target.as_ref());
+            out.put(&(relocation.ranges.len() as u32).to_le_bytes());
+            for range in &relocation.ranges {
+                out.put(&range.from.to_le_bytes());
+                out.put(&range.to.to_le_bytes());
+                out.put(&range.len.to_le_bytes());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid relocation manifest");
+        let mut data = Reader(data.strip_prefix(MANIFEST_MAGIC).ok_or_else(invalid)?);
+
+        let mut relocations = HashMap::new();
+        for _ in 0..data.u32().ok_or_else(invalid)? {
+            let source = data.id().ok_or_else(invalid)?;
+            let target = data.id().ok_or_else(invalid)?;
+            let ranges = (0..data.u32().ok_or_else(invalid)?)
+                .map(|_| {
+                    Some(Moved {
+                        from: data.u32()?,
+                        to: data.u32()?,
+                        len: data.u32()?,
+                    })
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(invalid)?;
+
+            relocations.insert(
+                source,
+                Relocation {
+                    source,
+                    target,
+                    ranges,
+                },
+            );
+        }
+
+        Ok(Self(relocations))
+    }
+}
+
+fn encoded_len(relocation: &Relocation) -> usize {
+    RELOCATION_LEN + relocation.ranges.len() * RANGE_LEN
+}
+
+/// Backends report missing objects as an I/O error, or in their own
+/// way
+fn is_not_found(error: &BackendError) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            if error.kind() == io::ErrorKind::NotFound {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+
+    let debug = format!("{error:?}");
+    debug.contains("NotFound") || debug.contains("NoSuchKey")
+}
+
+struct Writer<'a>(&'a mut [u8]);
+
+impl Writer<'_> {
+    fn put(&mut self, data: &[u8]) {
+        let (head, tail) = std::mem::take(&mut self.0).split_at_mut(data.len());
+        head.copy_from_slice(data);
+        self.0 = tail;
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn id(&mut self) -> Option<ObjectId> {
+        Some(ObjectId::from_bytes(self.take(32)?))
+    }
+}
+
+/// Read relocated objects from the objects that hold their ranges.
+///
+/// Relocations can be added while the backend is in use, so a repack
+/// can be checked before the original objects are deleted.
+pub struct Relocated {
+    upstream: Arc<dyn Backend>,
+    relocations: Arc<RwLock<Relocations>>,
+}
+
+impl Relocated {
+    pub fn new(upstream: Arc<dyn Backend>, relocations: Arc<RwLock<Relocations>>) -> Arc<Self> {
+        Arc::new(Self {
+            upstream,
+            relocations,
+        })
+    }
+
+    /// Objects to fetch from the upstream for `objects`
+    fn stored(&self, objects: &[ObjectId]) -> Vec<ObjectId> {
+        let relocations = self.relocations.read().unwrap();
+        objects
+            .iter()
+            .map(|id| relocations.get(id).map_or(*id, |r| r.target))
+            .collect()
+    }
+}
+
+impl Backend for Relocated {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.upstream.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let Some(relocation) = self.relocations.read().unwrap().get(id).cloned() else {
+            return self.upstream.read_object(id);
+        };
+
+        let target = self.upstream.read_object(&relocation.target)?;
+        let target = target.as_inner();
+        let mut data = vec![0; target.len()];
+        for range in &relocation.ranges {
+            let (from, to, len) = (range.from as usize, range.to as usize, range.len as usize);
+            let (Some(dest), Some(src)) =
+                (data.get_mut(from..from + len), target.get(to..to + len))
+            else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("relocated range of object {id} is out of bounds"),
+                )
+                .into());
+            };
+            dest.copy_from_slice(src);
+        }
+
+        Ok(Arc::new(ReadObject::new(*id, data.into())))
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(&self.stored(objects))
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(&self.stored(objects))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Moved, Relocated, Relocation, Relocations};
+    use infinitree::{
+        backends::{test::InMemoryBackend, Backend},
+        object::{ObjectId, WriteObject},
+    };
+    use std::sync::{Arc, RwLock};
+
+    fn id(n: u8) -> ObjectId {
+        ObjectId::from_bytes(&[n; 32])
+    }
+
+    fn relocation() -> Relocation {
+        Relocation {
+            source: id(1),
+            target: id(2),
+            ranges: vec![
+                Moved {
+                    from: 100,
+                    to: 0,
+                    len: 4,
+                },
+                Moved {
+                    from: 10,
+                    to: 4,
+                    len: 2,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let backend = InMemoryBackend::shared();
+        assert!(Relocations::load(backend.as_ref()).unwrap().is_empty());
+
+        let mut relocations = Relocations::default();
+        relocations.insert(relocation());
+        relocations.store(backend.as_ref()).unwrap();
+
+        assert_eq!(Relocations::load(backend.as_ref()).unwrap(), relocations);
+    }
+
+    #[test]
+    fn relocated_ranges_are_read_at_their_original_offset() {
+        let upstream = InMemoryBackend::shared();
+        let mut target = WriteObject::default();
+        target.set_id(id(2));
+        target.as_inner_mut()[..6].copy_from_slice(b"chunkz");
+        upstream.write_object(&target).unwrap();
+
+        let relocations = Arc::new(RwLock::new(Relocations::default()));
+        let backend = Relocated::new(upstream.clone(), relocations.clone());
+        assert!(backend.read_object(&id(1)).is_err());
+
+        relocations.write().unwrap().insert(relocation());
+
+        let object = backend.read_object(&id(1)).unwrap();
+        let data = object.as_inner();
+        assert_eq!(data.len(), target.as_inner().len());
+        assert_eq!(&data[100..104], b"chun");
+        assert_eq!(&data[10..12], b"kz");
+        assert!(data[..10].iter().all(|b| *b == 0));
+    }
+}
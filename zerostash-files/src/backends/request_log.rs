@@ -0,0 +1,144 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// Write a JSON line for every request made to the wrapped backends.
+///
+/// Each line records the backend, the operation, the objects and bytes
+/// involved, the latency, and the outcome. Credentials that end up in
+/// error messages, such as signed URLs, are redacted.
+#[derive(Debug)]
+pub struct RequestLog {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl RequestLog {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        }))
+    }
+
+    /// Log the requests made to `upstream` under the name `backend`
+    pub fn wrap(
+        self: &Arc<Self>,
+        backend: &'static str,
+        upstream: Arc<dyn Backend>,
+    ) -> Arc<Logged> {
+        Arc::new(Logged {
+            backend,
+            upstream,
+            log: self.clone(),
+        })
+    }
+
+    fn record<T>(
+        &self,
+        backend: &str,
+        method: &str,
+        ids: &[ObjectId],
+        bytes: impl FnOnce(&T) -> usize,
+        op: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = op();
+        let elapsed = start.elapsed();
+
+        let mut line = serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339(),
+            "backend": backend,
+            "method": method,
+            "objects": ids.len(),
+            "ms": elapsed.as_secs_f64() * 1000.0,
+            "status": if result.is_ok() { "ok" } else { "error" },
+        });
+        if let [id] = ids {
+            line["id"] = id.to_string().into();
+        }
+        match &result {
+            Ok(value) => line["bytes"] = bytes(value).into(),
+            Err(error) => line["error"] = crate::redact(&format!("{error:#}")).into(),
+        }
+
+        let mut out = self.out.lock().unwrap();
+        // a broken log must not fail the backup
+        _ = writeln!(out, "{line}").and_then(|_| out.flush());
+
+        result
+    }
+}
+
+/// Log every request to the upstream in a [`RequestLog`]
+pub struct Logged {
+    backend: &'static str,
+    upstream: Arc<dyn Backend>,
+    log: Arc<RequestLog>,
+}
+
+impl Backend for Logged {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let len = object.as_inner().len();
+        self.log.record(
+            self.backend,
+            "write_object",
+            &[*object.id()],
+            |_| len,
+            || self.upstream.write_object(object),
+        )
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.log.record(
+            self.backend,
+            "read_object",
+            &[*id],
+            |object: &Arc<ReadObject>| object.as_inner().len(),
+            || self.upstream.read_object(id),
+        )
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.log.record(
+            self.backend,
+            "preload",
+            objects,
+            |_| 0,
+            || self.upstream.preload(objects),
+        )
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.log.record(
+            self.backend,
+            "delete",
+            objects,
+            |_| 0,
+            || self.upstream.delete(objects),
+        )
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.log
+            .record(self.backend, "sync", &[], |_| 0, || self.upstream.sync())
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.log.record(
+            self.backend,
+            "keep_warm",
+            objects,
+            |_| 0,
+            || self.upstream.keep_warm(objects),
+        )
+    }
+}
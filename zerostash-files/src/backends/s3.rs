@@ -0,0 +1,372 @@
+//! An S3 client for the connection options the `infinitree-backends`
+//! client doesn't provide, like path-style addressing and private CAs.
+//!
+//! Requests are presigned with `rusty-s3`, and sent from the thread
+//! that calls the backend, so each request in flight holds one object.
+//!
+//! With `object_lock_days` set, every object is uploaded with an Object
+//! Lock retention in compliance mode. Deleting an object that is still
+//! locked skips it, and records it in [`RetainedObjects`].
+
+use base64::Engine;
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read},
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tracing::debug;
+use url::Url;
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// How long a signed request stays valid
+const SIGNATURE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Tries for requests that fail with a transient error
+const ATTEMPTS: u32 = 3;
+
+/// Where the bucket is, and how to talk to the server
+#[derive(Clone, Debug)]
+pub struct S3Options {
+    /// Base URL of the server, eg. `https://s3.us-east-1.amazonaws.com`
+    pub endpoint: Url,
+    /// Region to sign requests for
+    pub region: String,
+    /// Name of the bucket, optionally followed by a `/` and a prefix
+    /// for the object names
+    pub bucket: String,
+    /// Address objects as `endpoint/bucket/key` instead of
+    /// `bucket.endpoint/key`
+    pub path_style: bool,
+    /// PEM encoded CA certificates to trust next to the built-in roots
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Lock objects for this many days after upload
+    pub object_lock_days: Option<NonZeroU32>,
+    /// Where to record objects that couldn't be deleted because they
+    /// are locked
+    pub retained: Arc<RetainedObjects>,
+}
+
+/// Objects that were kept because their Object Lock retention hasn't
+/// expired yet
+#[derive(Default, Debug)]
+pub struct RetainedObjects(Mutex<Vec<ObjectId>>);
+
+impl RetainedObjects {
+    /// Remove and return the objects recorded so far
+    pub fn take(&self) -> Vec<ObjectId> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+
+    fn push(&self, id: ObjectId) {
+        self.0.lock().unwrap().push(id);
+    }
+}
+
+/// Store objects in an S3 bucket.
+pub struct S3Backend {
+    agent: ureq::Agent,
+    bucket: Bucket,
+    prefix: String,
+    credentials: Credentials,
+    object_lock_days: Option<NonZeroU32>,
+    retained: Arc<RetainedObjects>,
+}
+
+impl S3Backend {
+    pub fn new(options: S3Options, credentials: Credentials) -> io::Result<Arc<Self>> {
+        let (name, prefix) = match options.bucket.split_once('/') {
+            Some((name, prefix)) => (name.to_string(), format!("{prefix}/")),
+            None => (options.bucket.clone(), String::new()),
+        };
+
+        let style = if options.path_style {
+            UrlStyle::Path
+        } else {
+            UrlStyle::VirtualHost
+        };
+        let bucket = Bucket::new(options.endpoint, style, name, options.region)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+        let agent = ureq::AgentBuilder::new()
+            .tls_config(Arc::new(tls_config(&options.root_certificates)?))
+            .build();
+
+        Ok(Arc::new(Self {
+            agent,
+            bucket,
+            prefix,
+            credentials,
+            object_lock_days: options.object_lock_days,
+            retained: options.retained,
+        }))
+    }
+
+    fn key(&self, id: &ObjectId) -> String {
+        format!("{}{id}", self.prefix)
+    }
+
+    /// Headers that lock an object with `body` until the retention
+    /// expires. S3 wants a checksum on every locked upload.
+    fn object_lock_headers(&self, body: &[u8]) -> Vec<(&'static str, String)> {
+        let Some(days) = self.object_lock_days else {
+            return vec![];
+        };
+
+        let until = chrono::Utc::now() + chrono::Duration::days(days.get().into());
+        let checksum = base64::engine::general_purpose::STANDARD.encode(Sha256::digest(body));
+
+        vec![
+            ("x-amz-object-lock-mode", "COMPLIANCE".into()),
+            (
+                "x-amz-object-lock-retain-until-date",
+                until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            ),
+            ("x-amz-sdk-checksum-algorithm", "SHA256".into()),
+            ("x-amz-checksum-sha256", checksum),
+        ]
+    }
+
+    /// Whether the object is locked by a retention that hasn't expired
+    fn is_locked(&self, key: &str) -> io::Result<bool> {
+        let response = match self.call(|| {
+            let url = self
+                .bucket
+                .head_object(Some(&self.credentials), key)
+                .sign(SIGNATURE_TTL);
+            self.agent.head(url.as_str()).call()
+        }) {
+            Ok(response) => response,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        Ok(response
+            .header("x-amz-object-lock-retain-until-date")
+            .and_then(|until| chrono::DateTime::parse_from_rfc3339(until).ok())
+            .is_some_and(|until| until > chrono::Utc::now()))
+    }
+
+    /// Send a request built by `request`, and try again if it fails
+    /// with a server or network error.
+    ///
+    /// `request` is called for every try, so the signature is fresh.
+    fn call(
+        &self,
+        request: impl Fn() -> std::result::Result<ureq::Response, ureq::Error>,
+    ) -> io::Result<ureq::Response> {
+        let mut attempt = 1;
+        loop {
+            let error = match request() {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(404, _)) => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "no such object"))
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let body = response.into_string().unwrap_or_default();
+                    let error = io::Error::other(format!("S3 returned {status}: {body}"));
+                    if status < 500 && status != 429 {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(error @ ureq::Error::Transport(_)) => io::Error::other(error.to_string()),
+            };
+
+            if attempt == ATTEMPTS {
+                return Err(error);
+            }
+
+            debug!(%error, attempt, "retrying S3 request");
+            thread::sleep(Duration::from_millis(200 << attempt));
+            attempt += 1;
+        }
+    }
+}
+
+impl Backend for S3Backend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let key = self.key(object.id());
+        let headers = self.object_lock_headers(object.as_inner());
+        self.call(|| {
+            let mut action = self.bucket.put_object(Some(&self.credentials), &key);
+            for (name, value) in &headers {
+                action.headers_mut().insert(*name, value.as_str());
+            }
+            let url = action.sign(SIGNATURE_TTL);
+
+            let request = headers
+                .iter()
+                .fold(self.agent.put(url.as_str()), |request, (name, value)| {
+                    request.set(name, value)
+                });
+            request.send_bytes(object.as_inner())
+        })?;
+
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let key = self.key(id);
+        let response = self.call(|| {
+            let url = self
+                .bucket
+                .get_object(Some(&self.credentials), &key)
+                .sign(SIGNATURE_TTL);
+            self.agent.get(url.as_str()).call()
+        })?;
+
+        let mut body = vec![];
+        response.into_reader().read_to_end(&mut body)?;
+
+        Ok(Arc::new(ReadObject::new(*id, body.into())))
+    }
+
+    fn preload(&self, _objects: &[ObjectId]) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        for id in objects {
+            let key = self.key(id);
+            if self.object_lock_days.is_some() && self.is_locked(&key)? {
+                debug!(%id, "object is locked, not deleting");
+                self.retained.push(*id);
+                continue;
+            }
+
+            self.call(|| {
+                let url = self
+                    .bucket
+                    .delete_object(Some(&self.credentials), &key)
+                    .sign(SIGNATURE_TTL);
+                self.agent.delete(url.as_str()).call()
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn keep_warm(&self, _objects: &[ObjectId]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Trust the built-in roots, and the certificates in `pem_bundles`
+fn tls_config(pem_bundles: &[Vec<u8>]) -> io::Result<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    for pem in pem_bundles {
+        let mut found = false;
+        for certificate in rustls_pemfile::certs(&mut pem.as_slice()) {
+            roots.add(certificate?).map_err(io::Error::other)?;
+            found = true;
+        }
+
+        if !found {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no certificates in the CA bundle",
+            ));
+        }
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{S3Backend, S3Options};
+    use infinitree::object::ObjectId;
+    use rusty_s3::Credentials;
+    use std::num::NonZeroU32;
+
+    fn options(path_style: bool) -> S3Options {
+        S3Options {
+            endpoint: "https://[2001:db8::1]:9443".parse().unwrap(),
+            region: "us-east-1".into(),
+            bucket: "backups/laptop".into(),
+            path_style,
+            root_certificates: vec![],
+            object_lock_days: None,
+            retained: Default::default(),
+        }
+    }
+
+    #[test]
+    fn path_style_keeps_the_bucket_in_the_path() {
+        let id = ObjectId::from_bytes(&[1; 32]);
+        let credentials = Credentials::new("access", "secret");
+
+        let s3 = S3Backend::new(options(true), credentials.clone()).unwrap();
+        let url = s3
+            .bucket
+            .get_object(None, &s3.key(&id))
+            .sign(super::SIGNATURE_TTL);
+        assert_eq!(url.host_str(), Some("[2001:db8::1]"));
+        assert_eq!(url.port(), Some(9443));
+        assert_eq!(url.path(), format!("/backups/laptop/{id}"));
+
+        let s3 = S3Backend::new(options(false), credentials).unwrap();
+        assert_eq!(s3.key(&id), format!("laptop/{id}"));
+    }
+
+    #[test]
+    fn locked_uploads_carry_a_retention_and_checksum() {
+        let options = S3Options {
+            object_lock_days: NonZeroU32::new(30),
+            ..options(true)
+        };
+        let s3 = S3Backend::new(options, Credentials::new("access", "secret")).unwrap();
+
+        let headers = s3.object_lock_headers(b"hello");
+        let header = |name| {
+            headers
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        assert_eq!(header("x-amz-object-lock-mode"), Some("COMPLIANCE"));
+        assert_eq!(
+            header("x-amz-checksum-sha256"),
+            Some("LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=")
+        );
+
+        let until = header("x-amz-object-lock-retain-until-date").unwrap();
+        let until = chrono::DateTime::parse_from_rfc3339(until).unwrap();
+        let days = (until.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_hours() / 24;
+        assert!((29..=30).contains(&days));
+
+        let unlocked = S3Backend::new(options(true), Credentials::new("access", "secret")).unwrap();
+        assert!(unlocked.object_lock_headers(b"hello").is_empty());
+    }
+
+    #[test]
+    fn ca_bundles_without_certificates_are_refused() {
+        let options = S3Options {
+            root_certificates: vec![b"not a certificate".to_vec()],
+            ..options(true)
+        };
+
+        assert!(S3Backend::new(options, Credentials::new("access", "secret")).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Share a single backend between processes over a unix socket.
+//!
+//! The object server only ever sees encrypted objects, so it does
+//! not need access to the stash keys.
+//!
+//! Every request is a frame of `[op: u8][id: 32 bytes][len: u64 LE][payload]`,
+//! and every response is `[status: u8][len: u64 LE][payload]`.
+//!
+//! The [`Backend`] trait passes every object whole, so each request in
+//! flight holds one object in memory on both ends.
+
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+use tracing::{debug, warn};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+const OP_DELETE: u8 = 3;
+const OP_SYNC: u8 = 4;
+const OP_PRELOAD: u8 = 5;
+const OP_KEEP_WARM: u8 = 6;
+
+const STATUS_OK: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+const ID_LEN: usize = 32;
+
+/// Connect to an object server started by [`serve`].
+pub struct SocketBackend {
+    path: PathBuf,
+    connections: Mutex<Vec<UnixStream>>,
+}
+
+impl SocketBackend {
+    pub fn new(path: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let path = path.as_ref().to_owned();
+        let connection = UnixStream::connect(&path)?;
+
+        Ok(Arc::new(Self {
+            path,
+            connections: Mutex::new(vec![connection]),
+        }))
+    }
+
+    fn request(&self, op: u8, id: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        let pooled = self.connections.lock().unwrap().pop();
+        let mut stream = match pooled {
+            Some(stream) => stream,
+            None => UnixStream::connect(&self.path)?,
+        };
+
+        let mut writer = BufWriter::new(&mut stream);
+        write_frame(&mut writer, op, id, payload)?;
+        writer.flush()?;
+        drop(writer);
+
+        let (status, body) = read_response(&mut BufReader::new(&mut stream))?;
+
+        // only reuse connections that are in a known state
+        self.connections.lock().unwrap().push(stream);
+
+        match status {
+            STATUS_OK => Ok(body),
+            STATUS_NOT_FOUND => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                String::from_utf8_lossy(&body).to_string(),
+            )
+            .into()),
+            _ => Err(io::Error::other(String::from_utf8_lossy(&body).to_string()).into()),
+        }
+    }
+
+    fn request_many(&self, op: u8, objects: &[ObjectId]) -> Result<()> {
+        let ids = objects
+            .iter()
+            .flat_map(|id| id.as_ref().iter().copied())
+            .collect::<Vec<_>>();
+
+        self.request(op, &[0; ID_LEN], &ids).map(|_| ())
+    }
+}
+
+impl Backend for SocketBackend {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.request(OP_WRITE, object.id().as_ref(), object.as_inner())
+            .map(|_| ())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let body = self.request(OP_READ, id.as_ref(), &[])?;
+        Ok(Arc::new(ReadObject::new(*id, body.into())))
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.request_many(OP_PRELOAD, objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.request_many(OP_DELETE, objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.request(OP_SYNC, &[0; ID_LEN], &[]).map(|_| ())
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.request_many(OP_KEEP_WARM, objects)
+    }
+}
+
+/// Bind a socket that's only accessible to the current user at `path`.
+pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+
+    // a leftover socket from a previous run would fail the bind
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+/// Serve requests to `upstream` for every client connecting to `listener`.
+pub fn serve(listener: UnixListener, upstream: Arc<dyn Backend>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let upstream = upstream.clone();
+
+        thread::spawn(move || match handle_connection(stream, upstream) {
+            Ok(()) => debug!("client disconnected"),
+            Err(error) => warn!(%error, "client connection failed"),
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection(stream: UnixStream, upstream: Arc<dyn Backend>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let mut header = [0; 1 + ID_LEN + 8];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let op = header[0];
+        let id = ObjectId::from_bytes(&header[1..1 + ID_LEN]);
+        let len = u64::from_le_bytes(header[1 + ID_LEN..].try_into().unwrap()) as usize;
+
+        let result = match op {
+            OP_WRITE => {
+                let mut object = WriteObject::default();
+                object.set_id(id);
+
+                if len == object.as_inner().len() {
+                    reader.read_exact(object.as_inner_mut())?;
+                    upstream.write_object(&object).map(|_| None)
+                } else {
+                    io::copy(&mut (&mut reader).take(len as u64), &mut io::sink())?;
+                    Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid object size").into())
+                }
+            }
+            _ => {
+                let mut payload = vec![0; len];
+                reader.read_exact(&mut payload)?;
+                dispatch(upstream.as_ref(), op, id, payload)
+            }
+        };
+
+        match result {
+            Ok(Some(object)) => write_frame(&mut writer, STATUS_OK, &[], object.as_inner())?,
+            Ok(None) => write_frame(&mut writer, STATUS_OK, &[], &[])?,
+            Err(BackendError::Io { source }) if source.kind() == io::ErrorKind::NotFound => {
+                write_frame(
+                    &mut writer,
+                    STATUS_NOT_FOUND,
+                    &[],
+                    source.to_string().as_bytes(),
+                )?
+            }
+            Err(error) => {
+                write_frame(&mut writer, STATUS_ERROR, &[], error.to_string().as_bytes())?
+            }
+        }
+
+        writer.flush()?;
+    }
+}
+
+fn dispatch(
+    upstream: &dyn Backend,
+    op: u8,
+    id: ObjectId,
+    payload: Vec<u8>,
+) -> Result<Option<Arc<ReadObject>>> {
+    let ids = || {
+        payload
+            .chunks_exact(ID_LEN)
+            .map(ObjectId::from_bytes)
+            .collect::<Vec<_>>()
+    };
+
+    match op {
+        OP_READ => upstream.read_object(&id).map(Some),
+        OP_DELETE => upstream.delete(&ids()).map(|_| None),
+        OP_SYNC => upstream.sync().map(|_| None),
+        OP_PRELOAD => upstream.preload(&ids()).map(|_| None),
+        OP_KEEP_WARM => upstream.keep_warm(&ids()).map(|_| None),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown operation").into()),
+    }
+}
+
+fn write_frame(stream: &mut impl Write, head: u8, id: &[u8], payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&[head])?;
+    stream.write_all(id)?;
+    stream.write_all(&(payload.len() as u64).to_le_bytes())?;
+    stream.write_all(payload)
+}
+
+fn read_response(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 1 + 8];
+    stream.read_exact(&mut header)?;
+
+    let len = u64::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+
+    Ok((header[0], payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bind, serve, SocketBackend};
+    use crate::{Entry, Files};
+    use infinitree::{crypto::UsernamePassword, Infinitree};
+
+    #[test]
+    fn stash_roundtrips_through_socket() {
+        let key = || {
+            UsernamePassword::with_credentials("socket".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let path = std::env::temp_dir().join(format!("0s-objectd-{}", rand::random::<u64>()));
+
+        let listener = bind(&path).unwrap();
+        let upstream = infinitree::backends::test::InMemoryBackend::shared();
+        std::thread::spawn(move || serve(listener, upstream));
+
+        {
+            let stash =
+                Infinitree::<Files>::empty(SocketBackend::new(&path).unwrap(), key()).unwrap();
+            stash
+                .index()
+                .tree
+                .insert_file("test/file.rs", Entry::default())
+                .unwrap();
+            stash.commit(None).unwrap();
+            stash.backend().sync().unwrap();
+        }
+
+        let stash = Infinitree::<Files>::open(SocketBackend::new(&path).unwrap(), key()).unwrap();
+        stash.load_all().unwrap();
+        assert!(stash.index().tree.file("test/file.rs").unwrap().is_some());
+
+        _ = std::fs::remove_file(path);
+    }
+}
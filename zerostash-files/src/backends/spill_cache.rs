@@ -0,0 +1,293 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tracing::{debug, warn};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// A local directory to cache objects in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheDir {
+    pub path: PathBuf,
+    /// Never store more than this many bytes in the directory
+    pub max_size: u64,
+    /// Stop caching in the directory if less than this percent of the
+    /// file system would be free
+    pub min_free_percent: u8,
+}
+
+/// Cached objects in a directory, least recently used first
+struct Tier {
+    dir: CacheDir,
+    objects: Mutex<(VecDeque<(ObjectId, u64)>, u64)>,
+}
+
+/// Cache objects of the upstream in a list of local directories.
+///
+/// Objects go to the first directory that has room for them, both
+/// within its size cap and its free space floor, and spill over to
+/// the next one otherwise. If there's no room anywhere, the object is
+/// not cached, so a full disk never fails a backup.
+///
+/// Writes go to the upstream before they are cached.
+pub struct SpillCache {
+    tiers: Vec<Tier>,
+    upstream: Arc<dyn Backend>,
+}
+
+impl SpillCache {
+    pub fn new(dirs: Vec<CacheDir>, upstream: Arc<dyn Backend>) -> io::Result<Arc<Self>> {
+        let tiers = dirs
+            .into_iter()
+            .map(|dir| {
+                fs::create_dir_all(&dir.path)?;
+                let objects = scan(&dir.path)?;
+                Ok(Tier {
+                    dir,
+                    objects: Mutex::new(objects),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Arc::new(Self { tiers, upstream }))
+    }
+
+    fn store(&self, id: &ObjectId, data: &[u8]) {
+        let len = data.len() as u64;
+        let usable = |tier: &&Tier| len <= tier.dir.max_size && has_free_space(&tier.dir, len);
+
+        // fill the directories in order, and only start evicting once
+        // all of them are full
+        let fits = |tier: &&Tier| {
+            let objects = tier.objects.lock().unwrap();
+            objects.1 + len <= tier.dir.max_size
+        };
+
+        for tier in self
+            .tiers
+            .iter()
+            .filter(usable)
+            .filter(fits)
+            .chain(self.tiers.iter().filter(usable))
+        {
+            let mut objects = tier.objects.lock().unwrap();
+            let (lru, used) = &mut *objects;
+            while *used + len > tier.dir.max_size {
+                let Some((evicted, size)) = lru.pop_front() else {
+                    break;
+                };
+                _ = fs::remove_file(tier.dir.path.join(evicted.to_string()));
+                *used -= size;
+            }
+
+            match fs::write(tier.dir.path.join(id.to_string()), data) {
+                Ok(()) => {
+                    lru.push_back((*id, len));
+                    *used += len;
+                    return;
+                }
+                Err(error) => {
+                    warn!(%error, path = ?tier.dir.path, "failed to cache object; spilling over");
+                }
+            }
+        }
+
+        debug!(?id, "no room in the cache directories");
+    }
+
+    fn load(&self, id: &ObjectId) -> Option<Arc<ReadObject>> {
+        for tier in self.tiers.iter() {
+            let mut objects = tier.objects.lock().unwrap();
+            let (lru, used) = &mut *objects;
+            let Some(pos) = lru.iter().position(|(cached, _)| cached == id) else {
+                continue;
+            };
+
+            let entry = lru.remove(pos).unwrap();
+            match fs::read(tier.dir.path.join(id.to_string())) {
+                Ok(data) => {
+                    lru.push_back(entry);
+                    return Some(Arc::new(ReadObject::new(*id, data.into())));
+                }
+                Err(error) => {
+                    warn!(%error, ?id, "cached object is unreadable");
+                    *used -= entry.1;
+                }
+            }
+        }
+
+        None
+    }
+
+    fn is_cached(&self, id: &ObjectId) -> bool {
+        self.tiers.iter().any(|tier| {
+            let objects = tier.objects.lock().unwrap();
+            objects.0.iter().any(|(cached, _)| cached == id)
+        })
+    }
+
+    fn evict(&self, id: &ObjectId) {
+        for tier in self.tiers.iter() {
+            let mut objects = tier.objects.lock().unwrap();
+            let (lru, used) = &mut *objects;
+            if let Some(pos) = lru.iter().position(|(cached, _)| cached == id) {
+                let (_, size) = lru.remove(pos).unwrap();
+                _ = fs::remove_file(tier.dir.path.join(id.to_string()));
+                *used -= size;
+            }
+        }
+    }
+}
+
+impl Backend for SpillCache {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.upstream.write_object(object)?;
+        self.store(object.id(), object.as_inner());
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        if let Some(object) = self.load(id) {
+            return Ok(object);
+        }
+
+        let object = self.upstream.read_object(id)?;
+        self.store(id, object.as_inner());
+        Ok(object)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        let missing = objects
+            .iter()
+            .filter(|id| !self.is_cached(id))
+            .copied()
+            .collect::<Vec<_>>();
+
+        self.upstream.preload(&missing)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        for id in objects {
+            self.evict(id);
+        }
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
+
+/// Find the objects cached by a previous run, oldest first
+fn scan(path: &Path) -> io::Result<(VecDeque<(ObjectId, u64)>, u64)> {
+    let mut found = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let Some(id) = parse_id(&entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        found.push((modified, id, metadata.len()));
+    }
+
+    found.sort_by_key(|(modified, ..)| *modified);
+    let used = found.iter().map(|(_, _, size)| size).sum();
+    let lru = found.into_iter().map(|(_, id, size)| (id, size)).collect();
+
+    Ok((lru, used))
+}
+
+fn parse_id(name: &str) -> Option<ObjectId> {
+    if name.len() != 64 || !name.is_ascii() {
+        return None;
+    }
+
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&name[i..i + 2], 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(ObjectId::from_bytes(&bytes))
+}
+
+#[cfg(unix)]
+fn has_free_space(dir: &CacheDir, len: u64) -> bool {
+    if dir.min_free_percent == 0 {
+        return true;
+    }
+
+    let Ok(stat) = nix::sys::statvfs::statvfs(&dir.path) else {
+        return false;
+    };
+
+    let block = stat.fragment_size() as u64;
+    let total = stat.blocks() as u64 * block;
+    let available = (stat.blocks_available() as u64 * block).saturating_sub(len);
+
+    available * 100 >= total * dir.min_free_percent as u64
+}
+
+#[cfg(not(unix))]
+fn has_free_space(_dir: &CacheDir, _len: u64) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CacheDir, SpillCache};
+    use infinitree::{
+        backends::{test::InMemoryBackend, Backend},
+        object::{ObjectId, WriteObject},
+    };
+
+    fn object(id: u8) -> WriteObject {
+        let mut object = WriteObject::default();
+        object.set_id(ObjectId::from_bytes(&[id; 32]));
+        object
+    }
+
+    #[test]
+    fn spills_to_the_next_directory() {
+        let root = std::env::temp_dir().join(format!("0s-spill-{}", rand::random::<u64>()));
+        let dir = |name: &str| CacheDir {
+            path: root.join(name),
+            max_size: object(0).as_inner().len() as u64,
+            min_free_percent: 0,
+        };
+
+        let upstream = InMemoryBackend::shared();
+        let cache = SpillCache::new(vec![dir("first"), dir("second")], upstream.clone()).unwrap();
+
+        for id in 1..=3 {
+            cache.write_object(&object(id)).unwrap();
+        }
+
+        let cached = |name: &str, id: u8| {
+            root.join(name)
+                .join(ObjectId::from_bytes(&[id; 32]).to_string())
+                .exists()
+        };
+        assert!(cached("second", 2));
+        // once both are full, the oldest object makes room
+        assert!(!cached("first", 1));
+        assert!(cached("first", 3));
+
+        // evicted objects are still in the upstream
+        cache.read_object(&ObjectId::from_bytes(&[1; 32])).unwrap();
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use chrono::Timelike;
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    num::NonZeroU32,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// Bandwidth limits that depend on the time of day
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthSchedule {
+    /// Limit outside of the windows. Unlimited if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_kib_per_sec: Option<NonZeroU32>,
+
+    /// The first window that covers the current time overrides the
+    /// limit
+    #[serde(default, rename = "window", skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<BandwidthWindow>,
+}
+
+/// A time range in local time, like 01:00 to 06:00. Ranges that end
+/// before they start wrap around midnight.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthWindow {
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+
+    /// Unlimited if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_kib_per_sec: Option<NonZeroU32>,
+}
+
+impl BandwidthWindow {
+    fn covers(&self, time: TimeOfDay) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+impl BandwidthSchedule {
+    /// The limit in bytes per second at `time`, if there's any
+    pub fn limit_at(&self, time: TimeOfDay) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|w| w.covers(time))
+            .map_or(self.max_kib_per_sec, |w| w.max_kib_per_sec)
+            .map(|kib| u64::from(kib.get()) * 1024)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_kib_per_sec.is_none() && self.windows.iter().all(|w| w.max_kib_per_sec.is_none())
+    }
+}
+
+/// Minutes since midnight, written as `HH:MM`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u32,
+}
+
+impl TimeOfDay {
+    pub fn now() -> Self {
+        let now = chrono::Local::now();
+        Self {
+            minutes: now.hour() * 60 + now.minute(),
+        }
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid time `{s}`, expected eg. `01:30`");
+
+        let (hours, minutes) = s.trim().split_once(':').ok_or_else(invalid)?;
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+
+        // 24:00 is the end of the day
+        if minutes > 59 || hours > 24 || (hours == 24 && minutes > 0) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(value: TimeOfDay) -> Self {
+        value.to_string()
+    }
+}
+
+/// Limit the traffic to and from the upstream to the bandwidth the
+/// schedule allows at the time.
+///
+/// Reads and writes share the limit. The storage threads sleep when
+/// they run over it, which allows bursts of up to one second's worth
+/// of traffic.
+pub struct Throttled {
+    upstream: Arc<dyn Backend>,
+    schedule: BandwidthSchedule,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that can be sent without waiting. Negative when the
+    /// traffic ran over the limit.
+    available: f64,
+    updated: Instant,
+}
+
+impl Throttled {
+    pub fn new(upstream: Arc<dyn Backend>, schedule: BandwidthSchedule) -> Arc<Self> {
+        Arc::new(Self {
+            upstream,
+            schedule,
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                updated: Instant::now(),
+            }),
+        })
+    }
+
+    fn throttle(&self, bytes: usize) {
+        let Some(limit) = self.schedule.limit_at(TimeOfDay::now()) else {
+            return;
+        };
+        let limit = limit as f64;
+
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * limit;
+
+            bucket.available = (bucket.available + refill).min(limit) - bytes as f64;
+            bucket.updated = now;
+
+            Duration::from_secs_f64((-bucket.available).max(0.0) / limit)
+        };
+
+        thread::sleep(wait);
+    }
+}
+
+impl Backend for Throttled {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.throttle(object.as_inner().len());
+        self.upstream.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let object = self.upstream.read_object(id)?;
+        self.throttle(object.as_inner().len());
+        Ok(object)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BandwidthSchedule;
+
+    #[test]
+    fn windows_override_the_default_limit() {
+        let schedule: BandwidthSchedule = serde_json::from_str(
+            r#"{
+                "max_kib_per_sec": 2048,
+                "window": [
+                    { "from": "01:00", "to": "06:00" },
+                    { "from": "22:00", "to": "01:00", "max_kib_per_sec": 512 }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let at = |time: &str| schedule.limit_at(time.parse().unwrap());
+        assert_eq!(at("00:30"), Some(512 * 1024));
+        assert_eq!(at("01:00"), None);
+        assert_eq!(at("05:59"), None);
+        assert_eq!(at("06:00"), Some(2048 * 1024));
+        assert_eq!(at("23:00"), Some(512 * 1024));
+    }
+}
@@ -0,0 +1,55 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::sync::Arc;
+use tracing::debug_span;
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// Emit a tracing span for every operation on the upstream backend.
+pub struct Traced {
+    upstream: Arc<dyn Backend>,
+}
+
+impl Traced {
+    pub fn new(upstream: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self { upstream })
+    }
+}
+
+impl Backend for Traced {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let _span = debug_span!(
+            "write_object",
+            id = ?object.id(),
+            bytes = object.as_inner().len()
+        )
+        .entered();
+        self.upstream.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let _span = debug_span!("read_object", ?id).entered();
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        let _span = debug_span!("preload", objects = objects.len()).entered();
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        let _span = debug_span!("delete", objects = objects.len()).entered();
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let _span = debug_span!("sync").entered();
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
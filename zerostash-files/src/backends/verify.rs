@@ -0,0 +1,223 @@
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+    Digest, Hasher,
+};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, error};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/// Verify that every object written to the upstream round-trips.
+///
+/// A digest of every object is recorded on write. On `sync()`, after
+/// the upstream has flushed all pending writes, each object is read
+/// back and its digest compared to the recorded one, so a commit
+/// fails instead of silently leaving a corrupt object behind.
+///
+/// A commit writes the root object of the stash last, so the last
+/// object written is held back until everything before it is
+/// verified. A corrupt object is never published by a new root.
+pub struct VerifyWrites {
+    upstream: Arc<dyn Backend>,
+    held: Mutex<Option<WriteObject>>,
+    pending: Mutex<Vec<(ObjectId, Digest)>>,
+}
+
+impl VerifyWrites {
+    pub fn new(upstream: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self {
+            upstream,
+            held: Mutex::default(),
+            pending: Mutex::default(),
+        })
+    }
+
+    fn pass_on(&self, object: &WriteObject) -> Result<()> {
+        self.upstream.write_object(object)?;
+        self.pending
+            .lock()
+            .unwrap()
+            .push((*object.id(), digest(object.as_inner())));
+
+        Ok(())
+    }
+
+    fn release_held(&self) -> Result<()> {
+        let held = self.held.lock().unwrap().take();
+        match held {
+            Some(object) => self.pass_on(&object),
+            None => Ok(()),
+        }
+    }
+
+    fn verify_pending(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        for (id, expected) in pending {
+            let stored = self.upstream.read_object(&id)?;
+
+            if digest(stored.as_inner()) != expected {
+                error!(?id, "object failed to round-trip");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("object {id:?} does not match the uploaded data"),
+                )
+                .into());
+            }
+
+            debug!(?id, "object verified");
+        }
+
+        Ok(())
+    }
+}
+
+fn digest(data: &[u8]) -> Digest {
+    *Hasher::new().update(data).finalize().as_bytes()
+}
+
+impl Backend for VerifyWrites {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let mut copy = WriteObject::default();
+        copy.set_id(*object.id());
+        copy.as_inner_mut().copy_from_slice(object.as_inner());
+
+        let previous = self.held.lock().unwrap().replace(copy);
+        match previous {
+            Some(previous) => self.pass_on(&previous),
+            None => Ok(()),
+        }
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let is_held = self
+            .held
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|held| held.id() == id);
+        if is_held {
+            self.release_held()?;
+        }
+
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()?;
+        if let Err(error) = self.verify_pending() {
+            // don't let the root publish a corrupt object
+            self.held.lock().unwrap().take();
+            return Err(error);
+        }
+
+        self.release_held()?;
+        self.upstream.sync()?;
+        self.verify_pending()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Result, VerifyWrites};
+    use crate::{Entry, Files};
+    use infinitree::{
+        backends::Backend,
+        crypto::UsernamePassword,
+        object::{ObjectId, ReadObject, WriteObject},
+        Infinitree,
+    };
+    use std::sync::Arc;
+
+    fn key() -> infinitree::Key {
+        Arc::new(
+            UsernamePassword::with_credentials("verify_writes".to_string(), "password".to_string())
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn committed_objects_are_verified() {
+        let storage = VerifyWrites::new(infinitree::backends::test::InMemoryBackend::shared());
+
+        let tree = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        tree.index()
+            .tree
+            .insert_file("test/file.rs", Entry::default())
+            .unwrap();
+
+        tree.commit(None).unwrap();
+        tree.backend().sync().unwrap();
+        assert!(storage.pending.lock().unwrap().is_empty());
+        assert!(storage.held.lock().unwrap().is_none());
+
+        Infinitree::<Files>::open(storage, key()).unwrap();
+    }
+
+    /// Flips a byte of every object it stores
+    struct CorruptWrites(Arc<dyn Backend>);
+
+    impl Backend for CorruptWrites {
+        fn write_object(&self, object: &WriteObject) -> Result<()> {
+            let mut corrupt = WriteObject::default();
+            corrupt.set_id(*object.id());
+            corrupt.as_inner_mut().copy_from_slice(object.as_inner());
+            corrupt.as_inner_mut()[0] ^= 0xff;
+
+            self.0.write_object(&corrupt)
+        }
+
+        fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+            self.0.read_object(id)
+        }
+
+        fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+            self.0.preload(objects)
+        }
+
+        fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+            self.0.delete(objects)
+        }
+
+        fn sync(&self) -> Result<()> {
+            self.0.sync()
+        }
+
+        fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+            self.0.keep_warm(objects)
+        }
+    }
+
+    #[test]
+    fn corrupt_objects_fail_the_commit_before_the_root_is_written() {
+        let storage = VerifyWrites::new(Arc::new(CorruptWrites(
+            infinitree::backends::test::InMemoryBackend::shared(),
+        )));
+
+        let tree = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        tree.index()
+            .tree
+            .insert_file("test/file.rs", Entry::default())
+            .unwrap();
+
+        tree.commit(None).unwrap();
+        assert!(tree.backend().sync().is_err());
+        assert!(Infinitree::<Files>::open(storage, key()).is_err());
+    }
+}
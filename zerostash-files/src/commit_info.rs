@@ -0,0 +1,138 @@
+//! Who made a commit, and what it changed.
+//!
+//! Infinitree only keeps the time and the message of commits, so the
+//! rest is stored in the index. A commit doesn't have an ID until it's
+//! written, so its info is keyed by the commit before it.
+use crate::{activity::Snapshot, Files};
+use infinitree::{tree::CommitId, Infinitree};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitInfo {
+    /// The latest commit when this one was made
+    pub parent: Option<CommitId>,
+    pub author: String,
+    pub hostname: String,
+    /// Files that weren't in the stash before
+    pub files_added: u64,
+    /// Files that changed since the commit before
+    pub files_changed: u64,
+    /// Size of the new chunks the commit stored
+    pub bytes_written: u64,
+    /// Saved the progress of a backup that wasn't finished yet
+    #[serde(default)]
+    pub checkpoint: bool,
+}
+
+impl CommitInfo {
+    /// The current user and host, and the files stored by the next
+    /// commit of `stash`
+    pub fn new(stash: &Infinitree<Files>, activity: &Snapshot) -> Self {
+        Self {
+            parent: stash.commit_list().iter().last().map(|c| c.id),
+            author: author(),
+            hostname: hostname(),
+            files_added: activity.added,
+            files_changed: activity.changed,
+            bytes_written: activity.bytes_stored,
+            checkpoint: false,
+        }
+    }
+}
+
+/// The checkpoints in the history of `stash`, which the commit that
+/// finished their backup supersedes.
+///
+/// The commit info has to be loaded.
+pub fn checkpoints(stash: &Infinitree<Files>) -> HashSet<CommitId> {
+    let mut parent = None;
+    let mut checkpoints = HashSet::new();
+
+    for commit in stash.commit_list().iter() {
+        if stash
+            .index()
+            .info_after(parent)
+            .is_some_and(|info| info.checkpoint)
+        {
+            checkpoints.insert(commit.id);
+        }
+        parent = Some(commit.id);
+    }
+
+    checkpoints
+}
+
+impl Files {
+    /// Keep `info` for the next commit
+    pub fn record_commit(&self, info: CommitInfo) {
+        let parent = info.parent;
+        if self.commit_info.contains(&parent) {
+            self.commit_info.update_with(parent, |_| info);
+        } else {
+            self.commit_info.insert(parent, info);
+        }
+    }
+
+    /// Info about the commit made after `parent`, or the first commit
+    /// if there's no parent
+    pub fn info_after(&self, parent: Option<CommitId>) -> Option<CommitInfo> {
+        self.commit_info
+            .get(&parent)
+            .map(|info| info.as_ref().clone())
+    }
+}
+
+pub(crate) fn author() -> String {
+    #[cfg(unix)]
+    if let Ok(Some(user)) = nix::unistd::User::from_uid(nix::unistd::getuid()) {
+        return user.name;
+    }
+
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+pub(crate) fn hostname() -> String {
+    #[cfg(unix)]
+    if let Ok(name) = nix::unistd::gethostname() {
+        return name.to_string_lossy().into_owned();
+    }
+
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::CommitInfo;
+    use crate::{activity::Snapshot, Files};
+    use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+    #[test]
+    fn info_follows_the_commit_before() {
+        let stash = Infinitree::<Files>::empty(
+            InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("info".to_string(), "password".to_string()).unwrap(),
+        )
+        .unwrap();
+        let activity = |added| Snapshot {
+            added,
+            ..Default::default()
+        };
+
+        let first = CommitInfo::new(&stash, &activity(1));
+        stash.index().record_commit(first.clone());
+        stash.commit(None).unwrap();
+        let second = CommitInfo::new(&stash, &activity(2));
+        stash.index().record_commit(second.clone());
+        stash.commit(None).unwrap();
+
+        let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+        let index = stash.index();
+        assert_eq!(second.parent, Some(commits[0]));
+        assert_eq!(index.info_after(None), Some(first));
+        assert_eq!(index.info_after(Some(commits[0])).unwrap().files_added, 2);
+        assert_eq!(index.info_after(Some(commits[1])), None);
+    }
+}
@@ -0,0 +1,135 @@
+use infinitree::Digest;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of chunks a new stash's filter is sized for
+pub const DEFAULT_FILTER_CAPACITY: usize = 1 << 20;
+
+const BITS_PER_ITEM: usize = 10;
+const HASHES: u32 = 7;
+
+/// Bloom filter over the digests in the chunk index.
+///
+/// A negative answer means the chunk is definitely not in the index,
+/// so lookups can be skipped. A filter with no capacity is disabled,
+/// and reports every digest as possibly present.
+///
+/// Missing entries only ever cost deduplication, never data: a chunk
+/// the filter doesn't know about is simply written again.
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct DigestFilter {
+    bits: Vec<AtomicU64>,
+    items: AtomicU64,
+}
+
+impl DigestFilter {
+    pub fn with_capacity(items: usize) -> Self {
+        let words = (items.max(1) * BITS_PER_ITEM).div_ceil(64);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            items: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.bits.is_empty()
+    }
+
+    /// Number of digests the filter was sized for
+    pub fn capacity(&self) -> usize {
+        self.bits.len() * 64 / BITS_PER_ITEM
+    }
+
+    /// Number of digests inserted
+    pub fn len(&self) -> usize {
+        self.items.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// More digests were inserted than the filter was sized for, and
+    /// the rate of false positives is growing.
+    pub fn is_saturated(&self) -> bool {
+        self.is_enabled() && self.len() > self.capacity()
+    }
+
+    pub fn insert(&self, digest: &Digest) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut new = false;
+        for (word, mask) in self.positions(digest) {
+            new |= self.bits[word].fetch_or(mask, Ordering::Relaxed) & mask == 0;
+        }
+
+        if new {
+            self.items.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn may_contain(&self, digest: &Digest) -> bool {
+        !self.is_enabled()
+            || self
+                .positions(digest)
+                .all(|(word, mask)| self.bits[word].load(Ordering::Relaxed) & mask != 0)
+    }
+
+    fn positions(&self, digest: &Digest) -> impl Iterator<Item = (usize, u64)> {
+        // digests are uniformly distributed, so we can use them
+        // directly for double hashing
+        let h1 = u64::from_le_bytes(digest[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+        let nbits = self.bits.len() as u64 * 64;
+
+        (0..HASHES as u64).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % nbits;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DigestFilter;
+    use infinitree::Digest;
+
+    // spread the counter over the whole digest
+    fn digest(i: u32) -> Digest {
+        let mut d = [0u8; 32];
+        let mut x = (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        for chunk in d.chunks_mut(8) {
+            x ^= x >> 31;
+            x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            chunk.copy_from_slice(&x.to_le_bytes());
+        }
+        d
+    }
+
+    #[test]
+    fn no_false_negatives_and_few_false_positives() {
+        let filter = DigestFilter::with_capacity(10_000);
+        for i in 0..10_000 {
+            filter.insert(&digest(i));
+        }
+
+        assert!((0..10_000).all(|i| filter.may_contain(&digest(i))));
+
+        let false_positives = (10_000..20_000)
+            .filter(|i| filter.may_contain(&digest(*i)))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn disabled_filter_contains_everything() {
+        let filter = DigestFilter::default();
+        filter.insert(&digest(1));
+
+        assert!(!filter.is_enabled());
+        assert!(filter.may_contain(&digest(2)));
+        assert!(filter.is_empty());
+    }
+}
@@ -33,6 +33,8 @@ pub enum EntryError {
         #[from]
         source: SystemTimeError,
     },
+    #[error("Creating devices needs root")]
+    NeedsRoot,
     #[error("IO error: {source}")]
     IO {
         #[from]
@@ -52,6 +54,16 @@ pub enum FileType {
     File,
     Directory,
     Symlink(PathBuf),
+    /// Another name of a regular file, stored at the path in the same
+    /// commit. The contents are the same, and so are the chunks.
+    Hardlink(String),
+    /// Named pipe
+    Fifo,
+    Socket,
+    /// Character device with its device number
+    CharDevice(u64),
+    /// Block device with its device number
+    BlockDevice(u64),
 }
 
 impl Default for FileType {
@@ -66,12 +78,24 @@ impl FileType {
     }
 
     pub fn is_file(&self) -> bool {
-        matches!(self, Self::File)
+        matches!(self, Self::File | Self::Hardlink(_))
+    }
+
+    pub fn is_hardlink(&self) -> bool {
+        matches!(self, Self::Hardlink(_))
     }
 
     pub fn is_dir(&self) -> bool {
         matches!(self, Self::Directory)
     }
+
+    /// Whether it's a FIFO, a socket, or a device, which has no contents
+    pub fn is_special(&self) -> bool {
+        matches!(
+            self,
+            Self::Fifo | Self::Socket | Self::CharDevice(_) | Self::BlockDevice(_)
+        )
+    }
 }
 
 #[derive(clap::Args, Clone, Debug, Default)]
@@ -86,6 +110,33 @@ pub struct PreserveMetadata {
     /// Preserve modification and creation times.
     #[clap(short = 't', long = "preserve-times", default_value = "true")]
     pub times: bool,
+
+    /// Preserve extended attributes, including SELinux labels and file
+    /// capabilities. Some of them need root to restore.
+    #[clap(long = "preserve-xattrs", default_value = "true")]
+    pub xattrs: bool,
+
+    /// Preserve POSIX ACLs, and other attributes in the `system.`
+    /// namespace.
+    #[clap(long = "preserve-acls", default_value = "true")]
+    pub acls: bool,
+
+    /// Preserve NTFS security descriptors on Windows, with the owner,
+    /// the group and the DACL. Restoring the owner needs administrator
+    /// rights.
+    #[clap(long = "preserve-security", default_value = "true")]
+    pub security: bool,
+}
+
+impl PreserveMetadata {
+    /// Whether the extended attribute `name` is preserved
+    pub fn keeps_xattr(&self, name: &str) -> bool {
+        if name.starts_with("system.") {
+            self.acls
+        } else {
+            self.xattrs
+        }
+    }
 }
 
 pub(crate) fn normalize_filename(path: &impl AsRef<Path>) -> Result<String, EntryError> {
@@ -117,6 +168,36 @@ pub struct Entry {
     pub name: String,
 
     pub chunks: BTreeMap<u64, Arc<ChunkPointer>>,
+
+    /// Extended attributes by name, including ACLs and capabilities,
+    /// or alternate data streams on Windows
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+
+    /// BSD file flags, like `UF_HIDDEN`, on macOS
+    #[serde(default)]
+    pub bsd_flags: Option<u32>,
+
+    /// Creation time in seconds and nanoseconds, on macOS
+    #[serde(default)]
+    pub created: Option<(i64, u32)>,
+
+    /// File attributes, like hidden or system, on Windows
+    #[serde(default)]
+    pub windows_attributes: Option<u32>,
+
+    /// Self-relative NTFS security descriptor, on Windows
+    #[serde(default)]
+    pub security_descriptor: Option<Vec<u8>>,
+
+    /// The inode the file was stored from
+    #[serde(default)]
+    pub inode: Option<u64>,
+
+    /// Status change time in seconds and nanoseconds when the file was
+    /// stored
+    #[serde(default)]
+    pub ctime: Option<(i64, u32)>,
 }
 
 impl From<&Entry> for PathBuf {
@@ -143,16 +224,37 @@ impl PartialEq for Entry {
             && self.readonly == other.readonly
             && self.name == other.name
             && self.file_type == other.file_type
+            && self.xattrs == other.xattrs
+            && self.bsd_flags == other.bsd_flags
+            && self.created == other.created
+            && self.windows_attributes == other.windows_attributes
+            && self.security_descriptor == other.security_descriptor
     }
 }
 
 impl Entry {
+    /// Whether `self`, read from disk, is the same file that `indexed`
+    /// was stored from, so its contents don't need to be read again.
+    ///
+    /// The inode and the status change time aren't part of the
+    /// comparison of entries, as they change on every restore, but
+    /// here they catch files that were replaced or rewritten with the
+    /// same size and modification time. Entries stored without them
+    /// only compare the rest.
+    pub fn is_unchanged(&self, indexed: &Entry) -> bool {
+        self == indexed
+            && (indexed.inode.is_none() || self.inode == indexed.inode)
+            && (indexed.ctime.is_none() || self.ctime == indexed.ctime)
+    }
+
     #[cfg(windows)]
     pub fn from_metadata(
         metadata: fs::Metadata,
         path: &impl AsRef<Path>,
         preserve: &PreserveMetadata,
     ) -> Result<Entry, EntryError> {
+        use std::os::windows::fs::MetadataExt;
+
         let (unix_secs, unix_nanos) = if preserve.times {
             to_unix_mtime(&metadata)?
         } else {
@@ -182,11 +284,37 @@ impl Entry {
             },
 
             readonly: if_yes!(preserve.permissions, metadata.permissions().readonly()),
+            windows_attributes: if_yes!(
+                preserve.permissions,
+                metadata.file_attributes() & crate::windows::STORED_ATTRIBUTES
+            ),
+            security_descriptor: if preserve.security {
+                crate::windows::security_descriptor(path.as_ref())
+                    .map_err(|error| {
+                        tracing::warn!(%error, path = ?path.as_ref(), "failed to read security descriptor")
+                    })
+                    .ok()
+            } else {
+                None
+            },
+            xattrs: if preserve.xattrs && metadata.is_file() {
+                crate::windows::streams(path.as_ref())
+                    .map_err(|error| {
+                        tracing::warn!(%error, path = ?path.as_ref(), "failed to read alternate data streams")
+                    })
+                    .unwrap_or_default()
+            } else {
+                BTreeMap::new()
+            },
 
             size: metadata.len(),
             name,
 
-            chunks: Vec::new(),
+            chunks: Default::default(),
+            bsd_flags: None,
+            created: None,
+            inode: None,
+            ctime: None,
         })
     }
 
@@ -212,6 +340,7 @@ impl Entry {
             .to_str()
             .ok_or(EntryError::NonUtf8Path)?
             .to_string();
+        let (bsd_flags, created) = macos_metadata(&metadata, preserve);
 
         Ok(Entry {
             unix_secs,
@@ -226,21 +355,57 @@ impl Entry {
             } else if metadata.is_dir() {
                 FileType::Directory
             } else {
-                FileType::File
+                special_type(&metadata).unwrap_or(FileType::File)
             },
 
-            size: metadata.len(),
+            size: if is_special(&metadata) {
+                0
+            } else {
+                metadata.len()
+            },
             name,
 
             chunks: Default::default(),
+            xattrs: read_xattrs(path.as_ref(), preserve),
+            bsd_flags,
+            created,
+            windows_attributes: None,
+            security_descriptor: None,
+            inode: Some(metadata.ino()),
+            ctime: Some((metadata.ctime(), metadata.ctime_nsec() as u32)),
         })
     }
 
-    #[cfg(windows)]
+    /// Create the entry at `path`, discarding the contents of any
+    /// existing file.
+    ///
+    /// Regular files are returned to write their contents, and only
+    /// get their metadata from [`Entry::restore_metadata`] afterwards.
+    /// Everything else is restored with its metadata.
     pub fn restore_to(
         &self,
         path: &impl AsRef<Path>,
         preserve: &PreserveMetadata,
+    ) -> Result<Option<fs::File>, EntryError> {
+        self.restore(path, preserve, true)
+    }
+
+    /// Like [`Entry::restore_to`], but an existing file keeps its
+    /// contents up to the new size, so they can be updated in place.
+    pub fn restore_over(
+        &self,
+        path: &impl AsRef<Path>,
+        preserve: &PreserveMetadata,
+    ) -> Result<Option<fs::File>, EntryError> {
+        self.restore(path, preserve, false)
+    }
+
+    #[cfg(windows)]
+    fn restore(
+        &self,
+        path: &impl AsRef<Path>,
+        preserve: &PreserveMetadata,
+        truncate: bool,
     ) -> Result<Option<fs::File>, EntryError> {
         use FileType::*;
 
@@ -249,15 +414,55 @@ impl Entry {
                 fs::create_dir_all(path)?;
                 fs::File::open(path)?
             }
-            File => {
-                let file = open_file(path)?;
+            File | Hardlink(_) => {
+                let file = open_file(path, truncate)?;
                 file.set_len(self.size)?;
                 file
             }
             Symlink(ref pointed_to) => open_symlink(path, pointed_to)?,
+            Fifo | Socket | CharDevice(_) | BlockDevice(_) => {
+                return Err(io::Error::from(io::ErrorKind::Unsupported).into())
+            }
         };
 
         file.set_len(self.size)?;
+        if self.file_type.is_file() {
+            return Ok(Some(file));
+        }
+
+        self.restore_metadata(&file, path, preserve)?;
+        Ok(None)
+    }
+
+    /// Apply the metadata of the entry to `file` at `path`, once its
+    /// contents are written.
+    #[cfg(windows)]
+    pub fn restore_metadata(
+        &self,
+        file: &fs::File,
+        path: &impl AsRef<Path>,
+        preserve: &PreserveMetadata,
+    ) -> Result<(), EntryError> {
+        let path = path.as_ref();
+
+        // streams can't be written once the file is read-only
+        if preserve.xattrs {
+            for (name, contents) in self.xattrs.iter() {
+                if let Err(error) = crate::windows::write_stream(path, name, contents) {
+                    tracing::warn!(%error, name, ?path, "failed to restore alternate data stream");
+                }
+            }
+        }
+
+        if let Some(descriptor) = self
+            .security_descriptor
+            .as_ref()
+            .filter(|_| preserve.security)
+        {
+            if let Err(error) = crate::windows::set_security_descriptor(path, descriptor) {
+                tracing::warn!(%error, ?path, "failed to restore security descriptor");
+            }
+        }
 
         if let Some(readonly) = self.readonly {
             if preserve.permissions {
@@ -268,23 +473,20 @@ impl Entry {
             }
         }
 
-        Ok(if self.file_type.is_file() {
-            Some(file)
-        } else {
-            None
-        })
+        if let Some(attributes) = self.windows_attributes.filter(|_| preserve.permissions) {
+            crate::windows::set_attributes(path, attributes)?;
+        }
+
+        Ok(())
     }
 
     #[cfg(unix)]
-    pub fn restore_to(
+    fn restore(
         &self,
         path: &impl AsRef<Path>,
         preserve: &PreserveMetadata,
+        truncate: bool,
     ) -> Result<Option<fs::File>, EntryError> {
-        use std::{
-            os::unix::{fs::PermissionsExt, prelude::AsRawFd},
-            time::{Duration, SystemTime},
-        };
         use FileType::*;
 
         let file = match self.file_type {
@@ -292,12 +494,41 @@ impl Entry {
                 fs::create_dir_all(path)?;
                 fs::File::open(path)?
             }
-            File => {
-                let file = open_file(path)?;
+            File | Hardlink(_) => {
+                let file = open_file(path, truncate)?;
                 file.set_len(self.size)?;
                 file
             }
             Symlink(ref pointed_to) => open_symlink(path, pointed_to)?,
+            Fifo | Socket | CharDevice(_) | BlockDevice(_) => {
+                return self.restore_special(path.as_ref(), preserve)
+            }
+        };
+
+        if self.file_type.is_file() {
+            return Ok(Some(file));
+        }
+
+        self.restore_metadata(&file, path, preserve)?;
+        Ok(None)
+    }
+
+    /// Apply the metadata of the entry to `file` at `path`, once its
+    /// contents are written.
+    ///
+    /// Writing to a file changes its modification time, and may
+    /// clear its setuid bits and capabilities, so this has to come
+    /// after the last write.
+    #[cfg(unix)]
+    pub fn restore_metadata(
+        &self,
+        file: &fs::File,
+        path: &impl AsRef<Path>,
+        preserve: &PreserveMetadata,
+    ) -> Result<(), EntryError> {
+        use std::{
+            os::unix::{fs::PermissionsExt, prelude::AsRawFd},
+            time::{Duration, SystemTime},
         };
 
         if preserve.permissions {
@@ -320,11 +551,119 @@ impl Entry {
             )?;
         }
 
-        Ok(if self.file_type.is_file() {
-            Some(file)
-        } else {
-            None
-        })
+        // changing the owner clears capabilities, so they come last
+        self.restore_xattrs(path.as_ref(), preserve);
+
+        // flags like UF_IMMUTABLE would stop the other changes
+        #[cfg(target_os = "macos")]
+        if !self.file_type.is_symlink() {
+            self.restore_macos(file, path.as_ref(), preserve)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a FIFO, socket, or device node with `mknod`. Devices need
+    /// root, and fail with [`EntryError::NeedsRoot`] without it, so the
+    /// restore can leave them to a privileged pass.
+    #[cfg(unix)]
+    fn restore_special(
+        &self,
+        path: &Path,
+        preserve: &PreserveMetadata,
+    ) -> Result<Option<fs::File>, EntryError> {
+        use nix::{
+            errno::Errno,
+            fcntl::AtFlags,
+            sys::{
+                stat::{self, FchmodatFlags, Mode, SFlag, UtimensatFlags},
+                time::TimeSpec,
+            },
+            unistd::{self, Gid, Uid},
+        };
+        use std::time::{Duration, SystemTime};
+        use FileType::*;
+
+        let (kind, dev) = match self.file_type {
+            Fifo => (SFlag::S_IFIFO, 0),
+            Socket => (SFlag::S_IFSOCK, 0),
+            CharDevice(dev) => (SFlag::S_IFCHR, dev),
+            BlockDevice(dev) => (SFlag::S_IFBLK, dev),
+            _ => unreachable!("not a special file"),
+        };
+        let mode = Mode::from_bits_truncate(self.unix_perm.unwrap_or(0o600) as _);
+
+        match fs::remove_file(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            result => result?,
+        }
+
+        match stat::mknod(path, kind, mode, dev as _) {
+            Err(Errno::EPERM) if dev != 0 => return Err(EntryError::NeedsRoot),
+            result => result?,
+        }
+
+        // mknod applies the umask
+        if preserve.permissions {
+            stat::fchmodat(None, path, mode, FchmodatFlags::FollowSymlink)?;
+        }
+
+        if preserve.times {
+            let atime = TimeSpec::from(SystemTime::now().duration_since(UNIX_EPOCH)?);
+            let mtime = TimeSpec::from(Duration::new(self.unix_secs as u64, self.unix_nanos));
+            stat::utimensat(None, path, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+        }
+
+        if preserve.ownership {
+            unistd::fchownat(
+                None,
+                path,
+                self.unix_uid.map(Uid::from_raw),
+                self.unix_gid.map(Gid::from_raw),
+                AtFlags::AT_SYMLINK_NOFOLLOW,
+            )?;
+        }
+
+        self.restore_xattrs(path, preserve);
+        Ok(None)
+    }
+
+    /// Set the creation time and the BSD flags
+    #[cfg(target_os = "macos")]
+    fn restore_macos(
+        &self,
+        file: &fs::File,
+        path: &Path,
+        preserve: &PreserveMetadata,
+    ) -> Result<(), EntryError> {
+        use std::{os::macos::fs::FileTimesExt, time::Duration};
+
+        if let Some((secs, nanos)) = self.created.filter(|_| preserve.times) {
+            let created = UNIX_EPOCH + Duration::new(secs as u64, nanos);
+            file.set_times(fs::FileTimes::new().set_created(created))?;
+        }
+
+        if let Some(flags) = self.bsd_flags.filter(|_| preserve.permissions) {
+            nix::unistd::chflags(path, nix::sys::stat::FileFlag::from_bits_retain(flags as _))?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn restore_xattrs(&self, path: &Path, preserve: &PreserveMetadata) {
+        for (name, value) in self.xattrs.iter() {
+            if !preserve.keeps_xattr(name) {
+                continue;
+            }
+            if let Err(error) = xattr::set(path, name, value) {
+                tracing::warn!(%error, name, ?path, "failed to restore extended attribute");
+            }
+        }
     }
 }
 
@@ -393,10 +732,10 @@ fn open_symlink(
     }
 }
 
-fn open_file(path: impl AsRef<Path> + Copy) -> Result<fs::File, io::Error> {
+fn open_file(path: impl AsRef<Path> + Copy, truncate: bool) -> Result<fs::File, io::Error> {
     match fs::OpenOptions::new()
         .create(true)
-        .truncate(true)
+        .truncate(truncate)
         .write(true)
         .read(true)
         .open(path)
@@ -405,7 +744,7 @@ fn open_file(path: impl AsRef<Path> + Copy) -> Result<fs::File, io::Error> {
         Err(err) if err.kind() == io::ErrorKind::NotFound => {
             if let Some(parent) = path.as_ref().parent() {
                 fs::create_dir_all(parent)?;
-                open_file(path)
+                open_file(path, truncate)
             } else {
                 Err(err)
             }
@@ -414,8 +753,97 @@ fn open_file(path: impl AsRef<Path> + Copy) -> Result<fs::File, io::Error> {
     }
 }
 
+/// The BSD flags and the creation time of a file on macOS
+#[cfg(target_os = "macos")]
+fn macos_metadata(
+    metadata: &fs::Metadata,
+    preserve: &PreserveMetadata,
+) -> (Option<u32>, Option<(i64, u32)>) {
+    use std::os::macos::fs::MetadataExt;
+
+    (
+        if_yes!(preserve.permissions, metadata.st_flags()),
+        if_yes!(
+            preserve.times,
+            (metadata.st_birthtime(), metadata.st_birthtime_nsec() as u32)
+        ),
+    )
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn macos_metadata(
+    _metadata: &fs::Metadata,
+    _preserve: &PreserveMetadata,
+) -> (Option<u32>, Option<(i64, u32)>) {
+    (None, None)
+}
+
+/// The type of a FIFO, socket, or device
+#[cfg(unix)]
+fn special_type(metadata: &fs::Metadata) -> Option<FileType> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        Some(FileType::Fifo)
+    } else if file_type.is_socket() {
+        Some(FileType::Socket)
+    } else if file_type.is_char_device() {
+        Some(FileType::CharDevice(metadata.rdev()))
+    } else if file_type.is_block_device() {
+        Some(FileType::BlockDevice(metadata.rdev()))
+    } else {
+        None
+    }
+}
+
+/// Whether `metadata` is of a FIFO, socket, or device, which are
+/// stored without contents
+#[cfg(unix)]
+pub(crate) fn is_special(metadata: &fs::Metadata) -> bool {
+    special_type(metadata).is_some()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_special(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// The extended attributes of `path` that `preserve` keeps, without
+/// following symlinks. The ones that can't be read are skipped.
+#[cfg(unix)]
+fn read_xattrs(path: &Path, preserve: &PreserveMetadata) -> BTreeMap<String, Vec<u8>> {
+    let mut xattrs = BTreeMap::new();
+    if !preserve.xattrs && !preserve.acls {
+        return xattrs;
+    }
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        // the file system doesn't have them
+        Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return xattrs,
+        Err(error) => {
+            tracing::warn!(%error, ?path, "failed to list extended attributes");
+            return xattrs;
+        }
+    };
+
+    for name in names {
+        let Some(name) = name.to_str().filter(|n| preserve.keeps_xattr(n)) else {
+            continue;
+        };
+        match xattr::get(path, name) {
+            Ok(Some(value)) => _ = xattrs.insert(name.to_string(), value),
+            Ok(None) => {}
+            Err(error) => tracing::warn!(%error, name, ?path, "failed to read extended attribute"),
+        }
+    }
+
+    xattrs
+}
+
 #[inline(always)]
-fn to_unix_mtime(m: &fs::Metadata) -> Result<(i64, u32), EntryError> {
+pub(crate) fn to_unix_mtime(m: &fs::Metadata) -> Result<(i64, u32), EntryError> {
     let mtime: chrono::DateTime<chrono::Utc> = m.modified()?.into();
     Ok((mtime.timestamp(), mtime.timestamp_subsec_nanos()))
 }
@@ -440,4 +868,153 @@ mod tests {
         assert_eq!(Path::new("home/a/b"), get_path("/home/a/b").as_path());
         assert_eq!(Path::new("./a/b"), get_path("./a/b").as_path());
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_files_are_changed() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("0s-unchanged-{}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        let (path, replacement) = (dir.join("a"), dir.join("b"));
+        let preserve = PreserveMetadata {
+            permissions: true,
+            times: true,
+            ..Default::default()
+        };
+
+        fs::write(&path, b"aaaa").unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        let stored = Entry::from_metadata(metadata.clone(), &path, &preserve).unwrap();
+
+        // same size and mtime, but a different inode
+        fs::write(&replacement, b"bbbb").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&replacement)
+            .unwrap()
+            .set_modified(metadata.modified().unwrap())
+            .unwrap();
+        fs::rename(&replacement, &path).unwrap();
+        let current =
+            Entry::from_metadata(fs::symlink_metadata(&path).unwrap(), &path, &preserve).unwrap();
+
+        assert_eq!(current, stored);
+        assert!(!current.is_unchanged(&stored));
+        assert!(stored.is_unchanged(&stored.clone()));
+
+        let legacy = Entry {
+            inode: None,
+            ctime: None,
+            ..stored
+        };
+        assert!(current.is_unchanged(&legacy));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifos_are_recreated() {
+        use super::{Entry, FileType, PreserveMetadata};
+        use std::os::unix::fs::FileTypeExt;
+
+        let dir = std::env::temp_dir().join(format!("0s-fifo-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fifo = dir.join("fifo");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::from_bits_truncate(0o640)).unwrap();
+
+        let preserve = PreserveMetadata {
+            permissions: true,
+            times: true,
+            ..Default::default()
+        };
+        let metadata = std::fs::symlink_metadata(&fifo).unwrap();
+        let entry = Entry::from_metadata(metadata, &fifo, &preserve).unwrap();
+        assert_eq!(entry.file_type, FileType::Fifo);
+        assert_eq!(entry.size, 0);
+
+        let restored = dir.join("restored/fifo");
+        assert!(entry.restore_to(&restored, &preserve).unwrap().is_none());
+        let metadata = std::fs::symlink_metadata(&restored).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(metadata.file_type().is_fifo());
+        let restored = Entry::from_metadata(metadata, &restored, &preserve).unwrap();
+        assert_eq!(restored.unix_perm, entry.unix_perm);
+        assert_eq!(restored.unix_secs, entry.unix_secs);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn devices_need_root() {
+        use super::{Entry, EntryError, FileType, PreserveMetadata};
+
+        if nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(format!("0s-device-{}", rand::random::<u64>()));
+        let entry = Entry {
+            file_type: FileType::CharDevice(0x103),
+            unix_perm: Some(0o20666),
+            ..Default::default()
+        };
+
+        let restored = entry.restore_to(&dir.join("null"), &PreserveMetadata::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(restored, Err(EntryError::NeedsRoot)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn times_are_restored_after_the_contents() {
+        use super::{Entry, PreserveMetadata};
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("0s-times-{}", rand::random::<u64>()));
+        let path = dir.join("file");
+        let preserve = PreserveMetadata {
+            times: true,
+            ..Default::default()
+        };
+        let entry = Entry {
+            size: 5,
+            unix_secs: 1654041600,
+            ..Default::default()
+        };
+
+        let mut file = entry.restore_to(&path, &preserve).unwrap().unwrap();
+        file.write_all(b"hello").unwrap();
+        entry.restore_metadata(&file, &path, &preserve).unwrap();
+        drop(file);
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let restored = Entry::from_metadata(metadata, &path, &preserve).unwrap();
+        assert_eq!(restored.unix_secs, entry.unix_secs);
+    }
+
+    #[test]
+    fn acls_are_preserved_separately() {
+        use super::PreserveMetadata;
+
+        let acls = PreserveMetadata {
+            acls: true,
+            ..Default::default()
+        };
+        assert!(acls.keeps_xattr("system.posix_acl_access"));
+        assert!(!acls.keeps_xattr("security.capability"));
+        assert!(!acls.keeps_xattr("user.comment"));
+
+        let xattrs = PreserveMetadata {
+            xattrs: true,
+            ..Default::default()
+        };
+        assert!(!xattrs.keeps_xattr("system.posix_acl_default"));
+        assert!(xattrs.keeps_xattr("security.selinux"));
+        assert!(xattrs.keeps_xattr("user.comment"));
+    }
 }
@@ -0,0 +1,178 @@
+//! Progress journals for long-running operations.
+//!
+//! A journal records the items an operation has finished, so a run
+//! that was interrupted can skip them when it's restarted. Items are
+//! appended one per line, and the journal is removed when the
+//! operation finishes.
+//!
+//! Restores journal the files they wrote, `check` the objects it
+//! verified, and `copy` the commits it copied. A commit resumes from
+//! its checkpoints in the stash instead, and `gc` only reads.
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub struct Journal {
+    path: PathBuf,
+    done: HashSet<String>,
+    log: Mutex<BufWriter<fs::File>>,
+}
+
+impl Journal {
+    /// Open the journal of `operation` on `scope` in `dir`, and load
+    /// the progress of a previous run, if there was one.
+    ///
+    /// `scope` identifies what the operation works on, for instance
+    /// the stash and the target directory of a restore.
+    pub fn open(dir: impl AsRef<Path>, operation: &str, scope: &str) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let path = dir.as_ref().join(format!(
+            "{operation}-{:016x}.journal",
+            seahash::hash(scope.as_bytes())
+        ));
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        // a crash may have left the last line half written
+        let complete = contents.rfind('\n').map(|pos| pos + 1).unwrap_or(0);
+        let mut lines = contents[..complete].lines();
+
+        let header = format!("{operation} {}", escape(scope));
+        let done = match lines.next() {
+            Some(line) if line == header => lines.map(unescape).collect(),
+            _ => HashSet::new(),
+        };
+
+        if done.is_empty() {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            writeln!(file, "{header}")?;
+        } else {
+            file.set_len(complete as u64)?;
+            file.seek(SeekFrom::End(0))?;
+        }
+
+        Ok(Self {
+            path,
+            done,
+            log: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of items finished by previous runs
+    pub fn resumed(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Whether a previous run finished `item`
+    pub fn is_done(&self, item: &str) -> bool {
+        self.done.contains(item)
+    }
+
+    /// Record that `item` is finished.
+    ///
+    /// Records are buffered until the next [`Journal::sync`].
+    pub fn record(&self, item: &str) -> io::Result<()> {
+        writeln!(self.log.lock().unwrap(), "{}", escape(item))
+    }
+
+    /// Make all records so far durable
+    pub fn sync(&self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.flush()?;
+        log.get_ref().sync_data()
+    }
+
+    /// The operation completed, remove the journal
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.log);
+        fs::remove_file(&self.path)
+    }
+}
+
+fn escape(item: &str) -> String {
+    item.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut item = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            item.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => item.push('\n'),
+            Some(other) => item.push(other),
+            None => item.push('\\'),
+        }
+    }
+
+    item
+}
+
+#[cfg(test)]
+mod test {
+    use super::Journal;
+    use std::io::Write;
+
+    fn state_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("0s-journal-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn resumes_from_complete_records() {
+        let dir = state_dir();
+
+        let journal = Journal::open(&dir, "restore", "stash:/target").unwrap();
+        assert_eq!(journal.resumed(), 0);
+        journal.record("a/file").unwrap();
+        journal.record("with\nnewline\\").unwrap();
+        journal.sync().unwrap();
+
+        // simulate a crash in the middle of a write
+        let path = journal.path().to_owned();
+        drop(journal);
+        write!(
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap(),
+            "partial"
+        )
+        .unwrap();
+
+        let journal = Journal::open(&dir, "restore", "stash:/target").unwrap();
+        assert_eq!(journal.resumed(), 2);
+        assert!(journal.is_done("a/file"));
+        assert!(journal.is_done("with\nnewline\\"));
+        assert!(!journal.is_done("partial"));
+
+        let other = Journal::open(&dir, "restore", "stash:/elsewhere").unwrap();
+        assert_eq!(other.resumed(), 0);
+
+        journal.finish().unwrap();
+        other.finish().unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
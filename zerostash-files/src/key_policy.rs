@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Sources of the key that opens a stash.
+///
+/// This is not a cipher choice: every stash is encrypted with the same
+/// construction, whichever key type unlocks it.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyType {
+    /// Key derived from a username and password
+    Symmetric,
+    /// Symmetric key combined with a Yubikey HMAC-SHA1 challenge-response
+    YubikeyHmacSha1,
+    /// Symmetric key combined with a FIDO2 hmac-secret
+    Fido2HmacSecret,
+    /// Public key encryption for separate read and write keys
+    CryptoBox,
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyType::Symmetric => "symmetric",
+            KeyType::YubikeyHmacSha1 => "yubikey_hmac_sha1",
+            KeyType::Fido2HmacSecret => "fido2_hmac_secret",
+            KeyType::CryptoBox => "crypto_box",
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum KeyPolicyError {
+    #[error("Key type `{0}` is not allowed by the key policy")]
+    KeyTypeNotAllowed(KeyType),
+}
+
+/// Restrict the key types that may be used to open a stash.
+///
+/// Once recorded in a stash, the policy applies to every client,
+/// regardless of their local configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct KeyPolicy {
+    /// Key types that are allowed to open the stash
+    #[serde(default = "KeyPolicy::default_key_types")]
+    pub allowed: Vec<KeyType>,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            allowed: Self::default_key_types(),
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// SHA-1 based challenge-response is excluded by default
+    fn default_key_types() -> Vec<KeyType> {
+        vec![KeyType::Symmetric, KeyType::CryptoBox]
+    }
+
+    pub fn check(&self, key_type: KeyType) -> Result<(), KeyPolicyError> {
+        if self.allowed.contains(&key_type) {
+            Ok(())
+        } else {
+            Err(KeyPolicyError::KeyTypeNotAllowed(key_type))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyPolicy, KeyPolicyError, KeyType};
+
+    #[test]
+    fn default_policy_rejects_sha1() {
+        let policy = KeyPolicy::default();
+
+        assert_eq!(policy.check(KeyType::Symmetric), Ok(()));
+        assert_eq!(policy.check(KeyType::CryptoBox), Ok(()));
+        assert_eq!(
+            policy.check(KeyType::YubikeyHmacSha1),
+            Err(KeyPolicyError::KeyTypeNotAllowed(KeyType::YubikeyHmacSha1))
+        );
+    }
+}
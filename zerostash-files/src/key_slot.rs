@@ -0,0 +1,137 @@
+use infinitree::object::ObjectId;
+use serde::{Deserialize, Serialize};
+
+/// A user that can unlock the master key of a shared stash.
+///
+/// Slots are stored as separate trees on the backend, which only the
+/// user's own credentials open. The stash only keeps track of the
+/// objects that make up the slot, so it can be deleted without knowing
+/// the user's password.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct KeySlot {
+    pub objects: Vec<ObjectId>,
+}
+
+/// Restrictions on what the holder of a delegation token may do.
+///
+/// Tokens open a copy of the index that only holds what the scope
+/// covers, so the prefix limits which file contents the holder can
+/// decrypt. The expiry is only checked by the client.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TokenScope {
+    /// Only paths below this prefix are visible
+    pub prefix: Option<String>,
+    /// Unix timestamp after which the token is refused
+    pub expires: Option<i64>,
+    /// Unix timestamp of when the token was issued
+    pub issued: i64,
+}
+
+impl TokenScope {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Whether `path` is visible through the token
+    pub fn covers(&self, path: &str) -> bool {
+        let Some(prefix) = &self.prefix else {
+            return true;
+        };
+
+        let prefix = prefix.trim_matches('/');
+        let path = path.trim_start_matches('/');
+        prefix.is_empty()
+            || path == prefix
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+/// A read-only, time-limited key slot handed out to someone else.
+///
+/// The objects of the slot include the view of the stash it opens.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Token {
+    pub slot: KeySlot,
+    pub scope: TokenScope,
+}
+
+#[cfg(test)]
+mod test {
+    use super::TokenScope;
+    use crate::{Entry, FileType, Files};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn token_scope() {
+        let scope = TokenScope {
+            prefix: Some("/home/me/".into()),
+            expires: Some(100),
+            issued: 0,
+        };
+
+        assert!(scope.covers("home/me"));
+        assert!(scope.covers("home/me/photos/a.jpg"));
+        assert!(!scope.covers("home/meow"));
+        assert!(!scope.covers("etc/passwd"));
+
+        assert!(!scope.is_expired(99));
+        assert!(scope.is_expired(100));
+        assert!(TokenScope::default().covers("anything"));
+    }
+
+    #[test]
+    fn view_only_holds_covered_files() {
+        let entry = |file_type| Entry {
+            file_type,
+            ..Entry::default()
+        };
+
+        let stash = Files::default();
+        stash
+            .tree
+            .insert_file("home/me/a.txt", entry(FileType::File))
+            .unwrap();
+        stash
+            .tree
+            .insert_file(
+                "home/me/b.txt",
+                entry(FileType::Hardlink("etc/passwd".into())),
+            )
+            .unwrap();
+        stash
+            .tree
+            .insert_file("etc/passwd", entry(FileType::File))
+            .unwrap();
+        stash.tree.insert_directory("home/me/empty").unwrap();
+        stash
+            .zfs_snapshots
+            .insert("pool@snap".into(), Default::default());
+
+        let view = Files::default();
+        let scope = TokenScope {
+            prefix: Some("home/me".into()),
+            ..TokenScope::default()
+        };
+        assert_eq!(stash.copy_to_view(&view, &scope), 2);
+
+        let files = view.tree.iter_files().collect::<Vec<_>>();
+        assert_eq!(
+            files
+                .iter()
+                .map(|(path, _)| path.as_str())
+                .collect::<BTreeSet<_>>(),
+            BTreeSet::from(["home/me/a.txt", "home/me/b.txt"])
+        );
+        assert!(files
+            .iter()
+            .all(|(_, entry)| entry.file_type == FileType::File));
+        assert!(view
+            .tree
+            .node_by_path("home/me/empty")
+            .unwrap()
+            .is_some_and(|node| node.is_dir()));
+        assert_eq!(view.zfs_snapshots.len(), 0);
+    }
+}
@@ -0,0 +1,172 @@
+//! Read stashes written by older releases of zerostash.
+//!
+//! Older stashes may lack index fields that were added later, or keep
+//! their files in the flat `files` index instead of the tree. Instead of
+//! failing, the fields are loaded one by one, the ones that can't be
+//! are left empty, and the tree is rebuilt from the flat index.
+use crate::Files;
+use infinitree::Infinitree;
+use std::fmt;
+use tracing::warn;
+
+/// What had to be reconstructed to read a stash
+#[derive(Debug, Default)]
+pub struct Reconstructed {
+    /// Index fields that couldn't be loaded, and were left empty
+    pub missing: Vec<&'static str>,
+    /// Files added to the tree from the flat index
+    pub files: usize,
+}
+
+impl Reconstructed {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.files == 0
+    }
+}
+
+impl fmt::Display for Reconstructed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for field in self.missing.iter() {
+            writeln!(
+                f,
+                "The `{field}` index can't be loaded, continuing without it"
+            )?;
+        }
+        if self.files > 0 {
+            writeln!(
+                f,
+                "Rebuilt the directory tree from {} files in the index of an older stash. Run `0s upgrade-format` to store it",
+                self.files
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Load the directory tree, and add the files of the flat index to it
+pub fn load_tree(stash: &Infinitree<Files>) -> Reconstructed {
+    let mut reconstructed = Reconstructed::default();
+
+    if let Err(error) = stash.load(stash.index().tree()) {
+        warn!(%error, "failed to load the tree");
+        reconstructed.missing.push("tree");
+    }
+    if let Err(error) = stash.load(stash.index().files()) {
+        warn!(%error, "failed to load the flat file index");
+        reconstructed.missing.push("files");
+    }
+
+    reconstructed.files = stash.index().tree_from_flat_index();
+    reconstructed
+}
+
+/// Load the tags of paths, or continue without them
+pub fn load_tags(stash: &Infinitree<Files>) -> Reconstructed {
+    let mut reconstructed = Reconstructed::default();
+
+    if let Err(error) = stash.load(stash.index().tags()) {
+        warn!(%error, "failed to load the tags");
+        reconstructed.missing.push("tags");
+    }
+
+    reconstructed
+}
+
+/// Load the ZFS snapshots, or continue without them
+pub fn load_zfs_snapshots(stash: &Infinitree<Files>) -> Reconstructed {
+    let mut reconstructed = Reconstructed::default();
+
+    if let Err(error) = stash.load(stash.index().zfs_snapshots()) {
+        warn!(%error, "failed to load the zfs snapshots");
+        reconstructed.missing.push("zfs_snapshots");
+    }
+
+    reconstructed
+}
+
+/// Load the named streams, or continue without them
+pub fn load_streams(stash: &Infinitree<Files>) -> Reconstructed {
+    let mut reconstructed = Reconstructed::default();
+
+    if let Err(error) = stash.load(stash.index().streams()) {
+        warn!(%error, "failed to load the streams");
+        reconstructed.missing.push("streams");
+    }
+
+    reconstructed
+}
+
+/// Load the authors and changes of the commits, or continue without
+/// them
+pub fn load_commit_info(stash: &Infinitree<Files>) -> Reconstructed {
+    let mut reconstructed = Reconstructed::default();
+
+    if let Err(error) = stash.load(stash.index().commit_info()) {
+        warn!(%error, "failed to load the commit info");
+        reconstructed.missing.push("commit_info");
+    }
+
+    reconstructed
+}
+
+/// Load the names of commits, or continue without them
+pub fn load_commit_tags(stash: &Infinitree<Files>) -> Reconstructed {
+    let mut reconstructed = Reconstructed::default();
+
+    if let Err(error) = stash.load(stash.index().commit_tags()) {
+        warn!(%error, "failed to load the commit tags");
+        reconstructed.missing.push("commit_tags");
+    }
+
+    reconstructed
+}
+
+/// Load the key of the lock table, or continue without locks
+pub fn load_lock_key(stash: &Infinitree<Files>) -> Reconstructed {
+    let mut reconstructed = Reconstructed::default();
+
+    if let Err(error) = stash.load(stash.index().lock_key()) {
+        warn!(%error, "failed to load the lock key");
+        reconstructed.missing.push("lock_key");
+    }
+
+    reconstructed
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_tree;
+    use crate::{Entry, Files};
+    use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+    #[test]
+    fn rebuild_tree_from_flat_index() {
+        let backend = InMemoryBackend::shared();
+        let key = || {
+            UsernamePassword::with_credentials("legacy".to_string(), "password".to_string())
+                .unwrap()
+        };
+
+        let stash = Infinitree::<Files>::empty(backend.clone(), key()).unwrap();
+        stash
+            .index()
+            .files
+            .insert("home/me/notes.txt".to_string(), Entry::default());
+        stash.commit(None).unwrap();
+        stash.backend().sync().unwrap();
+
+        let stash = Infinitree::<Files>::open(backend, key()).unwrap();
+        let reconstructed = load_tree(&stash);
+        assert!(reconstructed.missing.is_empty());
+        assert_eq!(reconstructed.files, 1);
+
+        let entry = stash
+            .index()
+            .tree
+            .file("home/me/notes.txt")
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.name, "notes.txt");
+    }
+}
@@ -1,21 +1,68 @@
 use infinitree::{fields, ChunkPointer, Digest};
+use std::sync::Arc;
+pub mod activity;
+pub mod backends;
+mod commit_info;
+pub use commit_info::*;
+mod digest_filter;
+pub use digest_filter::*;
+mod key_policy;
+pub use key_policy::*;
+mod key_slot;
+pub use key_slot::*;
+mod params;
+pub use params::*;
+mod redact;
+pub use redact::*;
 pub mod tree;
 pub use tree::*;
 mod files;
 pub use files::*;
+pub mod journal;
+pub mod legacy;
+mod locks;
+pub use locks::*;
+pub mod pause;
+pub mod query;
+mod reflog;
+pub use reflog::*;
+mod tags;
+pub use tags::*;
+#[cfg(windows)]
+mod windows;
 mod zfs_snapshots;
 pub use zfs_snapshots::*;
 pub mod rollsum;
 pub mod splitter;
 mod stash;
 
+pub use stash::check;
+pub use stash::copy;
+pub use stash::diff;
+pub use stash::gc;
+pub use stash::import;
 pub use stash::list_snapshots::ZfsSnapshotList;
 pub use stash::restore;
+pub use stash::stats;
 pub use stash::store;
+pub use stash::verify_restore;
 
 type ChunkIndex = fields::VersionedMap<Digest, ChunkPointer>;
 type FileIndex = fields::VersionedMap<String, Entry>;
 type ZfsIndex = fields::VersionedMap<String, ZfsSnapshot>;
+type StreamIndex = fields::VersionedMap<String, ZfsSnapshot>;
+type KeyPolicyIndex = fields::Serialized<Option<KeyPolicy>>;
+type DigestFilterIndex = fields::Serialized<DigestFilter>;
+type ParamsIndex = fields::Serialized<StashParams>;
+type KeySlotIndex = fields::VersionedMap<String, KeySlot>;
+type TokenIndex = fields::VersionedMap<String, Token>;
+type TagIndex = fields::VersionedMap<String, Tags>;
+type ForgottenIndex = fields::Serialized<Vec<infinitree::tree::CommitId>>;
+type RefLogIndex = fields::Serialized<Vec<RefLogEntry>>;
+type CommitTagIndex =
+    fields::Serialized<std::collections::BTreeMap<String, infinitree::tree::CommitId>>;
+type LockKeyIndex = fields::Serialized<Option<String>>;
+type CommitInfoIndex = fields::VersionedMap<Option<infinitree::tree::CommitId>, CommitInfo>;
 
 #[derive(Clone, Default, infinitree::Index)]
 pub struct Files {
@@ -23,4 +70,179 @@ pub struct Files {
     pub files: FileIndex,
     pub zfs_snapshots: ZfsIndex,
     pub tree: Tree,
+    pub key_policy: KeyPolicyIndex,
+    pub digest_filter: DigestFilterIndex,
+    pub params: ParamsIndex,
+    pub key_slots: KeySlotIndex,
+    pub tags: TagIndex,
+    pub tokens: TokenIndex,
+    /// Commits dropped from the stash, whose data `prune` may delete
+    pub forgotten: ForgottenIndex,
+    /// State of the stash before destructive operations, for `undo`
+    pub reflog: RefLogIndex,
+    /// Data piped to `stream commit`, stored like ZFS snapshots
+    pub streams: StreamIndex,
+    /// Author and changes of commits, keyed by the commit before them
+    pub commit_info: CommitInfoIndex,
+    /// Names given to commits with `tag add`
+    pub commit_tags: CommitTagIndex,
+    /// Opens the tree that holds the locks of the stash
+    pub lock_key: LockKeyIndex,
+}
+
+impl Files {
+    /// Return the pointer to the chunk with `digest`, or store it
+    /// using `store` if it's not in the index yet.
+    ///
+    /// Index lookups are skipped for chunks the digest filter rules out.
+    pub fn insert_chunk(
+        &self,
+        digest: Digest,
+        store: impl FnOnce() -> ChunkPointer,
+    ) -> Arc<ChunkPointer> {
+        let filter = self.digest_filter.read();
+        let ptr = if filter.may_contain(&digest) {
+            self.chunks.insert_with(digest, store)
+        } else {
+            self.chunks.insert(digest, store())
+        };
+
+        filter.insert(&digest);
+        ptr
+    }
+
+    /// Replace the digest filter with one built from the chunk index.
+    ///
+    /// The chunk index needs to be loaded first.
+    pub fn rebuild_digest_filter(&self) {
+        let capacity = (self.chunks.len() * 2).max(DEFAULT_FILTER_CAPACITY);
+        let filter = DigestFilter::with_capacity(capacity);
+        self.chunks.for_each(|digest, _| filter.insert(digest));
+
+        *self.digest_filter.write() = filter;
+    }
+
+    /// Add the files of the flat index of older stashes to the tree,
+    /// and return how many were added.
+    ///
+    /// The flat index needs to be loaded first, and is left as is.
+    pub fn tree_from_flat_index(&self) -> usize {
+        let mut count = 0;
+
+        self.files.for_each(|path, entry| {
+            let mut entry = entry.clone();
+            entry.name = path
+                .rsplit('/')
+                .find(|s| !s.is_empty())
+                .unwrap_or_default()
+                .to_string();
+
+            if self.tree.insert_file(path, entry).is_ok() {
+                count += 1;
+            }
+        });
+
+        count
+    }
+
+    /// Copy what a token limited to `scope` may read into the index of
+    /// its view, and return how many files were copied.
+    ///
+    /// ZFS snapshots and streams aren't below any path, so they're only
+    /// copied if the scope has no prefix. Hardlinks to files outside the
+    /// prefix become regular files, as they have the same chunks.
+    ///
+    /// The tree, tags, ZFS snapshots and streams need to be loaded first.
+    pub fn copy_to_view(&self, view: &Files, scope: &TokenScope) -> usize {
+        let mut count = 0;
+
+        // `retain` is the only walk of the tree that includes
+        // directories, and keeps every node if the closure returns true
+        self.tree.retain(|path, node| {
+            if path.is_empty() || !scope.covers(path) {
+                return true;
+            }
+
+            match node {
+                Node::File { entry, .. } => {
+                    let mut entry = entry.as_ref().clone();
+                    if matches!(&entry.file_type, FileType::Hardlink(first) if !scope.covers(first))
+                    {
+                        entry.file_type = FileType::File;
+                    }
+                    if view.tree.insert_file(path, entry).is_ok() {
+                        count += 1;
+                    }
+                }
+                Node::Directory { .. } => _ = view.tree.insert_directory(path),
+            }
+            true
+        });
+
+        self.tags.for_each(|path, tags| {
+            if scope.covers(path) {
+                view.tags.insert(path.clone(), tags.clone());
+            }
+        });
+
+        if scope.prefix.is_none() {
+            self.zfs_snapshots.for_each(|name, zfs| {
+                view.zfs_snapshots.insert(name.clone(), zfs.clone());
+            });
+            self.streams.for_each(|name, stream| {
+                view.streams.insert(name.clone(), stream.clone());
+            });
+        }
+
+        *view.params.write() = self.params.read().clone();
+        *view.key_policy.write() = self.key_policy.read().clone();
+
+        count
+    }
+
+    /// Add `tags` to `path`, and remove the tags named in `remove`
+    pub fn tag_path(
+        &self,
+        path: &str,
+        tags: impl IntoIterator<Item = (String, String)>,
+        remove: &[String],
+    ) {
+        let mut updated = self
+            .tags
+            .get(path)
+            .map(|t| t.as_ref().clone())
+            .unwrap_or_default();
+        updated.extend(tags);
+        updated.retain(|key, _| !remove.contains(key));
+
+        if self.tags.contains(path) {
+            self.tags.update_with(path.to_string(), |_| updated);
+        } else {
+            self.tags.insert(path.to_string(), updated);
+        }
+    }
+
+    /// Tags that apply to `path`, including the ones inherited from
+    /// parent directories
+    pub fn tags_of(&self, path: &str) -> Tags {
+        let mut tags = self
+            .tags
+            .get("")
+            .map(|t| t.as_ref().clone())
+            .unwrap_or_default();
+
+        let mut prefix = String::with_capacity(path.len());
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+
+            if let Some(own) = self.tags.get(&prefix) {
+                tags.extend(own.as_ref().clone());
+            }
+        }
+
+        tags
+    }
 }
@@ -0,0 +1,527 @@
+//! Cooperative locks, so concurrent writers don't interleave.
+//!
+//! Each lock is written to a slot of its own on the backend of the
+//! stash, a small tree opened by a random key in the index and the
+//! number of the slot. Taking a lock only ever writes a free slot,
+//! then waits a moment and reads every slot back, so two clients
+//! racing for the stash both see each other and back off, instead of
+//! one overwriting the other's lock.
+//!
+//! Changing a slot doesn't need a commit of the stash, so a holder
+//! refreshes its lock while it works, and a lock that isn't refreshed
+//! for a while is stale, and ignored.
+use crate::{
+    commit_info::{author, hostname},
+    Files,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use infinitree::{
+    backends::{Backend, BackendError},
+    crypto::UsernamePassword,
+    fields,
+    object::{ObjectId, ReadObject, WriteObject},
+    Infinitree,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+use tracing::{error, warn};
+
+/// How often the holder refreshes its lock
+pub const HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// Seconds after the last heartbeat when a lock is stale
+pub const STALE_AFTER: i64 = 5 * 60;
+
+/// Number of slots, which is how many clients may try to lock the
+/// stash at once
+const SLOTS: usize = 16;
+
+/// How long to wait after writing a lock or a lock key before reading
+/// it back, so a client racing us has finished writing its own
+pub const SETTLE: Duration = Duration::from_millis(500);
+
+/// The slots are opened with this username and their number, and the
+/// key in the index as the password
+const LOCK_USER: &str = "zerostash-locks";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lock {
+    pub user: String,
+    pub hostname: String,
+    pub pid: u32,
+    /// Command holding the lock, eg. `commit`
+    pub operation: String,
+    /// Unix time the lock was taken
+    pub started: i64,
+    /// Unix time the holder last refreshed the lock
+    pub heartbeat: i64,
+}
+
+impl Lock {
+    fn new(operation: &str, now: i64) -> Self {
+        Self {
+            user: author(),
+            hostname: hostname(),
+            pid: std::process::id(),
+            operation: operation.to_string(),
+            started: now,
+            heartbeat: now,
+        }
+    }
+
+    /// Whether the holder stopped refreshing the lock by `now`
+    pub fn is_stale(&self, now: i64) -> bool {
+        now - self.heartbeat > STALE_AFTER
+    }
+}
+
+impl std::fmt::Display for Lock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let started = chrono::DateTime::from_timestamp(self.started, 0)
+            .unwrap_or_default()
+            .with_timezone(&chrono::Local);
+
+        write!(
+            f,
+            "{}@{} (pid {}) for {} since {}",
+            self.user,
+            self.hostname,
+            self.pid,
+            self.operation,
+            started.format("%Y %b %e %H:%M:%S")
+        )
+    }
+}
+
+#[derive(Clone, Default, infinitree::Index)]
+struct LockSlot {
+    lock: fields::Serialized<Option<(String, Lock)>>,
+}
+
+/// The lock in a slot, and the objects it's stored in
+#[derive(Default)]
+struct Slot {
+    lock: Option<(String, Lock)>,
+    objects: Vec<ObjectId>,
+}
+
+impl Slot {
+    fn holds(&self, id: &str) -> bool {
+        self.lock.as_ref().is_some_and(|(held, _)| held == id)
+    }
+}
+
+/// Remembers the objects read and written through it, so the old
+/// objects of a slot can be deleted when it's rewritten
+struct Recorder {
+    upstream: Arc<dyn Backend>,
+    objects: Mutex<Vec<ObjectId>>,
+}
+
+impl Recorder {
+    fn new(upstream: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self {
+            upstream,
+            objects: Mutex::default(),
+        })
+    }
+
+    fn objects(&self) -> Vec<ObjectId> {
+        self.objects.lock().unwrap().clone()
+    }
+}
+
+impl Backend for Recorder {
+    fn write_object(&self, object: &WriteObject) -> Result<(), BackendError> {
+        self.objects.lock().unwrap().push(*object.id());
+        self.upstream.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>, BackendError> {
+        self.objects.lock().unwrap().push(*id);
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<(), BackendError> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<(), BackendError> {
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<(), BackendError> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<(), BackendError> {
+        self.upstream.keep_warm(objects)
+    }
+}
+
+/// The lock slots of a stash
+#[derive(Clone)]
+pub struct Locks {
+    backend: Arc<dyn Backend>,
+    password: String,
+}
+
+impl Locks {
+    /// The locks of `stash`, or `None` if they were never set up.
+    ///
+    /// The `lock_key` field of the index has to be loaded.
+    pub fn open(stash: &Infinitree<Files>) -> Result<Option<Self>> {
+        let Some(password) = stash.index().lock_key.read().clone() else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            backend: stash.backend(),
+            password,
+        }))
+    }
+
+    /// Write empty lock slots for `stash`, and put their key in the
+    /// index.
+    ///
+    /// Nothing else is committed, so other clients only use the locks
+    /// once the caller commits the stash. Clients racing to set up
+    /// locks each commit their own key, so read it back after
+    /// [`SETTLE`], and [`remove`](Self::remove) the locks if another
+    /// client's replaced them.
+    pub fn setup(stash: &Infinitree<Files>) -> Result<Self> {
+        let password = (0..32)
+            .map(|_| format!("{:02x}", rand::random::<u8>()))
+            .collect::<String>();
+        let locks = Self {
+            backend: stash.backend(),
+            password: password.clone(),
+        };
+
+        for slot in 0..SLOTS {
+            locks.write(slot, None, &[])?;
+        }
+
+        *stash.index().lock_key.write() = Some(password);
+        Ok(locks)
+    }
+
+    /// Whether these are the locks `stash` uses.
+    ///
+    /// The `lock_key` field of the index has to be loaded.
+    pub fn are_used_by(&self, stash: &Infinitree<Files>) -> bool {
+        stash.index().lock_key.read().as_deref() == Some(self.password.as_str())
+    }
+
+    /// Delete the lock slots
+    pub fn remove(&self) -> Result<()> {
+        let objects = self
+            .read_all()?
+            .into_iter()
+            .flat_map(|slot| slot.objects)
+            .collect::<Vec<_>>();
+        self.backend.delete(&objects)?;
+
+        Ok(())
+    }
+
+    /// All locks on the stash, including stale ones, by their ID
+    pub fn list(&self) -> Result<BTreeMap<String, Lock>> {
+        Ok(self
+            .read_all()?
+            .into_iter()
+            .filter_map(|slot| slot.lock)
+            .collect())
+    }
+
+    /// Take the lock for `operation`, and keep it fresh until the
+    /// returned guard is dropped.
+    ///
+    /// Fails if anyone else holds a lock that isn't stale. If the lock
+    /// is lost while it's held, because someone removed it or it
+    /// couldn't be refreshed before going stale, `on_lost` is called
+    /// from the heartbeat thread, and has to stop the operation.
+    pub fn lock(
+        &self,
+        operation: &str,
+        on_lost: impl FnOnce(anyhow::Error) + Send + 'static,
+    ) -> Result<LockGuard> {
+        let now = timestamp();
+        let slots = self.read_all()?;
+        if let Some(lock) = live_lock(&slots, now, None) {
+            bail!(
+                "The stash is locked by {lock}. If it crashed, remove the lock with `0s locks unlock`"
+            );
+        }
+
+        // every slot is free or stale, and racing clients are less
+        // likely to pick the same one at random
+        let slot = rand::random::<usize>() % SLOTS;
+        let id = format!("{:016x}", rand::random::<u64>());
+        let lock = (id.clone(), Lock::new(operation, now));
+        self.write(slot, Some(lock), &slots[slot].objects)?;
+
+        // another client may be writing its lock at the same time, so
+        // let it finish before making sure we're the only one
+        std::thread::sleep(SETTLE);
+        let slots = self.read_all()?;
+        if !slots[slot].holds(&id) {
+            bail!("Another client locked the stash at the same time");
+        }
+        if let Some(lock) = live_lock(&slots, now, Some(slot)).cloned() {
+            self.release(slot, &id)?;
+            bail!("The stash was locked at the same time by {lock}");
+        }
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let heartbeat = std::thread::spawn({
+            let locks = self.clone();
+            let id = id.clone();
+            move || {
+                let mut refreshed = now;
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(HEARTBEAT) {
+                    let error = match locks.refresh(slot, &id) {
+                        Ok(true) => {
+                            refreshed = timestamp();
+                            continue;
+                        }
+                        Ok(false) => anyhow!("The lock was removed by someone else"),
+                        // retry while the lock is fresh enough to
+                        // survive until the next heartbeat
+                        Err(error)
+                            if timestamp() - refreshed
+                                < STALE_AFTER - HEARTBEAT.as_secs() as i64 =>
+                        {
+                            warn!(%error, "failed to refresh the lock");
+                            continue;
+                        }
+                        Err(error) => error.context("The lock went stale"),
+                    };
+
+                    error!(%error, "lost the lock on the stash");
+                    on_lost(error);
+                    return;
+                }
+            }
+        });
+
+        Ok(LockGuard {
+            locks: self.clone(),
+            slot,
+            id,
+            stop: Some(stop),
+            heartbeat: Some(heartbeat),
+        })
+    }
+
+    /// Remove the locks `select` picks, and return them
+    pub fn unlock(
+        &self,
+        mut select: impl FnMut(&str, &Lock) -> bool,
+    ) -> Result<BTreeMap<String, Lock>> {
+        let mut removed = BTreeMap::new();
+
+        for (slot, current) in self.read_all()?.into_iter().enumerate() {
+            let Some((id, lock)) = current.lock else {
+                continue;
+            };
+            if select(&id, &lock) {
+                self.write(slot, None, &current.objects)?;
+                removed.insert(id, lock);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Refresh the lock `id` in `slot`, or return `false` if the slot
+    /// doesn't hold it anymore
+    fn refresh(&self, slot: usize, id: &str) -> Result<bool> {
+        let Slot { lock, objects } = self.read(slot)?;
+        let Some((held, mut lock)) = lock.filter(|(held, _)| held == id) else {
+            return Ok(false);
+        };
+
+        lock.heartbeat = timestamp();
+        self.write(slot, Some((held, lock)), &objects)?;
+        Ok(true)
+    }
+
+    fn release(&self, slot: usize, id: &str) -> Result<()> {
+        let current = self.read(slot)?;
+        if current.holds(id) {
+            self.write(slot, None, &current.objects)?;
+        }
+
+        Ok(())
+    }
+
+    fn key(&self, slot: usize) -> Result<infinitree::Key> {
+        Ok(Arc::new(UsernamePassword::with_credentials(
+            format!("{LOCK_USER}-{slot}"),
+            self.password.clone(),
+        )?))
+    }
+
+    fn read_all(&self) -> Result<Vec<Slot>> {
+        (0..SLOTS).map(|slot| self.read(slot)).collect()
+    }
+
+    /// The lock in `slot`, and the objects it's stored in
+    fn read(&self, slot: usize) -> Result<Slot> {
+        let recorder = Recorder::new(self.backend.clone());
+        let tree = Infinitree::<LockSlot>::open(recorder.clone(), self.key(slot)?)
+            .context("Failed to open the locks")?;
+        tree.load(tree.index().lock())
+            .context("Failed to read the locks")?;
+
+        let lock = tree.index().lock.read().clone();
+        Ok(Slot {
+            lock,
+            objects: recorder.objects(),
+        })
+    }
+
+    /// Replace the contents of `slot` with `lock`, and delete the
+    /// `previous` objects it was stored in
+    fn write(
+        &self,
+        slot: usize,
+        lock: Option<(String, Lock)>,
+        previous: &[ObjectId],
+    ) -> Result<()> {
+        let recorder = Recorder::new(self.backend.clone());
+        let tree = Infinitree::<LockSlot>::empty(recorder.clone(), self.key(slot)?)?;
+        *tree.index().lock.write() = lock;
+        tree.commit(None).context("Failed to write the locks")?;
+        tree.backend().sync().context("Failed to write the locks")?;
+
+        // the root object is rewritten in place, so keep it
+        let written = recorder.objects().into_iter().collect::<HashSet<_>>();
+        let stale = previous
+            .iter()
+            .filter(|id| !written.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+        if !stale.is_empty() {
+            self.backend.delete(&stale)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A lock in `slots` that isn't stale, except the one in `skip`
+fn live_lock(slots: &[Slot], now: i64, skip: Option<usize>) -> Option<&Lock> {
+    slots
+        .iter()
+        .enumerate()
+        .filter(|(slot, _)| Some(*slot) != skip)
+        .filter_map(|(_, slot)| slot.lock.as_ref())
+        .map(|(_, lock)| lock)
+        .find(|lock| !lock.is_stale(now))
+}
+
+fn timestamp() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// Holds a lock, and refreshes it until dropped
+pub struct LockGuard {
+    locks: Locks,
+    slot: usize,
+    id: String,
+    stop: Option<mpsc::Sender<()>>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            _ = heartbeat.join();
+        }
+
+        if let Err(error) = self.locks.release(self.slot, &self.id) {
+            warn!(%error, "failed to release the lock");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Locks;
+    use crate::Files;
+    use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+    fn stash() -> Infinitree<Files> {
+        Infinitree::<Files>::empty(
+            InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("locks".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn only_one_client_holds_the_lock() {
+        let stash = stash();
+        assert!(Locks::open(&stash).unwrap().is_none());
+
+        let locks = Locks::setup(&stash).unwrap();
+        assert!(Locks::open(&stash).unwrap().is_some());
+
+        let held = locks.lock("commit", |_| {}).unwrap();
+        assert!(locks.lock("prune", |_| {}).is_err());
+
+        let listed = locks.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed.values().next().unwrap().operation, "commit");
+
+        drop(held);
+        assert!(locks.list().unwrap().is_empty());
+
+        let held = locks.lock("prune", |_| {}).unwrap();
+        assert_eq!(locks.unlock(|_, _| true).unwrap().len(), 1);
+        assert!(locks.lock("commit", |_| {}).is_ok());
+        drop(held);
+    }
+
+    #[test]
+    fn locks_replaced_by_another_client_are_removed() {
+        let stash = stash();
+        let ours = Locks::setup(&stash).unwrap();
+        let theirs = Locks::setup(&stash).unwrap();
+
+        assert!(!ours.are_used_by(&stash));
+        assert!(theirs.are_used_by(&stash));
+
+        ours.remove().unwrap();
+        assert!(ours.list().is_err());
+        assert!(theirs.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn racing_clients_dont_both_get_the_lock() {
+        let locks = Locks::setup(&stash()).unwrap();
+
+        let racing = (0..2)
+            .map(|_| {
+                let locks = locks.clone();
+                std::thread::spawn(move || locks.lock("commit", |_| {}))
+            })
+            .collect::<Vec<_>>();
+        let held = racing
+            .into_iter()
+            .map(|r| r.join().unwrap())
+            .filter(Result::is_ok)
+            .count();
+
+        assert!(held <= 1);
+    }
+}
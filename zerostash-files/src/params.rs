@@ -0,0 +1,336 @@
+use crate::KeyType;
+use chrono::{DateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Size of the objects written to the backend. This is fixed by the
+/// object format, and every object is padded to this size.
+pub const OBJECT_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Version of the stash format written by this release.
+///
+/// Bump it whenever older releases would misread something new in
+/// the index, eg. a new chunker or `Entry` field.
+///
+/// Version 3 added hardlinks, extended attributes, special files, the
+/// macOS and Windows metadata, and the inode and change time of
+/// entries.
+///
+/// Version 4 lets `prune` repack the live chunks of objects into new
+/// ones, which older releases can't find.
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Stashes written before the format was versioned. These may keep
+/// files in the flat `files` index instead of the tree.
+pub const LEGACY_FORMAT_VERSION: u32 = 1;
+
+fn legacy_format_version() -> u32 {
+    LEGACY_FORMAT_VERSION
+}
+
+/// Content defined chunking algorithm used to split files
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Chunker {
+    /// Seahash for files that fit in memory, bup's rollsum otherwise
+    #[default]
+    Auto,
+    /// Always use bup's rollsum
+    Bup,
+    /// Always use a seahash based rolling hash
+    Seahash,
+}
+
+/// Compression applied to objects before encryption
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    Lz4,
+}
+
+/// Number of commits `forget` keeps by default.
+///
+/// Unset fields don't restrict anything.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, clap::Args)]
+#[serde(default)]
+pub struct Retention {
+    /// Keep the last N commits
+    #[clap(long, value_name = "N")]
+    pub keep_last: Option<u32>,
+    /// Keep the last commit of the last N hours
+    #[clap(long, value_name = "N")]
+    pub keep_hourly: Option<u32>,
+    /// Keep the last commit of the last N days
+    #[clap(long, value_name = "N")]
+    pub keep_daily: Option<u32>,
+    /// Keep the last commit of the last N weeks
+    #[clap(long, value_name = "N")]
+    pub keep_weekly: Option<u32>,
+    /// Keep the last commit of the last N months
+    #[clap(long, value_name = "N")]
+    pub keep_monthly: Option<u32>,
+    /// Keep the last commit of the last N years
+    #[clap(long, value_name = "N")]
+    pub keep_yearly: Option<u32>,
+    /// Keep every commit made within this long of the latest one.
+    /// Eg: 30d, 2w, 1y6m
+    #[clap(long, value_name = "DURATION")]
+    pub keep_within: Option<Within>,
+}
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Why each commit is kept, given the times of the commits from
+    /// newest to oldest. Commits with no reasons are dropped.
+    ///
+    /// Like restic, the hourly, daily, etc. rules keep the newest
+    /// commit in each of the last N periods that have a commit.
+    pub fn reasons<Tz: TimeZone>(&self, times: &[DateTime<Tz>]) -> Vec<Vec<&'static str>>
+    where
+        Tz::Offset: fmt::Display,
+    {
+        let mut reasons = vec![vec![]; times.len()];
+
+        if let Some(n) = self.keep_last {
+            for kept in reasons.iter_mut().take(n as usize) {
+                kept.push("last");
+            }
+        }
+
+        let periods = [
+            (self.keep_hourly, "hourly", "%Y-%m-%d %H"),
+            (self.keep_daily, "daily", "%Y-%m-%d"),
+            (self.keep_weekly, "weekly", "%G-%V"),
+            (self.keep_monthly, "monthly", "%Y-%m"),
+            (self.keep_yearly, "yearly", "%Y"),
+        ];
+        for (count, reason, period) in periods {
+            let Some(count) = count else {
+                continue;
+            };
+
+            let mut last = None;
+            let mut kept = 0;
+            for (time, reasons) in times.iter().zip(reasons.iter_mut()) {
+                if kept == count {
+                    break;
+                }
+
+                let current = time.format(period).to_string();
+                if last.as_ref() != Some(&current) {
+                    reasons.push(reason);
+                    last = Some(current);
+                    kept += 1;
+                }
+            }
+        }
+
+        if let (Some(within), Some(newest)) = (self.keep_within, times.first()) {
+            for (time, reasons) in times.iter().zip(reasons.iter_mut()) {
+                if newest.clone() - time.clone() <= within.duration() {
+                    reasons.push("within");
+                }
+            }
+        }
+
+        reasons
+    }
+}
+
+/// A span of time, like `36h`, `30d`, `2w` or `1y6m`.
+///
+/// A month is counted as 30 days, and a year as 365.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Within {
+    hours: u32,
+}
+
+impl Within {
+    pub const fn days(days: u32) -> Self {
+        Self { hours: days * 24 }
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.hours.into())
+    }
+}
+
+impl FromStr for Within {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid duration `{s}`, expected eg. `30d` or `1y6m`");
+
+        let mut hours = 0u32;
+        let mut digits = String::new();
+        for c in s.trim().chars() {
+            if c.is_ascii_digit() {
+                digits.push(c);
+                continue;
+            }
+
+            let n: u32 = digits.parse().map_err(|_| invalid())?;
+            let unit = match c {
+                'h' => 1,
+                'd' => 24,
+                'w' => 7 * 24,
+                'm' => 30 * 24,
+                'y' => 365 * 24,
+                _ => return Err(invalid()),
+            };
+            hours = n
+                .checked_mul(unit)
+                .and_then(|h| hours.checked_add(h))
+                .ok_or_else(invalid)?;
+            digits.clear();
+        }
+
+        if !digits.is_empty() || hours == 0 {
+            return Err(invalid());
+        }
+
+        Ok(Self { hours })
+    }
+}
+
+impl fmt::Display for Within {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.hours % 24 == 0 {
+            write!(f, "{}d", self.hours / 24)
+        } else {
+            write!(f, "{}h", self.hours)
+        }
+    }
+}
+
+impl TryFrom<String> for Within {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Within> for String {
+    fn from(value: Within) -> Self {
+        value.to_string()
+    }
+}
+
+/// Parameters chosen when the stash was created.
+///
+/// Stashes created before these were recorded use the defaults.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct StashParams {
+    pub chunker: Chunker,
+    pub compression: Compression,
+    pub object_size: u32,
+    /// Type of the key the stash was created with
+    pub key_type: Option<KeyType>,
+    /// Default retention policy of the stash
+    pub retention: Retention,
+    /// Version of the format the stash was last written in
+    #[serde(default = "legacy_format_version")]
+    pub format_version: u32,
+    /// How long `undo` can revert destructive operations
+    pub undo_window: Within,
+}
+
+/// Stashes without parameters in the index predate format versions, so
+/// the default is the legacy format. New stashes set the current one.
+impl Default for StashParams {
+    fn default() -> Self {
+        Self {
+            chunker: Chunker::default(),
+            compression: Compression::default(),
+            object_size: OBJECT_SIZE,
+            key_type: None,
+            retention: Retention::default(),
+            format_version: LEGACY_FORMAT_VERSION,
+            undo_window: Within::days(7),
+        }
+    }
+}
+
+impl StashParams {
+    /// Refuse stashes written in a format this release doesn't know
+    pub fn check_format(&self) -> anyhow::Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            anyhow::bail!(
+                "The stash was written by a newer zerostash in format version {}, but this version only supports up to {FORMAT_VERSION}. Upgrade zerostash to use it",
+                self.format_version
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Whether `upgrade-format` has anything to do
+    pub fn needs_upgrade(&self) -> bool {
+        self.format_version < FORMAT_VERSION
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Retention, StashParams, Within, FORMAT_VERSION};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn refuses_newer_formats() {
+        let mut params = StashParams::default();
+        assert!(params.check_format().is_ok());
+        assert!(params.needs_upgrade());
+
+        params.format_version = FORMAT_VERSION;
+        assert!(params.check_format().is_ok());
+        assert!(!params.needs_upgrade());
+
+        params.format_version = FORMAT_VERSION + 1;
+        assert!(params.check_format().is_err());
+    }
+
+    #[test]
+    fn retention_keeps_newest_per_period() {
+        let at = |d, h| Utc.with_ymd_and_hms(2024, 5, d, h, 0, 0).unwrap();
+        // newest first, two commits on the 3rd
+        let times = [at(3, 18), at(3, 9), at(2, 12), at(1, 12), at(1, 8)];
+
+        let retention = Retention {
+            keep_last: Some(1),
+            keep_daily: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            retention.reasons(&times),
+            vec![vec!["last", "daily"], vec![], vec!["daily"], vec![], vec![]]
+        );
+
+        let retention = Retention {
+            keep_within: Some("1d".parse().unwrap()),
+            ..Default::default()
+        };
+        let kept = retention
+            .reasons(&times)
+            .iter()
+            .filter(|r| !r.is_empty())
+            .count();
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!("36h".parse::<Within>().unwrap().to_string(), "36h");
+        assert_eq!("2w".parse::<Within>().unwrap().to_string(), "14d");
+        assert_eq!("1y6m".parse::<Within>().unwrap().to_string(), "545d");
+        assert!("30".parse::<Within>().is_err());
+        assert!("d".parse::<Within>().is_err());
+        assert!("3x".parse::<Within>().is_err());
+    }
+}
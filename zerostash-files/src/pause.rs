@@ -0,0 +1,43 @@
+//! Pausing long-running operations from the outside.
+//!
+//! An operation checks [`Pause::is_paused`] between units of work, and
+//! stops at the next point where its progress is consistent, so the
+//! caller can persist it before waiting with [`Pause::wait`].
+use std::{fmt, sync::Arc};
+use tokio::sync::watch;
+
+/// Shared switch to pause and resume an operation
+#[derive(Clone)]
+pub struct Pause(Arc<watch::Sender<bool>>);
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl fmt::Debug for Pause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Pause").field(&self.is_paused()).finish()
+    }
+}
+
+impl Pause {
+    pub fn pause(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.0.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait while the operation is paused
+    pub async fn wait(&self) {
+        let mut paused = self.0.subscribe();
+        _ = paused.wait_for(|paused| !paused).await;
+    }
+}
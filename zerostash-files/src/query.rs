@@ -0,0 +1,482 @@
+//! Filter expressions over the files in a stash.
+//!
+//! An expression compares fields of an [`Entry`] to values, and
+//! combines the comparisons with `and`, `or`, `not` and parentheses:
+//!
+//! ```text
+//! size > 100M and mtime < 2023-01-01 and path ~ "logs/**"
+//! ```
+//!
+//! The fields are `path`, `name`, `size`, `mtime`, `uid`, `gid`,
+//! `mode` and `type`. Strings are compared with `=` and `!=`, or
+//! matched against a glob with `~` and `!~`. Numbers and times take
+//! `=`, `!=`, `<`, `<=`, `>` and `>=`.
+use crate::{Entry, FileType};
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use std::{fmt, str::FromStr};
+
+/// The values `type` can be compared to
+pub const TYPES: &[&str] = &["file", "dir", "symlink", "fifo", "socket", "device"];
+
+/// A parsed filter expression
+#[derive(Clone, Debug)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Compare(Field, Op, Value),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Path,
+    Name,
+    Size,
+    Mtime,
+    Uid,
+    Gid,
+    Mode,
+    Type,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Match => "~",
+            Op::NotMatch => "!~",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Number(i64),
+    Text(String),
+    Glob(glob::Pattern),
+}
+
+impl Query {
+    /// Parse an expression, for use as a `clap` value parser
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens, pos: 0 };
+
+        let query = parser.or()?;
+        match parser.next() {
+            None => Ok(query),
+            Some(token) => Err(format!("unexpected {token} in `{input}`")),
+        }
+    }
+
+    /// Match the files that match both `self` and `other`
+    pub fn and(self, other: Query) -> Self {
+        Query::And(Box::new(self), Box::new(other))
+    }
+
+    /// A single comparison, like `size > 100M`
+    pub fn compare(name: &str, op: Op, value: impl Into<String>) -> Result<Self, String> {
+        let value = value.into();
+
+        let field = match name.to_ascii_lowercase().as_str() {
+            "path" => Field::Path,
+            "name" => Field::Name,
+            "size" => Field::Size,
+            "mtime" => Field::Mtime,
+            "uid" => Field::Uid,
+            "gid" => Field::Gid,
+            "mode" => Field::Mode,
+            "type" => Field::Type,
+            _ => return Err(format!("unknown field `{name}`")),
+        };
+
+        let is_text = matches!(field, Field::Path | Field::Name | Field::Type);
+        let value = match (is_text, op) {
+            (true, Op::Match | Op::NotMatch) => {
+                Value::Glob(glob::Pattern::new(&value).map_err(|e| e.to_string())?)
+            }
+            (true, Op::Eq | Op::Ne) => Value::Text(value),
+            (false, Op::Match | Op::NotMatch) | (true, _) => {
+                return Err(format!("`{name}` can't be compared with `{op}`"))
+            }
+            (false, _) => Value::Number(
+                parse_number(field, &value)
+                    .ok_or_else(|| format!("invalid value `{value}` for `{name}`"))?,
+            ),
+        };
+
+        if field == Field::Type {
+            if let Value::Text(kind) = &value {
+                if !TYPES.contains(&kind.as_str()) {
+                    return Err(format!(
+                        "unknown type `{kind}`, expected one of {}",
+                        TYPES.join(", ")
+                    ));
+                }
+            }
+        }
+
+        Ok(Query::Compare(field, op, value))
+    }
+
+    /// Whether the file at `path` matches the expression
+    pub fn matches(&self, path: &str, entry: &Entry) -> bool {
+        match self {
+            Query::And(a, b) => a.matches(path, entry) && b.matches(path, entry),
+            Query::Or(a, b) => a.matches(path, entry) || b.matches(path, entry),
+            Query::Not(q) => !q.matches(path, entry),
+            Query::Compare(field, op, value) => compare(*field, *op, value, path, entry),
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn compare(field: Field, op: Op, value: &Value, path: &str, entry: &Entry) -> bool {
+    let number = match field {
+        Field::Path => return compare_text(op, value, path),
+        Field::Name => {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            return compare_text(op, value, name);
+        }
+        Field::Type => return compare_text(op, value, type_name(&entry.file_type)),
+        Field::Size => Some(entry.size as i64),
+        Field::Mtime => Some(entry.unix_secs),
+        Field::Uid => entry.unix_uid.map(i64::from),
+        Field::Gid => entry.unix_gid.map(i64::from),
+        Field::Mode => entry.unix_perm.map(|m| i64::from(m & 0o7777)),
+    };
+
+    // files stored without the field never match
+    let (Some(number), Value::Number(value)) = (number, value) else {
+        return false;
+    };
+
+    match op {
+        Op::Eq => number == *value,
+        Op::Ne => number != *value,
+        Op::Lt => number < *value,
+        Op::Le => number <= *value,
+        Op::Gt => number > *value,
+        Op::Ge => number >= *value,
+        Op::Match | Op::NotMatch => false,
+    }
+}
+
+fn compare_text(op: Op, value: &Value, text: &str) -> bool {
+    match (op, value) {
+        (Op::Eq, Value::Text(v)) => text == v,
+        (Op::Ne, Value::Text(v)) => text != v,
+        (Op::Match, Value::Glob(g)) => g.matches(text),
+        (Op::NotMatch, Value::Glob(g)) => !g.matches(text),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "`{w}`"),
+            Token::Quoted(q) => write!(f, "\"{q}\""),
+            Token::Op(op) => write!(f, "`{op}`"),
+            Token::Open => f.write_str("`(`"),
+            Token::Close => f.write_str("`)`"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => {
+                chars.next();
+                Token::Open
+            }
+            ')' => {
+                chars.next();
+                Token::Close
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err(format!("unterminated string in `{input}`")),
+                    }
+                }
+                Token::Quoted(text)
+            }
+            '=' | '!' | '<' | '>' | '~' => {
+                chars.next();
+                let next = chars.peek().copied();
+                let (op, double) = match (c, next) {
+                    ('=', Some('=')) => (Op::Eq, true),
+                    ('=', _) => (Op::Eq, false),
+                    ('!', Some('=')) => (Op::Ne, true),
+                    ('!', Some('~')) => (Op::NotMatch, true),
+                    ('<', Some('=')) => (Op::Le, true),
+                    ('<', _) => (Op::Lt, false),
+                    ('>', Some('=')) => (Op::Ge, true),
+                    ('>', _) => (Op::Gt, false),
+                    ('~', _) => (Op::Match, false),
+                    _ => return Err(format!("unexpected `{c}` in `{input}`")),
+                };
+                if double {
+                    chars.next();
+                }
+                Token::Op(op)
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()\"=!<>~".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Query, String> {
+        let mut query = self.and()?;
+        while self.keyword("or") {
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, String> {
+        let mut query = self.not()?;
+        while self.keyword("and") {
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+        Ok(query)
+    }
+
+    fn not(&mut self) -> Result<Query, String> {
+        if self.keyword("not") {
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Query, String> {
+        let name = match self.next() {
+            Some(Token::Open) => {
+                let query = self.or()?;
+                return match self.next() {
+                    Some(Token::Close) => Ok(query),
+                    _ => Err("missing `)`".into()),
+                };
+            }
+            Some(Token::Word(name)) => name,
+            Some(token) => return Err(format!("expected a field, found {token}")),
+            None => return Err("expected a field, found the end of the expression".into()),
+        };
+
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => return Err(format!("expected an operator after `{name}`")),
+        };
+
+        let value = match self.next() {
+            Some(Token::Word(v) | Token::Quoted(v)) => v,
+            _ => return Err(format!("expected a value after `{name} {op}`")),
+        };
+
+        Query::compare(&name, op, value)
+    }
+}
+
+fn parse_number(field: Field, value: &str) -> Option<i64> {
+    match field {
+        Field::Size => parse_size(value),
+        Field::Mtime => parse_time(value),
+        Field::Mode => i64::from_str_radix(value, 8).ok(),
+        _ => value.parse().ok(),
+    }
+}
+
+/// The name of `file_type` in [`TYPES`]
+pub fn type_name(file_type: &FileType) -> &'static str {
+    match file_type {
+        FileType::File | FileType::Hardlink(_) => "file",
+        FileType::Directory => "dir",
+        FileType::Symlink(_) => "symlink",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+        FileType::CharDevice(_) | FileType::BlockDevice(_) => "device",
+    }
+}
+
+/// Sizes like `100`, `4K`, `100M` or `2GiB`, in powers of 1024
+fn parse_size(value: &str) -> Option<i64> {
+    let upper = value.to_ascii_uppercase();
+    let trimmed = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+
+    let (digits, shift) = match trimmed.chars().last()? {
+        'K' => (&trimmed[..trimmed.len() - 1], 10),
+        'M' => (&trimmed[..trimmed.len() - 1], 20),
+        'G' => (&trimmed[..trimmed.len() - 1], 30),
+        'T' => (&trimmed[..trimmed.len() - 1], 40),
+        _ => (trimmed, 0),
+    };
+
+    digits.parse::<i64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Dates in local time like `2023-01-01`, or RFC 3339 timestamps
+fn parse_time(value: &str) -> Option<i64> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.timestamp());
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let midnight = Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()?;
+    Some(midnight.timestamp())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Query;
+    use crate::{Entry, FileType};
+
+    #[test]
+    fn filter_entries() {
+        let entry = |size, unix_secs| Entry {
+            size,
+            unix_secs,
+            unix_uid: Some(1000),
+            unix_perm: Some(0o100644),
+            ..Default::default()
+        };
+        // 2022-06-01 and 2024-06-01
+        let (old, new) = (1654041600, 1717200000);
+
+        let query: Query = r#"size > 100M and mtime < 2023-01-01 and path ~ "logs/**""#
+            .parse()
+            .unwrap();
+        assert!(query.matches("logs/app/1.log", &entry(200 << 20, old)));
+        assert!(!query.matches("logs/app/1.log", &entry(200 << 20, new)));
+        assert!(!query.matches("logs/app/1.log", &entry(10, old)));
+        assert!(!query.matches("cache/1.log", &entry(200 << 20, old)));
+
+        let query: Query = "not (uid = 1000 or name ~ *.tmp) OR mode = 644"
+            .parse()
+            .unwrap();
+        assert!(query.matches("a/b.txt", &entry(0, old)));
+
+        let query: Query = "type = dir and gid = 0".parse().unwrap();
+        let dir = Entry {
+            file_type: FileType::Directory,
+            unix_gid: Some(0),
+            ..Default::default()
+        };
+        assert!(query.matches("a", &dir));
+        assert!(!query.matches("a", &Entry::default()));
+
+        let query: Query = "type = fifo or type = device".parse().unwrap();
+        let special = |file_type| Entry {
+            file_type,
+            ..Default::default()
+        };
+        assert!(query.matches("a", &special(FileType::Fifo)));
+        assert!(query.matches("a", &special(FileType::BlockDevice(0))));
+        assert!(!query.matches("a", &special(FileType::Socket)));
+    }
+
+    #[test]
+    fn reject_invalid_expressions() {
+        for invalid in [
+            "",
+            "size >",
+            "size > lots",
+            "color = red",
+            "path < a",
+            "size ~ 10",
+            "type = pipe",
+            "(size > 1",
+            "size > 1 size < 2",
+            r#"path = "open"#,
+        ] {
+            assert!(Query::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
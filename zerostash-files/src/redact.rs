@@ -0,0 +1,57 @@
+//! Keep credentials out of logs and error messages.
+//!
+//! Errors from the storage can quote the request that failed, which
+//! may carry signed URLs or authorization headers. Anything that ends
+//! up in front of the user or in a log file goes through [`redact`]
+//! first.
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Hide credentials in signed URLs, headers, `key = value` pairs,
+/// URL userinfo, and secret keys or tokens of zerostash
+pub fn redact(message: &str) -> String {
+    static SECRETS: OnceLock<Regex> = OnceLock::new();
+    let secrets = SECRETS.get_or_init(|| {
+        Regex::new(concat!(
+            r"(?i)((?:x-amz-(?:signature|credential|security-token)|credential|signature|authorization|awsaccesskeyid",
+            r"|password|secret_?(?:access_?)?key|session_?token|token)\s*[=:]\s*)[^&\s,;]+",
+            r"|://[^/@\s]+@",
+            r"|\b[st]0s-1[02-9ac-hj-np-z]+",
+        ))
+        .expect("syntactically correct")
+    });
+
+    secrets
+        .replace_all(message, |caps: &regex::Captures| match caps.get(1) {
+            Some(key) => format!("{}[redacted]", key.as_str()),
+            None if caps[0].starts_with("://") => "://[redacted]@".to_string(),
+            None => "[redacted]".to_string(),
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::redact;
+
+    #[test]
+    fn redacts_credentials() {
+        assert_eq!(
+            redact("GET https://bucket.s3.amazonaws.com/obj?X-Amz-Credential=AKIA%2F&X-Amz-Signature=abcd (403)"),
+            "GET https://bucket.s3.amazonaws.com/obj?X-Amz-Credential=[redacted]&X-Amz-Signature=[redacted] (403)"
+        );
+        assert_eq!(
+            redact("failed to connect to s3://access:secret@host/bucket"),
+            "failed to connect to s3://[redacted]@host/bucket"
+        );
+        assert_eq!(
+            redact(r#"invalid config: session_token = "abc", password: hunter2"#),
+            "invalid config: session_token = [redacted], password: [redacted]"
+        );
+        assert_eq!(
+            redact("can't parse s0s-1qqqsyqcyq5rqwzqf"),
+            "can't parse [redacted]"
+        );
+        assert_eq!(redact("object not found"), "object not found");
+    }
+}
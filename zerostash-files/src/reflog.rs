@@ -0,0 +1,86 @@
+//! A record of the stash before destructive operations.
+//!
+//! Like git's reflog, every `forget`, `prune` and `zfs destroy` leaves
+//! an entry with what it's about to change, so `undo` can put it back
+//! within the undo window of the stash.
+use crate::{Files, ZfsSnapshot};
+use infinitree::{tree::CommitId, Infinitree};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RefLogEntry {
+    /// Unix time of the operation
+    pub time: i64,
+    pub operation: String,
+    /// The latest commit before the operation
+    pub head: Option<CommitId>,
+    /// The forgotten commits before the operation
+    pub forgotten: Vec<CommitId>,
+    /// ZFS snapshots the operation removed from the index
+    pub zfs_snapshots: Vec<(String, ZfsSnapshot)>,
+    /// Number of objects the operation deleted from the backend. These
+    /// can't be brought back.
+    pub deleted_objects: usize,
+}
+
+impl RefLogEntry {
+    /// Capture the current state of the stash before `operation`
+    pub fn new(stash: &Infinitree<Files>, operation: &str) -> Self {
+        Self {
+            time: chrono::Utc::now().timestamp(),
+            operation: operation.to_string(),
+            head: stash.commit_list().iter().last().map(|c| c.id),
+            forgotten: stash.index().forgotten.read().clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether `undo` can still revert the operation at `now`
+    pub fn is_undoable(&self, window: crate::Within, now: i64) -> bool {
+        self.deleted_objects == 0 && now - self.time <= window.duration().num_seconds()
+    }
+}
+
+impl Files {
+    /// Add an entry to the reflog, and drop the ones that are out of
+    /// the undo window
+    pub fn record(&self, entry: RefLogEntry) {
+        let window = self.params.read().undo_window.duration().num_seconds();
+        let mut reflog = self.reflog.write();
+
+        reflog.retain(|e| entry.time - e.time <= window);
+        reflog.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RefLogEntry;
+    use crate::Files;
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let files = Files::default();
+        let day = 24 * 3600;
+        let entry = |time, operation: &str| RefLogEntry {
+            time,
+            operation: operation.into(),
+            ..Default::default()
+        };
+
+        files.record(entry(0, "old"));
+        files.record(entry(6 * day, "recent"));
+        files.record(entry(8 * day, "forget"));
+
+        let reflog = files.reflog.read();
+        let operations = reflog
+            .iter()
+            .map(|e| e.operation.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(operations, ["recent", "forget"]);
+
+        let window = files.params.read().undo_window;
+        assert!(reflog[1].is_undoable(window, 9 * day));
+        assert!(!reflog[1].is_undoable(window, 16 * day));
+    }
+}
@@ -1,3 +1,10 @@
+pub mod check;
+pub mod copy;
+pub mod diff;
+pub mod gc;
+pub mod import;
 pub mod list_snapshots;
 pub mod restore;
+pub mod stats;
 pub mod store;
+pub mod verify_restore;
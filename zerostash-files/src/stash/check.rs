@@ -0,0 +1,241 @@
+//! Verify the integrity of a stash.
+//!
+//! The index of every commit is loaded, and the chunk lists of the
+//! files in them are checked for consistency. For stashes in a local
+//! directory, the objects the chunks point to must exist.
+//!
+//! Optionally, chunks are read from the backend, decrypted, and hashed
+//! again, which catches bit rot in the stored objects.
+use crate::{backends::Relocations, journal::Journal, stash::restore, Files};
+use infinitree::{
+    object::{ObjectId, Reader},
+    tree::CommitFilter,
+    ChunkPointer, Digest, Infinitree,
+};
+use rand::seq::IteratorRandom;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+use tracing::debug;
+
+/// Chunks to decrypt and hash again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadData {
+    None,
+    All,
+    /// A random share of the objects, between 0 and 1
+    Subset(f64),
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct Report {
+    pub commits: usize,
+    pub files: usize,
+    pub chunks: usize,
+    pub objects: usize,
+    /// Chunks that were decrypted and hashed again
+    pub chunks_read: usize,
+    /// Objects verified by an interrupted run, and skipped
+    pub objects_resumed: usize,
+    pub problems: Vec<String>,
+}
+
+/// A chunk, the length of its data, and a file it belongs to
+struct ChunkRef {
+    pointer: Arc<ChunkPointer>,
+    len: usize,
+    path: String,
+}
+
+/// Check every commit of the stash `open` returns.
+///
+/// Objects that are read and have no problems are recorded in
+/// `journal`, and skipped if an earlier run recorded them.
+pub fn check(
+    mut open: impl FnMut() -> anyhow::Result<Infinitree<Files>>,
+    read: ReadData,
+    local_dir: Option<&Path>,
+    journal: Option<&Journal>,
+) -> anyhow::Result<Report> {
+    let stash = open()?;
+    let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+    // repacked objects are stored in other objects
+    let relocations = match local_dir {
+        Some(_) => Relocations::load(stash.backend().as_ref())?,
+        None => Relocations::default(),
+    };
+
+    let mut report = Report::default();
+    let mut files = HashSet::new();
+    let mut broken = HashSet::new();
+    let mut chunks = HashMap::<Digest, ChunkRef>::new();
+
+    for id in commits {
+        report.commits += 1;
+
+        let snapshot = open()?;
+        snapshot.filter_commits(CommitFilter::UpTo(id));
+        if let Err(error) = snapshot.load_all() {
+            report.problems.push(format!(
+                "the index of commit {id:?} can't be loaded: {error:#}"
+            ));
+            continue;
+        }
+
+        for (path, entry) in restore::iter(&snapshot, ["*"]) {
+            if let Some(problem) = chunk_list_problem(&entry) {
+                // later commits usually keep the same broken entry
+                if broken.insert(path.clone()) {
+                    report.problems.push(format!("{path}: {problem}"));
+                }
+                continue;
+            }
+
+            let ends = entry.chunks.keys().copied().skip(1).chain([entry.size]);
+            for ((start, pointer), end) in entry.chunks.iter().zip(ends) {
+                chunks.entry(*pointer.hash()).or_insert_with(|| ChunkRef {
+                    pointer: pointer.clone(),
+                    len: (end - start) as usize,
+                    path: path.clone(),
+                });
+            }
+            files.insert(path);
+        }
+    }
+
+    report.files = files.len();
+    report.chunks = chunks.len();
+
+    let mut objects = HashMap::<ObjectId, Vec<&ChunkRef>>::new();
+    for chunk in chunks.values() {
+        objects
+            .entry(*chunk.pointer.object_id())
+            .or_default()
+            .push(chunk);
+    }
+    report.objects = objects.len();
+
+    if let Some(dir) = local_dir {
+        for (id, chunks) in objects.iter() {
+            let stored = relocations.get(id).map_or(*id, |r| r.target);
+            if !dir.join(stored.to_string()).exists() {
+                report.problems.push(format!(
+                    "{}: object {id} is missing, with {} chunks",
+                    chunks[0].path,
+                    chunks.len()
+                ));
+            }
+        }
+    }
+
+    let selected = match read {
+        ReadData::None => return Ok(report),
+        ReadData::All => objects.keys().copied().collect::<Vec<_>>(),
+        ReadData::Subset(share) => {
+            let count = (objects.len() as f64 * share).ceil() as usize;
+            objects
+                .keys()
+                .copied()
+                .choose_multiple(&mut rand::thread_rng(), count)
+        }
+    };
+
+    let stash = open()?;
+    let mut reader = stash.storage_reader()?;
+    let mut hasher = stash.hasher()?;
+    let mut buf = vec![];
+
+    for id in selected {
+        if journal.is_some_and(|j| j.is_done(&id.to_string())) {
+            report.objects_resumed += 1;
+            continue;
+        }
+
+        debug!(%id, "reading object");
+        let problems = report.problems.len();
+        for chunk in objects[&id].iter() {
+            report.chunks_read += 1;
+            buf.resize(chunk.len, 0);
+
+            if let Err(error) = reader.read_chunk(&chunk.pointer, &mut buf) {
+                report.problems.push(format!(
+                    "{}: a chunk in object {id} can't be read: {error}",
+                    chunk.path
+                ));
+                continue;
+            }
+
+            hasher.reset();
+            hasher.update(&buf);
+            if hasher.finalize().as_bytes() != chunk.pointer.hash() {
+                report.problems.push(format!(
+                    "{}: a chunk in object {id} doesn't match its hash",
+                    chunk.path
+                ));
+            }
+        }
+
+        if let Some(journal) = journal.filter(|_| report.problems.len() == problems) {
+            journal.record(&id.to_string())?;
+            journal.sync()?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Chunks have to start at the beginning of the file, and cover all
+/// of it in order
+fn chunk_list_problem(entry: &crate::Entry) -> Option<&'static str> {
+    if !entry.file_type.is_file() || entry.size == 0 {
+        return None;
+    }
+
+    match entry.chunks.keys().next() {
+        None => Some("the file has no chunks"),
+        Some(first) if *first != 0 => Some("the first chunk doesn't start the file"),
+        _ if entry.chunks.keys().any(|start| *start >= entry.size) => {
+            Some("a chunk starts past the end of the file")
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, ReadData};
+    use crate::{Entry, Files};
+    use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+    #[test]
+    fn report_files_without_chunks() {
+        let backend = InMemoryBackend::shared();
+        let key = || {
+            UsernamePassword::with_credentials("check".to_string(), "password".to_string()).unwrap()
+        };
+
+        let stash = Infinitree::<Files>::empty(backend.clone(), key()).unwrap();
+        let tree = &stash.index().tree;
+        tree.insert_file("empty", Entry::default()).unwrap();
+        tree.insert_file(
+            "broken",
+            Entry {
+                size: 10,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        stash.commit(None).unwrap();
+        stash.backend().sync().unwrap();
+
+        let open = || Ok(Infinitree::<Files>::open(backend.clone(), key())?);
+        let report = check(open, ReadData::All, None, None).unwrap();
+
+        assert_eq!(report.commits, 1);
+        assert_eq!(report.files, 2);
+        assert_eq!(report.chunks_read, 0);
+        assert_eq!(report.problems, ["broken: the file has no chunks"]);
+    }
+}
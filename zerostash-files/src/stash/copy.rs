@@ -0,0 +1,151 @@
+//! Copy files from one stash to another.
+//!
+//! The two stashes may use different keys, which means different
+//! hashes for the same data, so every chunk is read and decrypted from
+//! the source, hashed again, and stored in the destination unless it's
+//! already there. Nothing is written to the local disk on the way.
+use crate::{files::Entry, Files};
+use infinitree::{
+    object::{AEADReader, AEADWriter, PoolRef, Reader, Writer},
+    ChunkPointer, Digest, Hasher, Infinitree,
+};
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Copied {
+    pub files: usize,
+    /// Chunks stored in the destination
+    pub chunks: usize,
+    pub bytes: u64,
+    /// Chunks the destination already had
+    pub deduplicated: usize,
+}
+
+pub struct Copier<'dest> {
+    dest: &'dest Infinitree<Files>,
+    reader: PoolRef<AEADReader>,
+    writer: AEADWriter,
+    hasher: Hasher,
+    buf: Vec<u8>,
+    /// Chunks of the source that were copied already
+    copied: HashMap<Digest, Arc<ChunkPointer>>,
+    pub stats: Copied,
+}
+
+impl<'dest> Copier<'dest> {
+    pub fn new(source: &Infinitree<Files>, dest: &'dest Infinitree<Files>) -> anyhow::Result<Self> {
+        Ok(Self {
+            dest,
+            reader: source.storage_reader()?,
+            writer: dest.storage_writer()?,
+            hasher: dest.hasher()?,
+            buf: vec![],
+            copied: HashMap::new(),
+            stats: Copied::default(),
+        })
+    }
+
+    /// Copy the chunks of a file, and add it to the tree of the
+    /// destination at `path`
+    pub fn copy_file(&mut self, path: &str, entry: &Entry) -> anyhow::Result<()> {
+        let mut copy = entry.clone();
+
+        let ends = entry.chunks.keys().skip(1).copied().chain([entry.size]);
+        for ((start, pointer), end) in entry.chunks.iter().zip(ends) {
+            let copied = match self.copied.get(pointer.hash()) {
+                Some(copied) => copied.clone(),
+                None => {
+                    let copied = self.copy_chunk(pointer, (end - start) as usize)?;
+                    self.copied.insert(*pointer.hash(), copied.clone());
+                    copied
+                }
+            };
+            copy.chunks.insert(*start, copied);
+        }
+
+        self.dest
+            .index()
+            .tree
+            .insert_file(path, copy)
+            .map_err(|e| anyhow::anyhow!("{path}: {e:?}"))?;
+        self.stats.files += 1;
+
+        Ok(())
+    }
+
+    fn copy_chunk(
+        &mut self,
+        pointer: &ChunkPointer,
+        len: usize,
+    ) -> anyhow::Result<Arc<ChunkPointer>> {
+        self.buf.resize(len, 0);
+        self.reader.read_chunk(pointer, &mut self.buf)?;
+
+        self.hasher.reset();
+        let hash = *self.hasher.update(&self.buf).finalize().as_bytes();
+
+        if let Some(existing) = self.dest.index().chunks.get(&hash) {
+            self.stats.deduplicated += 1;
+            return Ok(existing);
+        }
+
+        let stored = self.writer.write_chunk(&hash, &self.buf)?;
+        self.stats.chunks += 1;
+        self.stats.bytes += len as u64;
+
+        Ok(self.dest.index().insert_chunk(hash, || stored))
+    }
+
+    /// Write out the chunks that are still buffered
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Copier;
+    use crate::{stash::restore, Entry, Files};
+    use infinitree::{
+        backends::test::InMemoryBackend, crypto::UsernamePassword, object::Writer, Infinitree,
+    };
+    use std::{collections::BTreeMap, sync::Arc};
+
+    #[test]
+    fn copy_between_keys() {
+        let stash = |name: &str| {
+            Infinitree::<Files>::empty(
+                InMemoryBackend::shared(),
+                UsernamePassword::with_credentials(name.to_string(), "password".to_string())
+                    .unwrap(),
+            )
+            .unwrap()
+        };
+        let (source, dest) = (stash("source"), stash("dest"));
+
+        let data = b"copied between stashes".to_vec();
+        let hash = *source.hasher().unwrap().update(&data).finalize().as_bytes();
+        let mut writer = source.storage_writer().unwrap();
+        let pointer = Arc::new(writer.write_chunk(&hash, &data).unwrap());
+        writer.flush().unwrap();
+
+        let entry = Entry {
+            size: data.len() as u64,
+            chunks: BTreeMap::from([(0, pointer)]),
+            ..Default::default()
+        };
+
+        let mut copier = Copier::new(&source, &dest).unwrap();
+        copier.copy_file("a", &entry).unwrap();
+        copier.copy_file("b", &entry).unwrap();
+        copier.flush().unwrap();
+        assert_eq!(copier.stats.files, 2);
+        assert_eq!(copier.stats.chunks, 1);
+
+        let copy = dest.index().tree.file("b").unwrap().unwrap();
+        let mut restored = vec![];
+        restore::write_contents(&dest, &copy, &mut restored).unwrap();
+        assert_eq!(restored, data);
+    }
+}
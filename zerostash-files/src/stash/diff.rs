@@ -0,0 +1,193 @@
+//! Compare the files of two commits, or a commit and the local
+//! filesystem.
+use crate::files::{self, normalize_filename, Entry};
+use ignore::WalkBuilder;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub change: Change,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+}
+
+impl FileChange {
+    /// Difference between the new and the old size, in bytes
+    pub fn size_delta(&self) -> i64 {
+        self.new_size.unwrap_or_default() as i64 - self.old_size.unwrap_or_default() as i64
+    }
+}
+
+/// The changes from `old` to `new`, ordered by path
+pub fn diff(
+    old: impl IntoIterator<Item = (String, Arc<Entry>)>,
+    new: impl IntoIterator<Item = (String, Arc<Entry>)>,
+) -> Vec<FileChange> {
+    let mut old = old.into_iter().collect::<BTreeMap<_, _>>();
+    let mut changes = vec![];
+
+    for (path, new) in new {
+        let change = match old.remove(&path) {
+            None => Change::Added,
+            Some(old) if is_modified(&old, &new) => {
+                changes.push(FileChange {
+                    path,
+                    change: Change::Modified,
+                    old_size: Some(old.size),
+                    new_size: Some(new.size),
+                });
+                continue;
+            }
+            Some(_) => continue,
+        };
+
+        changes.push(FileChange {
+            path,
+            change,
+            old_size: None,
+            new_size: Some(new.size),
+        });
+    }
+
+    changes.extend(old.into_iter().map(|(path, old)| FileChange {
+        path,
+        change: Change::Removed,
+        old_size: Some(old.size),
+        new_size: None,
+    }));
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    changes
+}
+
+/// Metadata that's missing on either side, like the owner of a file
+/// stored without `--preserve-ownership`, isn't compared. Neither are
+/// the chunks of files on the local filesystem.
+fn is_modified(old: &Entry, new: &Entry) -> bool {
+    let differs = |a: Option<u32>, b: Option<u32>| a.zip(b).is_some_and(|(a, b)| a != b);
+
+    old.size != new.size
+        || old.file_type != new.file_type
+        || (old.unix_secs, old.unix_nanos) != (new.unix_secs, new.unix_nanos)
+        || differs(old.unix_perm, new.unix_perm)
+        || differs(old.unix_uid, new.unix_uid)
+        || differs(old.unix_gid, new.unix_gid)
+        || (!old.chunks.is_empty()
+            && !new.chunks.is_empty()
+            && old
+                .chunks
+                .iter()
+                .map(|(start, cp)| (start, cp.hash()))
+                .ne(new.chunks.iter().map(|(start, cp)| (start, cp.hash()))))
+}
+
+/// Files and symlinks under `paths` on the local filesystem, keyed the
+/// same way as in the stash
+pub fn local_files(paths: &[PathBuf]) -> anyhow::Result<Vec<(String, Arc<Entry>)>> {
+    let preserve = files::PreserveMetadata {
+        permissions: true,
+        ownership: true,
+        times: true,
+    };
+
+    let mut files = vec![];
+    for path in paths {
+        let mut walk = WalkBuilder::new(fs::canonicalize(path)?);
+        walk.standard_filters(false);
+
+        for dir_entry in walk.build() {
+            let (path, metadata) = match dir_entry.and_then(|de| {
+                let metadata = de.metadata()?;
+                Ok((de.into_path(), metadata))
+            }) {
+                Ok(found) => found,
+                Err(error) => {
+                    warn!(%error, "failed to read local file; skipping");
+                    continue;
+                }
+            };
+
+            if !metadata.is_file() && !metadata.is_symlink() && !files::is_special(&metadata) {
+                continue;
+            }
+
+            let entry = Entry::from_metadata(metadata, &path, &preserve)?;
+            files.push((normalize_filename(&path)?, Arc::new(entry)));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Whether `path` in the stash is under one of the local `paths`
+pub fn is_under(path: &str, paths: &[PathBuf]) -> bool {
+    paths.iter().any(|prefix| {
+        let prefix = fs::canonicalize(prefix)
+            .ok()
+            .and_then(|p| normalize_filename(&p).ok())
+            .unwrap_or_default();
+
+        Path::new(path).starts_with(prefix)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, Change};
+    use crate::Entry;
+    use std::sync::Arc;
+
+    #[test]
+    fn added_removed_and_modified() {
+        let entry = |size, unix_secs, unix_uid| {
+            Arc::new(Entry {
+                size,
+                unix_secs,
+                unix_uid,
+                ..Default::default()
+            })
+        };
+
+        let old = [
+            ("kept".to_string(), entry(10, 1, Some(0))),
+            ("grown".to_string(), entry(10, 1, None)),
+            ("gone".to_string(), entry(5, 1, None)),
+        ];
+        let new = [
+            // the owner wasn't recorded in the new commit
+            ("kept".to_string(), entry(10, 1, None)),
+            ("grown".to_string(), entry(25, 2, None)),
+            ("new".to_string(), entry(7, 2, None)),
+        ];
+
+        let changes = diff(old, new)
+            .into_iter()
+            .map(|c| (c.path.clone(), c.change, c.size_delta()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            changes,
+            [
+                ("gone".to_string(), Change::Removed, -5),
+                ("grown".to_string(), Change::Modified, 15),
+                ("new".to_string(), Change::Added, 7),
+            ]
+        );
+    }
+}
@@ -0,0 +1,589 @@
+//! Estimate the storage that removing commits or paths would reclaim.
+//!
+//! Chunks are deduplicated across files and commits, so deleting a
+//! commit only frees the chunks that no other commit refers to. The
+//! report built here attributes every chunk to the single commit and
+//! the single path prefix that own it, if there is one.
+//!
+//! Sizes are counted before compression and encryption.
+use crate::{
+    backends::{Moved, Relocation},
+    files::Entry,
+    legacy,
+    stash::restore,
+    Files, OBJECT_SIZE,
+};
+use chrono::{DateTime, Utc};
+use infinitree::{
+    backends::Backend,
+    object::{ObjectId, WriteObject},
+    tree::{CommitFilter, CommitId},
+    ChunkPointer, Digest, Infinitree,
+};
+use rand::RngCore;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::Arc,
+};
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Owner {
+    One(usize),
+    Many,
+}
+
+impl Owner {
+    fn claim(&mut self, other: usize) {
+        if *self != Owner::One(other) {
+            *self = Owner::Many;
+        }
+    }
+}
+
+struct ChunkUse {
+    size: u64,
+    commit: Owner,
+    prefix: Owner,
+}
+
+/// Number of chunks and bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, size: u64) {
+        self.chunks += 1;
+        self.bytes += size;
+    }
+}
+
+/// Space freed by forgetting a single commit
+#[derive(Clone, Debug)]
+pub struct CommitReclaim {
+    pub id: CommitId,
+    pub time: DateTime<Utc>,
+    pub message: Option<String>,
+    pub reclaim: Usage,
+}
+
+/// Space freed by removing a path prefix from every commit
+#[derive(Clone, Debug)]
+pub struct PrefixReclaim {
+    pub prefix: String,
+    pub reclaim: Usage,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub commits: Vec<CommitReclaim>,
+    pub prefixes: Vec<PrefixReclaim>,
+    /// All chunks referenced by any commit
+    pub total: Usage,
+}
+
+#[derive(Default)]
+struct Accounting {
+    depth: usize,
+    chunks: HashMap<Digest, ChunkUse>,
+    prefixes: Vec<String>,
+    prefix_ids: HashMap<String, usize>,
+}
+
+impl Accounting {
+    fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            ..Default::default()
+        }
+    }
+
+    fn prefix_of(&self, path: &str) -> String {
+        let mut dirs = path.split('/').collect::<Vec<_>>();
+        dirs.pop();
+        dirs.truncate(self.depth);
+
+        if dirs.is_empty() {
+            "/".into()
+        } else {
+            dirs.join("/")
+        }
+    }
+
+    fn add_file(
+        &mut self,
+        commit: usize,
+        path: &str,
+        chunks: impl IntoIterator<Item = (Digest, u64)>,
+    ) {
+        let prefix = self.prefix_of(path);
+        let next_id = self.prefixes.len();
+        let prefix = *self.prefix_ids.entry(prefix.clone()).or_insert_with(|| {
+            self.prefixes.push(prefix);
+            next_id
+        });
+
+        for (digest, size) in chunks {
+            self.chunks
+                .entry(digest)
+                .and_modify(|chunk| {
+                    chunk.commit.claim(commit);
+                    chunk.prefix.claim(prefix);
+                })
+                .or_insert(ChunkUse {
+                    size,
+                    commit: Owner::One(commit),
+                    prefix: Owner::One(prefix),
+                });
+        }
+    }
+
+    fn finish(self, commits: usize) -> (Vec<Usage>, Vec<(String, Usage)>, Usage) {
+        let mut per_commit = vec![Usage::default(); commits];
+        let mut per_prefix = vec![Usage::default(); self.prefixes.len()];
+        let mut total = Usage::default();
+
+        for chunk in self.chunks.values() {
+            total.add(chunk.size);
+
+            if let Owner::One(commit) = chunk.commit {
+                per_commit[commit].add(chunk.size);
+            }
+            if let Owner::One(prefix) = chunk.prefix {
+                per_prefix[prefix].add(chunk.size);
+            }
+        }
+
+        (
+            per_commit,
+            self.prefixes.into_iter().zip(per_prefix).collect(),
+            total,
+        )
+    }
+}
+
+/// A commit, with the state of the stash as of it loaded
+pub(crate) struct CommitState {
+    pub id: CommitId,
+    pub time: DateTime<Utc>,
+    pub message: Option<String>,
+    pub stash: Infinitree<Files>,
+}
+
+impl CommitState {
+    /// Every file in the commit
+    pub fn files(&self) -> impl Iterator<Item = (String, Arc<Entry>)> + '_ {
+        restore::iter(&self.stash, ["*"])
+    }
+
+    /// The objects that hold the ZFS snapshots and streams of the
+    /// commit, or `None` if there are ZFS snapshots stored by older
+    /// versions, which don't list their chunks
+    pub fn stream_objects(&self) -> Option<HashSet<ObjectId>> {
+        let index = self.stash.index();
+        let mut objects = HashSet::new();
+        let mut legacy = false;
+
+        index.zfs_snapshots.for_each(|_, zfs| {
+            legacy |= zfs.chunks.is_empty() && zfs.size == 0;
+            objects.extend(zfs.chunks.values().map(|cp| *cp.object_id()));
+        });
+        index.streams.for_each(|_, stream| {
+            objects.extend(stream.chunks.values().map(|cp| *cp.object_id()));
+        });
+
+        (!legacy).then_some(objects)
+    }
+}
+
+/// The chunks of a file, with their size
+pub(crate) fn chunks_of(entry: &Entry) -> impl Iterator<Item = (&Arc<ChunkPointer>, u64)> {
+    let ends = entry.chunks.keys().copied().skip(1).chain([entry.size]);
+    entry
+        .chunks
+        .iter()
+        .zip(ends)
+        .map(|((start, cp), end)| (cp, end.saturating_sub(*start)))
+}
+
+/// Load the state of every commit of the stash separately, and pass
+/// them to `visit` in order.
+///
+/// `open` must return a freshly opened instance of the stash every
+/// time it is called. Files in the flat index of older stashes are
+/// added to the tree.
+pub(crate) fn for_each_commit(
+    mut open: impl FnMut() -> anyhow::Result<Infinitree<Files>>,
+    mut visit: impl FnMut(usize, CommitState) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let commits = open()?
+        .commit_list()
+        .iter()
+        .map(|c| (c.id, c.metadata.time.into(), c.metadata.message.clone()))
+        .collect::<Vec<(CommitId, DateTime<Utc>, Option<String>)>>();
+
+    for (idx, (id, time, message)) in commits.into_iter().enumerate() {
+        let stash = open()?;
+        stash.filter_commits(CommitFilter::UpTo(id));
+
+        // without the tree, only the flat index of an older stash can
+        // tell what the commit holds
+        let loaded = legacy::load_tree(&stash);
+        if loaded.missing.contains(&"files")
+            || (loaded.missing.contains(&"tree") && loaded.files == 0)
+        {
+            anyhow::bail!("Failed to load the files of commit {id:?}");
+        }
+        stash.load(stash.index().zfs_snapshots())?;
+        stash.load(stash.index().streams())?;
+
+        visit(
+            idx,
+            CommitState {
+                id,
+                time,
+                message,
+                stash,
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Build a reclamation report without modifying the stash.
+///
+/// `open` must return a freshly opened instance of the stash every
+/// time it is called, as the state of every commit is loaded
+/// separately. Paths are grouped by their first `depth` directories.
+pub fn report(
+    open: impl FnMut() -> anyhow::Result<Infinitree<Files>>,
+    depth: usize,
+) -> anyhow::Result<Report> {
+    let mut commits = vec![];
+    let mut accounting = Accounting::new(depth);
+
+    for_each_commit(open, |idx, commit| {
+        for (path, entry) in commit.files() {
+            let chunks = chunks_of(&entry).map(|(cp, size)| (*cp.hash(), size));
+            accounting.add_file(idx, &path, chunks);
+        }
+
+        commits.push((commit.id, commit.time, commit.message));
+        Ok(())
+    })?;
+
+    let (per_commit, per_prefix, total) = accounting.finish(commits.len());
+
+    Ok(Report {
+        commits: commits
+            .into_iter()
+            .zip(per_commit)
+            .map(|((id, time, message), reclaim)| CommitReclaim {
+                id,
+                time,
+                message,
+                reclaim,
+            })
+            .collect(),
+        prefixes: per_prefix
+            .into_iter()
+            .map(|(prefix, reclaim)| PrefixReclaim { prefix, reclaim })
+            .collect(),
+        total,
+    })
+}
+
+/// What pruning the forgotten commits of a stash would delete
+#[derive(Clone, Debug, Default)]
+pub struct PrunePlan {
+    /// Chunks that only forgotten commits refer to
+    pub dead_chunks: Vec<Digest>,
+    /// Objects that only hold dead chunks
+    pub dead_objects: Vec<ObjectId>,
+    /// Objects that hold live chunks next to dead ones, with the dead
+    /// chunks in them
+    pub partial_objects: Vec<(ObjectId, Usage)>,
+    /// The live chunks of the partial objects that only hold file
+    /// contents, which [`repack`] can move
+    pub live_chunks: Vec<(ObjectId, Vec<Arc<ChunkPointer>>)>,
+    /// Chunks in `dead_objects`
+    pub reclaimed: Usage,
+}
+
+/// Find the chunks and objects that only the `forgotten` commits use.
+///
+/// `open` is called the same way as for [`report`].
+pub fn prune_plan(
+    open: impl FnMut() -> anyhow::Result<Infinitree<Files>>,
+    forgotten: &HashSet<CommitId>,
+) -> anyhow::Result<PrunePlan> {
+    let mut live = HashSet::new();
+    let mut live_objects = HashSet::new();
+    let mut chunks = HashMap::new();
+
+    for_each_commit(open, |_, commit| {
+        let retained = !forgotten.contains(&commit.id);
+
+        for (_, entry) in commit.files() {
+            for (cp, size) in chunks_of(&entry) {
+                chunks.insert(*cp.hash(), (cp.clone(), size));
+                if retained {
+                    live.insert(*cp.hash());
+                }
+            }
+        }
+
+        let Some(objects) = commit.stream_objects() else {
+            anyhow::bail!("ZFS snapshots stored by older versions can't be pruned safely");
+        };
+        live_objects.extend(objects);
+        Ok(())
+    })?;
+
+    let mut plan = PrunePlan::default();
+    let mut objects = HashMap::<ObjectId, (Usage, Usage, Vec<Arc<ChunkPointer>>)>::new();
+    for (digest, (cp, size)) in chunks {
+        let (alive, dead, pointers) = objects.entry(*cp.object_id()).or_default();
+        if live.contains(&digest) {
+            alive.add(size);
+            pointers.push(cp);
+        } else {
+            dead.add(size);
+            plan.dead_chunks.push(digest);
+        }
+    }
+
+    for (object, (alive, dead, pointers)) in objects {
+        if dead.chunks == 0 {
+            continue;
+        }
+
+        if live_objects.contains(&object) {
+            plan.partial_objects.push((object, dead));
+        } else if alive.chunks == 0 {
+            plan.dead_objects.push(object);
+            plan.reclaimed.chunks += dead.chunks;
+            plan.reclaimed.bytes += dead.bytes;
+        } else {
+            plan.partial_objects.push((object, dead));
+            plan.live_chunks.push((object, pointers));
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Where the encrypted chunk is in its object.
+///
+/// `ChunkPointer` doesn't expose its offset, so it's read from the
+/// serialized pointer. Returns `None` if the pointer doesn't carry it
+/// in a form this version understands.
+fn chunk_range(cp: &ChunkPointer) -> Option<Range<u32>> {
+    let pointer = serde_json::to_value(cp).ok()?;
+    let offset = u32::try_from(pointer.get("offs")?.as_u64()?).ok()?;
+    let size = u32::try_from(pointer.get("size")?.as_u64()?).ok()?;
+
+    Some(offset..offset.checked_add(size)?)
+}
+
+/// Put every object into the first group that has room for its live
+/// bytes, largest objects first. Returns the indexes of `sizes` by
+/// group.
+fn pack(sizes: &[usize], capacity: usize) -> Vec<Vec<usize>> {
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|idx| std::cmp::Reverse(sizes[*idx]));
+
+    let mut groups: Vec<(usize, Vec<usize>)> = vec![];
+    for idx in order {
+        match groups
+            .iter_mut()
+            .find(|(used, _)| used + sizes[idx] <= capacity)
+        {
+            Some((used, members)) => {
+                *used += sizes[idx];
+                members.push(idx);
+            }
+            None => groups.push((sizes[idx], vec![idx])),
+        }
+    }
+
+    groups.into_iter().map(|(_, members)| members).collect()
+}
+
+/// Copy the live chunks of partially dead `objects` into as few new
+/// objects as they fit in, and return where they went.
+///
+/// Chunks are copied encrypted, as they are. Nothing is written if it
+/// wouldn't save any objects, or if the offsets of the chunks can't be
+/// found. The original objects are left for the caller to delete once
+/// the relocations are recorded.
+pub fn repack(
+    backend: &dyn Backend,
+    objects: &[(ObjectId, Vec<Arc<ChunkPointer>>)],
+) -> anyhow::Result<Vec<Relocation>> {
+    let capacity = OBJECT_SIZE as usize;
+
+    let mut sources = vec![];
+    for (id, chunks) in objects {
+        let Some(mut ranges) = chunks
+            .iter()
+            .map(|cp| chunk_range(cp))
+            .collect::<Option<Vec<_>>>()
+        else {
+            warn!("the offsets of chunks in their objects are unknown, not repacking");
+            return Ok(vec![]);
+        };
+        ranges.sort_by_key(|r| r.start);
+        ranges.dedup();
+        sources.push((*id, ranges));
+    }
+
+    let sizes = sources
+        .iter()
+        .map(|(_, ranges)| ranges.iter().map(|r| r.len()).sum())
+        .collect::<Vec<usize>>();
+    let groups = pack(&sizes, capacity);
+    if groups.len() >= sources.len() {
+        return Ok(vec![]);
+    }
+
+    let mut relocations = vec![];
+    for group in groups {
+        let mut target = WriteObject::default();
+        target.set_id(ObjectId::from_bytes(&rand::random::<[u8; 32]>()));
+        rand::thread_rng().fill_bytes(target.as_inner_mut());
+
+        let mut cursor = 0;
+        for idx in group {
+            let (source, ranges) = &sources[idx];
+            let object = backend.read_object(source)?;
+
+            let mut moved = vec![];
+            for range in ranges {
+                let Some(chunk) = object
+                    .as_inner()
+                    .get(range.start as usize..range.end as usize)
+                else {
+                    anyhow::bail!("A chunk is out of the bounds of object {source}");
+                };
+                target.as_inner_mut()[cursor..cursor + chunk.len()].copy_from_slice(chunk);
+                moved.push(Moved {
+                    from: range.start,
+                    to: cursor as u32,
+                    len: chunk.len() as u32,
+                });
+                cursor += chunk.len();
+            }
+
+            relocations.push(Relocation {
+                source: *source,
+                target: *target.id(),
+                ranges: moved,
+            });
+        }
+
+        backend.write_object(&target)?;
+    }
+    backend.sync()?;
+
+    Ok(relocations)
+}
+
+/// Collect the objects that hold file contents, ZFS snapshots or
+/// streams in any commit.
+///
+/// `open` is called the same way as for [`report`].
+pub fn data_objects(
+    open: impl FnMut() -> anyhow::Result<Infinitree<Files>>,
+) -> anyhow::Result<HashSet<ObjectId>> {
+    let mut objects = HashSet::new();
+
+    for_each_commit(open, |_, commit| {
+        for (_, entry) in commit.files() {
+            objects.extend(entry.chunks.values().map(|cp| *cp.object_id()));
+        }
+
+        match commit.stream_objects() {
+            Some(streams) => objects.extend(streams),
+            None => {
+                warn!(commit = ?commit.id, "the objects of ZFS snapshots stored by older versions can't be found");
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pack, Accounting, Usage};
+
+    #[test]
+    fn live_chunks_are_packed_largest_first() {
+        // 60 and 40 share one object, 50 and 30 the other
+        assert_eq!(pack(&[30, 60, 50, 40], 100), vec![vec![1, 3], vec![2, 0]]);
+        assert_eq!(pack(&[80, 80], 100).len(), 2);
+        assert!(pack(&[], 100).is_empty());
+    }
+
+    #[test]
+    fn only_exclusive_chunks_are_reclaimed() {
+        let mut acc = Accounting::new(1);
+
+        // commit 0: a/file; commit 1: a/file unchanged, b/file added
+        acc.add_file(0, "a/file", [([1; 32], 10), ([2; 32], 20)]);
+        acc.add_file(1, "a/file", [([1; 32], 10), ([2; 32], 20)]);
+        acc.add_file(1, "b/x/file", [([3; 32], 30), ([2; 32], 20)]);
+        acc.add_file(1, "top", [([4; 32], 40)]);
+
+        let (commits, prefixes, total) = acc.finish(2);
+
+        assert_eq!(commits[0], Usage::default());
+        assert_eq!(
+            commits[1],
+            Usage {
+                chunks: 2,
+                bytes: 70
+            }
+        );
+        assert_eq!(
+            prefixes,
+            vec![
+                (
+                    "a".to_string(),
+                    Usage {
+                        chunks: 1,
+                        bytes: 10
+                    }
+                ),
+                (
+                    "b".to_string(),
+                    Usage {
+                        chunks: 1,
+                        bytes: 30
+                    }
+                ),
+                (
+                    "/".to_string(),
+                    Usage {
+                        chunks: 1,
+                        bytes: 40
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            total,
+            Usage {
+                chunks: 4,
+                bytes: 100
+            }
+        );
+    }
+}
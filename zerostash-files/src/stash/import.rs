@@ -0,0 +1,258 @@
+//! Import the members of a tar archive into a stash.
+//!
+//! The archive is read as a stream, so it can come straight from a
+//! pipe. Files are chunked the same way `commit` chunks files on disk,
+//! which means they deduplicate against each other.
+use super::store::{splitter, MAX_FILE_SIZE};
+use crate::{
+    files::{normalize_filename, Entry, FileType},
+    Chunker, Files,
+};
+use infinitree::{
+    object::{AEADWriter, Writer},
+    ChunkPointer, Digest, Infinitree,
+};
+use std::{collections::BTreeMap, io::Read, path::Path, sync::Arc};
+use tar::EntryType;
+use tracing::{debug, warn};
+
+/// Contents of large files are read in windows of this size
+const WINDOW_SIZE: usize = 64 * 1024 * 1024;
+
+// file type bits of `st_mode`, which tar leaves out of the mode
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub files: usize,
+    pub bytes: u64,
+    /// Members that couldn't be imported, and why
+    pub skipped: Vec<String>,
+}
+
+/// Add the members of the tar archive in `input` to the tree of the
+/// stash, under `prefix`.
+///
+/// Directories are created as their contents are added, so empty
+/// directories, and special files like devices, are skipped.
+pub fn import_tar(
+    stash: &Infinitree<Files>,
+    input: impl Read,
+    prefix: &str,
+) -> anyhow::Result<Imported> {
+    let mut importer = Importer {
+        stash,
+        writer: stash.storage_writer()?,
+        chunker: stash.index().params.read().chunker,
+    };
+    let mut imported = Imported::default();
+
+    let mut archive = tar::Archive::new(input);
+    for member in archive.entries()? {
+        let mut member = member?;
+        let member_path = member.path()?.into_owned();
+
+        let path = match normalize_filename(&member_path) {
+            Ok(path) if !path.is_empty() => join(prefix, &path),
+            _ => {
+                imported
+                    .skipped
+                    .push(format!("{}: invalid path", member_path.display()));
+                continue;
+            }
+        };
+
+        let header = member.header();
+        let mode = header.mode()?;
+        let mut entry = Entry {
+            unix_secs: header.mtime()? as i64,
+            unix_perm: Some(mode),
+            unix_uid: header.uid()?.try_into().ok(),
+            unix_gid: header.gid()?.try_into().ok(),
+            readonly: Some(mode & 0o222 == 0),
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            ..Default::default()
+        };
+
+        match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                entry.unix_perm = Some(S_IFREG | mode);
+                entry.size = member.size();
+                entry.chunks = importer.store_contents(&mut member, entry.size)?;
+            }
+            EntryType::Symlink => {
+                let target = member.link_name()?.unwrap_or_default().into_owned();
+                entry.unix_perm = Some(S_IFLNK | mode);
+                entry.file_type = FileType::Symlink(target);
+            }
+            EntryType::Link => {
+                let target = member
+                    .link_name()?
+                    .and_then(|target| normalize_filename(&target).ok())
+                    .map(|target| join(prefix, &target))
+                    .and_then(|target| stash.index().tree.file(&target).ok().flatten());
+                let Some(target) = target else {
+                    imported
+                        .skipped
+                        .push(format!("{path}: the hard link's target isn't imported"));
+                    continue;
+                };
+                entry.unix_perm = target.unix_perm;
+                entry.size = target.size;
+                entry.chunks = target.chunks.clone();
+            }
+            EntryType::Directory => continue,
+            other => {
+                imported
+                    .skipped
+                    .push(format!("{path}: can't import {other:?} entries"));
+                continue;
+            }
+        }
+
+        debug!(%path, chunks = entry.chunks.len(), "imported");
+        imported.files += 1;
+        imported.bytes += entry.size;
+        stash
+            .index()
+            .tree
+            .insert_file(&path, entry)
+            .map_err(|e| anyhow::anyhow!("{path}: {e:?}"))?;
+    }
+
+    importer.writer.flush()?;
+    for skipped in imported.skipped.iter() {
+        warn!(%skipped, "skipped tar member");
+    }
+
+    Ok(imported)
+}
+
+fn join(prefix: &str, path: &str) -> String {
+    match normalize_filename(&Path::new(prefix)) {
+        Ok(prefix) if !prefix.is_empty() => format!("{prefix}/{path}"),
+        _ => path.to_string(),
+    }
+}
+
+struct Importer<'stash> {
+    stash: &'stash Infinitree<Files>,
+    writer: AEADWriter,
+    chunker: Chunker,
+}
+
+impl Importer<'_> {
+    fn store_contents(
+        &mut self,
+        input: &mut impl Read,
+        size: u64,
+    ) -> anyhow::Result<BTreeMap<u64, Arc<ChunkPointer>>> {
+        // windows of a large file are all split like a large file
+        let chunker = match self.chunker {
+            Chunker::Auto if size as usize >= MAX_FILE_SIZE => Chunker::Bup,
+            chunker => chunker,
+        };
+
+        let mut chunks = BTreeMap::new();
+        // the data from `offset` in the file that isn't stored yet
+        let mut buf = vec![];
+        let mut offset = 0;
+        let mut remaining = size;
+
+        loop {
+            let len = remaining.min((WINDOW_SIZE - buf.len()) as u64);
+            let start = buf.len();
+            buf.resize(start + len as usize, 0);
+            input.read_exact(&mut buf[start..])?;
+            remaining -= len;
+
+            // the last chunk of a window may continue in the next one,
+            // so it's carried over, and split again
+            let last_window = remaining == 0;
+            let mut stored = buf.len();
+            for (start, hash, data) in splitter(chunker, &buf, self.stash.hasher()?) {
+                if !last_window && start as usize + data.len() == buf.len() {
+                    stored = start as usize;
+                    break;
+                }
+                chunks.insert(offset + start, self.store_chunk(hash, data)?);
+            }
+
+            if last_window {
+                break;
+            }
+            buf.drain(..stored);
+            offset += stored as u64;
+        }
+
+        Ok(chunks)
+    }
+
+    fn store_chunk(&mut self, hash: Digest, data: &[u8]) -> anyhow::Result<Arc<ChunkPointer>> {
+        let index = self.stash.index();
+        if let Some(existing) = index.chunks.get(&hash) {
+            return Ok(existing);
+        }
+
+        let stored = self.writer.write_chunk(&hash, data)?;
+        Ok(index.insert_chunk(hash, || stored))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::import_tar;
+    use crate::{stash::restore, FileType, Files};
+    use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+    #[test]
+    fn import_files_and_links() {
+        let mut archive = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o640);
+        header.set_mtime(1700000000);
+        archive
+            .append_data(&mut header, "./dump/db.sql", &b"hello"[..])
+            .unwrap();
+
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        archive
+            .append_link(&mut link, "dump/latest.sql", "db.sql")
+            .unwrap();
+
+        let mut fifo = tar::Header::new_gnu();
+        fifo.set_entry_type(tar::EntryType::Fifo);
+        fifo.set_size(0);
+        archive
+            .append_data(&mut fifo, "dump/pipe", std::io::empty())
+            .unwrap();
+        let archive = archive.into_inner().unwrap();
+
+        let stash = Infinitree::<Files>::empty(
+            InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("import".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap();
+
+        let imported = import_tar(&stash, &archive[..], "backups").unwrap();
+        assert_eq!(imported.files, 2);
+        assert_eq!(imported.skipped.len(), 1);
+
+        let tree = &stash.index().tree;
+        let file = tree.file("backups/dump/db.sql").unwrap().unwrap();
+        assert_eq!(file.unix_perm, Some(0o100640));
+        assert_eq!(file.unix_secs, 1700000000);
+
+        let mut contents = vec![];
+        restore::write_contents(&stash, &file, &mut contents).unwrap();
+        assert_eq!(contents, b"hello");
+
+        let link = tree.file("backups/dump/latest.sql").unwrap().unwrap();
+        assert_eq!(link.file_type, FileType::Symlink("db.sql".into()));
+    }
+}
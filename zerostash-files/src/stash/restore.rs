@@ -1,13 +1,25 @@
-use crate::{files, Files};
+use super::store;
+use crate::{files, journal::Journal, Chunker, Files};
+use anyhow::Context;
 use flume as mpsc;
 use futures::future::join_all;
 use infinitree::{fields::QueryAction, object, Infinitree, *};
-use memmap2::MmapOptions;
-use std::{env, path::PathBuf, sync::Arc};
+use memmap2::{Mmap, MmapOptions};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 use tokio::task;
-use tracing::{error, trace};
+use tracing::{debug, debug_span, error, info, trace, warn, Instrument, Span};
 
 type ThreadWork = (PathBuf, Arc<files::Entry>);
+type Deferred = Option<Arc<Mutex<Fixups>>>;
 
 type Sender = mpsc::Sender<ThreadWork>;
 type Receiver = mpsc::Receiver<ThreadWork>;
@@ -34,6 +46,82 @@ pub struct Options {
     #[clap(short = 'm', long = "min-size")]
     pub min_size: Option<u64>,
 
+    /// Only paths that match a filter expression. Eg:
+    /// 'size > 100M and mtime < 2023-01-01 and path ~ "logs/**"'
+    #[clap(long = "where", value_name = "EXPR", value_parser = crate::query::Query::parse)]
+    pub filter: Option<crate::query::Query>,
+
+    /// Also restore the paths that match this glob, like the ones
+    /// given as arguments. Can be repeated.
+    #[clap(long = "include", value_name = "GLOB")]
+    pub includes: Vec<String>,
+
+    /// Skip the paths that match this glob, and everything in the
+    /// directories that match it. Can be repeated.
+    #[clap(long = "exclude", value_name = "GLOB", value_parser = parse_glob)]
+    pub excludes: Vec<glob::Pattern>,
+
+    /// Skip the paths that match the globs in this file, one per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    #[clap(long = "exclude-file", value_name = "PATH", value_parser = ExcludeFile::read)]
+    pub exclude_files: Vec<ExcludeFile>,
+
+    /// Only restore paths tagged with `key=value`. Can be repeated.
+    #[clap(long = "tag", value_name = "KEY=VALUE", value_parser = crate::parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Chunk files that already exist at the destination, and only
+    /// read the chunks that differ from the stash.
+    #[clap(long = "reuse-local")]
+    pub reuse_local: bool,
+
+    /// With --reuse-local, also chunk the files whose size and
+    /// modification time match the stash, instead of skipping them
+    #[clap(long, requires = "reuse_local")]
+    pub checksum: bool,
+
+    /// Keep track of the restored files, and skip the ones that an
+    /// interrupted restore of the same commit to the same directory
+    /// with --resume finished
+    #[clap(long)]
+    pub resume: bool,
+
+    /// When not running as root, write the devices, ownership and
+    /// permissions that can't be restored into a shell script, so they
+    /// can be applied later with `sudo sh PATH`
+    #[clap(long = "fixup-script", value_name = "PATH")]
+    pub fixup_script: Option<PathBuf>,
+
+    /// Only paths the delegation token used to open the stash covers
+    #[clap(skip)]
+    pub scope: Option<crate::TokenScope>,
+
+    /// Restore the paths listed in this file first, one glob or path
+    /// prefix per line, in the order they are listed
+    #[clap(long = "priority-file", value_name = "PATH")]
+    pub priority_file: Option<PathBuf>,
+
+    /// Create this file once the paths in --priority-file are
+    /// restored, while the rest of the files are still being restored.
+    /// Services that depend on them can wait for it to appear.
+    #[clap(
+        long = "priority-done",
+        value_name = "PATH",
+        requires = "priority_file"
+    )]
+    pub priority_done: Option<PathBuf>,
+
+    /// Remove this many leading components from the restored paths.
+    /// Files with no components left are skipped.
+    #[clap(long = "strip-components", value_name = "N", default_value_t = 0)]
+    pub strip_components: usize,
+
+    /// Rewrite the restored paths with a regular expression, after
+    /// --strip-components. Can be repeated, and applied in order.
+    /// Eg: '^home/[^/]+=>home/restored'
+    #[clap(long = "transform", value_name = "REGEX=>REPLACEMENT", value_parser = Transform::parse)]
+    pub transforms: Vec<Transform>,
+
     /// Change directory before restore operation.
     #[clap(short = 'c', long = "chdir")]
     pub chdir: Option<PathBuf>,
@@ -43,9 +131,191 @@ pub struct Options {
     #[cfg(target_family = "unix")]
     #[clap(short = 'C', long = "chroot")]
     pub chroot: Option<PathBuf>,
+
+    /// Files and bytes restored so far
+    #[clap(skip)]
+    pub activity: crate::activity::Activity,
+}
+
+/// Rewrites the paths of restored files
+#[derive(Clone, Debug)]
+pub struct Transform {
+    pattern: regex::Regex,
+    replacement: String,
+}
+
+impl Transform {
+    pub fn parse(transform: &str) -> Result<Self, String> {
+        let Some((pattern, replacement)) = transform.split_once("=>") else {
+            return Err(format!(
+                "invalid transform `{transform}`, expected `REGEX=>REPLACEMENT`"
+            ));
+        };
+
+        Ok(Self {
+            pattern: regex::Regex::new(pattern).map_err(|e| e.to_string())?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+fn parse_glob(glob: &str) -> Result<glob::Pattern, String> {
+    glob::Pattern::new(glob).map_err(|e| format!("invalid glob `{glob}`: {e}"))
 }
 
-fn iter<V: AsRef<[T]>, T: AsRef<str>>(stash: &Infinitree<Files>, glob: V) -> FileIterator {
+/// Globs to exclude from a restore, read from a file
+#[derive(Clone, Debug)]
+pub struct ExcludeFile {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl ExcludeFile {
+    pub fn read(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("can't read {path}: {e}"))?;
+
+        Ok(Self {
+            patterns: contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(parse_glob)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Directory in the target of a restore that holds its journal, so it
+/// can be resumed
+pub const STATE_DIR: &str = ".zerostash-restore-state";
+
+/// Files recorded in the restore journal between syncs
+const JOURNAL_SYNC_INTERVAL: usize = 1024;
+
+/// Summary of the work a restore would do
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Number of files to restore
+    pub files: usize,
+    /// Total size of the restored files
+    pub bytes: u64,
+    /// Objects that need to be read from the backend
+    pub objects: HashSet<object::ObjectId>,
+}
+
+/// Reassemble the contents of a file from its chunks, and write them to
+/// `out`
+pub fn write_contents(
+    stash: &Infinitree<Files>,
+    entry: &files::Entry,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut reader = stash.storage_reader()?;
+    let mut buf = vec![];
+
+    let ends = entry.chunks.keys().skip(1).copied().chain([entry.size]);
+    for ((start, pointer), end) in entry.chunks.iter().zip(ends) {
+        buf.resize((end - start) as usize, 0);
+        reader
+            .read_chunk(pointer, &mut buf)
+            .with_context(|| format!("failed to read the chunk at offset {start}"))?;
+        out.write_all(&buf)?;
+    }
+
+    Ok(())
+}
+
+/// Restore a single file to `target`, instead of under its own path
+pub fn restore_file(
+    stash: &Infinitree<Files>,
+    entry: &files::Entry,
+    target: &Path,
+    preserve: &files::PreserveMetadata,
+) -> anyhow::Result<()> {
+    let file = entry
+        .restore_to(&target, preserve)
+        .with_context(|| format!("Failed to create {}", target.display()))?;
+
+    if let Some(file) = file {
+        let mut out = io::BufWriter::new(file);
+        write_contents(stash, entry, &mut out)?;
+        let file = out.into_inner().map_err(io::IntoInnerError::into_error)?;
+
+        entry
+            .restore_metadata(&file, &target, preserve)
+            .with_context(|| format!("Failed to restore the metadata of {}", target.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Read up to `len` bytes of the file at `path`, starting at `offset`
+///
+/// Only the chunks that cover the range are read, so previews don't
+/// need to restore the whole file. The range is cut short at the end
+/// of the file.
+pub fn read_range(
+    stash: &Infinitree<Files>,
+    path: &str,
+    offset: u64,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let entry = stash
+        .index()
+        .tree
+        .file(path)
+        .map_err(|e| anyhow::anyhow!("{path}: {e:?}"))?
+        .with_context(|| format!("{path}: no such file"))?;
+
+    read_entry_range(stash, &entry, offset, len)
+}
+
+/// Read up to `len` bytes of a file, starting at `offset`
+pub fn read_entry_range(
+    stash: &Infinitree<Files>,
+    entry: &files::Entry,
+    offset: u64,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let end = entry.size.min(offset.saturating_add(len as u64));
+    if offset >= end {
+        return Ok(vec![]);
+    }
+
+    let mut reader = stash.storage_reader()?;
+    let mut buf = vec![];
+    let mut out = Vec::with_capacity((end - offset) as usize);
+
+    // the chunk `offset` falls into, and the ones after it
+    let first = entry
+        .chunks
+        .range(..=offset)
+        .next_back()
+        .map_or(0, |(start, _)| *start);
+    let ends = entry
+        .chunks
+        .range(first..)
+        .skip(1)
+        .map(|(start, _)| *start)
+        .chain([entry.size]);
+
+    for ((start, pointer), chunk_end) in entry.chunks.range(first..end).zip(ends) {
+        buf.resize((chunk_end - start) as usize, 0);
+        reader
+            .read_chunk(pointer, &mut buf)
+            .with_context(|| format!("failed to read the chunk at offset {start}"))?;
+
+        let from = offset.saturating_sub(*start) as usize;
+        let to = (end.min(chunk_end) - start) as usize;
+        out.extend_from_slice(&buf[from..to]);
+    }
+
+    Ok(out)
+}
+
+pub(crate) fn iter<V: AsRef<[T]>, T: AsRef<str>>(
+    stash: &Infinitree<Files>,
+    glob: V,
+) -> FileIterator {
     let matchers = glob
         .as_ref()
         .iter()
@@ -69,7 +339,9 @@ fn iter<V: AsRef<[T]>, T: AsRef<str>>(stash: &Infinitree<Files>, glob: V) -> Fil
             }
         })
         .unwrap()
-        .filter_map(|(path, entry)| entry.map(|e| (path, e)));
+        .filter_map(|(path, entry)| entry.map(|e| (path, e)))
+        // the tree may have been rebuilt from the flat index
+        .filter(move |(path, _)| !matches!(stash.index().tree.file(path), Ok(Some(_))));
 
     Box::new(filtered_files.chain(filtered_tree))
 }
@@ -79,13 +351,40 @@ impl Options {
         &'stash self,
         stash: &'stash Infinitree<Files>,
     ) -> impl Iterator<Item = (String, Arc<crate::files::Entry>)> + 'stash {
-        let globs = if !self.globs.is_empty() {
-            self.globs.clone()
-        } else {
-            vec!["*".into()]
-        };
+        let mut globs = self
+            .globs
+            .iter()
+            .chain(self.includes.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        if globs.is_empty() {
+            globs.push("*".into());
+        }
+
+        iter(stash, globs).filter(|(path, md)| {
+            if self.is_excluded(path) {
+                return false;
+            }
+
+            if let Some(scope) = &self.scope {
+                if !scope.covers(path) {
+                    return false;
+                }
+            }
+
+            if let Some(filter) = &self.filter {
+                if !filter.matches(path, md) {
+                    return false;
+                }
+            }
+
+            if !self.tags.is_empty() {
+                let tags = stash.index().tags_of(path);
+                if !crate::matches(&tags, &self.tags) {
+                    return false;
+                }
+            }
 
-        iter(stash, globs).filter(|(_, md)| {
             if let Some(max) = self.max_size {
                 if max > md.size {
                     return false;
@@ -102,23 +401,257 @@ impl Options {
         })
     }
 
+    /// Whether an exclude glob matches `path`, or a directory it's in
+    fn is_excluded(&self, path: &str) -> bool {
+        let mut patterns = self
+            .excludes
+            .iter()
+            .chain(self.exclude_files.iter().flat_map(|f| f.patterns.iter()))
+            .peekable();
+        if patterns.peek().is_none() {
+            return false;
+        }
+
+        let path = path.trim_start_matches('/');
+        let mut dirs = path
+            .match_indices('/')
+            .map(|(end, _)| &path[..end])
+            .chain([path]);
+
+        dirs.any(|dir| patterns.clone().any(|p| p.matches(dir)))
+    }
+
+    /// Collect the files and objects a restore with these options
+    /// would touch, without writing anything
+    pub fn plan(&self, stash: &Infinitree<Files>) -> Plan {
+        let mut plan = Plan::default();
+
+        for (_, md) in self.list(stash) {
+            plan.files += 1;
+            plan.bytes += md.size;
+            plan.objects
+                .extend(md.chunks.values().map(|cp| *cp.object_id()));
+        }
+
+        plan
+    }
+
     pub async fn from_iter(
         &self,
         stash: &Infinitree<Files>,
         threads: usize,
     ) -> anyhow::Result<u64> {
+        // read before changing directories, so relative paths work
+        let priorities = match &self.priority_file {
+            Some(path) => Some(Priorities::read(path)?),
+            None => None,
+        };
+        let script = match &self.fixup_script {
+            Some(path) => Some(
+                fs::File::create(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?,
+            ),
+            None => None,
+        };
+
         self.setup_env()?;
-        let (sender, workers) = self.start_workers(stash, threads)?;
+        let deferred = self.deferred_metadata();
+        let finished = Finished {
+            activity: self.activity.clone(),
+            journal: self.open_journal(stash)?.map(Arc::new),
+            unsynced: Arc::default(),
+            skipped_devices: Arc::default(),
+        };
+
+        match priorities {
+            Some(priorities) => {
+                self.restore_prioritized(stash, threads, priorities, &deferred, &finished)
+                    .await?
+            }
+            None => {
+                let files = self.list(stash).collect::<Vec<_>>();
+                for (_, md) in files.iter() {
+                    self.activity.found(md.size);
+                }
+                self.activity.listed();
 
-        for (path, md) in self.list(stash) {
-            trace!(?path, "queued");
-            sender.send_async((path.into(), md)).await.unwrap();
+                self.restore_all(stash, threads, files.into_iter(), &deferred, &finished)
+                    .await?
+            }
+        }
+
+        // the workers are gone, so this is the last reference
+        if let Some(journal) = finished.journal.and_then(Arc::into_inner) {
+            let resumed = journal.resumed();
+            journal
+                .finish()
+                .context("Failed to remove the restore journal")?;
+            _ = fs::remove_dir(STATE_DIR);
+            if resumed > 0 {
+                info!(files = resumed, "skipped files restored by an earlier run");
+            }
+        }
+
+        let fixups = deferred
+            .map(|fixups| std::mem::take(&mut *fixups.lock().unwrap()))
+            .unwrap_or_default();
+
+        if let Some(script) = script {
+            fixups
+                .write_script(script, &env::current_dir()?)
+                .context("Failed to write the fixup script")?;
+            if !fixups.is_empty() {
+                info!(
+                    entries = fixups.len(),
+                    devices = fixups.devices(),
+                    "ownership and devices deferred to the fixup script"
+                );
+            }
+        } else if !fixups.is_empty() {
+            warn!(
+                entries = fixups.len(),
+                devices = fixups.devices(),
+                "ownership and devices not restored without root; use --fixup-script to save them"
+            );
+        }
+
+        let skipped = finished.skipped_devices.load(Ordering::Relaxed);
+        if skipped > 0 {
+            warn!(devices = skipped, "devices not created without root");
+        }
+
+        Ok(0)
+    }
+
+    async fn restore_prioritized(
+        &self,
+        stash: &Infinitree<Files>,
+        threads: usize,
+        priorities: Priorities,
+        deferred: &Deferred,
+        finished: &Finished,
+    ) -> anyhow::Result<()> {
+        let files = self.list(stash).collect::<Vec<_>>();
+        for (_, md) in files.iter() {
+            self.activity.found(md.size);
+        }
+        self.activity.listed();
+
+        let (urgent, rest) = priorities.split(files);
+        let files = urgent.len();
+        let start = std::time::Instant::now();
+        self.restore_all(stash, threads, urgent.into_iter(), deferred, finished)
+            .await?;
+        info!(
+            files,
+            elapsed_secs = start.elapsed().as_secs(),
+            remaining = rest.len(),
+            "priority paths restored"
+        );
+        if let Some(path) = &self.priority_done {
+            mark_done(path, files, rest.len())
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+        }
+
+        self.restore_all(stash, threads, rest.into_iter(), deferred, finished)
+            .await
+    }
+
+    async fn restore_all(
+        &self,
+        stash: &Infinitree<Files>,
+        threads: usize,
+        files: impl Iterator<Item = (String, Arc<files::Entry>)>,
+        deferred: &Deferred,
+        finished: &Finished,
+    ) -> anyhow::Result<()> {
+        let (sender, workers) = self.start_workers(stash, threads, deferred, finished)?;
+        let files = files.collect::<Vec<_>>();
+
+        // hardlinks are linked to their first name if that's restored
+        // too, and restored as a copy otherwise
+        let firsts = files
+            .iter()
+            .filter(|(_, md)| !md.file_type.is_hardlink())
+            .filter_map(|(path, _)| {
+                let target = self.target_of(path)?;
+                Some((path.trim_start_matches('/').to_string(), target))
+            })
+            .collect::<HashMap<_, _>>();
+        let mut links = vec![];
+
+        for (path, md) in files {
+            let Some(target) = self.target_of(&path) else {
+                debug!(?path, "nothing left of the path; skipping");
+                self.activity.done(md.size);
+                continue;
+            };
+            if finished.is_done(&target) && is_unchanged(&target, &md) {
+                trace!(?target, "restored by an earlier run");
+                if let Some(fixups) = deferred {
+                    fixups.lock().unwrap().add(&target, &md);
+                }
+                self.activity.done(md.size);
+                continue;
+            }
+
+            if let files::FileType::Hardlink(first) = &md.file_type {
+                if let Some(first) = firsts.get(first) {
+                    links.push((target, first.clone(), md.size));
+                    continue;
+                }
+            }
+
+            trace!(?target, "queued");
+            sender.send_async((target, md)).await.unwrap();
         }
 
         drop(sender);
         join_all(workers).await;
 
-        Ok(0)
+        for (target, first, size) in links {
+            match link(&first, &target) {
+                Ok(()) => {
+                    trace!(?target, ?first, "linked");
+                    finished.record(&target);
+                }
+                Err(error) => error!(%error, ?target, ?first, "failed to restore hardlink"),
+            }
+            self.activity.done(size);
+        }
+
+        finished.sync()
+    }
+
+    /// Where to restore the file stored at `path`, relative to the
+    /// directory of the restore, if anywhere
+    pub fn target_of(&self, path: &str) -> Option<PathBuf> {
+        if self.strip_components == 0 && self.transforms.is_empty() {
+            return Some(path.into());
+        }
+
+        self.target_path(path)
+    }
+
+    fn target_path(&self, path: &str) -> Option<PathBuf> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        for _ in 0..self.strip_components {
+            components.next()?;
+        }
+
+        let mut target = components.collect::<Vec<_>>().join("/");
+        if target.is_empty() {
+            return None;
+        }
+
+        for transform in self.transforms.iter() {
+            target = transform
+                .pattern
+                .replace(&target, transform.replacement.as_str())
+                .into_owned();
+        }
+
+        Some(target.into())
     }
 
     #[cfg(unix)]
@@ -143,27 +676,79 @@ impl Options {
         Ok(())
     }
 
+    /// Collect the ownership that can't be applied, if the restore
+    /// isn't running as root
+    #[cfg(not(target_os = "windows"))]
+    fn deferred_metadata(&self) -> Deferred {
+        use nix::unistd::{Gid, Uid};
+
+        if !self.preserve.ownership || Uid::effective().is_root() {
+            return None;
+        }
+
+        Some(Arc::new(Mutex::new(Fixups::new(
+            Uid::effective().as_raw(),
+            Gid::effective().as_raw(),
+        ))))
+    }
+
+    #[cfg(target_os = "windows")]
+    fn deferred_metadata(&self) -> Deferred {
+        None
+    }
+
+    /// The journal of restoring the current commit of `stash` here,
+    /// which is only kept with `--resume`
+    fn open_journal(&self, stash: &Infinitree<Files>) -> anyhow::Result<Option<Journal>> {
+        if !self.resume {
+            return Ok(None);
+        }
+
+        let commit = stash.commit_list().iter().last().map(|c| c.id);
+        let scope = format!("{commit:?}");
+
+        Journal::open(STATE_DIR, "restore", &scope)
+            .map(Some)
+            .context("Failed to open the restore journal")
+    }
+
     fn start_workers(
         &self,
         stash: &Infinitree<Files>,
         threads: usize,
+        deferred: &Deferred,
+        finished: &Finished,
     ) -> anyhow::Result<(Sender, Vec<task::JoinHandle<()>>)> {
         let mut preserve = self.preserve.clone();
-
-        #[cfg(not(target_os = "windows"))]
-        if !nix::unistd::Uid::effective().is_root() {
+        if deferred.is_some() {
             preserve.ownership = false;
         }
 
+        let reuse = if self.reuse_local {
+            Some(Reuse {
+                chunker: stash.index().params.read().chunker,
+                hasher: stash.hasher()?,
+                checksum: self.checksum,
+            })
+        } else {
+            None
+        };
+
         let (sender, receiver) = mpsc::bounded(threads);
         let workers = (0..threads)
             .map(|_| {
-                task::spawn(process_packet_loop(
-                    self.force,
-                    preserve.clone(),
-                    receiver.clone(),
-                    stash.storage_reader().unwrap(),
-                ))
+                task::spawn(
+                    process_packet_loop(
+                        self.force,
+                        finished.clone(),
+                        preserve.clone(),
+                        reuse.clone(),
+                        deferred.clone(),
+                        receiver.clone(),
+                        stash.storage_reader().unwrap(),
+                    )
+                    .instrument(Span::current()),
+                )
             })
             .collect::<Vec<_>>();
         Ok((sender, workers))
@@ -172,10 +757,21 @@ impl Options {
 
 async fn process_packet_loop(
     force: bool,
+    finished: Finished,
     preserve: files::PreserveMetadata,
+    reuse: Option<Reuse>,
+    deferred: Deferred,
     r: Receiver,
     mut objreader: impl object::Reader + 'static,
 ) {
+    let activity = &finished.activity;
+    let defer = |path: &Path, metadata: &files::Entry| {
+        if let Some(fixups) = &deferred {
+            fixups.lock().unwrap().add(path, metadata);
+        }
+        finished.record(path);
+    };
+
     // Since resources here are all managed by RAII, and they all
     // implement Drop, we can simply go through the Arc<_>s,
     // mmap them, open the corresponding objects to extract details,
@@ -186,6 +782,55 @@ async fn process_packet_loop(
 
     // This loop is managing an mmap of a file that's written
     while let Ok((path, metadata)) = r.recv_async().await {
+        let _span = debug_span!("restoring", ?path, size = metadata.size).entered();
+
+        let reusable = reuse
+            .as_ref()
+            .filter(|_| metadata.file_type.is_file() && metadata.size > 0);
+
+        if reusable.is_some_and(|r| !r.checksum && is_unchanged(&path, &metadata)) {
+            // only the metadata may differ
+            let updated = metadata
+                .restore_over(&path, &preserve)
+                .and_then(|fd| match fd {
+                    Some(fd) => metadata.restore_metadata(&fd, &path, &preserve),
+                    None => Ok(()),
+                });
+            match updated {
+                Ok(()) => {
+                    trace!(?path, "unchanged");
+                    defer(&path, &metadata);
+                    activity.done(metadata.size);
+                    continue;
+                }
+                Err(error) => {
+                    error!(%error, ?path, "failed to update unchanged file; restoring from the stash");
+                }
+            }
+        }
+
+        let local = reusable.and_then(|r| LocalFile::open(&path, r.chunker, r.hasher.clone()));
+
+        if let Some(local) = local {
+            let durable = finished.is_journaled();
+            match restore_reusing(&path, &metadata, &preserve, &mut objreader, local, durable) {
+                Ok(transfer) => {
+                    debug!(
+                        ?path,
+                        reused = transfer.reused,
+                        fetched = transfer.fetched,
+                        "restored"
+                    );
+                    defer(&path, &metadata);
+                    activity.done(metadata.size);
+                    continue;
+                }
+                Err(error) => {
+                    error!(%error, ?path, "failed to reuse local file; restoring from the stash");
+                }
+            }
+        }
+
         match metadata.restore_to(&path, &preserve) {
             Ok(Some(fd)) => {
                 let mut mmap = unsafe {
@@ -200,11 +845,41 @@ async fn process_packet_loop(
                     objreader.read_chunk(cp, &mut mmap[start..]).unwrap();
                 }
 
+                // the journal may only list files that survive a crash
+                if finished.is_journaled() {
+                    if let Err(error) = mmap.flush() {
+                        error!(%error, ?path, "failed to write file");
+                        activity.done(metadata.size);
+                        continue;
+                    }
+                }
+                drop(mmap);
+
+                if let Err(error) = metadata.restore_metadata(&fd, &path, &preserve) {
+                    error!(%error, ?path, "failed to restore metadata");
+
+                    if !force {
+                        panic!("error while restoring file");
+                    }
+                }
+
                 trace!(?path, "restored");
+                defer(&path, &metadata);
             }
             Ok(None) => {
                 trace!(?path, file_type = ?metadata.file_type, "no chunks restored for file");
+                defer(&path, &metadata);
             }
+            Err(files::EntryError::NeedsRoot) => match &deferred {
+                Some(fixups) => {
+                    debug!(?path, "creating the device needs root; deferred");
+                    fixups.lock().unwrap().add_device(&path, &metadata);
+                }
+                None => {
+                    warn!(?path, "creating devices needs root; skipping");
+                    finished.skipped_devices.fetch_add(1, Ordering::Relaxed);
+                }
+            },
             Err(error) => {
                 error!(%error, ?path, "failed to restore file");
 
@@ -213,5 +888,743 @@ async fn process_packet_loop(
                 }
             }
         }
+        activity.done(metadata.size);
+    }
+}
+
+/// Make `target` another name of `first`, replacing whatever is at
+/// `target`. Where hardlinks aren't supported, `first` is copied.
+fn link(first: &Path, target: &Path) -> io::Result<()> {
+    match fs::remove_file(target) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        result => result?,
+    }
+
+    fs::hard_link(first, target).or_else(|error| {
+        debug!(%error, ?target, "failed to link; copying");
+        fs::copy(first, target).map(|_| ())
+    })
+}
+
+/// Where the workers report the files they're done with
+#[derive(Clone)]
+struct Finished {
+    activity: crate::activity::Activity,
+    journal: Option<Arc<Journal>>,
+    /// Records since the journal was last synced
+    unsynced: Arc<AtomicUsize>,
+    /// Devices that couldn't be created, and have no fixup either
+    skipped_devices: Arc<AtomicUsize>,
+}
+
+impl Finished {
+    /// Whether restored files are recorded, so their contents have to
+    /// be on disk before they are
+    fn is_journaled(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Whether an earlier run restored `path` already
+    fn is_done(&self, path: &Path) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|j| j.is_done(&path.to_string_lossy()))
+    }
+
+    /// Record that `path` is restored, so a resumed run skips it. The
+    /// contents of the file have to be synced already.
+    fn record(&self, path: &Path) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+
+        if let Err(error) = journal.record(&path.to_string_lossy()) {
+            warn!(%error, ?path, "failed to record the file in the restore journal");
+        }
+
+        if self.unsynced.fetch_add(1, Ordering::Relaxed) + 1 >= JOURNAL_SYNC_INTERVAL {
+            if let Err(error) = self.sync() {
+                warn!(%error, "failed to sync the restore journal");
+            }
+        }
+    }
+
+    /// Make the records so far durable
+    fn sync(&self) -> anyhow::Result<()> {
+        if let Some(journal) = &self.journal {
+            self.unsynced.store(0, Ordering::Relaxed);
+            journal
+                .sync()
+                .context("Failed to write the restore journal")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Ownership that a restore without root privileges couldn't apply,
+/// batched by owner and mode into a script that a privileged user can
+/// run later
+#[derive(Debug, Default)]
+pub struct Fixups {
+    /// Uid and gid of the restoring user, which need no fixing
+    current: (u32, u32),
+    /// Paths by (uid, gid, is a symlink)
+    owners: BTreeMap<(Option<u32>, Option<u32>, bool), Vec<PathBuf>>,
+    /// Paths by mode, for setuid, setgid and sticky files
+    modes: BTreeMap<u32, Vec<PathBuf>>,
+    /// Devices to create, with their type (`b` or `c`), major and
+    /// minor number, and permissions
+    devices: Vec<(PathBuf, char, u64, u64, u32)>,
+    entries: usize,
+}
+
+/// Paths per line in the fixup script
+const FIXUP_BATCH: usize = 64;
+
+impl Fixups {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            current: (uid, gid),
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, path: &Path, entry: &files::Entry) {
+        let uid = entry.unix_uid.filter(|uid| *uid != self.current.0);
+        let gid = entry.unix_gid.filter(|gid| *gid != self.current.1);
+        if uid.is_none() && gid.is_none() {
+            return;
+        }
+
+        let is_symlink = entry.file_type.is_symlink();
+        self.owners
+            .entry((uid, gid, is_symlink))
+            .or_default()
+            .push(path.to_owned());
+        self.entries += 1;
+
+        // changing the owner clears the setuid and setgid bits, so
+        // they're set again afterwards
+        if let Some(mode) = entry.unix_perm.map(|m| m & 0o7777) {
+            if mode & 0o7000 != 0 && !is_symlink {
+                self.modes.entry(mode).or_default().push(path.to_owned());
+            }
+        }
+    }
+
+    /// Add a device that couldn't be created without root, and its
+    /// owner, who can only be set once it exists
+    pub fn add_device(&mut self, path: &Path, entry: &files::Entry) {
+        let (kind, dev) = match entry.file_type {
+            files::FileType::CharDevice(dev) => ('c', dev),
+            files::FileType::BlockDevice(dev) => ('b', dev),
+            _ => return,
+        };
+        let (major, minor) = major_minor(dev);
+        let mode = entry.unix_perm.map_or(0o600, |m| m & 0o7777);
+        self.devices
+            .push((path.to_owned(), kind, major, minor, mode));
+        self.entries += 1;
+
+        // the script creates it as root
+        if entry.unix_uid.is_some() || entry.unix_gid.is_some() {
+            self.owners
+                .entry((entry.unix_uid, entry.unix_gid, false))
+                .or_default()
+                .push(path.to_owned());
+        }
+    }
+
+    /// Number of devices to create
+    pub fn devices(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Number of entries that need fixing
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Write a shell script that applies the fixups to the files
+    /// restored into `dir`
+    pub fn write_script(&self, mut out: impl Write, dir: &Path) -> io::Result<()> {
+        writeln!(out, "#!/bin/sh")?;
+        writeln!(
+            out,
+            "# Ownership that zerostash couldn't restore without root."
+        )?;
+        writeln!(out, "# Run as root to finish the restore.")?;
+        writeln!(out, "set -e")?;
+        writeln!(out, "cd {}", shell_quote(dir))?;
+
+        for (path, kind, major, minor, mode) in self.devices.iter() {
+            writeln!(
+                out,
+                "mknod -m {mode:o} -- {} {kind} {major} {minor}",
+                shell_quote(path)
+            )?;
+        }
+
+        for ((uid, gid, is_symlink), paths) in self.owners.iter() {
+            let owner = match (uid, gid) {
+                (Some(uid), Some(gid)) => format!("{uid}:{gid}"),
+                (Some(uid), None) => uid.to_string(),
+                (None, Some(gid)) => format!(":{gid}"),
+                (None, None) => unreachable!(),
+            };
+            let flags = if *is_symlink { " -h" } else { "" };
+            write_batches(&mut out, &format!("chown{flags} {owner}"), paths)?;
+        }
+
+        for (mode, paths) in self.modes.iter() {
+            write_batches(&mut out, &format!("chmod {mode:o}"), paths)?;
+        }
+
+        out.flush()
+    }
+}
+
+fn write_batches(out: &mut impl Write, command: &str, paths: &[PathBuf]) -> io::Result<()> {
+    for batch in paths.chunks(FIXUP_BATCH) {
+        write!(out, "{command} --")?;
+        for path in batch {
+            write!(out, " {}", shell_quote(path))?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+/// The major and minor number of a device
+#[cfg(unix)]
+fn major_minor(dev: u64) -> (u64, u64) {
+    let dev = dev as libc::dev_t;
+    (libc::major(dev) as u64, libc::minor(dev) as u64)
+}
+
+#[cfg(not(unix))]
+fn major_minor(dev: u64) -> (u64, u64) {
+    (dev >> 8, dev & 0xff)
+}
+
+/// Create the marker of the priority paths, with the number of files
+/// restored and still to go.
+///
+/// The contents are written to a temporary file first, so whoever
+/// waits for the marker never sees it half written.
+fn mark_done(path: &Path, files: usize, remaining: usize) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    fs::write(&tmp, format!("restored {files}\nremaining {remaining}\n"))?;
+    fs::rename(&tmp, path)
+}
+
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Paths to restore before everything else
+struct Priorities {
+    patterns: Vec<(String, glob::Pattern)>,
+}
+
+impl Priorities {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read priority file {}", path.display()))?;
+
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let prefix = line.trim_matches('/').to_string();
+                Ok((prefix, glob::Pattern::new(line.trim_start_matches('/'))?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    /// Split `files` into the ones that match a line of the file,
+    /// sorted by the line, and the rest.
+    ///
+    /// Hardlinks are only linked to a first name restored in the same
+    /// batch, so every name of a hardlinked file goes into the batch of
+    /// its most urgent one.
+    fn split(
+        &self,
+        files: Vec<(String, Arc<files::Entry>)>,
+    ) -> (
+        Vec<(String, Arc<files::Entry>)>,
+        Vec<(String, Arc<files::Entry>)>,
+    ) {
+        let mut ranks = HashMap::<&str, usize>::new();
+        for (path, md) in files.iter() {
+            if let Some(rank) = self.rank(path) {
+                ranks
+                    .entry(link_group(path, md))
+                    .and_modify(|best| *best = (*best).min(rank))
+                    .or_insert(rank);
+            }
+        }
+
+        let ranks = files
+            .iter()
+            .map(|(path, md)| ranks.get(link_group(path, md)).copied())
+            .collect::<Vec<_>>();
+
+        let mut urgent = vec![];
+        let mut rest = vec![];
+        for (rank, file) in ranks.into_iter().zip(files) {
+            match rank {
+                Some(rank) => urgent.push((rank, file)),
+                None => rest.push(file),
+            }
+        }
+        urgent.sort_by_key(|(rank, _)| *rank);
+
+        (urgent.into_iter().map(|(_, file)| file).collect(), rest)
+    }
+
+    /// Position of the first line in the file that matches `path`
+    fn rank(&self, path: &str) -> Option<usize> {
+        let path = path.trim_start_matches('/');
+        self.patterns.iter().position(|(prefix, pattern)| {
+            pattern.matches(path)
+                || path
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+}
+
+/// The first name of the hardlinked file at `path`, which all of its
+/// names link to
+fn link_group<'a>(path: &'a str, md: &'a files::Entry) -> &'a str {
+    match &md.file_type {
+        files::FileType::Hardlink(first) => first,
+        _ => path.trim_start_matches('/'),
+    }
+}
+
+/// How to reuse the files that already exist at the destination
+#[derive(Clone)]
+struct Reuse {
+    chunker: Chunker,
+    hasher: Hasher,
+    /// Chunk files even if their size and modification time match
+    checksum: bool,
+}
+
+/// Whether the file at `path` has the size and modification time of
+/// `entry`, so its contents are taken to be restored already
+fn is_unchanged(path: &Path, entry: &files::Entry) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return false;
+    };
+
+    metadata.is_file()
+        && metadata.len() == entry.size
+        && files::to_unix_mtime(&metadata)
+            .is_ok_and(|mtime| mtime == (entry.unix_secs, entry.unix_nanos))
+}
+
+/// Bytes of a file that were reused from the local copy, and read
+/// from the stash
+#[derive(Debug, Default)]
+struct Transfer {
+    reused: usize,
+    fetched: usize,
+}
+
+/// A file that already exists at the destination, chunked the same
+/// way as the stash
+struct LocalFile {
+    mmap: Mmap,
+    chunks: HashMap<Digest, usize>,
+}
+
+impl LocalFile {
+    fn open(path: &Path, chunker: Chunker, hasher: Hasher) -> Option<Self> {
+        let file = fs::File::open(path).ok()?;
+        if !file.metadata().ok()?.is_file() {
+            return None;
+        }
+
+        let mmap = unsafe { MmapOptions::new().map(&file) }.ok()?;
+        let chunks = store::splitter(chunker, &mmap, hasher)
+            .map(|(start, digest, _)| (digest, start as usize))
+            .collect();
+
+        Some(Self { mmap, chunks })
+    }
+}
+
+/// Restore a file over an existing copy, only reading the chunks
+/// from the stash that aren't available locally.
+///
+/// If every reusable chunk is already at the right offset, the file
+/// is updated in place. Otherwise the new contents are assembled next
+/// to the old file, then renamed over it.
+fn restore_reusing(
+    path: &Path,
+    metadata: &files::Entry,
+    preserve: &files::PreserveMetadata,
+    objreader: &mut impl object::Reader,
+    local: LocalFile,
+    durable: bool,
+) -> anyhow::Result<Transfer> {
+    let ends = metadata
+        .chunks
+        .keys()
+        .copied()
+        .skip(1)
+        .chain([metadata.size]);
+    let chunks = metadata
+        .chunks
+        .iter()
+        .zip(ends)
+        .map(|((start, cp), end)| {
+            let found = local.chunks.get(cp.hash()).copied();
+            (*start as usize, end as usize, cp, found)
+        })
+        .collect::<Vec<_>>();
+
+    let in_place = chunks
+        .iter()
+        .all(|(start, _, _, found)| found.iter().all(|offset| offset == start));
+
+    let mut transfer = Transfer::default();
+    if in_place {
+        // nothing needs to be copied, so the old mapping would only be
+        // in the way of resizing the file
+        drop(local);
+
+        let fd = metadata
+            .restore_over(&path, preserve)?
+            .context("not a regular file")?;
+        let mut mmap = unsafe {
+            MmapOptions::new()
+                .len(metadata.size as usize)
+                .map_mut(&fd)?
+        };
+
+        for (start, end, cp, found) in chunks {
+            if found.is_some() {
+                transfer.reused += end - start;
+            } else {
+                objreader.read_chunk(cp, &mut mmap[start..])?;
+                transfer.fetched += end - start;
+            }
+        }
+
+        if durable {
+            mmap.flush()?;
+        }
+        drop(mmap);
+
+        metadata.restore_metadata(&fd, &path, preserve)?;
+        return Ok(transfer);
+    }
+
+    let name = path.file_name().context("no file name")?.to_string_lossy();
+    let partial = path.with_file_name(format!(".{name}.0s-partial"));
+
+    let assemble = || -> anyhow::Result<()> {
+        let fd = metadata
+            .restore_to(&partial, preserve)?
+            .context("not a regular file")?;
+        let mut mmap = unsafe {
+            MmapOptions::new()
+                .len(metadata.size as usize)
+                .map_mut(&fd)?
+        };
+
+        for (start, end, cp, found) in chunks {
+            match found {
+                Some(offset) => {
+                    let len = end - start;
+                    mmap[start..end].copy_from_slice(&local.mmap[offset..offset + len]);
+                    transfer.reused += len;
+                }
+                None => {
+                    objreader.read_chunk(cp, &mut mmap[start..])?;
+                    transfer.fetched += end - start;
+                }
+            }
+        }
+
+        if durable {
+            mmap.flush()?;
+        }
+        drop(mmap);
+
+        metadata.restore_metadata(&fd, &partial, preserve)?;
+        Ok(fs::rename(&partial, path)?)
+    };
+
+    if let Err(error) = assemble() {
+        _ = fs::remove_file(&partial);
+        return Err(error);
+    }
+
+    Ok(transfer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{link, mark_done, read_range, Fixups, Options, Priorities, Transform};
+    use crate::{Entry, FileType, Files};
+    use infinitree::{
+        backends::test::InMemoryBackend, crypto::UsernamePassword, object::Writer, Infinitree,
+    };
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    #[test]
+    fn read_range_across_chunks() {
+        let stash = Infinitree::<Files>::empty(
+            InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("range".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap();
+
+        let mut writer = stash.storage_writer().unwrap();
+        let mut entry = Entry {
+            size: 12,
+            ..Default::default()
+        };
+        for (start, data) in [(0, b"0123"), (4, b"4567"), (8, b"89ab")] {
+            let hash = *stash.hasher().unwrap().update(data).finalize().as_bytes();
+            let pointer = writer.write_chunk(&hash, data).unwrap();
+            entry.chunks.insert(start, Arc::new(pointer));
+        }
+        writer.flush().unwrap();
+        stash.index().tree.insert_file("file", entry).unwrap();
+
+        let read = |offset, len| read_range(&stash, "file", offset, len).unwrap();
+        assert_eq!(read(2, 7), b"2345678");
+        assert_eq!(read(4, 4), b"4567");
+        assert_eq!(read(10, 100), b"ab");
+        assert!(read(12, 1).is_empty());
+        assert!(read_range(&stash, "missing", 0, 1).is_err());
+    }
+
+    #[test]
+    fn rewrite_target_paths() {
+        let options = Options {
+            strip_components: 1,
+            transforms: vec![Transform::parse("^me/=>you/").unwrap()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            options.target_path("/home/me/notes.txt"),
+            Some(PathBuf::from("you/notes.txt"))
+        );
+        assert_eq!(options.target_path("home"), None);
+        assert!(Transform::parse("no arrow").is_err());
+    }
+
+    #[test]
+    fn exclude_paths_and_directories() {
+        let excludes = ["*.log", "home/me/cache"]
+            .into_iter()
+            .map(|g| super::parse_glob(g).unwrap())
+            .collect();
+        let options = Options {
+            excludes,
+            ..Default::default()
+        };
+
+        assert!(options.is_excluded("var/app.log"));
+        assert!(options.is_excluded("/home/me/cache/thumbnails/1.png"));
+        assert!(!options.is_excluded("home/me/cached.txt"));
+        assert!(!options.is_excluded("home/me/notes.txt"));
+        assert!(!Options::default().is_excluded("var/app.log"));
+    }
+
+    #[test]
+    fn unchanged_files_match_size_and_mtime() {
+        let path = std::env::temp_dir().join(format!("0s-unchanged-{}", rand::random::<u64>()));
+        std::fs::write(&path, b"data").unwrap();
+        let (unix_secs, unix_nanos) =
+            crate::files::to_unix_mtime(&std::fs::metadata(&path).unwrap()).unwrap();
+
+        let mut entry = Entry {
+            size: 4,
+            unix_secs,
+            unix_nanos,
+            ..Default::default()
+        };
+        assert!(super::is_unchanged(&path, &entry));
+
+        entry.size = 5;
+        assert!(!super::is_unchanged(&path, &entry));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!super::is_unchanged(&path, &entry));
+    }
+
+    #[test]
+    fn fixup_script_batches_by_owner() {
+        let entry = |uid, gid, perm, file_type| Entry {
+            unix_uid: Some(uid),
+            unix_gid: Some(gid),
+            unix_perm: Some(perm),
+            file_type,
+            ..Default::default()
+        };
+
+        let mut fixups = Fixups::new(1000, 1000);
+        fixups.add(Path::new("mine"), &entry(1000, 1000, 0o644, FileType::File));
+        fixups.add(Path::new("bin/su"), &entry(0, 0, 0o104755, FileType::File));
+        fixups.add(
+            Path::new("etc/it's"),
+            &entry(0, 0, 0o100644, FileType::File),
+        );
+        fixups.add(
+            Path::new("link"),
+            &entry(0, 1000, 0o777, FileType::Symlink("bin/su".into())),
+        );
+        assert_eq!(fixups.len(), 3);
+
+        let mut script = vec![];
+        fixups
+            .write_script(&mut script, Path::new("/restore"))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(script).unwrap(),
+            r#"#!/bin/sh
+# Ownership that zerostash couldn't restore without root.
+# Run as root to finish the restore.
+set -e
+cd '/restore'
+chown -h 0 -- 'link'
+chown 0:0 -- 'bin/su' 'etc/it'\''s'
+chmod 4755 -- 'bin/su'
+"#
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fixup_script_creates_devices() {
+        let device = |file_type| Entry {
+            unix_uid: Some(0),
+            unix_gid: Some(6),
+            unix_perm: Some(0o60660),
+            file_type,
+            ..Default::default()
+        };
+
+        let mut fixups = Fixups::new(1000, 1000);
+        fixups.add_device(Path::new("dev/sda"), &device(FileType::BlockDevice(0x800)));
+        fixups.add_device(Path::new("dev/tty5"), &device(FileType::CharDevice(0x405)));
+        fixups.add_device(Path::new("fifo"), &device(FileType::Fifo));
+        assert_eq!(fixups.len(), 2);
+        assert_eq!(fixups.devices(), 2);
+
+        let mut script = vec![];
+        fixups
+            .write_script(&mut script, Path::new("/restore"))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(script).unwrap(),
+            r#"#!/bin/sh
+# Ownership that zerostash couldn't restore without root.
+# Run as root to finish the restore.
+set -e
+cd '/restore'
+mknod -m 660 -- 'dev/sda' b 8 0
+mknod -m 660 -- 'dev/tty5' c 4 5
+chown 0:6 -- 'dev/sda' 'dev/tty5'
+"#
+        );
+    }
+
+    #[test]
+    fn link_replaces_the_target() {
+        let dir = std::env::temp_dir().join(format!("0s-link-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first");
+        let target = dir.join("sub/target");
+        std::fs::write(&first, b"contents").unwrap();
+
+        link(&first, &target).unwrap();
+        std::fs::remove_file(&target).unwrap();
+        std::fs::write(&target, b"stale").unwrap();
+        link(&first, &target).unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"contents");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let first = std::fs::metadata(&first).unwrap();
+            let target = std::fs::metadata(&target).unwrap();
+            assert_eq!(first.ino(), target.ino());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn priority_marker_is_written_whole() {
+        let dir = std::env::temp_dir().join(format!("0s-marker-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("priority.done");
+
+        mark_done(&marker, 3, 10).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&marker).unwrap(),
+            "restored 3\nremaining 10\n"
+        );
+        assert!(!dir.join("priority.done.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn priority_batches_keep_hardlinks_together() {
+        let priorities = Priorities {
+            patterns: vec![("urgent".into(), glob::Pattern::new("urgent").unwrap())],
+        };
+        let file = |file_type| {
+            Arc::new(Entry {
+                file_type,
+                ..Default::default()
+            })
+        };
+
+        let (urgent, rest) = priorities.split(vec![
+            ("later/first".into(), file(FileType::File)),
+            ("later/other".into(), file(FileType::File)),
+            (
+                "urgent/link".into(),
+                file(FileType::Hardlink("later/first".into())),
+            ),
+        ]);
+
+        let names = |files: Vec<(String, Arc<Entry>)>| {
+            files.into_iter().map(|(path, _)| path).collect::<Vec<_>>()
+        };
+        assert_eq!(names(urgent), ["later/first", "urgent/link"]);
+        assert_eq!(names(rest), ["later/other"]);
     }
 }
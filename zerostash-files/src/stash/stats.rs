@@ -0,0 +1,183 @@
+//! Account for the space a stash uses.
+//!
+//! Logical sizes are the sizes of the files as they are on disk. Chunks
+//! are counted once, no matter how many files or commits refer to them,
+//! which is the size after deduplication. Objects are padded to the
+//! object size of the stash before they are stored, so the stored size
+//! is the number of objects times the object size.
+use crate::{
+    stash::{gc, restore},
+    Files,
+};
+use chrono::{DateTime, Utc};
+use infinitree::{tree::CommitId, Infinitree};
+use std::collections::{BTreeMap, HashSet};
+
+/// What a single commit added to the stash
+#[derive(Clone, Debug)]
+pub struct CommitStats {
+    pub id: CommitId,
+    pub time: DateTime<Utc>,
+    pub message: Option<String>,
+    pub files: usize,
+    /// Size of the files in the commit
+    pub logical_bytes: u64,
+    /// Chunks that no earlier commit refers to
+    pub added_chunks: usize,
+    pub added_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub commits: Vec<CommitStats>,
+    /// Size of the files in the latest commit
+    pub logical_bytes: u64,
+    pub files: usize,
+    /// Chunks referenced by any commit, and their size
+    pub chunks: usize,
+    pub deduplicated_bytes: u64,
+    pub objects: usize,
+    pub stored_bytes: u64,
+}
+
+/// Files and their size under a directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirUsage {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Walk every commit of the stash, and total up the space it uses.
+///
+/// `open` must return a freshly opened instance of the stash every
+/// time it is called, as the state of every commit is loaded
+/// separately.
+pub fn stats(mut open: impl FnMut() -> anyhow::Result<Infinitree<Files>>) -> anyhow::Result<Stats> {
+    let object_size = u64::from(open()?.index().params.read().object_size);
+
+    let mut stats = Stats::default();
+    let mut chunks = HashSet::new();
+    let mut objects = HashSet::new();
+
+    gc::for_each_commit(open, |_, snapshot| {
+        let mut commit = CommitStats {
+            id: snapshot.id,
+            time: snapshot.time,
+            message: snapshot.message.clone(),
+            files: 0,
+            logical_bytes: 0,
+            added_chunks: 0,
+            added_bytes: 0,
+        };
+
+        for (_, entry) in snapshot.files() {
+            commit.files += 1;
+            commit.logical_bytes += entry.size;
+
+            for (cp, size) in gc::chunks_of(&entry) {
+                if chunks.insert(*cp.hash()) {
+                    commit.added_chunks += 1;
+                    commit.added_bytes += size;
+                    objects.insert(*cp.object_id());
+                }
+            }
+        }
+
+        stats.chunks += commit.added_chunks;
+        stats.deduplicated_bytes += commit.added_bytes;
+        stats.commits.push(commit);
+        Ok(())
+    })?;
+
+    if let Some(latest) = stats.commits.last() {
+        stats.files = latest.files;
+        stats.logical_bytes = latest.logical_bytes;
+    }
+    stats.objects = objects.len();
+    stats.stored_bytes = stats.objects as u64 * object_size;
+
+    Ok(stats)
+}
+
+/// Add up the files in the tree for every directory, down to `depth`
+/// levels below the root. The root is `/`.
+///
+/// The tree needs to be loaded first.
+pub fn tree_usage(stash: &Infinitree<Files>, depth: usize) -> BTreeMap<String, DirUsage> {
+    let mut usage = BTreeMap::<String, DirUsage>::new();
+
+    for (path, entry) in restore::iter(stash, ["*"]) {
+        let mut dirs = path.split('/').collect::<Vec<_>>();
+        dirs.pop();
+        dirs.truncate(depth);
+
+        let parents = (0..=dirs.len()).map(|n| {
+            if n == 0 {
+                "/".into()
+            } else {
+                dirs[..n].join("/")
+            }
+        });
+        for dir in parents {
+            let dir = usage.entry(dir).or_default();
+            dir.files += 1;
+            dir.bytes += entry.size;
+        }
+    }
+
+    usage
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tree_usage, DirUsage};
+    use crate::{Entry, Files};
+    use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+    #[test]
+    fn directories_add_up_their_files() {
+        let stash = Infinitree::<Files>::empty(
+            InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("stats".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap();
+
+        let file = |size| Entry {
+            size,
+            ..Default::default()
+        };
+        let tree = &stash.index().tree;
+        tree.insert_file("home/me/a", file(10)).unwrap();
+        tree.insert_file("home/me/deep/b", file(20)).unwrap();
+        tree.insert_file("etc/c", file(5)).unwrap();
+
+        let usage = tree_usage(&stash, 2);
+        let at = |dir: &str| usage[dir];
+
+        assert_eq!(usage.len(), 4);
+        assert_eq!(
+            at("/"),
+            DirUsage {
+                files: 3,
+                bytes: 35
+            }
+        );
+        assert_eq!(
+            at("home"),
+            DirUsage {
+                files: 2,
+                bytes: 30
+            }
+        );
+        // deeper directories are counted in their parents
+        assert_eq!(
+            at("home/me"),
+            DirUsage {
+                files: 2,
+                bytes: 30
+            }
+        );
+        assert_eq!(at("etc"), DirUsage { files: 1, bytes: 5 });
+    }
+}
@@ -1,26 +1,89 @@
 use crate::{
+    activity::{Activity, Worker},
     files::{self, normalize_filename},
     rollsum::{BupSplit, SeaSplit},
     splitter::FileSplitter,
-    Files,
+    Chunker, Files,
 };
 use anyhow::Context;
 use flume as mpsc;
 use futures::future::join_all;
-use ignore::{DirEntry, WalkBuilder};
+use ignore::{overrides::OverrideBuilder, DirEntry, WalkBuilder, WalkState};
 use infinitree::{
     object::{Pool, Writer},
     Digest, Infinitree,
 };
 use memmap2::{Mmap, MmapOptions};
-use std::{collections::BTreeMap, fs, io::Read, num::NonZeroUsize, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::Read,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tokio::task;
-use tracing::{debug, debug_span, error, trace, warn, Instrument};
+use tracing::{debug, debug_span, error, trace, warn, Instrument, Span};
+
+// (path on disk, path in the index, metadata)
+type QueuedFile = (PathBuf, String, files::Entry);
+type Sender = mpsc::Sender<QueuedFile>;
+type Receiver = mpsc::Receiver<QueuedFile>;
+
+pub(crate) const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Directory entries that the walker threads can find ahead of the
+/// files being queued
+const WALK_QUEUE_SIZE: usize = 4096;
+
+/// Files with this name list paths to skip in their directory, in
+/// .gitignore syntax
+pub const IGNORE_FILENAME: &str = ".zerostashignore";
+
+/// Marks a directory as a cache that doesn't need to be backed up
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// A [`CACHEDIR_TAG`] file has to start with this to be valid
+pub const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Files starting with this prefix in an overlay directory mark
+/// deleted files in the stash
+pub const WHITEOUT_PREFIX: &str = ".wh.";
 
-type Sender = mpsc::Sender<(PathBuf, files::Entry)>;
-type Receiver = mpsc::Receiver<(PathBuf, files::Entry)>;
+/// Marks a directory in an overlay that replaces the contents of the
+/// directory with the same path in the stash
+pub const OPAQUE_MARKER: &str = ".wh..wh..opq";
 
-const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+/// What [`Options::walk`] found
+struct Walk {
+    /// The stored paths of everything found, to tell which files
+    /// were removed
+    found: HashSet<String>,
+    /// The other names of hardlinked files, whose first name was
+    /// queued
+    links: Vec<QueuedFile>,
+    /// Whether the walk stopped early, because it was paused
+    paused: bool,
+}
+
+/// What a commit would store, as found by [`Options::dry_run`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DryRun {
+    /// Files that aren't in the stash yet
+    pub added: u64,
+    pub changed: u64,
+    pub unchanged: u64,
+    /// Files in the stash that are gone from the paths
+    pub removed: u64,
+    /// Size of the added and changed files, which would be chunked
+    pub bytes: u64,
+    /// Chunks that would be stored
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+    /// Chunks that are already in the stash, or in another file
+    pub deduplicated_chunks: u64,
+    pub deduplicated_bytes: u64,
+}
 
 #[derive(clap::Args, Debug, Default, Clone)]
 pub struct Options {
@@ -30,16 +93,27 @@ pub struct Options {
     #[clap(flatten)]
     pub preserve: files::PreserveMetadata,
 
-    /// Force hashing all files even if size and modification time is the same
-    #[clap(short = 'f', long)]
+    /// Read and hash all files, even the ones whose size, modification
+    /// time, inode and status change time are the same as in the stash
+    #[clap(short = 'f', long, visible_alias = "force-rescan")]
     pub force: bool,
 
     /// Ignore files larger than the given value in bytes.
     #[clap(short = 'M', long = "max-size")]
     pub max_size: Option<u64>,
 
+    /// Walk directories on this many threads. By default, it depends on
+    /// the number of CPUs.
+    #[clap(long, value_name = "N")]
+    pub scan_threads: Option<usize>,
+
     /// Do not cross file system boundaries during directory walk.
-    #[clap(short = 'x', long = "same-file-system")]
+    /// Mount points are stored as empty directories.
+    #[clap(
+        short = 'x',
+        long = "same-file-system",
+        visible_alias = "one-file-system"
+    )]
     pub same_fs: bool,
 
     /// Ignore hidden files.
@@ -70,22 +144,259 @@ pub struct Options {
     #[clap(short = 'I', long = "dot-ignore")]
     pub ignore: bool,
 
-    /// Follow symbolic links.
-    #[clap(short = 'l', long = "follow-links")]
+    /// Follow symbolic links, and store the files they point to.
+    /// Otherwise the links themselves are stored.
+    #[clap(short = 'l', long = "follow-links", visible_alias = "follow-symlinks")]
     pub follow_links: bool,
+
+    /// Skip paths matching the pattern, in .gitignore syntax. Patterns
+    /// with a slash are relative to the current directory. Can be
+    /// repeated.
+    #[clap(long = "exclude", value_name = "PATTERN")]
+    pub excludes: Vec<String>,
+
+    /// Skip paths matching the patterns in the file, in .gitignore
+    /// syntax. Can be repeated.
+    #[clap(long = "exclude-file", value_name = "PATH")]
+    pub exclude_files: Vec<PathBuf>,
+
+    /// Skip directories marked as caches by a valid CACHEDIR.TAG file.
+    #[clap(long = "exclude-caches")]
+    pub exclude_caches: bool,
+
+    /// Skip directories that contain a file with the given name. Can be
+    /// repeated.
+    #[clap(long = "exclude-if-present", value_name = "FILENAME")]
+    pub exclude_markers: Vec<String>,
+
+    /// Tag the committed paths with `key=value`. Can be repeated.
+    #[clap(long = "tag", value_name = "KEY=VALUE", value_parser = crate::parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Stop queueing files when paused. The files already queued are
+    /// still stored, and calling [`Options::add_recursive`] again
+    /// skips them.
+    #[clap(skip)]
+    pub pause: crate::pause::Pause,
+
+    /// Progress of the workers, as shown by `0s top`
+    #[clap(skip)]
+    pub activity: crate::activity::Activity,
+
+    /// Read the paths from these snapshots, but store them under their
+    /// original names
+    #[clap(skip)]
+    pub snapshots: Vec<Snapshot>,
+}
+
+/// A read-only copy of a file system that paths are read from, like a
+/// Windows shadow copy
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// Where the file system is mounted, eg. `C:\`
+    pub original: PathBuf,
+    /// Where the snapshot can be read
+    pub mounted: PathBuf,
 }
 
 impl Options {
+    /// Where to read `path` from, which is in a snapshot if there's
+    /// one for it
+    fn source_of(&self, path: &Path) -> PathBuf {
+        for snapshot in self.snapshots.iter() {
+            if let Ok(rest) = path.strip_prefix(&snapshot.original) {
+                return snapshot.mounted.join(rest);
+            }
+        }
+
+        path.to_owned()
+    }
+
+    /// The original name of `path`, which may be read from a snapshot
+    fn stored_path(&self, path: &Path) -> PathBuf {
+        for snapshot in self.snapshots.iter() {
+            if let Ok(rest) = path.strip_prefix(&snapshot.mounted) {
+                return snapshot.original.join(rest);
+            }
+        }
+
+        path.to_owned()
+    }
+
     pub async fn add_recursive(
         &self,
         stash: &Infinitree<Files>,
         threads: usize,
     ) -> anyhow::Result<()> {
-        let (sender, workers) = start_workers(stash, threads, self.force)?;
-        let dir_walk = self.dir_walk()?;
-        let mut current_file_list = std::collections::HashSet::new();
+        let (sender, workers) = start_workers(stash, threads, self.force, &self.activity)?;
+        let walk = self.walk(
+            |path| {
+                stash.index().tree.insert_directory(path).unwrap();
+            },
+            |file| {
+                sender.send(file).unwrap();
+                Ok(())
+            },
+        );
+
+        drop(sender);
+        join_all(workers).await;
+        let walk = walk?;
+
+        let links = walk
+            .links
+            .into_iter()
+            .map(|(_, key, entry)| (key, entry))
+            .collect();
+        add_links(stash, links, self.force);
+
+        // only part of the tree was walked, so nothing can be removed
+        if walk.paused {
+            return Ok(());
+        }
+
+        let source_paths = self
+            .paths
+            .iter()
+            .map(normalize_filename)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !self.tags.is_empty() {
+            for path in source_paths.iter() {
+                stash.index().tag_path(path, self.tags.clone(), &[]);
+            }
+        }
+
+        stash.index().tree.retain(|p, _| {
+            for sp in source_paths.iter() {
+                if p.starts_with(sp) {
+                    // if the current directory is part of the new commit, diff
+                    return walk.found.contains(p);
+                }
+            }
+
+            // if it's unrelated, keep it in the index
+            true
+        });
+
+        Ok(())
+    }
+
+    /// Walk the paths like [`Options::add_recursive`], and chunk the
+    /// new and changed files to see what a commit would store, without
+    /// writing anything to the stash.
+    pub fn dry_run(&self, stash: &Infinitree<Files>) -> anyhow::Result<DryRun> {
+        let hasher = stash.hasher()?;
+        let chunker = stash.index().params.read().chunker;
+        let tree = &stash.index().tree;
+
+        let mut dry_run = DryRun::default();
+        let mut seen = HashSet::new();
+        let mut buf = Vec::with_capacity(MAX_FILE_SIZE);
+
+        // chunk a file, and count what's new
+        let mut count = |(path, key, entry): QueuedFile| -> anyhow::Result<()> {
+            let indexed = tree.node_by_path(&key).ok().flatten();
+            match indexed.as_deref() {
+                Some(crate::Node::File { refs: _, entry: e })
+                    if !self.force && entry.is_unchanged(e) =>
+                {
+                    dry_run.unchanged += 1;
+                    return Ok(());
+                }
+                Some(crate::Node::File { refs: _, entry: _ }) => dry_run.changed += 1,
+                _ => dry_run.added += 1,
+            }
+
+            // the contents of hardlinks are stored once
+            let size = entry.size as usize;
+            if size == 0 || entry.file_type.is_symlink() || entry.file_type.is_hardlink() {
+                return Ok(());
+            }
+
+            let mut osfile = match fs::File::open(&path) {
+                Ok(f) => f,
+                Err(error) => {
+                    warn!(%error, ?path, "failed to open file; skipping");
+                    return Ok(());
+                }
+            };
+
+            buf.clear();
+            if size < MAX_FILE_SIZE {
+                osfile.read_to_end(&mut buf)?;
+            }
+            let mut mmap = MmappedFile::new(size, osfile);
+            let data = if size < MAX_FILE_SIZE {
+                &buf[..size.min(buf.len())]
+            } else {
+                mmap.open()
+            };
+
+            dry_run.bytes += data.len() as u64;
+            for (_, hash, chunk) in splitter(chunker, data, hasher.clone()) {
+                let len = chunk.len() as u64;
+                if stash.index().chunks.contains(&hash) || !seen.insert(hash) {
+                    dry_run.deduplicated_chunks += 1;
+                    dry_run.deduplicated_bytes += len;
+                } else {
+                    dry_run.new_chunks += 1;
+                    dry_run.new_bytes += len;
+                }
+            }
+
+            Ok(())
+        };
+
+        // the same walk as a commit, with nothing written
+        let walk = self.walk(|_| {}, &mut count)?;
+        for link in walk.links {
+            count(link)?;
+        }
+
+        let source_paths = self
+            .paths
+            .iter()
+            .map(normalize_filename)
+            .collect::<Result<Vec<_>, _>>()?;
+        tree.retain(|p, node| {
+            let removed = matches!(node, crate::Node::File { .. })
+                && source_paths.iter().any(|sp| p.starts_with(sp))
+                && !walk.found.contains(p);
+            if removed {
+                dry_run.removed += 1;
+            }
+            true
+        });
+
+        Ok(dry_run)
+    }
+
+    /// Walk the paths on several threads, pass the directories to
+    /// `directory`, and the files that need to be read to `queue`.
+    ///
+    /// A file with several names is queued under the first one once
+    /// all of them are found, and the others are returned as links.
+    fn walk(
+        &self,
+        mut directory: impl FnMut(&str),
+        mut queue: impl FnMut(QueuedFile) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Walk> {
+        let dir_walk = self.parallel_walk()?;
+        let mut found = HashSet::new();
+        let mut paused = false;
+
+        // files with hardlinks by inode, which are queued once all
+        // their names are found
+        let mut inodes: HashMap<_, Vec<_>> = HashMap::new();
+
+        for dir_entry in dir_walk.iter() {
+            if self.pause.is_paused() {
+                debug!("paused, draining queued files");
+                paused = true;
+                break;
+            }
 
-        for dir_entry in dir_walk {
             let (metadata, path) = match dir_entry {
                 Ok(de) => (de.metadata(), de.path().to_owned()),
                 Err(error) => {
@@ -94,13 +405,13 @@ impl Options {
                 }
             };
 
-            current_file_list.insert(normalize_filename(&path)?);
+            let stored = self.stored_path(&path);
+            found.insert(normalize_filename(&stored)?);
 
             let metadata = match metadata {
-                Ok(md) if md.is_file() || md.is_symlink() => md,
+                Ok(md) if md.is_file() || md.is_symlink() || files::is_special(&md) => md,
                 Ok(md) if md.is_dir() => {
-                    let path_str = path.to_str().unwrap();
-                    stash.index().tree.insert_directory(path_str).unwrap();
+                    directory(stored.to_str().unwrap());
                     continue;
                 }
                 Err(error) => {
@@ -110,6 +421,7 @@ impl Options {
                 _ => continue,
             };
 
+            let inode = inode_of(&metadata);
             let entry = match files::Entry::from_metadata(metadata, &path, &self.preserve) {
                 Ok(e) => e,
                 Err(error) => {
@@ -118,36 +430,68 @@ impl Options {
                 }
             };
 
+            let key = stored.to_string_lossy().to_string();
+            if let Some(inode) = inode {
+                inodes.entry(inode).or_default().push((path, key, entry));
+                continue;
+            }
+
             trace!(?path, "queued");
-            sender.send((path, entry)).unwrap();
+            self.activity.queued();
+            self.activity.found(entry.size);
+            queue((path, key, entry))?;
         }
+        drop(dir_walk);
 
-        drop(sender);
-        join_all(workers).await;
+        let mut links = vec![];
+        for names in inodes.into_values() {
+            let ((path, key, entry), others) = hardlinks(names);
+            links.extend(others);
 
-        let source_paths = self
-            .paths
-            .iter()
-            .map(normalize_filename)
-            .collect::<Result<Vec<_>, _>>()?;
+            trace!(?path, "queued");
+            self.activity.queued();
+            self.activity.found(entry.size);
+            queue((path, key, entry))?;
+        }
+        if !paused {
+            self.activity.listed();
+        }
 
-        stash.index().tree.retain(|p, _| {
-            for sp in source_paths.iter() {
-                if p.starts_with(sp) {
-                    // if the current directory is part of the new commit, diff
-                    return current_file_list.contains(p);
-                }
-            }
+        Ok(Walk {
+            found,
+            links,
+            paused,
+        })
+    }
 
-            // if it's unrelated, keep it in the index
-            true
+    #[cfg(test)]
+    fn dir_walk(&self) -> anyhow::Result<impl Iterator<Item = Result<DirEntry, ignore::Error>>> {
+        Ok(self.walk_builder()?.build())
+    }
+
+    /// Walk the paths on several threads. Entries are queued up to a
+    /// limit, and the walk stops when the receiver is dropped.
+    fn parallel_walk(&self) -> anyhow::Result<mpsc::Receiver<Result<DirEntry, ignore::Error>>> {
+        let mut builder = self.walk_builder()?;
+        builder.threads(self.scan_threads.unwrap_or(0));
+
+        let (sender, receiver) = mpsc::bounded(WALK_QUEUE_SIZE);
+        let walk = builder.build_parallel();
+        std::thread::spawn(move || {
+            walk.run(|| {
+                let sender = sender.clone();
+                Box::new(move |entry| match sender.send(entry) {
+                    Ok(_) => WalkState::Continue,
+                    Err(_) => WalkState::Quit,
+                })
+            })
         });
 
-        Ok(())
+        Ok(receiver)
     }
 
-    fn dir_walk(&self) -> anyhow::Result<impl Iterator<Item = Result<DirEntry, ignore::Error>>> {
-        let mut paths = self.paths.iter();
+    fn walk_builder(&self) -> anyhow::Result<WalkBuilder> {
+        let mut paths = self.paths.iter().map(|p| self.source_of(p));
         let mut builder = WalkBuilder::new(paths.next().context("no path available")?);
 
         for path in paths {
@@ -165,30 +509,262 @@ impl Options {
         builder.git_global(self.git_global);
         builder.ignore(self.ignore);
         builder.follow_links(self.follow_links);
+        builder.add_custom_ignore_filename(IGNORE_FILENAME);
+
+        for file in self.exclude_files.iter() {
+            if let Some(error) = builder.add_ignore(file) {
+                return Err(error).with_context(|| format!("Failed to read {}", file.display()));
+            }
+        }
+
+        if !self.excludes.is_empty() {
+            let mut overrides = OverrideBuilder::new(std::env::current_dir()?);
+            for pattern in self.excludes.iter() {
+                // overrides are allowlists, unless negated
+                overrides
+                    .add(&format!("!{pattern}"))
+                    .with_context(|| format!("Invalid exclude pattern: {pattern}"))?;
+            }
+            builder.overrides(overrides.build()?);
+        }
+
+        if self.exclude_caches || !self.exclude_markers.is_empty() {
+            let caches = self.exclude_caches;
+            let markers = self.exclude_markers.clone();
+            builder.filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                !is_dir || !is_marked(entry.path(), caches, &markers)
+            });
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Device and inode of a regular file that has other names
+#[cfg(unix)]
+fn inode_of(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode_of(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Pick the first of the names of a hardlinked file, and turn the
+/// others into links to it. The walk is in no particular order, so the
+/// smallest name is picked to keep the same one between commits.
+fn hardlinks(mut names: Vec<QueuedFile>) -> (QueuedFile, Vec<QueuedFile>) {
+    names.sort_by(|a, b| a.1.cmp(&b.1));
+    let mut others = names.split_off(1);
+    let first = names.pop().expect("hardlinks have a name");
+
+    let target = first.1.trim_start_matches('/').to_string();
+    for (path, _, entry) in others.iter_mut() {
+        trace!(?path, first = ?target, "hardlink");
+        entry.file_type = files::FileType::Hardlink(target.clone());
+    }
+
+    (first, others)
+}
+
+/// Store the other names of hardlinked files with the chunks of the
+/// first name, which the workers stored already, instead of reading
+/// the same contents again
+fn add_links(stash: &Infinitree<Files>, links: Vec<(String, files::Entry)>, force: bool) {
+    let tree = &stash.index().tree;
+
+    for (key, mut entry) in links {
+        let files::FileType::Hardlink(ref first) = entry.file_type else {
+            continue;
+        };
+
+        if let Ok(Some(indexed)) = tree.file(&key) {
+            if !force && entry.is_unchanged(&indexed) {
+                debug!(?key, "already indexed, skipping");
+                continue;
+            }
+        }
+
+        let Ok(Some(stored)) = tree.file(first) else {
+            warn!(
+                ?key,
+                ?first,
+                "the first name of the hardlink wasn't stored; skipping"
+            );
+            continue;
+        };
+
+        entry.chunks = stored.chunks.clone();
+        tree.insert_file(&key, entry).unwrap();
+    }
+}
+
+/// Whether the directory at `path` is a cache, or has one of the
+/// `markers` in it
+fn is_marked(path: &Path, caches: bool, markers: &[String]) -> bool {
+    (caches && is_cache_dir(path)) || markers.iter().any(|m| path.join(m).exists())
+}
+
+/// Whether the directory has a CACHEDIR.TAG that starts with the
+/// signature, as in <https://bford.info/cachedir/>
+fn is_cache_dir(path: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_SIGNATURE.len()];
+    fs::File::open(path.join(CACHEDIR_TAG))
+        .and_then(|mut f| f.read_exact(&mut signature))
+        .is_ok_and(|_| signature == CACHEDIR_SIGNATURE)
+}
+
+/// Fold the changes in an overlay directory into the stash.
+///
+/// Paths under `upper` are added relative to it, whiteouts remove the
+/// corresponding files, and opaque directories replace their
+/// counterparts in the stash.
+pub async fn add_overlay(
+    stash: &Infinitree<Files>,
+    upper: &Path,
+    preserve: &files::PreserveMetadata,
+    threads: usize,
+) -> anyhow::Result<()> {
+    let (sender, workers) = start_workers(stash, threads, false, &Default::default())?;
+    let tree = &stash.index().tree;
+
+    let mut walk = WalkBuilder::new(upper);
+    walk.standard_filters(false);
+    // markers need to be processed before the rest of the directory
+    walk.sort_by_file_name(|a, b| {
+        let is_marker = |n: &std::ffi::OsStr| n.to_string_lossy().starts_with(WHITEOUT_PREFIX);
+        is_marker(b).cmp(&is_marker(a)).then(a.cmp(b))
+    });
+
+    for dir_entry in walk.build() {
+        let dir_entry = match dir_entry {
+            Ok(de) => de,
+            Err(error) => {
+                warn!(%error, "failed to process file; skipping");
+                continue;
+            }
+        };
+
+        let path = dir_entry.path().to_owned();
+        let relative = path.strip_prefix(upper)?;
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+
+        let name = dir_entry.file_name().to_string_lossy();
+        let parent = relative
+            .parent()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let in_parent = |name: &str| {
+            if parent.is_empty() {
+                name.to_string()
+            } else {
+                format!("{parent}/{name}")
+            }
+        };
+
+        if name == OPAQUE_MARKER {
+            // markers sort first, so the contents of the overlay
+            // directory are re-added after this
+            debug!(?path, "replacing directory");
+            let mut children = vec![];
+            if let Ok(Some(node)) = tree.node_by_path(&parent) {
+                if let crate::Node::Directory { entries } = node.as_ref() {
+                    let mut current = entries.first_entry();
+                    while let Some(entry) = current {
+                        children.push(in_parent(entry.key()));
+                        current = entry.next();
+                    }
+                }
+            }
+
+            for child in children {
+                _ = tree.remove(&child);
+            }
+            continue;
+        }
 
-        Ok(builder.build())
+        if let Some(deleted) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let key = in_parent(deleted);
+            debug!(path = %key, "removing");
+            _ = tree.remove(&key);
+            continue;
+        }
+
+        let key = relative.to_string_lossy().to_string();
+        let metadata = match dir_entry.metadata() {
+            Ok(md) if md.is_dir() => {
+                tree.insert_directory(&key).unwrap();
+                continue;
+            }
+            Ok(md) if md.is_file() || md.is_symlink() || files::is_special(&md) => md,
+            Ok(_) => continue,
+            Err(error) => {
+                warn!(%error, ?path, "failed to get file metadata; skipping");
+                continue;
+            }
+        };
+
+        let entry = match files::Entry::from_metadata(metadata, &path, preserve) {
+            Ok(e) => e,
+            Err(error) => {
+                error!(%error, ?path, "failed to ingest file; aborting");
+                break;
+            }
+        };
+
+        trace!(?path, "queued");
+        sender.send((path, key, entry)).unwrap();
     }
+
+    drop(sender);
+    join_all(workers).await;
+
+    Ok(())
 }
 
 fn start_workers(
     stash: &Infinitree<Files>,
     threads: usize,
     force: bool,
+    activity: &Activity,
 ) -> anyhow::Result<(Sender, Vec<task::JoinHandle<()>>)> {
     // make sure the input and output queues are generous
     let (sender, receiver) = mpsc::bounded(threads * 2);
     let balancer = Pool::new(NonZeroUsize::new(threads).unwrap(), stash.storage_writer()?)?;
     let hasher = stash.hasher()?;
 
-    let workers = (0..threads)
-        .map(|_| {
-            task::spawn(process_file_loop(
-                force,
-                receiver.clone(),
-                stash.index().clone(),
-                hasher.clone(),
-                balancer.clone(),
-            ))
+    let index = stash.index();
+    if index.digest_filter.read().is_saturated() {
+        warn!("chunk digest filter is saturated and should be rebuilt");
+    } else if !index.digest_filter.read().is_enabled()
+        && stash.commit_list().iter().next().is_none()
+    {
+        *index.digest_filter.write() =
+            crate::DigestFilter::with_capacity(crate::DEFAULT_FILTER_CAPACITY);
+    }
+
+    let workers = activity
+        .start_workers(threads)
+        .into_iter()
+        .map(|worker| {
+            task::spawn(
+                process_file_loop(
+                    force,
+                    receiver.clone(),
+                    activity.clone(),
+                    worker,
+                    stash.index().clone(),
+                    hasher.clone(),
+                    balancer.clone(),
+                )
+                .instrument(Span::current()),
+            )
         })
         .collect::<Vec<_>>();
 
@@ -198,35 +774,49 @@ fn start_workers(
 async fn process_file_loop(
     force: bool,
     r: Receiver,
+    activity: Activity,
+    worker: Arc<Worker>,
     index: crate::Files,
     hasher: infinitree::Hasher,
     writer: Pool<impl Writer + Clone + 'static>,
 ) {
     let mut buf = Vec::with_capacity(MAX_FILE_SIZE);
 
-    while let Ok((path, entry)) = r.recv_async().await {
+    while let Ok((path, path_str, entry)) = r.recv_async().await {
         buf.clear();
-        let path_str = path.to_string_lossy();
-
-        if !force {
-            let tree = &index.tree;
-            if let Ok(Some(node)) = tree.node_by_path(&path_str) {
-                match node.as_ref() {
-                    crate::Node::File { refs: _, entry: e } if *e.as_ref() == entry => {
-                        debug!(?path, "already indexed, skipping");
-                        continue;
-                    }
-                    crate::Node::File { refs: _, entry: _ } => {
-                        debug!(?path, "adding new file");
+        activity.dequeued();
+        worker.idle();
+
+        let indexed = index.tree.node_by_path(&path_str).ok().flatten();
+        match indexed.as_deref() {
+            Some(crate::Node::File { refs: _, entry: e }) if entry.is_unchanged(e) => {
+                if !force {
+                    // entries stored without an inode and a change time
+                    // get them now, without reading the file
+                    let size = entry.size;
+                    if e.inode != entry.inode || e.ctime != entry.ctime {
+                        let mut entry = entry;
+                        entry.chunks = e.chunks.clone();
+                        index.tree.insert_file(&path_str, entry).unwrap();
                     }
-                    crate::Node::Directory { .. } => {}
+
+                    debug!(?path, "already indexed, skipping");
+                    activity.done(size);
+                    continue;
                 }
             }
+            Some(crate::Node::File { refs: _, entry: _ }) => {
+                debug!(?path, "adding new file");
+                activity.changed();
+            }
+            _ => activity.added(),
         }
 
         let size = entry.size;
+        worker.start(&path_str);
         if size == 0 || entry.file_type.is_symlink() {
             index.tree.insert_file(&path_str, entry).unwrap();
+            activity.done(size);
             continue;
         }
 
@@ -234,6 +824,7 @@ async fn process_file_loop(
             Ok(f) => f,
             Err(error) => {
                 warn!(%error, ?path, "failed to open file; skipping");
+                activity.done(size);
                 continue;
             }
         };
@@ -243,13 +834,19 @@ async fn process_file_loop(
             osfile,
             &mut buf,
             path.clone(),
+            &path_str,
             &index,
             hasher.clone(),
             &writer,
+            &activity,
         )
         .instrument(debug_span!("indexing", ?path, size))
         .await;
+        worker.read(size);
+        activity.done(size);
     }
+
+    worker.idle();
 }
 
 async fn index_file(
@@ -257,9 +854,11 @@ async fn index_file(
     mut osfile: fs::File,
     buf: &mut Vec<u8>,
     path: PathBuf,
+    path_str: &str,
     index: &crate::Files,
     hasher: infinitree::Hasher,
     writer: &Pool<impl Writer + Clone + 'static>,
+    activity: &Activity,
 ) {
     let size = entry.size as usize;
 
@@ -267,20 +866,25 @@ async fn index_file(
         osfile.read_to_end(buf).unwrap();
     }
 
+    let chunker = index.params.read().chunker;
     let mut mmap = MmappedFile::new(size, osfile);
     let (_, chunks) = async_scoped::TokioScope::scope_and_block(|s| {
-        let splitter: Box<dyn Iterator<Item = (u64, Digest, &[u8])>> = if size < MAX_FILE_SIZE {
-            Box::new(FileSplitter::<SeaSplit>::new(&buf[0..size], hasher))
+        let data = if size < MAX_FILE_SIZE {
+            &buf[0..size]
         } else {
-            Box::new(FileSplitter::<BupSplit>::new(mmap.open(), hasher))
+            mmap.open()
         };
 
-        for (start, hash, data) in splitter {
+        for (start, hash, data) in splitter(chunker, data, hasher) {
             let mut writer = writer.clone();
 
             s.spawn(async move {
-                let store = || writer.write_chunk(&hash, data).unwrap();
-                let ptr = index.chunks.insert_with(hash, store);
+                let store = || {
+                    activity.chunk_stored(data.len() as u64);
+                    writer.write_chunk(&hash, data).unwrap()
+                };
+                let ptr = index.insert_chunk(hash, store);
+                activity.chunk();
                 (start, ptr)
             })
         }
@@ -296,10 +900,25 @@ async fn index_file(
 
     debug!(?path, chunks = entry.chunks.len(), "indexed");
 
-    let path_str = path.to_str().unwrap();
     index.tree.insert_file(path_str, entry).unwrap();
 }
 
+/// Split `data` into chunks the same way a file of the same size is
+/// split when it's stored
+pub(crate) fn splitter(
+    chunker: Chunker,
+    data: &[u8],
+    hasher: infinitree::Hasher,
+) -> Box<dyn Iterator<Item = (u64, Digest, &[u8])> + '_> {
+    match chunker {
+        Chunker::Auto if data.len() < MAX_FILE_SIZE => {
+            Box::new(FileSplitter::<SeaSplit>::new(data, hasher))
+        }
+        Chunker::Auto | Chunker::Bup => Box::new(FileSplitter::<BupSplit>::new(data, hasher)),
+        Chunker::Seahash => Box::new(FileSplitter::<SeaSplit>::new(data, hasher)),
+    }
+}
+
 struct MmappedFile {
     mmap: Option<Mmap>,
     len: usize,
@@ -325,3 +944,189 @@ impl MmappedFile {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Options;
+    use crate::Files;
+    use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+    #[test]
+    fn dry_run_counts_duplicates() {
+        let dir = std::env::temp_dir().join(format!("0s-dry-run-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = (0..100_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        std::fs::write(dir.join("a"), &data).unwrap();
+        std::fs::write(dir.join("b"), &data).unwrap();
+        std::fs::write(dir.join("empty"), b"").unwrap();
+
+        let stash = Infinitree::<Files>::empty(
+            InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("dry-run".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap();
+        let options = Options {
+            paths: vec![dir.clone()],
+            ..Default::default()
+        };
+        let dry_run = options.dry_run(&stash).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dry_run.added, 3);
+        assert_eq!(dry_run.bytes, 2 * data.len() as u64);
+        assert_eq!(dry_run.new_bytes, data.len() as u64);
+        assert_eq!(dry_run.deduplicated_bytes, data.len() as u64);
+        assert_eq!(dry_run.new_chunks, dry_run.deduplicated_chunks);
+    }
+
+    #[test]
+    fn walk_skips_excluded_paths() {
+        let dir = std::env::temp_dir().join(format!("0s-exclude-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("app/node_modules/dep")).unwrap();
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        std::fs::write(dir.join("app/main.js"), b"main").unwrap();
+        std::fs::write(dir.join("app/debug.log"), b"log").unwrap();
+        std::fs::write(dir.join("app/node_modules/dep/index.js"), b"dep").unwrap();
+        std::fs::write(dir.join("app/.zerostashignore"), b"node_modules/\n").unwrap();
+        std::fs::write(dir.join("target/build"), b"build").unwrap();
+        std::fs::write(dir.join("excludes"), b"target\n").unwrap();
+
+        let options = Options {
+            paths: vec![dir.clone()],
+            excludes: vec!["*.log".to_string()],
+            exclude_files: vec![dir.join("excludes")],
+            ..Default::default()
+        };
+        let mut walked = options
+            .dir_walk()
+            .unwrap()
+            .map(|e| e.unwrap().path().strip_prefix(&dir).unwrap().to_owned())
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect::<Vec<_>>();
+        walked.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let expected = ["app", "app/.zerostashignore", "app/main.js", "excludes"]
+            .map(std::path::PathBuf::from);
+        assert_eq!(walked, expected);
+    }
+
+    #[test]
+    fn walk_skips_marked_directories() {
+        let dir = std::env::temp_dir().join(format!("0s-caches-{}", rand::random::<u64>()));
+        for sub in ["cache", "fake-cache", "build", "src"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+            std::fs::write(dir.join(sub).join("file"), b"data").unwrap();
+        }
+        let mut tag = super::CACHEDIR_SIGNATURE.to_vec();
+        tag.extend_from_slice(b"\n# This is a cache\n");
+        std::fs::write(dir.join("cache/CACHEDIR.TAG"), tag).unwrap();
+        std::fs::write(dir.join("fake-cache/CACHEDIR.TAG"), b"not a signature").unwrap();
+        std::fs::write(dir.join("build/.nobackup"), b"").unwrap();
+
+        let options = Options {
+            paths: vec![dir.clone()],
+            exclude_caches: true,
+            exclude_markers: vec![".nobackup".to_string()],
+            ..Default::default()
+        };
+        let mut walked = options
+            .dir_walk()
+            .unwrap()
+            .map(|e| e.unwrap().path().strip_prefix(&dir).unwrap().to_owned())
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect::<Vec<_>>();
+        walked.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let expected = [
+            "fake-cache",
+            "fake-cache/CACHEDIR.TAG",
+            "fake-cache/file",
+            "src",
+            "src/file",
+        ]
+        .map(std::path::PathBuf::from);
+        assert_eq!(walked, expected);
+    }
+
+    #[test]
+    fn snapshots_are_stored_under_the_original_paths() {
+        use std::path::{Path, PathBuf};
+
+        let options = Options {
+            snapshots: vec![super::Snapshot {
+                original: "/home".into(),
+                mounted: "/run/snapshot/home".into(),
+            }],
+            ..Default::default()
+        };
+
+        let source = options.source_of(Path::new("/home/me/notes.txt"));
+        assert_eq!(source, PathBuf::from("/run/snapshot/home/me/notes.txt"));
+        assert_eq!(
+            options.stored_path(&source),
+            PathBuf::from("/home/me/notes.txt")
+        );
+        assert_eq!(
+            options.source_of(Path::new("/etc/hosts")),
+            PathBuf::from("/etc/hosts")
+        );
+    }
+
+    #[test]
+    fn parallel_walk_matches_the_serial_walk() {
+        let dir = std::env::temp_dir().join(format!("0s-parallel-{}", rand::random::<u64>()));
+        for i in 0..20 {
+            std::fs::create_dir_all(dir.join(format!("{i}/sub"))).unwrap();
+            std::fs::write(dir.join(format!("{i}/file")), b"data").unwrap();
+            std::fs::write(dir.join(format!("{i}/sub/file")), b"data").unwrap();
+        }
+
+        let options = Options {
+            paths: vec![dir.clone()],
+            scan_threads: Some(4),
+            ..Default::default()
+        };
+        let mut serial = options
+            .dir_walk()
+            .unwrap()
+            .map(|e| e.unwrap().into_path())
+            .collect::<Vec<_>>();
+        let mut parallel = options
+            .parallel_walk()
+            .unwrap()
+            .iter()
+            .map(|e| e.unwrap().into_path())
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        serial.sort();
+        parallel.sort();
+        assert_eq!(serial.len(), 81);
+        assert_eq!(parallel, serial);
+    }
+
+    #[test]
+    fn hardlinks_link_to_the_smallest_name() {
+        use crate::files::{Entry, FileType};
+
+        let name = |key: &str| (key.into(), key.to_string(), Entry::default());
+        let (first, others) = super::hardlinks(vec![name("/b/c"), name("/a"), name("/b")]);
+
+        assert_eq!(first.1, "/a");
+        assert_eq!(
+            others
+                .into_iter()
+                .map(|(_, key, entry)| (key, entry.file_type))
+                .collect::<Vec<_>>(),
+            [
+                ("/b".to_string(), FileType::Hardlink("a".to_string())),
+                ("/b/c".to_string(), FileType::Hardlink("a".to_string()))
+            ]
+        );
+    }
+}
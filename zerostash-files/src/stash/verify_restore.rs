@@ -0,0 +1,258 @@
+//! Go through the motions of a restore without writing any files.
+//!
+//! Every chunk of the selected files is fetched, decrypted, and hashed
+//! again to compare with the digest stored in the index. If a local
+//! directory is given, the files in it are compared to the contents of
+//! the stash, byte by byte.
+//!
+//! After a restore, [`verify_written`] hashes the files that were
+//! written instead, and compares them to the digests in the index,
+//! without reading anything from the backend.
+use crate::{files::Entry, FileType, Files};
+use infinitree::{object::Reader, Infinitree};
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::debug;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub files: usize,
+    pub bytes: u64,
+    pub problems: Vec<String>,
+}
+
+pub fn verify(
+    stash: &Infinitree<Files>,
+    files: impl IntoIterator<Item = (String, Arc<Entry>)>,
+    local: Option<&Path>,
+) -> anyhow::Result<Report> {
+    let mut reader = stash.storage_reader()?;
+    let mut hasher = stash.hasher()?;
+    let mut report = Report::default();
+    let mut buf = vec![];
+    let mut local_buf = vec![];
+
+    for (path, entry) in files {
+        debug!(%path, "verifying");
+        report.files += 1;
+
+        let local_path = local.map(|dir| dir.join(&path));
+        if let FileType::Symlink(target) = &entry.file_type {
+            if let Some(problem) = local_path.and_then(|lp| symlink_problem(&lp, target)) {
+                report.problems.push(format!("{path}: {problem}"));
+            }
+            continue;
+        }
+
+        let mut local_file = match local_path.map(|lp| open_local(&lp, &entry)) {
+            Some(Err(problem)) => {
+                report.problems.push(format!("{path}: {problem}"));
+                None
+            }
+            Some(Ok(file)) => Some(file),
+            None => None,
+        };
+
+        let ends = entry.chunks.keys().skip(1).copied().chain([entry.size]);
+        for ((start, pointer), end) in entry.chunks.iter().zip(ends) {
+            buf.resize((end - start) as usize, 0);
+            if let Err(error) = reader.read_chunk(pointer, &mut buf) {
+                report.problems.push(format!(
+                    "{path}: the chunk at offset {start} can't be read: {error}"
+                ));
+                // the local file can't be compared past a missing chunk
+                local_file = None;
+                continue;
+            }
+            report.bytes += buf.len() as u64;
+
+            hasher.reset();
+            hasher.update(&buf);
+            if hasher.finalize().as_bytes() != pointer.hash() {
+                report.problems.push(format!(
+                    "{path}: the chunk at offset {start} doesn't match its hash"
+                ));
+            }
+
+            if let Some(file) = local_file.as_mut() {
+                local_buf.resize(buf.len(), 0);
+                if file.read_exact(&mut local_buf).is_err() || local_buf != buf {
+                    report.problems.push(format!(
+                        "{path}: the local file differs from offset {start}"
+                    ));
+                    local_file = None;
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Hash the chunks of restored files at their target paths, and
+/// compare them to the digests stored in the index
+pub fn verify_written(
+    stash: &Infinitree<Files>,
+    files: impl IntoIterator<Item = (PathBuf, Arc<Entry>)>,
+) -> anyhow::Result<Report> {
+    let mut hasher = stash.hasher()?;
+    let mut report = Report::default();
+    let mut buf = vec![];
+
+    for (path, entry) in files {
+        debug!(?path, "verifying");
+        report.files += 1;
+        let name = path.display();
+
+        match &entry.file_type {
+            FileType::Symlink(target) => {
+                if let Some(problem) = symlink_problem(&path, target) {
+                    report.problems.push(format!("{name}: {problem}"));
+                }
+                continue;
+            }
+            FileType::Directory => continue,
+            FileType::Fifo
+            | FileType::Socket
+            | FileType::CharDevice(_)
+            | FileType::BlockDevice(_) => continue,
+            FileType::File | FileType::Hardlink(_) => {}
+        }
+
+        let mut file = match open_local(&path, &entry) {
+            Ok(file) => file,
+            Err(problem) => {
+                report.problems.push(format!("{name}: {problem}"));
+                continue;
+            }
+        };
+
+        let ends = entry.chunks.keys().skip(1).copied().chain([entry.size]);
+        for ((start, pointer), end) in entry.chunks.iter().zip(ends) {
+            buf.resize((end - start) as usize, 0);
+            if let Err(error) = file.read_exact(&mut buf) {
+                report
+                    .problems
+                    .push(format!("{name}: can't read offset {start}: {error}"));
+                break;
+            }
+            report.bytes += buf.len() as u64;
+
+            hasher.reset();
+            hasher.update(&buf);
+            if hasher.finalize().as_bytes() != pointer.hash() {
+                report
+                    .problems
+                    .push(format!("{name}: differs from the stash at offset {start}"));
+                break;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Open the local copy of a file, if it has the right type and size
+fn open_local(path: &Path, entry: &Entry) -> Result<fs::File, String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Err("missing from the local directory".into())
+        }
+        Err(error) => return Err(format!("the local file can't be read: {error}")),
+    };
+
+    if !metadata.is_file() {
+        return Err("the local path is not a file".into());
+    }
+    if metadata.len() != entry.size {
+        return Err(format!(
+            "the local file is {} bytes instead of {}",
+            metadata.len(),
+            entry.size
+        ));
+    }
+
+    fs::File::open(path).map_err(|error| format!("the local file can't be read: {error}"))
+}
+
+fn symlink_problem(path: &Path, target: &Path) -> Option<String> {
+    match fs::read_link(path) {
+        Ok(local) if local == target => None,
+        Ok(local) => Some(format!("the local symlink points to {local:?}")),
+        Err(_) => Some("the local path is not a symlink".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{verify, verify_written};
+    use crate::{Entry, Files};
+    use infinitree::{
+        backends::test::InMemoryBackend, crypto::UsernamePassword, object::Writer, Infinitree,
+    };
+    use std::{collections::BTreeMap, fs, sync::Arc};
+
+    #[test]
+    fn compare_with_local_files() {
+        let stash = Infinitree::<Files>::empty(
+            InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("verify".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap();
+
+        let data = b"the contents of the file".to_vec();
+        let mut hasher = stash.hasher().unwrap();
+        let hash = *hasher.update(&data).finalize().as_bytes();
+        let mut writer = stash.storage_writer().unwrap();
+        let pointer = writer.write_chunk(&hash, &data).unwrap();
+        writer.flush().unwrap();
+
+        let entry = Arc::new(Entry {
+            size: data.len() as u64,
+            chunks: BTreeMap::from([(0, Arc::new(pointer))]),
+            ..Default::default()
+        });
+        let files = || {
+            [
+                ("same".to_string(), entry.clone()),
+                ("changed".to_string(), entry.clone()),
+                ("missing".to_string(), entry.clone()),
+            ]
+        };
+
+        let local = std::env::temp_dir().join(format!("0s-verify-{}", rand::random::<u64>()));
+        fs::create_dir_all(&local).unwrap();
+        fs::write(local.join("same"), &data).unwrap();
+        fs::write(local.join("changed"), b"THE contents of the file").unwrap();
+
+        let report = verify(&stash, files(), None).unwrap();
+        assert_eq!(report.files, 3);
+        assert_eq!(report.bytes, 3 * data.len() as u64);
+        assert!(report.problems.is_empty());
+
+        let report = verify(&stash, files(), Some(&local)).unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                "changed: the local file differs from offset 0",
+                "missing: missing from the local directory",
+            ]
+        );
+
+        let written = files()
+            .into_iter()
+            .map(|(path, entry)| (local.join(path), entry));
+        let report = verify_written(&stash, written).unwrap();
+        fs::remove_dir_all(&local).unwrap();
+        assert_eq!(report.files, 3);
+        assert_eq!(report.problems.len(), 2);
+        assert!(report.problems[0].ends_with("changed: differs from the stash at offset 0"));
+        assert!(report.problems[1].ends_with("missing: missing from the local directory"));
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::BTreeMap;
+
+/// User defined `key=value` pairs attached to a path in the stash.
+///
+/// Tags apply to the path and everything below it.
+pub type Tags = BTreeMap<String, String>;
+
+/// Parse a `key=value` tag from the command line
+pub fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid tag `{tag}`, expected `key=value`")),
+    }
+}
+
+/// Whether `tags` has every tag in `required`
+pub fn matches(tags: &Tags, required: &[(String, String)]) -> bool {
+    required
+        .iter()
+        .all(|(key, value)| tags.get(key) == Some(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, parse_tag, Tags};
+
+    #[test]
+    fn parse_and_match_tags() {
+        assert_eq!(
+            parse_tag("project=alpha"),
+            Ok(("project".into(), "alpha".into()))
+        );
+        assert_eq!(parse_tag("empty="), Ok(("empty".into(), "".into())));
+        assert!(parse_tag("=alpha").is_err());
+        assert!(parse_tag("project").is_err());
+
+        let tags = Tags::from([
+            ("project".into(), "alpha".into()),
+            ("owner".into(), "ops".into()),
+        ]);
+        assert!(matches(&tags, &[]));
+        assert!(matches(&tags, &[parse_tag("project=alpha").unwrap()]));
+        assert!(!matches(&tags, &[parse_tag("project=beta").unwrap()]));
+        assert!(!matches(&tags, &[parse_tag("team=x").unwrap()]));
+    }
+}
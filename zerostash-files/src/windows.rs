@@ -0,0 +1,155 @@
+//! Windows specific metadata: file attributes, alternate data streams,
+//! and NTFS security descriptors.
+use std::{
+    collections::BTreeMap, ffi::c_void, fs, io, iter, os::windows::ffi::OsStrExt, path::Path, ptr,
+};
+use windows_sys::Win32::{
+    Foundation::{ERROR_HANDLE_EOF, INVALID_HANDLE_VALUE},
+    Security::{
+        GetFileSecurityW, SetFileSecurityW, DACL_SECURITY_INFORMATION, GROUP_SECURITY_INFORMATION,
+        OWNER_SECURITY_INFORMATION,
+    },
+    Storage::FileSystem::{
+        FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard, SetFileAttributesW,
+        FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_NOT_CONTENT_INDEXED,
+        FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM, WIN32_FIND_STREAM_DATA,
+    },
+};
+
+/// The attributes that are stored. The rest, like compression or
+/// sparseness, are up to the file system.
+pub(crate) const STORED_ATTRIBUTES: u32 = FILE_ATTRIBUTE_READONLY
+    | FILE_ATTRIBUTE_HIDDEN
+    | FILE_ATTRIBUTE_SYSTEM
+    | FILE_ATTRIBUTE_ARCHIVE
+    | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED;
+
+/// The owner, the group, and the DACL. The SACL needs a privilege
+/// that backup tools rarely have.
+const SECURITY_INFORMATION: u32 =
+    OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION;
+
+fn wide(path: &Path) -> Vec<u16> {
+    path.as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect()
+}
+
+pub(crate) fn set_attributes(path: &Path, attributes: u32) -> io::Result<()> {
+    match unsafe { SetFileAttributesW(wide(path).as_ptr(), attributes & STORED_ATTRIBUTES) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// The self-relative security descriptor of `path`
+pub(crate) fn security_descriptor(path: &Path) -> io::Result<Vec<u8>> {
+    let path = wide(path);
+    let mut needed = 0;
+
+    // the first call only finds out the size
+    unsafe {
+        GetFileSecurityW(
+            path.as_ptr(),
+            SECURITY_INFORMATION,
+            ptr::null_mut(),
+            0,
+            &mut needed,
+        )
+    };
+    if needed == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut descriptor = vec![0u8; needed as usize];
+    let read = unsafe {
+        GetFileSecurityW(
+            path.as_ptr(),
+            SECURITY_INFORMATION,
+            descriptor.as_mut_ptr() as *mut c_void,
+            needed,
+            &mut needed,
+        )
+    };
+    match read {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(descriptor),
+    }
+}
+
+/// Apply a descriptor read by [`security_descriptor`]. Setting an owner
+/// other than the current user needs administrator rights.
+pub(crate) fn set_security_descriptor(path: &Path, descriptor: &[u8]) -> io::Result<()> {
+    let set = unsafe {
+        SetFileSecurityW(
+            wide(path).as_ptr(),
+            SECURITY_INFORMATION,
+            descriptor.as_ptr() as *mut c_void,
+        )
+    };
+    match set {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// The alternate data streams of `path` by name, without the unnamed
+/// stream that holds the contents
+pub(crate) fn streams(path: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut streams = BTreeMap::new();
+    let mut data: WIN32_FIND_STREAM_DATA = unsafe { std::mem::zeroed() };
+
+    let find = unsafe {
+        FindFirstStreamW(
+            wide(path).as_ptr(),
+            FindStreamInfoStandard,
+            &mut data as *mut _ as *mut c_void,
+            0,
+        )
+    };
+    if find == INVALID_HANDLE_VALUE {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(code) if code as u32 == ERROR_HANDLE_EOF => Ok(streams),
+            _ => Err(error),
+        };
+    }
+
+    let result = loop {
+        let len = data.cStreamName.iter().position(|c| *c == 0).unwrap_or(0);
+        let name = String::from_utf16_lossy(&data.cStreamName[..len]);
+
+        // names look like `:Zone.Identifier:$DATA`
+        if let Some(name) = name
+            .strip_prefix(':')
+            .and_then(|n| n.strip_suffix(":$DATA"))
+            .filter(|n| !n.is_empty())
+        {
+            let mut stream = path.as_os_str().to_owned();
+            stream.push(format!(":{name}"));
+            match fs::read(&stream) {
+                Ok(contents) => _ = streams.insert(name.to_string(), contents),
+                Err(error) => break Err(error),
+            }
+        }
+
+        if unsafe { FindNextStreamW(find, &mut data as *mut _ as *mut c_void) } == 0 {
+            let error = io::Error::last_os_error();
+            break match error.raw_os_error() {
+                Some(code) if code as u32 == ERROR_HANDLE_EOF => Ok(()),
+                _ => Err(error),
+            };
+        }
+    };
+
+    unsafe { FindClose(find) };
+    result.map(|_| streams)
+}
+
+/// Write the alternate data stream `name` of `path`
+pub(crate) fn write_stream(path: &Path, name: &str, contents: &[u8]) -> io::Result<()> {
+    let mut stream = path.as_os_str().to_owned();
+    stream.push(format!(":{name}"));
+    fs::write(stream, contents)
+}
@@ -1,11 +1,21 @@
 use chrono::{DateTime, Utc};
-use infinitree::object::{AEADReader, AEADWriter, BufferedSink, PoolRef};
+use flume as mpsc;
+use infinitree::{
+    object::{AEADReader, AEADWriter, Pool, PoolRef, Reader, Writer},
+    ChunkPointer, Hasher,
+};
 use std::{
+    collections::BTreeMap,
     io::{self, Read, Write},
-    process::{ChildStdin, ChildStdout},
+    sync::Arc,
+    thread,
     time::{Duration, SystemTime},
 };
 
+/// Size of the chunks a snapshot stream is split into. Chunks are
+/// packed into objects that are uploaded whole.
+const STREAM_CHUNK_SIZE: usize = 4_100_000;
+
 #[derive(thiserror::Error, Debug)]
 pub enum SnapshotError {
     #[error("IO error: {source}")]
@@ -29,9 +39,17 @@ pub enum SnapshotError {
 
 #[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct ZfsSnapshot {
+    /// Snapshots captured by older versions are stored as a single stream
     pub stream: infinitree::object::Stream,
     pub creation_time_secs: u64,
     pub creation_time_nanos: u128,
+
+    /// Chunks of the stream, keyed by their offset
+    #[serde(default)]
+    pub chunks: BTreeMap<u64, Arc<ChunkPointer>>,
+    /// Length of the stream
+    #[serde(default)]
+    pub size: u64,
 }
 
 impl From<&ZfsSnapshot> for DateTime<Utc> {
@@ -46,50 +64,147 @@ impl From<&ZfsSnapshot> for DateTime<Utc> {
 }
 
 impl ZfsSnapshot {
+    /// Split the stream into chunks, and hash, compress, encrypt and
+    /// upload them on `threads` threads.
     pub fn from_stdout(
-        writer: AEADWriter,
-        stdin: &mut ChildStdout,
+        writer: Pool<AEADWriter>,
+        hasher: Hasher,
+        stdout: &mut impl Read,
+        threads: usize,
     ) -> Result<ZfsSnapshot, SnapshotError> {
-        let mut sink = BufferedSink::with_chunk_size(writer, 4_100_000);
-        let mut buf = vec![0; 1_000_000];
+        let (sender, receiver) = mpsc::bounded::<(u64, Vec<u8>)>(threads);
+
+        let (chunks, size) = thread::scope(|s| {
+            let workers = (0..threads.max(1))
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    let mut writer = writer.clone();
+                    let mut hasher = hasher.clone();
+
+                    s.spawn(move || {
+                        let mut stored = vec![];
+                        for (start, block) in receiver.iter() {
+                            hasher.reset();
+                            let hash = *hasher.update(&block).finalize().as_bytes();
+                            stored.push((start, Arc::new(writer.write_chunk(&hash, &block)?)));
+                        }
+
+                        Ok::<_, SnapshotError>(stored)
+                    })
+                })
+                .collect::<Vec<_>>();
+            drop(receiver);
+
+            let read = read_blocks(stdout, &sender);
+            drop(sender);
 
-        loop {
-            let read_amount = stdin.read(&mut buf)?;
-            if read_amount == 0 {
-                break;
+            let mut chunks = BTreeMap::new();
+            for worker in workers {
+                chunks.extend(worker.join().expect("snapshot worker panicked")?);
             }
-            sink.write_all(&buf[..read_amount])?;
-        }
 
-        let stream = sink.finish()?;
+            Ok::<_, SnapshotError>((chunks, read?))
+        })?;
 
         let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
         let creation_time_secs = since_epoch.as_secs();
         let creation_time_nanos = since_epoch.as_nanos();
 
         Ok(Self {
-            stream,
+            stream: Default::default(),
             creation_time_secs,
             creation_time_nanos,
+            chunks,
+            size,
         })
     }
 
     pub fn to_stdin(
         &self,
-        reader: PoolRef<AEADReader>,
-        lock: &mut ChildStdin,
+        mut reader: PoolRef<AEADReader>,
+        stdin: &mut impl Write,
     ) -> Result<(), SnapshotError> {
-        let mut stream = self.stream.open_reader(reader);
-        let mut buf = vec![0; 1_000_000];
+        if self.chunks.is_empty() {
+            let mut stream = self.stream.open_reader(reader);
+            io::copy(&mut stream, stdin)?;
+            return Ok(());
+        }
 
-        loop {
-            let read_amount = stream.read(&mut buf)?;
-            if read_amount == 0 {
-                break;
-            }
-            lock.write_all(&buf[..read_amount])?;
+        let mut buf = vec![0; STREAM_CHUNK_SIZE];
+        let ends = self.chunks.keys().skip(1).copied().chain([self.size]);
+        for ((start, pointer), end) in self.chunks.iter().zip(ends) {
+            let block = &mut buf[..(end - start) as usize];
+            reader.read_chunk(pointer, block)?;
+            stdin.write_all(block)?;
         }
 
         Ok(())
     }
 }
+
+/// Send fixed size blocks of `stdout` to the workers, and return the
+/// number of bytes read
+fn read_blocks(
+    stdout: &mut impl Read,
+    sender: &mpsc::Sender<(u64, Vec<u8>)>,
+) -> Result<u64, SnapshotError> {
+    let mut offset = 0;
+
+    loop {
+        let mut block = Vec::with_capacity(STREAM_CHUNK_SIZE);
+        stdout
+            .by_ref()
+            .take(STREAM_CHUNK_SIZE as u64)
+            .read_to_end(&mut block)?;
+
+        if block.is_empty() {
+            return Ok(offset);
+        }
+
+        let len = block.len() as u64;
+        if sender.send((offset, block)).is_err() {
+            // all workers exited, the error is reported by them
+            return Ok(offset);
+        }
+
+        offset += len;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ZfsSnapshot, STREAM_CHUNK_SIZE};
+    use crate::Files;
+    use infinitree::{crypto::UsernamePassword, object::Pool, Infinitree};
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn stream_larger_than_an_object_roundtrips() {
+        let stash = Infinitree::<Files>::empty(
+            infinitree::backends::test::InMemoryBackend::shared(),
+            UsernamePassword::with_credentials("zfs".to_string(), "password".to_string()).unwrap(),
+        )
+        .unwrap();
+
+        let data = (0..STREAM_CHUNK_SIZE * 3)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let writer = Pool::new(
+            NonZeroUsize::new(4).unwrap(),
+            stash.storage_writer().unwrap(),
+        )
+        .unwrap();
+        let snapshot =
+            ZfsSnapshot::from_stdout(writer, stash.hasher().unwrap(), &mut data.as_slice(), 4)
+                .unwrap();
+        assert_eq!(snapshot.size, data.len() as u64);
+
+        let mut restored = vec![];
+        snapshot
+            .to_stdin(stash.storage_reader().unwrap(), &mut restored)
+            .unwrap();
+
+        assert_eq!(data, restored);
+    }
+}
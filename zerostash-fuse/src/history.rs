@@ -0,0 +1,222 @@
+//! Every commit of a stash side by side
+//!
+//! The root of the mount holds a read-only directory for each commit,
+//! named after the time it was made, similar to `.zfs/snapshot`. The
+//! index of a commit is only loaded the first time its directory is
+//! visited.
+
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use chrono::{DateTime, Local};
+use fuse_mt::*;
+use infinitree::{
+    tree::{CommitFilter, CommitId},
+    Infinitree,
+};
+use nix::libc;
+use tokio::runtime::Handle;
+use tracing::{debug, warn};
+use zerostash_files::Files;
+
+use crate::mount::{ZerostashFs, DIR_ATTR, TTL};
+
+type Opener = Box<dyn FnMut() -> anyhow::Result<Infinitree<Files>> + Send>;
+
+pub async fn mount(
+    open: impl FnMut() -> anyhow::Result<Infinitree<Files>> + Send + 'static,
+    mountpoint: &str,
+    threads: usize,
+    on_ready: impl FnOnce(),
+) -> anyhow::Result<()> {
+    let fs = fuse_mt::FuseMT::new(HistoryFs::new(Box::new(open), threads)?, 1);
+
+    let handle = spawn_mount(
+        fs,
+        mountpoint,
+        &[
+            OsStr::new("ro"),
+            OsStr::new("nodev"),
+            OsStr::new("nosuid"),
+            OsStr::new("noatime"),
+            OsStr::new("fsname=zerostash-history"),
+        ],
+    )?;
+    on_ready();
+
+    tokio::signal::ctrl_c().await?;
+    handle.join();
+
+    Ok(())
+}
+
+struct Commit {
+    name: OsString,
+    id: CommitId,
+    /// Loaded on first use. `None` if the index can't be loaded.
+    view: OnceLock<Option<ZerostashFs>>,
+}
+
+pub struct HistoryFs {
+    commits: Vec<Commit>,
+    open: Mutex<Opener>,
+    threads: usize,
+    runtime: Handle,
+}
+
+impl HistoryFs {
+    fn new(mut open: Opener, threads: usize) -> anyhow::Result<Self> {
+        let stash = open()?;
+
+        let mut names = HashSet::new();
+        let commits = stash
+            .commit_list()
+            .iter()
+            .map(|commit| {
+                let time: DateTime<Local> = commit.metadata.time.into();
+                let mut name = time.format("%Y-%m-%dT%H:%M:%S").to_string();
+
+                // commits made within the same second
+                let mut n = 1;
+                while !names.insert(name.clone()) {
+                    name = format!("{}.{n}", time.format("%Y-%m-%dT%H:%M:%S"));
+                    n += 1;
+                }
+
+                Commit {
+                    name: name.into(),
+                    id: commit.id,
+                    view: OnceLock::new(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            commits,
+            open: Mutex::new(open),
+            threads,
+            runtime: Handle::current(),
+        })
+    }
+
+    /// The view of the commit `path` is in, and the path within it
+    fn resolve(&self, path: &Path) -> Result<(&ZerostashFs, PathBuf), libc::c_int> {
+        let mut components = path.strip_prefix("/").unwrap_or(path).components();
+        let Some(Component::Normal(name)) = components.next() else {
+            return Err(libc::ENOENT);
+        };
+        let Some(commit) = self.commits.iter().find(|c| c.name == name) else {
+            return Err(libc::ENOENT);
+        };
+
+        let view = commit.view.get_or_init(|| self.load(commit.id));
+        let Some(view) = view else {
+            return Err(libc::EIO);
+        };
+
+        Ok((view, Path::new("/").join(components.as_path())))
+    }
+
+    fn load(&self, id: CommitId) -> Option<ZerostashFs> {
+        debug!(?id, "loading commit");
+        let _runtime = self.runtime.enter();
+
+        let stash = match (*self.open.lock().unwrap())() {
+            Ok(stash) => stash,
+            Err(error) => {
+                warn!(%error, ?id, "failed to open the stash");
+                return None;
+            }
+        };
+        stash.filter_commits(CommitFilter::UpTo(id));
+
+        match ZerostashFs::open(Arc::new(stash), self.threads, false) {
+            Ok(view) => Some(view),
+            Err(error) => {
+                warn!(%error, ?id, "failed to load the commit");
+                None
+            }
+        }
+    }
+
+    fn is_root(path: &Path) -> bool {
+        path.components().all(|c| c == Component::RootDir)
+    }
+}
+
+impl FilesystemMT for HistoryFs {
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        Ok(())
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
+        if Self::is_root(path) {
+            return Ok((TTL, DIR_ATTR));
+        }
+
+        let (view, path) = self.resolve(path)?;
+        view.getattr(req, &path, fh)
+    }
+
+    fn readlink(&self, req: RequestInfo, path: &Path) -> ResultData {
+        let (view, path) = self.resolve(path)?;
+        view.readlink(req, &path)
+    }
+
+    fn opendir(&self, _req: RequestInfo, _path: &Path, flags: u32) -> ResultOpen {
+        Ok((0, flags))
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
+        if Self::is_root(path) {
+            return Ok(self
+                .commits
+                .iter()
+                .map(|commit| DirectoryEntry {
+                    name: commit.name.clone(),
+                    kind: fuse_mt::FileType::Directory,
+                })
+                .collect());
+        }
+
+        let (view, path) = self.resolve(path)?;
+        view.readdir(req, &path, fh)
+    }
+
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        let (view, path) = self.resolve(path)?;
+        view.open(req, &path, flags)
+    }
+
+    fn release(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> ResultEmpty {
+        let (view, path) = self.resolve(path)?;
+        view.release(req, &path, fh, flags, lock_owner, flush)
+    }
+
+    fn read(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
+    ) -> CallbackResult {
+        match self.resolve(path) {
+            Ok((view, path)) => view.read(req, &path, fh, offset, size, callback),
+            Err(error) => callback(Err(error)),
+        }
+    }
+}
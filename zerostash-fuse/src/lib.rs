@@ -1,6 +1,9 @@
 #![deny(unused_crate_dependencies)]
 pub mod chunks;
+pub mod history;
 pub mod mount;
+pub mod overlay;
+mod prefetch;
 
 #[cfg(test)]
 use criterion as _;
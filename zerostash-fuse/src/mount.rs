@@ -18,11 +18,12 @@ use infinitree::{
 use nix::libc;
 use scc::ebr::{AtomicShared, Guard, Shared, Tag};
 use tokio::{runtime::Handle, task::JoinHandle};
-use tracing::debug;
+use tracing::{debug, warn};
 use zerostash_files::{Entry, FileType, Files, Node};
 
 use crate::chunks::ChunkStack;
 use crate::chunks::ChunkStackCache;
+use crate::prefetch::Prefetcher;
 
 const MAX_BUFFER_SIZE: usize = infinitree::BLOCK_SIZE;
 use zerostash_files::rollsum::CHUNK_SIZE_LIMIT;
@@ -32,6 +33,7 @@ pub async fn mount(
     mountpoint: &str,
     threads: usize,
     read_write: bool,
+    on_ready: impl FnOnce(),
 ) -> anyhow::Result<()> {
     let stash = Arc::new(stash);
 
@@ -45,7 +47,7 @@ pub async fn mount(
 
     let mount_type = if read_write { "rw" } else { "ro" };
 
-    let filesystem = ZerostashFs::open(stash, threads, read_write).unwrap();
+    let filesystem = ZerostashFs::open(stash, threads, read_write)?;
     let fs = fuse_mt::FuseMT::new(filesystem, 1);
 
     // Mount the filesystem.
@@ -60,6 +62,7 @@ pub async fn mount(
             OsStr::new("fsname=zerostash"),
         ],
     )?;
+    on_ready();
 
     // Wait until we are done.
     tokio::signal::ctrl_c().await?;
@@ -84,10 +87,11 @@ async fn auto_commit(stash: Arc<Infinitree<Files>>) {
 
 pub struct ZerostashFs {
     commit_timestamp: SystemTime,
-    stash: Arc<Infinitree<Files>>,
+    pub(crate) stash: Arc<Infinitree<Files>>,
     writer: Option<Pool<AEADWriter>>,
     chunks_cache: scc::HashMap<PathBuf, ChunkStackCache>,
     open_handles: scc::HashMap<u64, OpenFileHandle>,
+    prefetch: Prefetcher,
     runtime: Handle,
 }
 
@@ -360,11 +364,14 @@ impl IngestChanges {
 
 impl ZerostashFs {
     pub fn open(stash: Arc<Infinitree<Files>>, threads: usize, read_write: bool) -> Result<Self> {
-        stash.load(stash.index().tree()).unwrap();
+        // older stashes may not have a tree, which the caller rebuilds
+        if let Err(error) = stash.load(stash.index().tree()) {
+            warn!(%error, "failed to load the tree");
+        }
 
         let commit_timestamp = match stash.commit_list().last() {
             Some(last) => last.metadata.time,
-            None => panic!("stash is empty"),
+            None => return Err(std::io::Error::other("the stash is empty")),
         };
 
         let writer = if read_write {
@@ -381,6 +388,7 @@ impl ZerostashFs {
 
         Ok(ZerostashFs {
             commit_timestamp,
+            prefetch: Prefetcher::new(stash.clone(), Handle::current()),
             stash,
             writer,
             open_handles: scc::HashMap::new(),
@@ -437,6 +445,26 @@ impl FilesystemMT for ZerostashFs {
         }
     }
 
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        debug!("readlink: {:?}", path);
+
+        let path_str = path.to_str().unwrap();
+        let entry = {
+            let index = self.stash.index();
+            let tree = &index.tree;
+            tree.file(path_str)
+        };
+
+        let Ok(Some(entry)) = entry else {
+            return Err(libc::ENOENT);
+        };
+
+        match &entry.file_type {
+            FileType::Symlink(target) => Ok(target.as_os_str().as_encoded_bytes().to_vec()),
+            _ => Err(libc::EINVAL),
+        }
+    }
+
     fn opendir(&self, _req: RequestInfo, _path: &Path, flags: u32) -> ResultOpen {
         debug!("opendir");
         Ok((0, flags))
@@ -533,10 +561,9 @@ impl FilesystemMT for ZerostashFs {
         let mut current = entries.first_entry();
         while let Some(entry) = current {
             if let Some(node) = index.tree.node_by_ref(entry.get()) {
-                let kind = if node.is_dir() {
-                    fuse_mt::FileType::Directory
-                } else {
-                    fuse_mt::FileType::RegularFile
+                let kind = match node.as_ref() {
+                    Node::File { entry, .. } => match_filetype(entry.file_type.clone()),
+                    Node::Directory { .. } => fuse_mt::FileType::Directory,
                 };
                 let directory_entry = DirectoryEntry {
                     name: entry.key().clone().into(),
@@ -580,6 +607,10 @@ impl FilesystemMT for ZerostashFs {
             return callback(Err(libc::EINVAL));
         }
 
+        if offset == 0 {
+            self.prefetch.read_from_start(path);
+        }
+
         let size = size as usize;
         let sort_chunks = || entry.chunks.clone().into_iter().collect::<Vec<_>>();
         let mut obj_reader = self.stash.storage_reader().unwrap();
@@ -737,7 +768,7 @@ impl FilesystemMT for ZerostashFs {
         let mut writer = self.writer.as_ref().unwrap().clone();
         chunks.insert(
             last_chunk_start,
-            index.chunks.insert_with(hash, move || {
+            index.insert_chunk(hash, move || {
                 writer.write_chunk(&hash, &truncated_chunk).unwrap()
             }),
         );
@@ -853,6 +884,13 @@ impl FilesystemMT for ZerostashFs {
             size: 0,
             name,
             chunks: Default::default(),
+            xattrs: Default::default(),
+            bsd_flags: None,
+            created: None,
+            windows_attributes: None,
+            security_descriptor: None,
+            inode: None,
+            ctime: None,
         });
 
         let attr = file_to_fuse(&entry, SystemTime::now());
@@ -944,9 +982,9 @@ impl FilesystemMT for ZerostashFs {
     }
 }
 
-const TTL: Duration = Duration::from_secs(1);
+pub(crate) const TTL: Duration = Duration::from_secs(1);
 
-const DIR_ATTR: FileAttr = FileAttr {
+pub(crate) const DIR_ATTR: FileAttr = FileAttr {
     size: 0,
     blocks: 0,
     atime: SystemTime::UNIX_EPOCH,
@@ -982,19 +1020,26 @@ fn file_to_fuse(file: &Entry, atime: SystemTime) -> FileAttr {
         uid: file
             .unix_uid
             .unwrap_or_else(|| nix::unistd::getuid().into()),
-        rdev: 0,
+        rdev: match file.file_type {
+            FileType::CharDevice(dev) | FileType::BlockDevice(dev) => dev as u32,
+            _ => 0,
+        },
         flags: 0,
     }
 }
 
-fn strip_path(path: &Path) -> &Path {
+pub(crate) fn strip_path(path: &Path) -> &Path {
     path.strip_prefix("/").unwrap()
 }
 
 fn match_filetype(file_type: FileType) -> fuse_mt::FileType {
     match file_type {
-        FileType::File => fuse_mt::FileType::RegularFile,
+        FileType::File | FileType::Hardlink(_) => fuse_mt::FileType::RegularFile,
         FileType::Symlink(_) => fuse_mt::FileType::Symlink,
+        FileType::Fifo => fuse_mt::FileType::NamedPipe,
+        FileType::Socket => fuse_mt::FileType::Socket,
+        FileType::CharDevice(_) => fuse_mt::FileType::CharDevice,
+        FileType::BlockDevice(_) => fuse_mt::FileType::BlockDevice,
         FileType::Directory => panic!("Must be a file!"),
     }
 }
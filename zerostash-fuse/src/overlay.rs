@@ -0,0 +1,570 @@
+//! Union mount of a stash and a local directory
+//!
+//! Reads fall through to the stash, while every change lands in the
+//! `upper` directory. Files deleted from the stash are recorded as
+//! whiteouts, so the changes can be folded back into a new commit.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use fuse_mt::*;
+use infinitree::{object::Reader, Infinitree};
+use nix::libc;
+use tracing::debug;
+use zerostash_files::{
+    store::{OPAQUE_MARKER, WHITEOUT_PREFIX},
+    Files, Node,
+};
+
+use crate::mount::{strip_path, ZerostashFs, DIR_ATTR, TTL};
+
+pub async fn mount(
+    stash: Infinitree<Files>,
+    mountpoint: &str,
+    upper: PathBuf,
+    threads: usize,
+    on_ready: impl FnOnce(),
+) -> anyhow::Result<()> {
+    fs::create_dir_all(&upper)?;
+
+    let lower = ZerostashFs::open(Arc::new(stash), threads, false)?;
+    let fs = fuse_mt::FuseMT::new(OverlayFs::new(lower, upper), 1);
+
+    let handle = spawn_mount(
+        fs,
+        mountpoint,
+        &[
+            OsStr::new("rw"),
+            OsStr::new("nodev"),
+            OsStr::new("nosuid"),
+            OsStr::new("noatime"),
+            OsStr::new("fsname=zerostash-overlay"),
+        ],
+    )?;
+    on_ready();
+
+    tokio::signal::ctrl_c().await?;
+    handle.join();
+
+    Ok(())
+}
+
+enum Handle {
+    Upper(fs::File),
+    Lower(u64),
+}
+
+pub struct OverlayFs {
+    lower: ZerostashFs,
+    upper: PathBuf,
+    handles: scc::HashMap<u64, Handle>,
+}
+
+impl OverlayFs {
+    pub fn new(lower: ZerostashFs, upper: PathBuf) -> Self {
+        Self {
+            lower,
+            upper,
+            handles: scc::HashMap::new(),
+        }
+    }
+
+    fn upper_path(&self, path: &Path) -> PathBuf {
+        self.upper.join(strip_path(path))
+    }
+
+    fn whiteout_path(&self, path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.upper_path(path.parent().unwrap_or(Path::new("/")))
+            .join(format!("{WHITEOUT_PREFIX}{name}"))
+    }
+
+    /// The stash version of `path` is masked by a whiteout or an opaque
+    /// directory
+    fn hidden(&self, path: &Path) -> bool {
+        let mut dir = self.upper.clone();
+
+        for component in strip_path(path).components() {
+            let name = component.as_os_str().to_string_lossy();
+            if dir.join(OPAQUE_MARKER).exists()
+                || dir.join(format!("{WHITEOUT_PREFIX}{name}")).exists()
+            {
+                return true;
+            }
+            dir.push(component);
+        }
+
+        false
+    }
+
+    fn in_upper(&self, path: &Path) -> bool {
+        fs::symlink_metadata(self.upper_path(path)).is_ok()
+    }
+
+    fn lower_node(&self, path: &Path) -> Option<Arc<Node>> {
+        if self.hidden(path) {
+            return None;
+        }
+
+        let path_str = strip_path(path).to_str()?;
+        self.lower.stash.index().tree.node_by_path(path_str).ok()?
+    }
+
+    fn handle(&self, handle: Handle) -> u64 {
+        let mut fh = rand::random();
+        while self.handles.contains(&fh) {
+            fh = rand::random();
+        }
+
+        _ = self.handles.insert(fh, handle);
+        fh
+    }
+
+    /// Make sure the parent directories of `path` exist in `upper`
+    fn create_parents(&self, path: &Path) -> std::io::Result<()> {
+        match self.upper_path(path).parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+    }
+
+    /// Copy a file from the stash to `upper` so it can be modified
+    fn copy_up(&self, path: &Path) -> Result<(), libc::c_int> {
+        if self.in_upper(path) {
+            return Ok(());
+        }
+
+        let node = self.lower_node(path).ok_or(libc::ENOENT)?;
+        self.create_parents(path).map_err(io_error)?;
+
+        let target = self.upper_path(path);
+        let entry = match node.as_ref() {
+            Node::Directory { .. } => return fs::create_dir(&target).map_err(io_error),
+            Node::File { entry, .. } => entry.clone(),
+        };
+
+        debug!(?path, size = entry.size, "copy up");
+
+        let mut buf = vec![0; entry.size as usize];
+        let mut reader = self.lower.stash.storage_reader().map_err(|_| libc::EIO)?;
+        for (start, chunk) in entry.chunks.iter() {
+            reader
+                .read_chunk(chunk, &mut buf[*start as usize..])
+                .map_err(|_| libc::EIO)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(entry.unix_perm.unwrap_or(0o644))
+            .open(&target)
+            .map_err(io_error)?;
+        file.write_all(&buf).map_err(io_error)?;
+
+        Ok(())
+    }
+
+    fn remove_whiteout(&self, path: &Path) -> bool {
+        fs::remove_file(self.whiteout_path(path)).is_ok()
+    }
+
+    fn whiteout(&self, path: &Path) -> Result<(), libc::c_int> {
+        self.create_parents(path).map_err(io_error)?;
+        fs::File::create(self.whiteout_path(path)).map_err(io_error)?;
+        Ok(())
+    }
+
+    fn merged_dir(&self, req: RequestInfo, path: &Path) -> BTreeMap<OsString, FileType> {
+        let mut entries = BTreeMap::new();
+
+        if path == Path::new("/") || self.lower_node(path).is_some() {
+            if let Ok(lower) = self.lower.readdir(req, path, 0) {
+                entries.extend(lower.into_iter().map(|e| (e.name, e.kind)));
+            }
+        }
+
+        let Ok(upper) = fs::read_dir(self.upper_path(path)) else {
+            return entries;
+        };
+
+        let mut upper = upper.flatten().collect::<Vec<_>>();
+        // apply whiteouts before adding the new entries
+        upper.sort_by_key(|e| !e.file_name().to_string_lossy().starts_with(WHITEOUT_PREFIX));
+
+        for entry in upper {
+            let name = entry.file_name();
+            let name_str = name.to_string_lossy();
+
+            if name_str == OPAQUE_MARKER {
+                entries.clear();
+            } else if let Some(deleted) = name_str.strip_prefix(WHITEOUT_PREFIX) {
+                entries.remove(OsStr::new(deleted));
+            } else if let Ok(md) = entry.metadata() {
+                entries.insert(name, metadata_to_fuse(&md).kind);
+            }
+        }
+
+        entries
+    }
+}
+
+impl FilesystemMT for OverlayFs {
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        Ok(())
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+        debug!("getattr = {:?}", path);
+
+        if let Ok(md) = fs::symlink_metadata(self.upper_path(path)) {
+            return Ok((TTL, metadata_to_fuse(&md)));
+        }
+
+        if self.hidden(path) {
+            return Err(libc::ENOENT);
+        }
+
+        self.lower.getattr(req, path, None)
+    }
+
+    fn readlink(&self, req: RequestInfo, path: &Path) -> ResultData {
+        debug!("readlink: {:?}", path);
+
+        if self.in_upper(path) {
+            let target = fs::read_link(self.upper_path(path)).map_err(io_error)?;
+            return Ok(target.into_os_string().into_encoded_bytes());
+        }
+
+        if self.hidden(path) {
+            return Err(libc::ENOENT);
+        }
+
+        self.lower.readlink(req, path)
+    }
+
+    fn opendir(&self, _req: RequestInfo, _path: &Path, flags: u32) -> ResultOpen {
+        Ok((0, flags))
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+        debug!("readdir: {:?}", path);
+
+        if !self.in_upper(path) && self.lower_node(path).is_none() && path != Path::new("/") {
+            return Err(libc::ENOENT);
+        }
+
+        Ok(self
+            .merged_dir(req, path)
+            .into_iter()
+            .map(|(name, kind)| DirectoryEntry { name, kind })
+            .collect())
+    }
+
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        debug!("open: {:?}", path);
+
+        let writable = flags & (libc::O_RDWR | libc::O_WRONLY) as u32 > 0;
+        if writable {
+            self.copy_up(path)?;
+        }
+
+        if self.in_upper(path) {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .open(self.upper_path(path))
+                .map_err(io_error)?;
+
+            return Ok((self.handle(Handle::Upper(file)), flags));
+        }
+
+        if self.hidden(path) {
+            return Err(libc::ENOENT);
+        }
+
+        let (fh, flags) = self.lower.open(req, path, flags)?;
+        Ok((self.handle(Handle::Lower(fh)), flags))
+    }
+
+    fn release(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> ResultEmpty {
+        match self.handles.remove(&fh) {
+            Some((_, Handle::Lower(fh))) => {
+                self.lower.release(req, path, fh, flags, lock_owner, flush)
+            }
+            Some((_, Handle::Upper(_))) => Ok(()),
+            None => Err(libc::EBADF),
+        }
+    }
+
+    fn read(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
+    ) -> CallbackResult {
+        let lower_fh = {
+            let Some(handle) = self.handles.get(&fh) else {
+                return callback(Err(libc::EBADF));
+            };
+
+            match handle.get() {
+                Handle::Lower(fh) => *fh,
+                Handle::Upper(file) => {
+                    let mut buf = vec![0; size as usize];
+                    return match file.read_at(&mut buf, offset) {
+                        Ok(read) => callback(Ok(&buf[..read])),
+                        Err(e) => callback(Err(io_error(e))),
+                    };
+                }
+            }
+        };
+
+        self.lower.read(req, path, lower_fh, offset, size, callback)
+    }
+
+    fn write(
+        &self,
+        _req: RequestInfo,
+        _path: &Path,
+        fh: u64,
+        offset: u64,
+        data: Vec<u8>,
+        _flags: u32,
+    ) -> ResultWrite {
+        let Some(handle) = self.handles.get(&fh) else {
+            return Err(libc::EBADF);
+        };
+
+        let Handle::Upper(file) = handle.get() else {
+            return Err(libc::EBADF);
+        };
+
+        file.write_all_at(&data, offset).map_err(io_error)?;
+        data.len().try_into().map_err(|_| libc::EINVAL)
+    }
+
+    fn fsync(&self, _req: RequestInfo, _path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
+        let Some(handle) = self.handles.get(&fh) else {
+            return Err(libc::EBADF);
+        };
+
+        match handle.get() {
+            Handle::Upper(file) if datasync => file.sync_data().map_err(io_error),
+            Handle::Upper(file) => file.sync_all().map_err(io_error),
+            Handle::Lower(_) => Ok(()),
+        }
+    }
+
+    fn truncate(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, size: u64) -> ResultEmpty {
+        debug!("truncate {:?}: size {}", path, size);
+
+        self.copy_up(path)?;
+        OpenOptions::new()
+            .write(true)
+            .open(self.upper_path(path))
+            .and_then(|f| f.set_len(size))
+            .map_err(io_error)
+    }
+
+    fn create(
+        &self,
+        _req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> ResultCreate {
+        debug!("create {:?}/{:?}", parent, name);
+
+        let path = parent.join(name);
+        self.create_parents(&path).map_err(io_error)?;
+        self.remove_whiteout(&path);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(self.upper_path(&path))
+            .map_err(io_error)?;
+        let attr = metadata_to_fuse(&file.metadata().map_err(io_error)?);
+
+        Ok(CreatedEntry {
+            ttl: TTL,
+            attr,
+            fh: self.handle(Handle::Upper(file)),
+            flags,
+        })
+    }
+
+    fn mkdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32) -> ResultEntry {
+        debug!("mkdir: {:?}/{:?}", parent, name);
+
+        let path = parent.join(name);
+        self.create_parents(&path).map_err(io_error)?;
+
+        let target = self.upper_path(&path);
+        fs::DirBuilder::new()
+            .mode(mode)
+            .create(&target)
+            .map_err(io_error)?;
+
+        // a directory deleted from the stash must not show its old contents
+        if self.remove_whiteout(&path) {
+            fs::File::create(target.join(OPAQUE_MARKER)).map_err(io_error)?;
+        }
+
+        Ok((TTL, DIR_ATTR))
+    }
+
+    fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        debug!("unlink: {:?}/{:?}", parent, name);
+
+        let path = parent.join(name);
+        let in_lower = self.lower_node(&path).is_some();
+
+        if self.in_upper(&path) {
+            fs::remove_file(self.upper_path(&path)).map_err(io_error)?;
+        } else if !in_lower {
+            return Err(libc::ENOENT);
+        }
+
+        if in_lower {
+            self.whiteout(&path)?;
+        }
+
+        Ok(())
+    }
+
+    fn rmdir(&self, req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        debug!("rmdir: {:?}/{:?}", parent, name);
+
+        let path = parent.join(name);
+        if !self.merged_dir(req, &path).is_empty() {
+            return Err(libc::ENOTEMPTY);
+        }
+
+        let in_lower = self.lower_node(&path).is_some();
+        if self.in_upper(&path) {
+            // only whiteouts are left in the directory
+            fs::remove_dir_all(self.upper_path(&path)).map_err(io_error)?;
+        } else if !in_lower {
+            return Err(libc::ENOENT);
+        }
+
+        if in_lower {
+            self.whiteout(&path)?;
+        }
+
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        _req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        newparent: &Path,
+        newname: &OsStr,
+    ) -> ResultEmpty {
+        debug!(
+            "rename: {:?}/{:?} -> {:?}/{:?}",
+            parent, name, newparent, newname
+        );
+
+        let path = parent.join(name);
+        let new_path = newparent.join(newname);
+        let lower = self.lower_node(&path);
+
+        // same as overlayfs: let userspace fall back to copy & delete
+        if lower.as_ref().is_some_and(|n| n.is_dir()) {
+            return Err(libc::EXDEV);
+        }
+
+        self.copy_up(&path)?;
+        self.create_parents(&new_path).map_err(io_error)?;
+        fs::rename(self.upper_path(&path), self.upper_path(&new_path)).map_err(io_error)?;
+        self.remove_whiteout(&new_path);
+
+        if lower.is_some() {
+            self.whiteout(&path)?;
+        }
+
+        Ok(())
+    }
+
+    fn chmod(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, mode: u32) -> ResultEmpty {
+        debug!("chmod: {:?} {:#o}", path, mode);
+
+        self.copy_up(path)?;
+        fs::set_permissions(self.upper_path(path), fs::Permissions::from_mode(mode))
+            .map_err(io_error)
+    }
+
+    fn chown(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        _fh: Option<u64>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> ResultEmpty {
+        debug!("chown {:?} to {:?}:{:?}", path, uid, gid);
+
+        self.copy_up(path)?;
+        std::os::unix::fs::chown(self.upper_path(path), uid, gid).map_err(io_error)
+    }
+}
+
+fn io_error(error: std::io::Error) -> libc::c_int {
+    error.raw_os_error().unwrap_or(libc::EIO)
+}
+
+fn metadata_to_fuse(md: &fs::Metadata) -> FileAttr {
+    let time = |secs: i64, nanos: i64| {
+        UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64) + Duration::from_nanos(nanos as u64)
+    };
+
+    let kind = if md.is_dir() {
+        fuse_mt::FileType::Directory
+    } else if md.is_symlink() {
+        fuse_mt::FileType::Symlink
+    } else {
+        fuse_mt::FileType::RegularFile
+    };
+
+    FileAttr {
+        size: md.size(),
+        blocks: md.blocks(),
+        atime: time(md.atime(), md.atime_nsec()),
+        mtime: time(md.mtime(), md.mtime_nsec()),
+        ctime: time(md.ctime(), md.ctime_nsec()),
+        crtime: UNIX_EPOCH,
+        kind,
+        perm: (md.mode() & 0o7777) as u16,
+        nlink: md.nlink() as u32,
+        uid: md.uid(),
+        gid: md.gid(),
+        rdev: md.rdev() as u32,
+        flags: 0,
+    }
+}
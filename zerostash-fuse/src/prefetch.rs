@@ -0,0 +1,114 @@
+//! Prefetch the rest of a directory that's being copied out of the
+//! mount.
+//!
+//! File managers copy directories one file at a time, so every file
+//! pays for the round-trips to the storage. Once a few sibling files
+//! are read from the start in a row, the objects of the remaining
+//! files in the directory are preloaded in the background.
+
+use infinitree::{object::ObjectId, Infinitree};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::runtime::Handle;
+use tracing::debug;
+use zerostash_files::{Files, Node};
+
+/// Number of sibling files read in a row that starts a prefetch
+const SEQUENTIAL_FILES: usize = 2;
+
+/// Upper bound on the objects preloaded for a single directory
+const MAX_OBJECTS: usize = 256;
+
+#[derive(Default)]
+struct Streak {
+    dir: PathBuf,
+    read: HashSet<PathBuf>,
+}
+
+pub(crate) struct Prefetcher {
+    stash: Arc<Infinitree<Files>>,
+    streak: Mutex<Streak>,
+    prefetched: Mutex<HashSet<PathBuf>>,
+    runtime: Handle,
+}
+
+impl Prefetcher {
+    pub(crate) fn new(stash: Arc<Infinitree<Files>>, runtime: Handle) -> Self {
+        Self {
+            stash,
+            streak: Mutex::default(),
+            prefetched: Mutex::default(),
+            runtime,
+        }
+    }
+
+    /// Record a read from the start of the file at `path`
+    pub(crate) fn read_from_start(&self, path: &Path) {
+        let Some(dir) = path.parent() else {
+            return;
+        };
+
+        let read = {
+            let mut streak = self.streak.lock().unwrap();
+            if streak.dir != dir {
+                streak.dir = dir.to_owned();
+                streak.read.clear();
+            }
+            streak.read.insert(path.to_owned());
+
+            if streak.read.len() < SEQUENTIAL_FILES {
+                return;
+            }
+            streak.read.clone()
+        };
+
+        if !self.prefetched.lock().unwrap().insert(dir.to_owned()) {
+            return;
+        }
+
+        let objects = self.remaining_objects(dir, &read);
+        if objects.is_empty() {
+            return;
+        }
+
+        debug!(?dir, objects = objects.len(), "prefetching directory");
+        let stash = self.stash.clone();
+        self.runtime.spawn_blocking(move || {
+            if let Err(error) = stash.backend().preload(&objects) {
+                debug!(?error, "prefetch failed");
+            }
+        });
+    }
+
+    fn remaining_objects(&self, dir: &Path, read: &HashSet<PathBuf>) -> Vec<ObjectId> {
+        let index = self.stash.index();
+        let Ok(Some(node)) = index.tree.node_by_path(dir.to_str().unwrap()) else {
+            return vec![];
+        };
+        let Node::Directory { entries } = node.as_ref() else {
+            return vec![];
+        };
+
+        let mut objects = HashSet::new();
+        let mut current = entries.first_entry();
+        while let Some(entry) = current {
+            if !read.contains(&dir.join(entry.key())) {
+                if let Some(node) = index.tree.node_by_ref(entry.get()) {
+                    if let Node::File { entry, .. } = node.as_ref() {
+                        objects.extend(entry.chunks.values().map(|cp| *cp.object_id()));
+                    }
+                }
+            }
+
+            if objects.len() >= MAX_OBJECTS {
+                break;
+            }
+            current = entry.next();
+        }
+
+        objects.into_iter().take(MAX_OBJECTS).collect()
+    }
+}
@@ -0,0 +1,147 @@
+//! APFS local snapshots
+//!
+//! Snapshots are taken with `tmutil`, and mounted read-only with
+//! `mount_apfs`, which needs root and Full Disk Access.
+
+use anyhow::{bail, Context};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+use tracing::{info, warn};
+use zerostash_files::store::Snapshot;
+
+/// Mounted local snapshots that are unmounted and deleted when dropped
+#[derive(Debug, Default)]
+pub struct LocalSnapshot {
+    date: String,
+    mounts: Vec<PathBuf>,
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl LocalSnapshot {
+    /// Snapshot the volumes that hold `paths`, which need to be absolute,
+    /// and mount them.
+    ///
+    /// Each path is read from the snapshot of its volume. Volumes mounted
+    /// below a path aren't in the snapshot, and show up as empty
+    /// directories.
+    pub fn create(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let output = run(Command::new("tmutil").arg("localsnapshot"))
+            .context("Failed to create a local snapshot")?;
+
+        // prints `Created local snapshot with date: 2024-01-01-123456`
+        let Some(date) = output
+            .lines()
+            .find_map(|l| l.split_once("date:"))
+            .map(|(_, date)| date.trim().to_string())
+        else {
+            bail!("Unexpected output when creating a local snapshot: {output}");
+        };
+        info!(%date, "local snapshot created");
+
+        // clean up from here on, even if mounting fails
+        let mut snapshot = Self {
+            date,
+            ..Default::default()
+        };
+
+        let mut mounted = BTreeMap::new();
+        for path in paths {
+            let (device, volume) = volume_of(path)?;
+
+            let target = match mounted.get(&volume) {
+                Some(target) => PathBuf::clone(target),
+                None => {
+                    let target = snapshot.mount(&device, &volume)?;
+                    mounted.insert(volume.clone(), target.clone());
+                    target
+                }
+            };
+
+            // the paths of the data volume are firmlinked from `/`
+            let relative = path
+                .strip_prefix(&volume)
+                .or_else(|_| path.strip_prefix("/"))
+                .unwrap_or(path);
+
+            snapshot.snapshots.push(Snapshot {
+                original: path.clone(),
+                mounted: target.join(relative),
+            });
+        }
+
+        Ok(snapshot)
+    }
+
+    fn mount(&mut self, device: &str, volume: &Path) -> anyhow::Result<PathBuf> {
+        let name = format!("com.apple.TimeMachine.{}.local", self.date);
+        let target = std::env::temp_dir().join(format!("0s-snapshot-{}", rand::random::<u64>()));
+        std::fs::create_dir(&target)
+            .with_context(|| format!("Failed to create {}", target.display()))?;
+
+        let mount = Command::new("mount_apfs")
+            .args(["-o", "rdonly,nobrowse", "-s", name.as_str(), device])
+            .arg(&target)
+            .output();
+        if let Err(error) = check(mount) {
+            _ = std::fs::remove_dir(&target);
+            return Err(error)
+                .with_context(|| format!("Failed to mount the snapshot of {}", volume.display()));
+        }
+
+        info!(volume = %volume.display(), target = %target.display(), "snapshot mounted");
+        self.mounts.push(target.clone());
+        Ok(target)
+    }
+}
+
+impl Drop for LocalSnapshot {
+    fn drop(&mut self) {
+        for target in self.mounts.iter() {
+            match run(Command::new("umount").arg(target)) {
+                Ok(_) => _ = std::fs::remove_dir(target),
+                Err(error) => {
+                    warn!(target = %target.display(), "failed to unmount snapshot: {error:#}")
+                }
+            }
+        }
+
+        if let Err(error) =
+            run(Command::new("tmutil").args(["deletelocalsnapshots", self.date.as_str()]))
+        {
+            warn!(date = %self.date, "failed to delete local snapshot: {error:#}");
+        }
+    }
+}
+
+/// The device and mount point of the volume holding `path`
+fn volume_of(path: &Path) -> anyhow::Result<(String, PathBuf)> {
+    let output = run(Command::new("df").arg("-P").arg(path))
+        .with_context(|| format!("Failed to find the volume of {}", path.display()))?;
+
+    // Filesystem 512-blocks Used Available Capacity Mounted on
+    let line = output.lines().nth(1).unwrap_or_default();
+    let device = line.split_whitespace().next();
+    let volume = line.find(" /").map(|i| line[i + 1..].trim_end());
+
+    match (device, volume) {
+        (Some(device), Some(volume)) => Ok((device.to_string(), PathBuf::from(volume))),
+        _ => bail!("Unexpected output from df: {output}"),
+    }
+}
+
+fn run(command: &mut Command) -> anyhow::Result<String> {
+    check(command.output())
+}
+
+fn check(output: std::io::Result<Output>) -> anyhow::Result<String> {
+    let output = output.context("Failed to run command")?;
+
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -1,14 +1,23 @@
 //! Zerostash Abscissa Application
 
-use crate::{commands::EntryPoint, prelude::*};
+use crate::{
+    commands::{EntryPoint, Output},
+    prelude::*,
+};
 use abscissa_core::{
     application::{self, AppCell},
     config::{self, CfgCell},
-    trace, Application, FrameworkError, StandardPaths,
+    terminal::component::Terminal,
+    trace::{self, Tracing},
+    Application, Component, FrameworkError, StandardPaths,
 };
 use abscissa_tokio::TokioComponent;
 use anyhow::Result;
-use std::num::NonZeroUsize;
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, RwLock},
+};
+use zerostash_files::backends::{Relocations, RequestLog, RetainedObjects, TransferStats};
 
 /// Application state
 pub static APP: AppCell<ZerostashApp> = AppCell::new();
@@ -21,6 +30,22 @@ pub struct ZerostashApp {
 
     /// Application state.
     state: application::State<Self>,
+
+    /// Traffic to and from all opened stashes
+    transfer_stats: Arc<TransferStats>,
+
+    /// Objects that deletes skipped because S3 Object Lock still
+    /// protects them
+    retained_objects: Arc<RetainedObjects>,
+
+    /// Objects of the opened stashes that were repacked into others
+    relocations: Arc<RwLock<Relocations>>,
+
+    /// Where to log backend requests, if anywhere
+    request_log: Option<Arc<RequestLog>>,
+
+    /// Format of the results commands print
+    output: Output,
 }
 
 /// Initialize a new application instance.
@@ -32,6 +57,11 @@ impl Default for ZerostashApp {
         Self {
             config: CfgCell::default(),
             state: application::State::default(),
+            transfer_stats: Arc::default(),
+            retained_objects: Arc::default(),
+            relocations: Arc::default(),
+            request_log: None,
+            output: Output::default(),
         }
     }
 }
@@ -56,12 +86,42 @@ impl Application for ZerostashApp {
         &self.state
     }
 
+    /// Register the components provided by the framework.
+    ///
+    /// If trace export is enabled, we install our own tracing
+    /// subscriber instead of the one provided by the framework.
+    fn framework_components(
+        &mut self,
+        command: &Self::Cmd,
+    ) -> Result<Vec<Box<dyn Component<Self>>>, FrameworkError> {
+        let terminal = Terminal::new(self.term_colors(command));
+
+        let Some(endpoint) = command.trace_endpoint() else {
+            let tracing = Tracing::new(self.tracing_config(command), self.term_colors(command))
+                .expect("tracing subsystem failed to initialize");
+            return Ok(vec![Box::new(terminal), Box::new(tracing)]);
+        };
+
+        crate::telemetry::init(&endpoint, log_level(command.verbose))
+            .expect("tracing subsystem failed to initialize");
+
+        Ok(vec![Box::new(terminal)])
+    }
+
     /// Register all components used by this application.
     ///
     /// If you would like to add additional components to your application
     /// beyond the default ones provided by the framework, this is the place
     /// to do so.
     fn register_components(&mut self, command: &Self::Cmd) -> Result<(), FrameworkError> {
+        crate::error::redact_panics();
+
+        self.output = command.output;
+        if let Some(path) = &command.debug_http {
+            self.request_log =
+                Some(RequestLog::create(path).expect("failed to create the request log"));
+        }
+
         let mut framework_components = self.framework_components(command)?;
         framework_components.push(Box::new(TokioComponent::new()?));
         let mut app_components = self.state.components_mut();
@@ -82,17 +142,39 @@ impl Application for ZerostashApp {
 
     /// Get tracing configuration from command-line options
     fn tracing_config(&self, command: &EntryPoint) -> trace::Config {
-        match command.verbose {
-            0 => "info",
-            1 => "debug",
-            _ => "trace",
-        }
-        .to_owned()
-        .into()
+        log_level(command.verbose).to_owned().into()
+    }
+}
+
+fn log_level(verbose: usize) -> &'static str {
+    match verbose {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
     }
 }
 
 impl ZerostashApp {
+    pub(crate) fn transfer_stats(&self) -> Arc<TransferStats> {
+        self.transfer_stats.clone()
+    }
+
+    pub(crate) fn retained_objects(&self) -> Arc<RetainedObjects> {
+        self.retained_objects.clone()
+    }
+
+    pub(crate) fn relocations(&self) -> Arc<RwLock<Relocations>> {
+        self.relocations.clone()
+    }
+
+    pub(crate) fn request_log(&self) -> Option<Arc<RequestLog>> {
+        self.request_log.clone()
+    }
+
+    pub(crate) fn output(&self) -> Output {
+        self.output
+    }
+
     pub(crate) fn get_worker_threads(&self) -> usize {
         use std::cmp;
         cmp::min(
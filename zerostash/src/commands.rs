@@ -1,15 +1,80 @@
 //! Zerostash Subcommands
 
+mod all;
+use all::*;
+mod keygen;
+pub(crate) use keygen::write_key_file;
+use keygen::*;
 mod keys;
 use keys::*;
+mod cat;
+use cat::*;
+mod check;
+use check::*;
 mod checkout;
 use checkout::*;
+mod copy;
+use copy::*;
 mod commit;
 use commit::*;
+mod diff;
+use diff::*;
+mod find;
+use find::*;
+mod forget;
+use forget::*;
+mod gc;
+use gc::*;
+mod import;
+use import::*;
+mod info;
+use info::*;
+mod init;
+use init::*;
+mod locks;
+use locks::*;
 mod log;
 use log::*;
 mod ls;
 use ls::*;
+mod overlay;
+use overlay::*;
+mod passwd;
+use passwd::*;
+mod prune;
+use prune::*;
+mod rebuild_filter;
+use rebuild_filter::*;
+#[cfg(unix)]
+mod objectd;
+#[cfg(unix)]
+use objectd::*;
+mod selftest;
+use selftest::*;
+mod stash;
+use stash::*;
+mod stats;
+use stats::*;
+mod stream;
+use stream::*;
+mod systemd;
+use systemd::*;
+mod tag;
+use tag::*;
+mod tag_path;
+use tag_path::*;
+mod token;
+use token::*;
+mod top;
+use top::*;
+mod undo;
+use undo::*;
+mod upgrade_format;
+use upgrade_format::*;
+mod user;
+use user::*;
+mod verify_restore;
+use verify_restore::*;
 mod wipe;
 use wipe::*;
 mod zfs;
@@ -20,10 +85,11 @@ pub mod mount;
 pub use mount::*;
 
 use crate::{
-    config::{Key, SymmetricKey, YubikeyCRConfig, YubikeyCRKey},
+    config::{Key, Role, SymmetricKey, YubikeyCRConfig, YubikeyCRKey},
     prelude::*,
 };
-use abscissa_core::{Command, Configurable, Runnable};
+use abscissa_core::{Command, Configurable, FrameworkError, Runnable};
+use anyhow::Context;
 use clap::{ArgGroup, Parser};
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -35,12 +101,49 @@ pub const CONFIG_FILE: &str = "zerostash.toml";
 /// Subcommands need to be listed in an enum.
 #[derive(Debug, Parser)]
 pub enum ZerostashCmd {
+    /// Run a subcommand on every configured stash, or a group of them
+    All(All),
+
+    /// Write the contents of a file in the stash to stdout
+    Cat(Cat),
+
+    /// Verify the integrity of a stash
+    Check(Check),
+
     /// Check out files
     Checkout(Checkout),
 
+    /// Copy commits from one stash to another
+    Copy(CopyStash),
+
     /// Add files to a stash
     Commit(Commit),
 
+    /// Show the files that changed between two commits
+    Diff(Diff),
+
+    /// Search for files by their path and metadata
+    Find(Find),
+
+    /// Drop the commits a retention policy doesn't keep
+    Forget(Forget),
+
+    /// Report the storage that pruning would reclaim
+    Gc(Gc),
+
+    /// Import an archive from stdin
+    Import(Import),
+
+    /// Show the settings recorded in a stash
+    Info(Info),
+
+    /// Create a new stash
+    Init(Init),
+
+    /// List or remove the locks of writers on the stash
+    #[clap(subcommand)]
+    Locks(Locks),
+
     /// List commits in the stash
     Log(Log),
 
@@ -51,9 +154,76 @@ pub enum ZerostashCmd {
     #[cfg(feature = "fuse")]
     Mount(Mount),
 
+    /// Generate a random key file for unattended access
+    Keygen(Keygen),
+
     /// Key management & generation
+    #[clap(alias = "key")]
     Keys(Keys),
 
+    /// Work with changes made through an overlay mount
+    #[clap(subcommand)]
+    Overlay(Overlay),
+
+    /// Change the username and password of a stash
+    Passwd(Passwd),
+
+    /// Delete the data that only forgotten commits use
+    Prune(Prune),
+
+    /// Rebuild the filter used to speed up chunk deduplication
+    RebuildFilter(RebuildFilter),
+
+    /// Share the backend of a stash with other processes over a unix socket
+    #[cfg(unix)]
+    Objectd(Objectd),
+
+    /// Test a backend by storing, restoring and checking generated files
+    Selftest(Selftest),
+
+    /// Manage the stashes in the config file
+    #[clap(subcommand)]
+    Stash(Stashes),
+
+    /// Show the space the stash uses
+    #[clap(alias = "du")]
+    Stats(Stats),
+
+    /// Store and restore data piped through stdin
+    #[clap(subcommand)]
+    Stream(Stream),
+
+    /// Generate systemd units for scheduled backups
+    #[clap(subcommand)]
+    Systemd(Systemd),
+
+    /// Name commits, to refer to them by a tag instead of their ID
+    #[clap(subcommand)]
+    Tag(Tag),
+
+    /// Add or remove tags on a path in the stash
+    TagPath(TagPath),
+
+    /// Manage read-only delegation tokens
+    #[clap(subcommand)]
+    Token(Token),
+
+    /// Show the live activity of a running commit
+    Top(Top),
+
+    /// Revert the last forget or zfs destroy
+    Undo(Undo),
+
+    /// Rewrite the index of a stash in the current format
+    UpgradeFormat(UpgradeFormat),
+
+    /// Manage the users of a shared stash
+    #[clap(subcommand)]
+    User(User),
+
+    /// Read files from the stash and compare them, without writing them out
+    VerifyRestore(VerifyRestore),
+
     /// Delete all data of a stash
     Wipe(Wipe),
 
@@ -82,6 +252,44 @@ pub struct EntryPoint {
     /// Use the specified config file
     #[clap(long)]
     pub insecure_config: bool,
+
+    /// Export traces to an OpenTelemetry collector at this OTLP/HTTP endpoint.
+    /// Defaults to the value of `OTEL_EXPORTER_OTLP_ENDPOINT`.
+    #[clap(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Log every request to the storage backends to this file as JSON
+    /// lines, with credentials redacted
+    #[clap(long, value_name = "PATH")]
+    pub debug_http: Option<PathBuf>,
+
+    /// Print the results of `ls`, `log`, `stats`, `check` and `diff` as
+    /// text, or as a JSON object per line
+    #[clap(long, value_enum, default_value_t, global = true)]
+    pub output: Output,
+}
+
+/// Format of the results commands print
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Output {
+    #[default]
+    Text,
+    Json,
+}
+
+impl Output {
+    /// Whether to print JSON lines
+    pub(crate) fn is_json(self) -> bool {
+        self == Self::Json
+    }
+}
+
+impl EntryPoint {
+    pub(crate) fn trace_endpoint(&self) -> Option<String> {
+        self.otlp_endpoint
+            .clone()
+            .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok())
+    }
 }
 
 #[derive(clap::Args, Clone, Debug)]
@@ -112,6 +320,10 @@ pub struct StashArgs {
     /// Commit ID to load before doing any operations on the stash
     #[clap(long)]
     pub commit_id: Option<infinitree::tree::CommitId>,
+
+    /// Create a new stash if none exists at the location
+    #[clap(long)]
+    pub create: bool,
 }
 
 impl StashArgs {
@@ -134,15 +346,48 @@ impl StashArgs {
         }
     }
 
+    /// What the key in use may do with the stash
+    pub(crate) fn role(&self) -> Role {
+        self.parse_stash()
+            .role(self.key().as_ref())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")))
+    }
+
+    /// Exit if the key in use may not modify the stash
+    pub(crate) fn require_write(&self) {
+        if !self.role().can_write() {
+            fatal_error("The key is read-only, and can't modify the stash");
+        }
+    }
+
+    /// Exit if the key in use may not remove anything from the stash
+    pub(crate) fn require_delete(&self) {
+        if !self.role().can_delete() {
+            fatal_error("The key can't remove data from the stash");
+        }
+    }
+
+    /// Restrictions of the key in use, if it's a delegation token
+    pub(crate) fn token_scope(&self) -> Option<zerostash_files::TokenScope> {
+        self.parse_stash()
+            .token_scope(self.key().as_ref())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")))
+    }
+
     pub(crate) fn parse_stash(&self) -> crate::config::Stash {
         crate::config::Stash::from_str(&self.stash).unwrap()
     }
 
     pub(crate) fn open_with(&self, key: Option<Key>) -> Stash {
-        let stash = crate::config::Stash::from_str(&self.stash)
-            .unwrap()
-            .open_or_new(key)
-            .unwrap();
+        self.open_config(self.parse_stash(), key)
+    }
+
+    /// Open the stash with a modified configuration
+    pub(crate) fn open_config(&self, config: crate::config::Stash, key: Option<Key>) -> Stash {
+        let stash = config
+            .open_or_create(key, self.create)
+            .and_then(|outcome| outcome.into_stash(&config.alias))
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
 
         if let Some(commit) = self.commit_id {
             stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
@@ -154,6 +399,202 @@ impl StashArgs {
     pub(crate) fn open(&self) -> Stash {
         self.open_with(self.key())
     }
+
+    /// Open the stash locked for `operation` like [`open_locked`], or
+    /// exit if someone else holds the lock
+    pub(crate) fn open_locked(&self, operation: &str) -> (Stash, zerostash_files::LockGuard) {
+        self.open_config_locked(self.parse_stash(), self.key(), operation)
+    }
+
+    /// Open the stash with a modified configuration, locked for
+    /// `operation`
+    pub(crate) fn open_config_locked(
+        &self,
+        config: crate::config::Stash,
+        key: Option<Key>,
+        operation: &str,
+    ) -> (Stash, zerostash_files::LockGuard) {
+        let (stash, guard) = open_locked(&config, key, self.create, operation)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if let Some(commit) = self.commit_id {
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
+        }
+
+        (stash, guard)
+    }
+}
+
+/// A commit given by its ID, a tag, or by a point in time
+#[derive(Clone, Debug)]
+pub(crate) enum CommitSelector {
+    Id(infinitree::tree::CommitId),
+    /// The last commit made at or before the time
+    Time(chrono::DateTime<chrono::Utc>),
+    /// A name given to the commit with `tag add`
+    Tag(String),
+}
+
+impl FromStr for CommitSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+        if let Ok(id) = s.parse() {
+            return Ok(Self::Id(id));
+        }
+
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Self::Time(time.with_timezone(&Utc)));
+        }
+
+        // a day means the end of it, in local time
+        let local = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|day| day.and_hms_opt(23, 59, 59))
+            });
+        let Some(local) = local else {
+            return Ok(Self::Tag(s.to_string()));
+        };
+
+        Local
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|time| Self::Time(time.with_timezone(&Utc)))
+            .ok_or_else(|| format!("invalid time `{s}`"))
+    }
+}
+
+impl CommitSelector {
+    /// Find the commit in the stash
+    pub(crate) fn resolve(&self, stash: &Stash) -> Result<infinitree::tree::CommitId, String> {
+        if let Self::Tag(_) = self {
+            report_reconstructed(zerostash_files::legacy::load_commit_tags(stash));
+        }
+        // a point in time means a finished backup
+        let checkpoints = match self {
+            Self::Time(_) => {
+                report_reconstructed(zerostash_files::legacy::load_commit_info(stash));
+                zerostash_files::checkpoints(stash)
+            }
+            _ => Default::default(),
+        };
+        let commits = stash.commit_list();
+
+        match self {
+            Self::Id(id) => commits
+                .iter()
+                .find(|c| c.id == *id)
+                .map(|c| c.id)
+                .ok_or_else(|| format!("no commit {id:?} in the stash")),
+            Self::Time(time) => commits
+                .iter()
+                .rev()
+                .filter(|c| !checkpoints.contains(&c.id))
+                .find(|c| chrono::DateTime::<chrono::Utc>::from(c.metadata.time) <= *time)
+                .map(|c| c.id)
+                .ok_or_else(|| format!("no commit was made before {time}")),
+            Self::Tag(name) => stash
+                .index()
+                .commit_tags
+                .read()
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("no commit or tag `{name}` in the stash")),
+        }
+    }
+}
+
+/// Load the directory tree, and tell the user what had to be
+/// reconstructed to read an older stash
+pub(crate) fn load_tree(stash: &Stash) {
+    report_reconstructed(zerostash_files::legacy::load_tree(stash));
+}
+
+/// Print `value` on a single line, for `--output json`
+pub(crate) fn json_line(
+    out: &mut impl Write,
+    value: &impl serde::Serialize,
+) -> std::io::Result<()> {
+    let line = serde_json::to_string(value).map_err(std::io::Error::other)?;
+    writeln!(out, "{line}")
+}
+
+pub(crate) fn report_reconstructed(reconstructed: zerostash_files::legacy::Reconstructed) {
+    if !reconstructed.is_empty() {
+        _ = write!(std::io::stderr().lock(), "{reconstructed}");
+    }
+}
+
+/// Open the stash locked for `operation`. The lock is held until the
+/// guard is dropped, and the process exits if it's lost before that.
+///
+/// The stash is opened again once the lock is held, so the index
+/// includes everything the previous holder committed.
+pub(crate) fn open_locked(
+    config: &crate::config::Stash,
+    key: Option<Key>,
+    create: bool,
+    operation: &str,
+) -> anyhow::Result<(Stash, zerostash_files::LockGuard)> {
+    let (stash, mut reopen) = config.open_reopenable(key, create)?;
+    stash
+        .load(stash.index().lock_key())
+        .context("Failed to read the lock key")?;
+
+    let locks = match zerostash_files::Locks::open(&stash)? {
+        Some(locks) => locks,
+        None => set_up_locks(&stash, &mut reopen)?,
+    };
+    let guard = locks.lock(operation, |e| fatal_error(format!("{e:#}")))?;
+
+    // a new stash has nothing to read back before its first commit
+    if stash.commit_list().iter().next().is_none() {
+        return Ok((stash, guard));
+    }
+
+    Ok((reopen()?, guard))
+}
+
+/// Put locks on a stash made before they existed, and return the locks
+/// every client uses. This commits the stash, so the whole index is
+/// loaded first.
+///
+/// New stashes get their locks when they're created, see
+/// [`crate::config::Stash::create`].
+fn set_up_locks(
+    stash: &Stash,
+    reopen: &mut impl FnMut() -> anyhow::Result<Stash>,
+) -> anyhow::Result<zerostash_files::Locks> {
+    tracing::info!("setting up locks on the stash");
+    stash.load_all()?;
+    let ours = zerostash_files::Locks::setup(stash)?;
+    stash
+        .commit(Some("Set up locks".into()))
+        .context("Failed to write metadata")?;
+    stash
+        .backend()
+        .sync()
+        .context("Failed to write to storage")?;
+
+    // the last client to commit its lock key wins a race to set them
+    // up, so use whichever key is in the stash once the others are done
+    std::thread::sleep(zerostash_files::SETTLE);
+    let current = reopen()?;
+    current
+        .load(current.index().lock_key())
+        .context("Failed to read the lock key")?;
+    if ours.are_used_by(&current) {
+        return Ok(ours);
+    }
+
+    tracing::info!("another client set up the locks at the same time");
+    ours.remove()?;
+    zerostash_files::Locks::open(&current)?.context("The lock key of the stash went missing")
 }
 
 impl Runnable for EntryPoint {
@@ -161,21 +602,71 @@ impl Runnable for EntryPoint {
         use ZerostashCmd::*;
         abscissa_tokio::run(&APP, async move {
             match &*self.cmd {
+                All(cmd) => cmd.run().await,
+                Cat(cmd) => cmd.run().await,
+                Check(cmd) => cmd.run().await,
                 Checkout(cmd) => cmd.run().await,
+                Copy(cmd) => cmd.run().await,
                 Commit(cmd) => cmd.run().await,
+                Diff(cmd) => cmd.run().await,
+                Find(cmd) => cmd.run().await,
+                Forget(cmd) => cmd.run().await,
+                Gc(cmd) => cmd.run().await,
+                Import(cmd) => cmd.run().await,
+                Info(cmd) => cmd.run().await,
+                Init(cmd) => cmd.run().await,
+                Locks(cmd) => cmd.run().await,
                 Log(cmd) => cmd.run().await,
                 Ls(cmd) => cmd.run().await,
+                Keygen(cmd) => cmd.run().await,
                 Keys(cmd) => cmd.run().await,
+                Overlay(cmd) => cmd.run().await,
+                Passwd(cmd) => cmd.run().await,
+                Prune(cmd) => cmd.run().await,
+                RebuildFilter(cmd) => cmd.run().await,
+                #[cfg(unix)]
+                Objectd(cmd) => cmd.run().await,
+                Selftest(cmd) => cmd.run().await,
+                Stash(cmd) => cmd.run().await,
+                Stats(cmd) => cmd.run().await,
+                Stream(cmd) => cmd.run().await,
+                Systemd(cmd) => cmd.run().await,
+                Tag(cmd) => cmd.run().await,
+                TagPath(cmd) => cmd.run().await,
+                Token(cmd) => cmd.run().await,
+                Top(cmd) => cmd.run().await,
+                Undo(cmd) => cmd.run().await,
+                UpgradeFormat(cmd) => cmd.run().await,
+                User(cmd) => cmd.run().await,
+                VerifyRestore(cmd) => cmd.run().await,
                 Wipe(cmd) => cmd.run().await,
                 Zfs(cmd) => cmd.run().await,
                 #[cfg(feature = "fuse")]
                 Mount(cmd) => cmd.run().await,
             }
         })
-        .unwrap()
+        .unwrap();
+
+        log_transfer_stats();
+        crate::telemetry::shutdown();
     }
 }
 
+fn log_transfer_stats() {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    let stats = APP.transfer_stats();
+    tracing::debug!(
+        objects_read = stats.objects_read.load(Relaxed),
+        bytes_read = stats.bytes_read.load(Relaxed),
+        objects_written = stats.objects_written.load(Relaxed),
+        bytes_written = stats.bytes_written.load(Relaxed),
+        errors = stats.errors.load(Relaxed),
+        backend_ms = stats.busy_micros.load(Relaxed) / 1000,
+        "backend traffic"
+    );
+}
+
 /// This trait allows you to define how application configuration is loaded.
 impl Configurable<ZerostashConfig> for EntryPoint {
     /// Location of the configuration file
@@ -204,4 +695,13 @@ impl Configurable<ZerostashConfig> for EntryPoint {
             None
         }
     }
+
+    /// Remember where the configuration was loaded from
+    fn process_config(
+        &self,
+        mut config: ZerostashConfig,
+    ) -> Result<ZerostashConfig, FrameworkError> {
+        config.file = self.config_path();
+        Ok(config)
+    }
 }
@@ -0,0 +1,186 @@
+//! `all` subcommand
+
+use crate::{config::Key, prelude::*};
+use std::{
+    ffi::OsString,
+    process::{self, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Command, Debug)]
+pub struct All {
+    /// Only run on the stashes in this group
+    #[clap(short, long)]
+    group: Option<String>,
+
+    /// Number of stashes to work on at the same time
+    #[clap(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Subcommand to run on every stash, followed by its arguments
+    /// without the stash. Eg: `commit /home`
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+enum Status {
+    Ok,
+    Failed(Option<i32>),
+    Skipped(&'static str),
+}
+
+struct Outcome {
+    alias: String,
+    status: Status,
+    elapsed: Duration,
+}
+
+#[async_trait]
+impl AsyncRunnable for All {
+    async fn run(&self) {
+        let config = APP.config();
+        let stashes = config.stashes_in(self.group.as_deref());
+        if stashes.is_empty() {
+            match &self.group {
+                Some(group) => fatal_error(format!("No stashes in group {group}")),
+                None => fatal_error("No stashes in the config file"),
+            }
+        }
+
+        let executable = std::env::current_exe().expect("Can't find the path of 0s");
+
+        // global options, like the config file, are passed on to every
+        // run
+        let globals = std::env::args_os()
+            .skip(1)
+            .take_while(|arg| arg != "all")
+            .collect::<Vec<_>>();
+
+        let queue = Mutex::new(stashes.into_iter());
+        let outcomes = Mutex::new(vec![]);
+
+        std::thread::scope(|s| {
+            for _ in 0..self.jobs.max(1) {
+                s.spawn(|| loop {
+                    let Some(alias) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+
+                    let outcome = self.run_on(&executable, &globals, alias);
+                    outcomes.lock().unwrap().push(outcome);
+                });
+            }
+        });
+
+        let mut outcomes = outcomes.into_inner().unwrap();
+        outcomes.sort_by(|a, b| a.alias.cmp(&b.alias));
+
+        let failed = print_summary(&outcomes);
+        if failed > 0 {
+            fatal_error(format!("{} failed on {failed} stashes", self.command[0]));
+        }
+    }
+}
+
+impl All {
+    fn run_on(&self, executable: &std::path::Path, globals: &[OsString], alias: String) -> Outcome {
+        let start = Instant::now();
+
+        let key = APP.config().resolve_stash(&alias).map(|stash| stash.key);
+        if needs_input(key.as_ref()) {
+            return Outcome {
+                alias,
+                status: Status::Skipped("needs interactive credentials"),
+                elapsed: start.elapsed(),
+            };
+        }
+
+        let output = process::Command::new(executable)
+            .args(globals)
+            .arg(&self.command[0])
+            .arg(&alias)
+            .args(&self.command[1..])
+            .stdin(Stdio::null())
+            .output();
+
+        let status = match output {
+            Ok(output) => {
+                // keep the output of a stash together, even when
+                // several run at the same time
+                let stdout = std::io::stdout();
+                let mut out = stdout.lock();
+                let _ = writeln!(out, "==> {alias}");
+                let _ = out.write_all(&output.stdout);
+                let _ = out.write_all(&output.stderr);
+                let _ = writeln!(out);
+
+                if output.status.success() {
+                    Status::Ok
+                } else {
+                    Status::Failed(output.status.code())
+                }
+            }
+            Err(e) => {
+                println!("==> {alias}\nFailed to start 0s: {e}\n");
+                Status::Failed(None)
+            }
+        };
+
+        Outcome {
+            alias,
+            status,
+            elapsed: start.elapsed(),
+        }
+    }
+}
+
+/// Whether opening the stash would prompt for credentials, which
+/// nobody is around to answer
+fn needs_input(key: Option<&Key>) -> bool {
+    match key {
+        None | Some(Key::Interactive) => true,
+        Some(Key::Userpass(k)) => k.password.is_none() && !k.keychain,
+        Some(_) => false,
+    }
+}
+
+/// Print one line per stash, and return the number of failures
+fn print_summary(outcomes: &[Outcome]) -> usize {
+    let width = outcomes.iter().map(|o| o.alias.len()).max().unwrap_or(0);
+    let (mut ok, mut failed, mut skipped) = (0, 0, 0);
+
+    println!("Summary:");
+    for outcome in outcomes {
+        let status = match outcome.status {
+            Status::Ok => {
+                ok += 1;
+                "ok".to_string()
+            }
+            Status::Failed(Some(code)) => {
+                failed += 1;
+                format!("failed (exit code {code})")
+            }
+            Status::Failed(None) => {
+                failed += 1;
+                "failed".to_string()
+            }
+            Status::Skipped(reason) => {
+                skipped += 1;
+                format!("skipped: {reason}")
+            }
+        };
+
+        println!(
+            "  {:width$}  {:>8.1}s  {status}",
+            outcome.alias,
+            outcome.elapsed.as_secs_f64()
+        );
+    }
+    println!(
+        "{} stashes: {ok} ok, {failed} failed, {skipped} skipped",
+        outcomes.len()
+    );
+
+    failed
+}
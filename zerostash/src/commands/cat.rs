@@ -0,0 +1,59 @@
+//! `cat` subcommand
+
+use crate::{
+    commands::{load_tree, CommitSelector},
+    prelude::*,
+};
+use zerostash_files::{restore, FileType};
+
+#[derive(Command, Debug)]
+pub struct Cat {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Path of the file in the stash
+    path: String,
+
+    /// Read the file as of a commit ID or tag, or the last commit before a
+    /// date like `2024-03-01` or `2024-03-01 18:00`
+    #[clap(long, value_name = "COMMIT|TIME", conflicts_with = "commit_id")]
+    at: Option<CommitSelector>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Cat {
+    /// Start the application.
+    async fn run(&self) {
+        if !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+
+        let path = self.path.trim_start_matches('/');
+        if let Some(scope) = self.stash.token_scope() {
+            if !scope.covers(path) {
+                fatal_error(format!("{path} is outside of the paths the token may read"));
+            }
+        }
+
+        let stash = self.stash.open();
+        if let Some(at) = &self.at {
+            let commit = at.resolve(&stash).unwrap_or_else(|e| fatal_error(e));
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
+        }
+        load_tree(&stash);
+
+        let entry = match stash.index().tree.file(path) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => fatal_error(format!("{path} is not a file in the stash")),
+            Err(e) => fatal_error(format!("{path}: {e:?}")),
+        };
+        if let FileType::Symlink(target) = &entry.file_type {
+            fatal_error(format!("{path} is a symlink to {target:?}"));
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        if let Err(e) = restore::write_contents(&stash, &entry, &mut stdout) {
+            fatal_error(format!("{e:#}"));
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! `check` subcommand
+
+use crate::{commands::json_line, prelude::*};
+use std::path::Path;
+use zerostash_files::{
+    check::{self, ReadData},
+    journal::Journal,
+};
+
+#[derive(Command, Debug)]
+pub struct Check {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Decrypt every chunk, and verify its hash
+    #[clap(long, conflicts_with = "read_data_subset")]
+    read_data: bool,
+
+    /// Decrypt and verify the chunks in a random sample of the
+    /// objects. Eg: 5%
+    #[clap(long, value_name = "PERCENT", value_parser = parse_percent)]
+    read_data_subset: Option<f64>,
+
+    /// Skip the objects an interrupted check with `--resume` has
+    /// verified already
+    #[clap(long)]
+    resume: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Check {
+    /// Start the application.
+    async fn run(&self) {
+        let read = match (self.read_data, self.read_data_subset) {
+            (true, _) => ReadData::All,
+            (false, Some(share)) => ReadData::Subset(share),
+            (false, None) => ReadData::None,
+        };
+        if read != ReadData::None && !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+
+        let config = self.stash.parse_stash();
+        let open = config
+            .opener(self.stash.key())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        let journal = self
+            .resume
+            .then(|| Journal::open(config.state_dir(), "check", &config.backend.location()))
+            .transpose()
+            .unwrap_or_else(|e| fatal_error(format!("Failed to open the check journal: {e}")));
+
+        let report = check::check(
+            open,
+            read,
+            config.backend.local_dir().map(Path::new),
+            journal.as_ref(),
+        )
+        .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if let Some(journal) = journal {
+            _ = journal.finish();
+        }
+
+        if APP.output().is_json() {
+            _ = json_line(&mut std::io::stdout().lock(), &report);
+            if !report.problems.is_empty() {
+                fatal_error(format!("Found {} problems", report.problems.len()));
+            }
+            return;
+        }
+
+        println!("Commits:       {}", report.commits);
+        println!("Files:         {}", report.files);
+        println!("Chunks:        {}", report.chunks);
+        println!("Objects:       {}", report.objects);
+        if read != ReadData::None {
+            println!("Chunks read:   {}", report.chunks_read);
+        }
+        if report.objects_resumed > 0 {
+            println!(
+                "Objects verified before resuming: {}",
+                report.objects_resumed
+            );
+        }
+
+        if report.problems.is_empty() {
+            println!("No problems found");
+            return;
+        }
+
+        for problem in report.problems.iter() {
+            println!("{problem}");
+        }
+        fatal_error(format!("Found {} problems", report.problems.len()));
+    }
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent = value
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .map_err(|e| e.to_string())?;
+
+    if percent <= 0.0 || percent > 100.0 {
+        return Err(format!("{value} is not between 0% and 100%"));
+    }
+
+    Ok(percent / 100.0)
+}
@@ -1,7 +1,18 @@
 //! `checkout` subcommand
 
-use crate::prelude::*;
-use zerostash_files::restore;
+use crate::{
+    commands::{load_tree, report_reconstructed, CommitSelector},
+    prelude::*,
+    progress::ProgressArgs,
+};
+use humansize::{format_size, BINARY};
+use infinitree::object::ObjectId;
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tracing::{info_span, Instrument};
+use zerostash_files::{legacy, restore, verify_restore, FileType};
 
 #[derive(Command, Debug)]
 pub struct Checkout {
@@ -10,18 +21,227 @@ pub struct Checkout {
 
     #[clap(flatten)]
     options: restore::Options,
+
+    /// Restore the files as of a commit ID or tag, or the last commit
+    /// before a date like `2024-03-01` or `2024-03-01 18:00`
+    #[clap(long, value_name = "COMMIT|TIME", conflicts_with = "commit_id")]
+    at: Option<CommitSelector>,
+
+    /// Restore only this file, without the directories it's in
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["globs", "includes", "plan", "priority_file", "priority_done", "strip_components", "transforms"]
+    )]
+    single: Option<String>,
+
+    /// Where to write the file given to --single, or `-` for stdout.
+    /// Defaults to its name in the current directory.
+    #[clap(long, value_name = "PATH|-", requires = "single")]
+    to: Option<PathBuf>,
+
+    /// Read the restored files back, and compare them to the hashes
+    /// in the stash
+    #[clap(long)]
+    verify: bool,
+
+    #[clap(flatten)]
+    progress: ProgressArgs,
+
+    /// Print an estimate of the work involved, and ask for
+    /// confirmation before restoring
+    #[clap(long)]
+    plan: bool,
+
+    /// Don't ask for confirmation
+    #[clap(long)]
+    yes: bool,
 }
 
 #[async_trait]
 impl AsyncRunnable for Checkout {
     /// Start the application.
     async fn run(&self) {
+        if !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+
+        let options = restore::Options {
+            scope: self.stash.token_scope(),
+            ..self.options.clone()
+        };
+
         let stash = self.stash.open();
-        stash.load(stash.index().tree()).unwrap();
+        if let Some(at) = &self.at {
+            let commit = at.resolve(&stash).unwrap_or_else(|e| fatal_error(e));
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
+        }
+        load_tree(&stash);
+        report_reconstructed(legacy::load_tags(&stash));
 
-        self.options
+        if let Some(path) = &self.single {
+            self.restore_single(&options, &stash, path);
+            return;
+        }
+
+        if self.plan {
+            self.print_plan(&options, &stash);
+
+            if !self.yes && !confirm() {
+                println!("Restore cancelled");
+                return;
+            }
+        }
+
+        let progress = self.progress.start(&options.activity);
+        options
             .from_iter(&stash, APP.get_worker_threads())
+            .instrument(info_span!("restore", stash = %self.stash.stash))
             .await
             .expect("Error extracting data");
+        drop(progress);
+
+        if self.verify {
+            // the restore changed to its directory already
+            let written = options
+                .list(&stash)
+                .filter_map(|(path, md)| Some((options.target_of(&path)?, md)));
+            verify(&stash, written);
+        }
+    }
+}
+
+impl Checkout {
+    fn restore_single(&self, options: &restore::Options, stash: &Stash, path: &str) {
+        let path = path.trim_start_matches('/');
+        if let Some(scope) = &options.scope {
+            if !scope.covers(path) {
+                fatal_error(format!("{path} is outside of the paths the token may read"));
+            }
+        }
+
+        let entry = match stash.index().tree.file(path) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => fatal_error(format!("{path} is not a file in the stash")),
+            Err(e) => fatal_error(format!("{path}: {e:?}")),
+        };
+
+        let target = match &self.to {
+            Some(to) if to.as_os_str() == "-" => {
+                if let FileType::Symlink(target) = &entry.file_type {
+                    fatal_error(format!("{path} is a symlink to {target:?}"));
+                }
+
+                let mut stdout = std::io::stdout().lock();
+                if let Err(e) = restore::write_contents(stash, &entry, &mut stdout) {
+                    fatal_error(format!("{e:#}"));
+                }
+                return;
+            }
+            Some(to) => to.clone(),
+            None => path.rsplit('/').next().unwrap_or(path).into(),
+        };
+
+        if let Err(e) = restore::restore_file(stash, &entry, &target, &options.preserve) {
+            fatal_error(format!("{e:#}"));
+        }
+        if self.verify {
+            verify(stash, [(target, entry)]);
+        }
+    }
+
+    fn print_plan(&self, options: &restore::Options, stash: &Stash) {
+        let plan = options.plan(stash);
+        let cache = self
+            .stash
+            .parse_stash()
+            .backend
+            .cache_paths()
+            .into_iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        let is_cached = |id: &ObjectId| {
+            cache
+                .iter()
+                .any(|dir| Path::new(dir).join(id.to_string()).exists())
+        };
+
+        let cached = plan.objects.iter().filter(|id| is_cached(id)).count();
+        let to_fetch = plan.objects.len() - cached;
+
+        println!("Files:         {}", plan.files);
+        println!("Total size:    {}", format_size(plan.bytes, BINARY));
+        println!("Objects:       {}", plan.objects.len());
+
+        if !cache.is_empty() {
+            let hit_rate = match plan.objects.len() {
+                0 => 100.0,
+                n => cached as f64 * 100.0 / n as f64,
+            };
+            println!("Cache hits:    {hit_rate:.1}% (estimated)");
+        }
+
+        // time a single object download to estimate the throughput
+        let sample = plan.objects.iter().find(|id| !is_cached(id));
+
+        let Some(sample) = sample else {
+            println!("Duration:      all objects are cached");
+            return;
+        };
+
+        let start = Instant::now();
+        match stash.backend().read_object(sample) {
+            Ok(object) => {
+                let elapsed = start.elapsed();
+                let throughput = object.as_inner().len() as f64 / elapsed.as_secs_f64();
+                let estimate = (to_fetch * object.as_inner().len()) as f64 / throughput;
+
+                println!(
+                    "Throughput:    {}/s (measured)",
+                    format_size(throughput as u64, BINARY)
+                );
+                println!("Duration:      {} (estimated)", format_duration(estimate));
+            }
+            Err(error) => println!("Duration:      unknown, failed to reach backend: {error}"),
+        }
     }
 }
+
+/// Exit if the restored files don't match the stash
+fn verify(
+    stash: &Stash,
+    written: impl IntoIterator<Item = (PathBuf, std::sync::Arc<zerostash_files::Entry>)>,
+) {
+    let report = verify_restore::verify_written(stash, written)
+        .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+    for problem in report.problems.iter() {
+        println!("{problem}");
+    }
+    if !report.problems.is_empty() {
+        fatal_error(format!(
+            "{} of {} restored files don't match the stash",
+            report.problems.len(),
+            report.files
+        ));
+    }
+    println!(
+        "Verified {} files, {}",
+        report.files,
+        format_size(report.bytes, BINARY)
+    );
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.ceil() as u64;
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn confirm() -> bool {
+    let reply = rprompt::prompt_reply("Proceed with restore? [y/N] ").unwrap_or_default();
+    matches!(reply.trim(), "y" | "Y" | "yes")
+}
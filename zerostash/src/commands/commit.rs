@@ -1,6 +1,18 @@
 //! `commit` subcommand
 
-use crate::{migration::migration, prelude::*};
+use crate::{config::hooks, control, migration::migration, prelude::*, progress::ProgressArgs};
+use anyhow::Context;
+use humansize::{format_size, BINARY};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{info, info_span, warn, Instrument};
+use zerostash_files::{store::DryRun, CommitInfo};
 
 #[derive(Command, Debug)]
 pub struct Commit {
@@ -13,24 +25,260 @@ pub struct Commit {
     /// Commit message to include in the changeset
     #[clap(short = 'm', long)]
     message: Option<String>,
+
+    #[clap(flatten)]
+    progress: ProgressArgs,
+
+    /// Only walk the paths and chunk the new and changed files, and
+    /// print what would be stored, without writing anything
+    #[clap(long, conflicts_with = "control_socket")]
+    dry_run: bool,
+
+    /// Commit what's stored so far every N minutes, so an interrupted
+    /// backup continues from the last checkpoint when it's run again
+    #[clap(
+        long,
+        value_name = "MINUTES",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "dry_run"
+    )]
+    checkpoint_interval: Option<u64>,
+
+    /// Accept `pause`, `resume`, `status`, and `activity` commands on
+    /// a Unix socket at this path. Watch it with `0s top`.
+    #[clap(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Back up from a Volume Shadow Copy of each drive, so files that
+    /// are open, like Outlook data files or registry hives, are read
+    /// in a consistent state. Needs an elevated prompt.
+    #[cfg(windows)]
+    #[clap(long, conflicts_with = "dry_run")]
+    vss: bool,
+
+    /// Back up from a temporary snapshot of each volume, so the backup
+    /// is consistent even while files change. Uses APFS snapshots on
+    /// macOS, which need Full Disk Access, and LVM or btrfs on Linux.
+    /// Needs root.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[clap(long, value_enum, value_name = "MODE", conflicts_with = "dry_run")]
+    snapshot: Option<SnapshotMode>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum SnapshotMode {
+    /// Snapshot the volumes that support it, and read the rest as they are
+    Auto,
 }
 
 #[async_trait]
 impl AsyncRunnable for Commit {
     /// Start the application.
     async fn run(&self) {
-        let mut stash = self.stash.open();
+        if !self.dry_run {
+            self.stash.require_write();
+        }
+        if self.dry_run {
+            let stash = self.stash.open();
+            stash.load_all().unwrap();
+            let dry_run = self
+                .options
+                .dry_run(&stash)
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+            print_dry_run(&dry_run);
+            return;
+        }
+
+        let pause = &self.options.pause;
+        control::handle_signals(pause).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        if let Some(path) = &self.control_socket {
+            control::listen(path, pause, &self.options.activity)
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        }
+
+        let config = self.stash.parse_stash();
+        let mut env = vec![("ZEROSTASH_STASH", config.alias.clone())];
+        hooks::run("pre_backup", config.hooks.pre_backup.as_deref(), &env)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        let (mut stash, lock) = self.stash.open_locked("commit");
         stash.load_all().unwrap();
         migration(&mut stash);
 
-        self.options
-            .add_recursive(&stash, APP.get_worker_threads())
-            .await
-            .unwrap();
+        let result = self.backup(&stash).await;
+        drop(lock);
+
+        match result {
+            Ok(info) => {
+                let commit = stash
+                    .commit_list()
+                    .iter()
+                    .last()
+                    .map(|c| format!("{:?}", c.id))
+                    .unwrap_or_default();
+                env.extend([
+                    ("ZEROSTASH_COMMIT", commit),
+                    ("ZEROSTASH_FILES_ADDED", info.files_added.to_string()),
+                    ("ZEROSTASH_FILES_CHANGED", info.files_changed.to_string()),
+                    ("ZEROSTASH_BYTES_WRITTEN", info.bytes_written.to_string()),
+                ]);
+                hooks::run("post_backup", config.hooks.post_backup.as_deref(), &env)
+                    .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+            }
+            Err(error) => {
+                env.push(("ZEROSTASH_ERROR", format!("{error:#}")));
+                if let Err(e) = hooks::run("on_error", config.hooks.on_error.as_deref(), &env) {
+                    warn!("{e:#}");
+                }
+                fatal_error(format!("{error:#}"));
+            }
+        }
+    }
+}
+
+impl Commit {
+    /// Store the paths, and commit them
+    async fn backup(&self, stash: &Stash) -> anyhow::Result<CommitInfo> {
+        #[allow(unused_mut)]
+        let mut options = self.options.clone();
 
+        // snapshots are deleted when these go out of scope
+        #[cfg(windows)]
+        let _shadow_copies = if self.vss {
+            options.paths = absolute(&options.paths)?;
+            let copies = crate::vss::shadow_copies(&options.paths)?;
+            options.snapshots = copies.iter().map(|c| c.snapshot.clone()).collect();
+            copies
+        } else {
+            vec![]
+        };
+        #[cfg(target_os = "macos")]
+        let _local_snapshot = if self.snapshot.is_some() {
+            options.paths = absolute(&options.paths)?;
+            let snapshot = crate::apfs::LocalSnapshot::create(&options.paths)?;
+            options.snapshots = snapshot.snapshots.clone();
+            Some(snapshot)
+        } else {
+            None
+        };
+        #[cfg(target_os = "linux")]
+        let _volume_snapshots = if self.snapshot.is_some() {
+            options.paths = absolute(&options.paths)?;
+            let snapshots = crate::snapshot::VolumeSnapshots::create(&options.paths)?;
+            options.snapshots = snapshots.snapshots.clone();
+            // btrfs snapshots are taken inside the subvolume
+            options
+                .excludes
+                .push(format!("{}*", crate::snapshot::PREFIX));
+            Some(snapshots)
+        } else {
+            None
+        };
+
+        let progress = self.progress.start(&options.activity);
+        let pause = &options.pause;
+
+        // checkpoints stop the walk like a pause, and resume right
+        // after the commit
+        let checkpoint_due = Arc::new(AtomicBool::new(false));
+        let checkpoints = self.checkpoint_interval.map(|minutes| {
+            let pause = pause.clone();
+            let checkpoint_due = checkpoint_due.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_secs(minutes * 60)).await;
+                    if !pause.is_paused() {
+                        checkpoint_due.store(true, Ordering::SeqCst);
+                        pause.pause();
+                    }
+                }
+            })
+        });
+
+        loop {
+            options
+                .add_recursive(stash, APP.get_worker_threads())
+                .instrument(info_span!("store", stash = %self.stash.stash))
+                .await?;
+
+            if !pause.is_paused() {
+                break;
+            }
+
+            // keep what's stored so far in case we're stopped while
+            // paused. Resuming walks the paths again, but skips files
+            // that are already in the checkpoint.
+            stash.index().record_commit(CommitInfo {
+                checkpoint: true,
+                ..CommitInfo::new(stash, &options.activity.snapshot())
+            });
+            stash
+                .commit(Some("Checkpoint".to_string()))
+                .context("Failed to write metadata")?;
+            stash
+                .backend()
+                .sync()
+                .context("Failed to write to storage")?;
+
+            if checkpoint_due.swap(false, Ordering::SeqCst) {
+                info!("checkpoint committed");
+                pause.resume();
+                continue;
+            }
+
+            info!("paused, progress is committed");
+            pause.wait().await;
+            info!("resuming");
+        }
+
+        if let Some(checkpoints) = checkpoints {
+            checkpoints.abort();
+        }
+        drop(progress);
+        if let Some(path) = &self.control_socket {
+            _ = std::fs::remove_file(path);
+        }
+
+        let info = CommitInfo::new(stash, &options.activity.snapshot());
+        stash.index().record_commit(info.clone());
         stash
             .commit(self.message.clone())
-            .expect("Failed to write metadata");
-        stash.backend().sync().expect("Failed to write to storage");
+            .context("Failed to write metadata")?;
+        stash
+            .backend()
+            .sync()
+            .context("Failed to write to storage")?;
+
+        Ok(info)
     }
 }
+
+/// Snapshots are matched by absolute paths
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn absolute(paths: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    paths
+        .iter()
+        .map(std::path::absolute)
+        .collect::<Result<_, _>>()
+        .context("Failed to resolve paths")
+}
+
+fn print_dry_run(dry_run: &DryRun) {
+    println!(
+        "Files:         {} added, {} changed, {} unchanged, {} removed",
+        dry_run.added, dry_run.changed, dry_run.unchanged, dry_run.removed
+    );
+    println!("To chunk:      {}", format_size(dry_run.bytes, BINARY));
+    println!(
+        "New:           {} in {} chunks",
+        format_size(dry_run.new_bytes, BINARY),
+        dry_run.new_chunks
+    );
+    println!(
+        "Deduplicated:  {} in {} chunks",
+        format_size(dry_run.deduplicated_bytes, BINARY),
+        dry_run.deduplicated_chunks
+    );
+    println!("Nothing was written to the stash");
+}
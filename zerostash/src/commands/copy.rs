@@ -0,0 +1,182 @@
+//! `copy` subcommand
+
+use crate::{
+    commands::{load_tree, open_locked, CommitSelector},
+    prelude::*,
+};
+use anyhow::Context;
+use humansize::{format_size, BINARY};
+use infinitree::tree::CommitFilter;
+use std::{collections::HashSet, str::FromStr};
+use zerostash_files::{copy::Copier, journal::Journal, restore, LockGuard};
+
+#[derive(Command, Debug)]
+pub struct CopyStash {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Stash to copy into, by name or location
+    #[clap(long, value_name = "STASH")]
+    to: String,
+
+    /// List of globs to match in the database
+    globs: Vec<String>,
+
+    /// Commits to copy, by ID, tag or date. The latest one by default
+    #[clap(long = "commit", value_name = "COMMIT")]
+    commits: Vec<CommitSelector>,
+
+    /// Copy every commit
+    #[clap(long, conflicts_with = "commits")]
+    all: bool,
+
+    /// Skip the commits an interrupted copy with `--resume` has copied
+    /// already
+    #[clap(long)]
+    resume: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for CopyStash {
+    /// Start the application.
+    async fn run(&self) {
+        if !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+
+        let source = self.stash.open();
+        let (dest, _lock) = self
+            .open_dest()
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        let mut commits = if self.all {
+            source.commit_list().iter().map(|c| c.id).collect()
+        } else if self.commits.is_empty() {
+            source
+                .commit_list()
+                .last()
+                .map(|c| c.id)
+                .into_iter()
+                .collect()
+        } else {
+            self.commits
+                .iter()
+                .map(|c| c.resolve(&source).unwrap_or_else(fatal_error))
+                .collect::<Vec<_>>()
+        };
+        let list = source.commit_list();
+        commits.sort_by_key(|id| list.iter().position(|c| c.id == *id));
+        commits.dedup();
+
+        let config = self.stash.parse_stash();
+        let mut open = config
+            .opener(self.stash.key())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        let journal = self
+            .resume
+            .then(|| self.open_journal(&config))
+            .transpose()
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        let mut copier =
+            Copier::new(&source, &dest).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        let mut previous = HashSet::new();
+
+        for id in commits {
+            let snapshot = open().unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+            snapshot.filter_commits(CommitFilter::UpTo(id));
+            load_tree(&snapshot);
+
+            let options = restore::Options {
+                globs: self.globs.clone(),
+                scope: self.stash.token_scope(),
+                ..Default::default()
+            };
+            let mut current = HashSet::new();
+
+            // the files of a copied commit are still needed to find
+            // the ones the next commit removed
+            let copied = journal
+                .as_ref()
+                .is_some_and(|j| j.is_done(&format!("{id:?}")));
+            if copied {
+                current.extend(options.list(&snapshot).map(|(path, _)| path));
+                previous = current;
+                continue;
+            }
+
+            for (path, entry) in options.list(&snapshot) {
+                copier
+                    .copy_file(&path, &entry)
+                    .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+                current.insert(path);
+            }
+
+            for path in previous.difference(&current) {
+                _ = dest.index().tree.remove(path);
+            }
+            previous = current;
+
+            let message = match list.iter().find(|c| c.id == id) {
+                Some(commit) => format!(
+                    "Copy of {id:?}: {}",
+                    commit
+                        .metadata
+                        .message
+                        .as_deref()
+                        .unwrap_or("No commit message")
+                ),
+                None => format!("Copy of {id:?}"),
+            };
+            copier
+                .flush()
+                .and_then(|_| Ok(dest.commit(Some(message))?))
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+            // only record commits that made it to the destination
+            if let Some(journal) = &journal {
+                dest.backend()
+                    .sync()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| {
+                        journal.record(&format!("{id:?}"))?;
+                        Ok(journal.sync()?)
+                    })
+                    .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+            }
+        }
+
+        dest.backend()
+            .sync()
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        if let Some(journal) = journal {
+            _ = journal.finish();
+        }
+
+        let stats = copier.stats;
+        println!("Files:         {}", stats.files);
+        println!("Chunks copied: {}", stats.chunks);
+        println!("Deduplicated:  {}", stats.deduplicated);
+        println!("Uploaded:      {}", format_size(stats.bytes, BINARY));
+    }
+}
+
+impl CopyStash {
+    fn open_dest(&self) -> anyhow::Result<(Stash, LockGuard)> {
+        let config = crate::config::Stash::from_str(&self.to)?;
+        if !config.role(None)?.can_write() {
+            anyhow::bail!("The key of {} is read-only", self.to);
+        }
+
+        let (stash, lock) = open_locked(&config, None, false, "copy")?;
+        stash.load_all()?;
+        Ok((stash, lock))
+    }
+
+    /// The journal of copying between these two stashes
+    fn open_journal(&self, source: &crate::config::Stash) -> anyhow::Result<Journal> {
+        let dest = crate::config::Stash::from_str(&self.to)?;
+        let scope = format!("{} {}", source.backend.location(), dest.backend.location());
+
+        Journal::open(source.state_dir(), "copy", &scope).context("Failed to open the copy journal")
+    }
+}
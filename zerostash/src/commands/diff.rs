@@ -0,0 +1,123 @@
+//! `diff` subcommand
+
+use crate::{
+    commands::{json_line, load_tree, CommitSelector},
+    prelude::*,
+};
+use humansize::{format_size, BINARY};
+use infinitree::tree::CommitFilter;
+use std::{path::PathBuf, sync::Arc};
+use zerostash_files::{
+    diff::{self, Change},
+    restore, Entry,
+};
+
+#[derive(Command, Debug)]
+pub struct Diff {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Commit to compare from, by ID, tag or date
+    from: CommitSelector,
+
+    /// Commit to compare to. Defaults to the latest commit
+    to: Option<CommitSelector>,
+
+    /// Compare the commit with these paths on the local filesystem
+    #[clap(long, value_name = "PATH", conflicts_with = "to")]
+    local: Vec<PathBuf>,
+
+    /// Print the changes as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Diff {
+    /// Start the application.
+    async fn run(&self) {
+        let mut old = self.files_at(Some(&self.from));
+        let new = if self.local.is_empty() {
+            self.files_at(self.to.as_ref())
+        } else {
+            old.retain(|(path, _)| diff::is_under(path, &self.local));
+            diff::local_files(&self.local).unwrap_or_else(|e| fatal_error(format!("{e:#}")))
+        };
+
+        let changes = diff::diff(old, new);
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&changes).unwrap());
+            return;
+        }
+        if APP.output().is_json() {
+            let mut stdout = std::io::stdout().lock();
+            for change in changes.iter() {
+                if json_line(&mut stdout, change).is_err() {
+                    return;
+                }
+            }
+            return;
+        }
+
+        let (mut added, mut removed, mut modified, mut delta) = (0, 0, 0, 0);
+        for change in changes.iter() {
+            let (mark, size) = match change.change {
+                Change::Added => {
+                    added += 1;
+                    (
+                        "+",
+                        format_size(change.new_size.unwrap_or_default(), BINARY),
+                    )
+                }
+                Change::Removed => {
+                    removed += 1;
+                    (
+                        "-",
+                        format_size(change.old_size.unwrap_or_default(), BINARY),
+                    )
+                }
+                Change::Modified => {
+                    modified += 1;
+                    ("M", format_delta(change.size_delta()))
+                }
+            };
+            delta += change.size_delta();
+
+            println!("{mark} {} ({size})", change.path);
+        }
+
+        println!(
+            "{added} added, {removed} removed, {modified} modified, {}",
+            format_delta(delta)
+        );
+    }
+}
+
+impl Diff {
+    /// Files in the stash as of `commit`, or the latest commit
+    fn files_at(&self, commit: Option<&CommitSelector>) -> Vec<(String, Arc<Entry>)> {
+        let stash = self
+            .stash
+            .parse_stash()
+            .opener(self.stash.key())
+            .and_then(|mut open| open())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if let Some(commit) = commit {
+            let id = commit.resolve(&stash).unwrap_or_else(|e| fatal_error(e));
+            stash.filter_commits(CommitFilter::UpTo(id));
+        }
+        load_tree(&stash);
+
+        let options = restore::Options {
+            scope: self.stash.token_scope(),
+            ..Default::default()
+        };
+        options.list(&stash).collect()
+    }
+}
+
+fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{sign}{}", format_size(delta.unsigned_abs(), BINARY))
+}
@@ -0,0 +1,213 @@
+//! `find` subcommand
+
+use crate::{
+    commands::{load_tree, CommitSelector},
+    prelude::*,
+};
+use chrono::Local;
+use std::collections::BTreeSet;
+use zerostash_files::{
+    query::{Field, Op, Query, Value},
+    restore, Entry, FileType, Within,
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    #[value(name = "f")]
+    File,
+    #[value(name = "l")]
+    Symlink,
+    #[value(name = "d")]
+    Directory,
+}
+
+#[derive(Command, Debug)]
+pub struct Find {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Globs to match the paths against. Everything matches if none
+    /// are given.
+    globs: Vec<String>,
+
+    /// Only names that match the glob
+    #[clap(long, value_name = "GLOB", value_parser = name)]
+    name: Option<Query>,
+
+    /// Only files modified after a date, or within a period like `7d`
+    #[clap(long, value_name = "DATE|AGE", value_parser = newer_than)]
+    newer_than: Option<Query>,
+
+    /// Only files modified before a date, or longer ago than a period
+    /// like `1y`
+    #[clap(long, value_name = "DATE|AGE", value_parser = older_than)]
+    older_than: Option<Query>,
+
+    /// Only files larger than the size. Eg: 100M
+    #[clap(long, value_name = "SIZE", value_parser = larger_than)]
+    larger_than: Option<Query>,
+
+    /// Only files smaller than the size. Eg: 4K
+    #[clap(long, value_name = "SIZE", value_parser = smaller_than)]
+    smaller_than: Option<Query>,
+
+    /// Only files owned by the user ID
+    #[clap(long, value_parser = uid)]
+    uid: Option<Query>,
+
+    /// Only files owned by the group ID
+    #[clap(long, value_parser = gid)]
+    gid: Option<Query>,
+
+    /// Only files (f), symlinks (l), or directories (d). Directories
+    /// are only listed when asked for.
+    #[clap(long = "type", value_enum)]
+    kind: Option<Kind>,
+
+    /// Only files that match a filter expression, as in `ls --where`
+    #[clap(long = "where", value_name = "EXPR", value_parser = Query::parse)]
+    filter: Option<Query>,
+
+    /// Search the files as of a commit ID or tag, or the last commit before
+    /// a date like `2024-03-01` or `2024-03-01 18:00`
+    #[clap(long, value_name = "COMMIT|TIME", conflicts_with = "commit_id")]
+    at: Option<CommitSelector>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Find {
+    /// Start the application.
+    async fn run(&self) {
+        let query = self.query();
+        let matches = |path: &str, entry: &Entry| query.iter().all(|q| q.matches(path, entry));
+
+        let stash = self.stash.open();
+        if let Some(at) = &self.at {
+            let commit = at.resolve(&stash).unwrap_or_else(|e| fatal_error(e));
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
+        }
+        load_tree(&stash);
+
+        let options = restore::Options {
+            scope: self.stash.token_scope(),
+            ..Default::default()
+        };
+
+        let mut stdout = std::io::stdout().lock();
+        let mut count = 0;
+
+        if self.kind == Some(Kind::Directory) {
+            // directories don't have entries of their own
+            let mut dirs = BTreeSet::new();
+            for (path, _) in options.list(&stash) {
+                let mut parent = path.as_str();
+                while let Some((dir, _)) = parent.rsplit_once('/') {
+                    if !dirs.insert(dir.to_string()) {
+                        break;
+                    }
+                    parent = dir;
+                }
+            }
+
+            let entry = Entry {
+                file_type: FileType::Directory,
+                ..Default::default()
+            };
+            for dir in dirs.into_iter().filter(|dir| matches(dir, &entry)) {
+                count += 1;
+                if writeln!(stdout, "{dir}").is_err() {
+                    return;
+                }
+            }
+        } else {
+            for (path, _) in options
+                .list(&stash)
+                .filter(|(path, entry)| matches(path, entry))
+            {
+                count += 1;
+                if writeln!(stdout, "{path}").is_err() {
+                    return;
+                }
+            }
+        }
+
+        _ = writeln!(std::io::stderr().lock(), "Total entries: {count}");
+    }
+}
+
+impl Find {
+    /// Combine the globs and predicates into a single query, if any
+    /// are given
+    fn query(&self) -> Option<Query> {
+        let globs = self
+            .globs
+            .iter()
+            .map(|glob| Query::compare("path", Op::Match, glob.as_str()))
+            .map(|query| query.unwrap_or_else(|e| fatal_error(e)))
+            .reduce(|a, b| Query::Or(Box::new(a), Box::new(b)));
+
+        let kind = self.kind.map(|kind| {
+            let kind = match kind {
+                Kind::File => "file",
+                Kind::Symlink => "symlink",
+                Kind::Directory => "dir",
+            };
+            Query::compare("type", Op::Eq, kind).unwrap()
+        });
+
+        [
+            globs,
+            kind,
+            self.name.clone(),
+            self.newer_than.clone(),
+            self.older_than.clone(),
+            self.larger_than.clone(),
+            self.smaller_than.clone(),
+            self.uid.clone(),
+            self.gid.clone(),
+            self.filter.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .reduce(Query::and)
+    }
+}
+
+fn name(value: &str) -> Result<Query, String> {
+    Query::compare("name", Op::Match, value)
+}
+
+fn larger_than(value: &str) -> Result<Query, String> {
+    Query::compare("size", Op::Gt, value)
+}
+
+fn smaller_than(value: &str) -> Result<Query, String> {
+    Query::compare("size", Op::Lt, value)
+}
+
+fn uid(value: &str) -> Result<Query, String> {
+    Query::compare("uid", Op::Eq, value)
+}
+
+fn gid(value: &str) -> Result<Query, String> {
+    Query::compare("gid", Op::Eq, value)
+}
+
+fn newer_than(value: &str) -> Result<Query, String> {
+    modified(Op::Gt, value)
+}
+
+fn older_than(value: &str) -> Result<Query, String> {
+    modified(Op::Lt, value)
+}
+
+/// Compare the modification time to a date, or to a period before now
+fn modified(op: Op, value: &str) -> Result<Query, String> {
+    match value.parse::<Within>() {
+        Ok(age) => {
+            let time = (Local::now() - age.duration()).timestamp();
+            Ok(Query::Compare(Field::Mtime, op, Value::Number(time)))
+        }
+        Err(_) => Query::compare("mtime", op, value),
+    }
+}
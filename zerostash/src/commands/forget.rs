@@ -0,0 +1,186 @@
+//! `forget` subcommand
+
+use super::prune::prune;
+use crate::prelude::*;
+use chrono::{DateTime, Local, Utc};
+use infinitree::tree::{CommitFilter, CommitId};
+use std::collections::HashSet;
+use zerostash_files::{RefLogEntry, Retention};
+
+#[derive(Command, Debug)]
+pub struct Forget {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Commits to keep. Defaults to the retention policy of the stash
+    #[clap(flatten)]
+    retention: Retention,
+
+    /// Keep the commits that tagged a path with `key=value`, eg.
+    /// through `commit --tag`. Can be repeated.
+    #[clap(long = "keep-tag", value_name = "KEY=VALUE", value_parser = zerostash_files::parse_tag)]
+    keep_tags: Vec<(String, String)>,
+
+    /// Prune the data of the forgotten commits right away
+    #[clap(long)]
+    prune: bool,
+
+    /// Only print which commits would be forgotten
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Forget {
+    /// Start the application.
+    async fn run(&self) {
+        self.stash.require_write();
+        if self.prune {
+            self.stash.require_delete();
+        }
+
+        let (stash, _lock) = if self.dry_run {
+            (self.stash.open(), None)
+        } else {
+            let (stash, lock) = self.stash.open_locked("forget");
+            (stash, Some(lock))
+        };
+        stash.load_all().unwrap();
+
+        let retention = if self.retention.is_empty() && self.keep_tags.is_empty() {
+            stash.index().params.read().retention.clone()
+        } else {
+            self.retention.clone()
+        };
+        if retention.is_empty() && self.keep_tags.is_empty() {
+            fatal_error("No commits to keep were given, and the stash has no retention policy");
+        }
+
+        let forgotten = stash
+            .index()
+            .forgotten
+            .read()
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let commits = stash
+            .commit_list()
+            .iter()
+            .filter(|c| !forgotten.contains(&c.id))
+            .map(|c| {
+                let time: DateTime<Utc> = c.metadata.time.into();
+                (c.id, time.with_timezone(&Local), c.metadata.message.clone())
+            })
+            .rev()
+            .collect::<Vec<_>>();
+
+        // checkpoints are superseded by the commit that finished their
+        // backup, so they don't take up a slot of the policy
+        let checkpoints = zerostash_files::checkpoints(&stash);
+        let times = commits
+            .iter()
+            .filter(|(id, _, _)| !checkpoints.contains(id))
+            .map(|(_, t, _)| *t)
+            .collect::<Vec<_>>();
+        let mut kept = retention.reasons(&times).into_iter();
+        let mut reasons = commits
+            .iter()
+            .map(|(id, _, _)| match checkpoints.contains(id) {
+                true => vec![],
+                false => kept.next().unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+
+        // the index of the stash is the state of the latest commit, so
+        // its data can't be dropped
+        if let Some(latest) = reasons.first_mut() {
+            latest.push("latest");
+        }
+
+        // tags would point to commits that are gone
+        let named = stash.index().commit_tags.read().clone();
+        for ((id, _, _), reasons) in commits.iter().zip(reasons.iter_mut()) {
+            if named.values().any(|named| named == id) {
+                reasons.push("named");
+            }
+        }
+
+        if !self.keep_tags.is_empty() {
+            let mut open = self
+                .stash
+                .parse_stash()
+                .opener(self.stash.key())
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+            for ((id, _, _), reasons) in commits.iter().zip(reasons.iter_mut()) {
+                let tagged = self
+                    .tagged(&mut open, *id)
+                    .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+                if tagged {
+                    reasons.push("tag");
+                }
+            }
+        }
+
+        let mut forget = vec![];
+        for ((id, time, message), reasons) in commits.iter().zip(reasons.iter()) {
+            let action = if reasons.is_empty() {
+                forget.push(*id);
+                "forget".to_string()
+            } else {
+                format!("keep ({})", reasons.join(", "))
+            };
+
+            println!(
+                "{id:?}\t{}\t{action}\t{}",
+                time.format("%Y %b %e %H:%M:%S"),
+                message.as_deref().unwrap_or("No commit message")
+            );
+        }
+        println!("Forgetting {} of {} commits", forget.len(), commits.len());
+
+        if self.dry_run {
+            return;
+        }
+
+        if !forget.is_empty() {
+            stash.index().record(RefLogEntry::new(&stash, "forget"));
+            stash
+                .index()
+                .forgotten
+                .write()
+                .extend(forget.iter().copied());
+            stash
+                .commit(Some(format!("Forget {} commits", forget.len())))
+                .expect("Failed to write metadata");
+            stash.backend().sync().expect("Failed to write to storage");
+        }
+
+        if self.prune {
+            prune(&self.stash, &stash, false);
+        }
+    }
+}
+
+impl Forget {
+    /// Whether the commit tagged a path with any of the `--keep-tag`s
+    fn tagged(
+        &self,
+        open: &mut impl FnMut() -> anyhow::Result<Stash>,
+        id: CommitId,
+    ) -> anyhow::Result<bool> {
+        let snapshot = open()?;
+        snapshot.filter_commits(CommitFilter::Single(id));
+        snapshot.load(snapshot.index().tags())?;
+
+        let mut tagged = false;
+        snapshot.index().tags.for_each(|_, tags| {
+            tagged |= self
+                .keep_tags
+                .iter()
+                .any(|tag| zerostash_files::matches(tags, std::slice::from_ref(tag)));
+        });
+
+        Ok(tagged)
+    }
+}
@@ -0,0 +1,95 @@
+//! `gc` subcommand
+
+use crate::prelude::*;
+use humansize::{format_size, BINARY};
+use std::cmp::Reverse;
+use zerostash_files::gc;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum SortBy {
+    /// Largest reclaimable size first
+    #[default]
+    Size,
+    /// Alphabetically for paths, oldest first for commits
+    Name,
+}
+
+#[derive(Command, Debug)]
+pub struct Gc {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Only report how much space forgetting each commit or path
+    /// prefix would reclaim, without deleting anything
+    #[clap(long, required = true)]
+    report_only: bool,
+
+    /// Number of leading directories that make up a path prefix
+    #[clap(long, default_value_t = 1)]
+    depth: usize,
+
+    /// Order of the rows in the report
+    #[clap(long, value_enum, default_value_t)]
+    sort: SortBy,
+}
+
+#[async_trait]
+impl AsyncRunnable for Gc {
+    /// Start the application.
+    async fn run(&self) {
+        let open = self
+            .stash
+            .parse_stash()
+            .opener(self.stash.key())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        let mut report = gc::report(open, self.depth).expect("Failed to build report");
+
+        match self.sort {
+            SortBy::Size => {
+                report.commits.sort_by_key(|c| Reverse(c.reclaim.bytes));
+                report.prefixes.sort_by_key(|p| Reverse(p.reclaim.bytes));
+            }
+            SortBy::Name => report.prefixes.sort_by(|a, b| a.prefix.cmp(&b.prefix)),
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        _ = self.print(&mut stdout, &report);
+    }
+}
+
+impl Gc {
+    fn print(&self, out: &mut impl Write, report: &gc::Report) -> std::io::Result<()> {
+        writeln!(out, "Forgetting a single commit reclaims:")?;
+        for commit in report.commits.iter() {
+            let local_time = commit.time.with_timezone(&chrono::Local);
+            writeln!(
+                out,
+                "{:?}\t{}\t{}\t{}\t{}",
+                commit.id,
+                local_time.format("%Y %b %e %H:%M:%S"),
+                format_size(commit.reclaim.bytes, BINARY),
+                commit.reclaim.chunks,
+                commit.message.as_deref().unwrap_or("No commit message")
+            )?;
+        }
+
+        writeln!(out, "\nRemoving a path from all commits reclaims:")?;
+        for prefix in report.prefixes.iter() {
+            writeln!(
+                out,
+                "{}\t{}\t{}",
+                prefix.prefix,
+                format_size(prefix.reclaim.bytes, BINARY),
+                prefix.reclaim.chunks,
+            )?;
+        }
+
+        writeln!(
+            out,
+            "\nReferenced in total: {} in {} chunks",
+            format_size(report.total.bytes, BINARY),
+            report.total.chunks
+        )
+    }
+}
@@ -0,0 +1,58 @@
+//! `import` subcommand
+
+use crate::{migration::migration, prelude::*};
+use humansize::{format_size, BINARY};
+use zerostash_files::import;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Tar,
+}
+
+#[derive(Command, Debug)]
+pub struct Import {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Format of the archive read from stdin
+    #[clap(long, value_enum)]
+    format: Format,
+
+    /// Directory in the stash to import the archive into
+    #[clap(long, value_name = "PATH", default_value = "")]
+    prefix: String,
+
+    /// Commit message to include in the changeset
+    #[clap(short = 'm', long)]
+    message: Option<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Import {
+    /// Start the application.
+    async fn run(&self) {
+        self.stash.require_write();
+        let (mut stash, _lock) = self.stash.open_locked("import");
+        stash.load_all().unwrap();
+        migration(&mut stash);
+
+        let imported = match self.format {
+            Format::Tar => import::import_tar(&stash, std::io::stdin().lock(), &self.prefix),
+        }
+        .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        stash
+            .commit(self.message.clone())
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+
+        for skipped in imported.skipped.iter() {
+            eprintln!("Skipped {skipped}");
+        }
+        println!(
+            "Imported {} files, {}",
+            imported.files,
+            format_size(imported.bytes, BINARY)
+        );
+    }
+}
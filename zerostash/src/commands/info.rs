@@ -0,0 +1,90 @@
+//! `info` subcommand
+
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use humansize::{format_size, BINARY};
+use zerostash_files::{Retention, StashParams};
+
+#[derive(Command, Debug)]
+pub struct Info {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for Info {
+    /// Start the application.
+    async fn run(&self) {
+        let stash = self.stash.open();
+        let mut stdout = std::io::stdout().lock();
+
+        let (commits, last) = {
+            let list = stash.commit_list();
+            let last: Option<DateTime<Utc>> = list.iter().last().map(|c| c.metadata.time.into());
+            (list.iter().count(), last)
+        };
+
+        _ = writeln!(stdout, "Stash:         {}", self.stash.stash);
+        _ = writeln!(stdout, "Commits:       {commits}");
+        if let Some(time) = last {
+            let local_time = time.with_timezone(&chrono::Local);
+            _ = writeln!(
+                stdout,
+                "Last commit:   {}",
+                local_time.format("%Y %b %e %H:%M:%S")
+            );
+        }
+
+        _ = print_params(&mut stdout, &stash.index().params.read());
+
+        if let Some(policy) = stash.index().key_policy.read().as_ref() {
+            let allowed = policy
+                .allowed
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            _ = writeln!(stdout, "Key policy:    {}", allowed.join(", "));
+        }
+    }
+}
+
+/// Print the settings recorded in the stash
+pub(super) fn print_params(out: &mut impl Write, params: &StashParams) -> std::io::Result<()> {
+    writeln!(out, "Chunker:       {:?}", params.chunker)?;
+    writeln!(out, "Compression:   {:?}", params.compression)?;
+    writeln!(
+        out,
+        "Object size:   {} (objects are padded to this size)",
+        format_size(params.object_size, BINARY)
+    )?;
+    if let Some(key_type) = params.key_type {
+        writeln!(out, "Key type:      {key_type}")?;
+    }
+    writeln!(out, "Format:        {}", params.format_version)?;
+    writeln!(
+        out,
+        "Retention:     {}",
+        format_retention(&params.retention)
+    )?;
+    writeln!(out, "Undo window:   {}", params.undo_window)
+}
+
+fn format_retention(retention: &Retention) -> String {
+    if retention.is_empty() {
+        return "keep everything".into();
+    }
+
+    [
+        ("last", retention.keep_last),
+        ("hourly", retention.keep_hourly),
+        ("daily", retention.keep_daily),
+        ("weekly", retention.keep_weekly),
+        ("monthly", retention.keep_monthly),
+        ("yearly", retention.keep_yearly),
+    ]
+    .into_iter()
+    .filter_map(|(name, n)| n.map(|n| format!("{name} {n}")))
+    .chain(retention.keep_within.map(|w| format!("within {w}")))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
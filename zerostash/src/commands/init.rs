@@ -0,0 +1,132 @@
+//! `init` subcommand
+
+use crate::{
+    config::{Backend, Key},
+    prelude::*,
+};
+use std::io::IsTerminal;
+use zerostash_files::{Chunker, Retention, StashParams, Within, FORMAT_VERSION};
+
+#[derive(Command, Debug)]
+pub struct Init {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Chunking algorithm used to split files
+    #[clap(long, value_enum, default_value_t)]
+    chunker: Chunker,
+
+    /// Default retention policy for `forget`
+    #[clap(flatten)]
+    retention: Retention,
+
+    /// How long `undo` can revert forget, prune and zfs destroy
+    #[clap(long, value_name = "DURATION", default_value = "7d")]
+    undo_window: Within,
+
+    /// Save the stash in the config file under this name. Asked for
+    /// on a terminal if not given.
+    #[clap(long, value_name = "NAME")]
+    alias: Option<String>,
+
+    /// Add the saved stash to a group for `0s all`
+    #[clap(short, long = "group", requires = "alias")]
+    groups: Vec<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Init {
+    /// Start the application.
+    async fn run(&self) {
+        let params = StashParams {
+            chunker: self.chunker,
+            retention: self.retention.clone(),
+            undo_window: self.undo_window,
+            format_version: FORMAT_VERSION,
+            ..Default::default()
+        };
+
+        let configured = APP.config().resolve_stash(&self.stash.stash).is_some();
+        if let Some(alias) = &self.alias {
+            if configured || APP.config().resolve_stash(alias).is_some() {
+                fatal_error(format!("{alias} is already configured"));
+            }
+        }
+
+        let stash = self
+            .stash
+            .parse_stash()
+            .create(self.stash.key(), params)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        zerostash_files::Locks::setup(&stash).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        stash
+            .commit(Some("Initialize stash".into()))
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+        validate(&stash).unwrap_or_else(|e| {
+            fatal_error(format!(
+                "The stash was written to {}, but can't be read back: {e:#}",
+                self.stash.stash
+            ))
+        });
+
+        println!("Created stash {}", self.stash.stash);
+        _ = super::print_params(&mut std::io::stdout(), &stash.index().params.read());
+
+        if !configured {
+            self.save_alias();
+        }
+    }
+}
+
+impl Init {
+    /// Add the stash to the config file, so it can be used by name
+    fn save_alias(&self) {
+        let alias = match &self.alias {
+            Some(alias) => alias.clone(),
+            None if std::io::stdin().is_terminal() => rprompt::prompt_reply(
+                "Name to save the stash as in the config file (empty to skip): ",
+            )
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+            None => return,
+        };
+        if alias.is_empty() {
+            return;
+        }
+
+        // passwords given on the command line are asked for again
+        // instead of being saved
+        let key = if let Some(path) = &self.stash.keyfile {
+            Key::KeyFile { path: path.clone() }
+        } else if let Some(s) = &self.stash.keystring {
+            toml::from_str(s).unwrap_or_else(|e| fatal_error(e))
+        } else {
+            Key::default()
+        };
+
+        let mut stash = self.stash.parse_stash();
+        if let Backend::Filesystem { path, .. } = &mut stash.backend {
+            if let Ok(absolute) = std::path::absolute(&*path) {
+                *path = absolute.to_string_lossy().into_owned();
+            }
+        }
+        stash.key = key;
+        stash.groups = self.groups.clone();
+        super::stash::update_config(|config| config.add_stash(&alias, stash));
+        println!("Saved as {alias} in the config file");
+    }
+}
+
+/// Read back every object of the index, to make sure the backend
+/// stores what it's given
+fn validate(stash: &Stash) -> anyhow::Result<()> {
+    let backend = stash.backend();
+    for id in stash.index_object_ids() {
+        backend.read_object(&id)?;
+    }
+
+    Ok(())
+}
@@ -1 +1,56 @@
+//! `keygen` subcommand
 
+use crate::{config::raw_key, prelude::*};
+use rand::RngCore;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Command, Debug)]
+pub struct Keygen {
+    /// Where to write the key
+    path: PathBuf,
+
+    /// Write the key as text instead of raw bytes
+    #[clap(short, long)]
+    armor: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Keygen {
+    /// Start the application.
+    async fn run(&self) {
+        let mut bytes = [0; raw_key::RAW_KEY_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let key = bytes.into();
+
+        let contents = if self.armor {
+            format!("{}\n", raw_key::armor(&key)).into_bytes()
+        } else {
+            bytes.to_vec()
+        };
+        bytes.fill(0);
+
+        write_key_file(&self.path, &contents);
+
+        println!(
+            "Key written to {}. Use it with `key = {{ source = \"file\", path = \"{}\" }}`",
+            self.path.display(),
+            self.path.display()
+        );
+    }
+}
+
+/// Write a new key file that only the current user can read
+pub(crate) fn write_key_file(path: &Path, contents: &[u8]) {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .unwrap_or_else(|e| fatal_error(format!("{}: {e}", path.display())));
+}
@@ -1,9 +1,10 @@
-use crate::config::Key;
+use super::{keygen::write_key_file, user::current_key};
+use crate::config::{bundle, raw_key, shamir, KdfParams, Key};
 use crate::keygen::{GenKeyCmd, Generate, GenerateKey};
 use crate::prelude::*;
 use anyhow::{anyhow, bail};
 use clap::ArgGroup;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Command, Debug)]
 pub struct Keys {
@@ -26,6 +27,19 @@ pub enum KeyCommand {
     /// Change the keys for an existing stash
     #[clap(alias = "ch")]
     Change(Change),
+    /// Save the password of a stash in the OS keychain
+    Store(Store),
+    /// Add a recovery key to the stash, and split it into shares
+    Split(Split),
+    /// Recover a key from its shares
+    Recover(Recover),
+    /// Export a key as a passphrase protected bundle
+    Export(Export),
+    /// Import a key from a bundle created by `export`
+    Import(Import),
+    /// Print an S3 policy that limits storage credentials to the role
+    /// of a key
+    Policy(Policy),
 }
 
 #[async_trait]
@@ -35,6 +49,12 @@ impl AsyncRunnable for KeyCommand {
         match self {
             Generate(g) => g.run().await,
             Change(c) => c.run().await,
+            Store(s) => s.run().await,
+            Split(s) => s.run().await,
+            Recover(r) => r.run().await,
+            Export(e) => e.run().await,
+            Import(i) => i.run().await,
+            Policy(p) => p.run().await,
         }
     }
 }
@@ -58,13 +78,282 @@ impl AsyncRunnable for Change {
             .key(old_key, &stash_cfg.alias)
             .unwrap_or_else(|_| fatal_error("Invalid new key"));
 
-        let stash = stash_cfg.try_open(Some(key)).unwrap();
+        let stash = stash_cfg
+            .try_open(Some(key))
+            .and_then(|outcome| outcome.into_stash(&stash_cfg.alias))
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
         if stash.reseal().is_err() {
             fatal_error("Failed to change key");
         }
     }
 }
 
+#[derive(Command, Debug)]
+pub struct Store {
+    /// Stash path or alias
+    stash: String,
+
+    /// Username. Defaults to the one in the stash's `keychain` key
+    #[clap(short, long)]
+    user: Option<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Store {
+    async fn run(&self) {
+        let stash_cfg = self
+            .stash
+            .parse::<crate::config::Stash>()
+            .unwrap_or_else(|e| fatal_error(e));
+
+        let user = match (&self.user, &stash_cfg.key) {
+            (Some(user), _) => user.clone(),
+            (None, Key::Keychain(k)) => k.user.clone(),
+            (None, _) => fatal_error("No `keychain` key configured for the stash, use --user"),
+        };
+
+        let password = rpassword::prompt_password("Password: ").unwrap_or_else(|e| fatal_error(e));
+        let confirm =
+            rpassword::prompt_password("Repeat password: ").unwrap_or_else(|e| fatal_error(e));
+        if password != confirm {
+            fatal_error("Passwords don't match");
+        }
+
+        crate::config::store_password(&stash_cfg.alias, &user, &password.into())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!("Password for {user} saved in the keychain");
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Split {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Number of shares to create
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(2..))]
+    shares: u8,
+
+    /// Number of shares needed to recover the key
+    #[clap(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(2..))]
+    threshold: u8,
+
+    /// Name of the key slot of the recovery key
+    #[clap(long, default_value = "recovery")]
+    slot: String,
+
+    /// Write every share into a separate file in this directory
+    /// instead of printing them
+    #[clap(short, long, value_name = "PATH")]
+    output_dir: Option<PathBuf>,
+
+    /// Name of the slot that keeps the current credentials working if
+    /// the stash isn't shared yet. Defaults to the current username.
+    #[clap(long)]
+    owner: Option<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Split {
+    /// The master key of the stash never leaves it, instead a random
+    /// recovery key gets a key slot, which can be revoked with `0s user
+    /// remove`.
+    async fn run(&self) {
+        self.stash.require_write();
+        let stash_cfg = self.stash.parse_stash();
+        let (current, current_user) = current_key(&self.stash);
+
+        let mut bytes = [0; raw_key::RAW_KEY_LEN];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+        let recovery: infinitree::crypto::RawKey = bytes.into();
+        bytes.fill(0);
+
+        let shares = shamir::split(&recovery, self.shares, self.threshold)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        let owner = self
+            .owner
+            .clone()
+            .or(current_user)
+            .unwrap_or_else(|| "owner".into());
+
+        stash_cfg
+            .add_key_slot(current, &owner, Key::Raw(recovery), &self.slot)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!(
+            "Split the recovery key of {} into {} shares, {} of which recover it.\n",
+            stash_cfg.alias, self.shares, self.threshold
+        );
+
+        for share in shares {
+            match &self.output_dir {
+                Some(dir) => {
+                    let path = dir.join(format!("{}-share-{}.txt", self.slot, share.index()));
+                    write_key_file(&path, format!("{}\n", share.armor()).as_bytes());
+                    println!("Share {}: {}", share.index(), path.display());
+                }
+                None => println!("Share {}: {}", share.index(), share.armor()),
+            }
+        }
+
+        println!(
+            "\nRecover the key with `0s keys recover`, and delete its slot with `0s user remove-slot {} {}`",
+            self.stash.stash, self.slot
+        );
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Recover {
+    /// Where to write the recovered key
+    path: PathBuf,
+
+    /// Shares of the key. Asked for interactively if not given
+    shares: Vec<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Recover {
+    async fn run(&self) {
+        let mut shares = self
+            .shares
+            .iter()
+            .map(|s| shamir::Share::parse(s))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if shares.is_empty() {
+            loop {
+                let line = rprompt::prompt_reply(format!("Share {}: ", shares.len() + 1))
+                    .unwrap_or_else(|e| fatal_error(e));
+                if line.trim().is_empty() {
+                    break;
+                }
+
+                match shamir::Share::parse(&line) {
+                    Ok(share) => shares.push(share),
+                    Err(e) => println!("{e:#}, try again"),
+                }
+
+                if shares
+                    .first()
+                    .is_some_and(|s| shares.len() >= s.threshold() as usize)
+                {
+                    break;
+                }
+            }
+        }
+
+        let key = shamir::combine(&shares).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        write_key_file(&self.path, format!("{}\n", raw_key::armor(&key)).as_bytes());
+
+        println!(
+            "Key written to {}. Open the stash with `-k {}`",
+            self.path.display(),
+            self.path.display()
+        );
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Export {
+    /// Key file, or the alias of a stash to export the key of
+    source: String,
+
+    /// Write the bundle to a file instead of printing it
+    #[clap(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    kdf: KdfParams,
+}
+
+#[async_trait]
+impl AsyncRunnable for Export {
+    async fn run(&self) {
+        let contents =
+            export_contents(&self.source).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!("Passphrase to protect the bundle:\n");
+        let passphrase =
+            rpassword::prompt_password("Passphrase: ").unwrap_or_else(|e| fatal_error(e));
+        let confirm =
+            rpassword::prompt_password("Repeat passphrase: ").unwrap_or_else(|e| fatal_error(e));
+        if passphrase != confirm {
+            fatal_error("Passphrases don't match");
+        }
+        if passphrase.is_empty() {
+            fatal_error("The passphrase can't be empty");
+        }
+
+        let armored = bundle::seal(&contents, &passphrase.into(), &self.kdf)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        match &self.output {
+            Some(path) => {
+                write_key_file(path, armored.as_bytes());
+                println!("Bundle written to {}", path.display());
+            }
+            None => print!("\n{armored}"),
+        }
+    }
+}
+
+/// The contents of the key file, or the key of a configured stash
+fn export_contents(source: &str) -> anyhow::Result<Vec<u8>> {
+    let path = Path::new(source);
+    if path.is_file() {
+        return Ok(std::fs::read(path)?);
+    }
+
+    let stash_cfg = source.parse::<crate::config::Stash>()?;
+    match &stash_cfg.key {
+        Key::KeyFile { path } => Ok(std::fs::read(path)?),
+        Key::Interactive | Key::Keychain(_) => {
+            bail!("The key of {source} isn't stored in the configuration")
+        }
+        Key::Userpass(k) if k.password.is_none() => {
+            bail!("The key of {source} has no password in the configuration")
+        }
+        key => Ok(toml::to_string(key)?.into_bytes()),
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Import {
+    /// File with the bundle. Read from standard input if not given
+    #[clap(short, long, value_name = "PATH")]
+    input: Option<PathBuf>,
+
+    /// Where to write the key file
+    path: PathBuf,
+}
+
+#[async_trait]
+impl AsyncRunnable for Import {
+    async fn run(&self) {
+        let armored = match &self.input {
+            Some(path) => std::fs::read_to_string(path),
+            None => std::io::read_to_string(std::io::stdin()),
+        }
+        .unwrap_or_else(|e| fatal_error(e));
+
+        let passphrase =
+            rpassword::prompt_password("Passphrase: ").unwrap_or_else(|e| fatal_error(e));
+        let contents = bundle::open(&armored, &passphrase.into())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        write_key_file(&self.path, &contents);
+        println!(
+            "Key written to {}. Use it with `key = {{ source = \"file\", path = \"{}\" }}`",
+            self.path.display(),
+            self.path.display()
+        );
+    }
+}
+
 #[derive(clap::Subcommand, Debug, Clone)]
 pub enum ChangeCmd {
     Toml(ChangeTo),
@@ -145,3 +434,31 @@ impl ChangeTo {
         unreachable!()
     }
 }
+
+#[derive(Command, Debug)]
+pub struct Policy {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for Policy {
+    /// Write-only, read-only and list-only keys are only limited by the
+    /// client when it comes to modifying the stash, so the storage
+    /// credentials that go with them need to be limited as well.
+    async fn run(&self) {
+        let stash_cfg = self.stash.parse_stash();
+        let role = self
+            .stash
+            .key()
+            .unwrap_or_else(|| stash_cfg.key.clone())
+            .role()
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        let Some(policy) = stash_cfg.backend.storage_policy(role) else {
+            fatal_error("Storage policies can only be generated for stashes on S3");
+        };
+
+        println!("{}", serde_json::to_string_pretty(&policy).unwrap());
+    }
+}
@@ -0,0 +1,118 @@
+//! `locks` subcommand
+
+use crate::{
+    commands::{json_line, report_reconstructed},
+    prelude::*,
+};
+use serde::Serialize;
+use zerostash_files::{legacy, Lock};
+
+#[derive(Command, Debug)]
+pub enum Locks {
+    /// List the locks on the stash
+    List(ListLocks),
+    /// Remove locks left behind by crashed clients
+    Unlock(Unlock),
+}
+
+#[async_trait]
+impl AsyncRunnable for Locks {
+    async fn run(&self) {
+        use Locks::*;
+        match self {
+            List(c) => c.run().await,
+            Unlock(c) => c.run().await,
+        }
+    }
+}
+
+/// A lock as `locks list --output json` prints it
+#[derive(Serialize)]
+struct LockLine<'a> {
+    id: &'a str,
+    stale: bool,
+    #[serde(flatten)]
+    lock: &'a Lock,
+}
+
+#[derive(Command, Debug)]
+pub struct ListLocks {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for ListLocks {
+    async fn run(&self) {
+        let stash = self.stash.open();
+        report_reconstructed(legacy::load_lock_key(&stash));
+
+        let locks = match zerostash_files::Locks::open(&stash) {
+            Ok(Some(locks)) => locks
+                .list()
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}"))),
+            Ok(None) => return,
+            Err(e) => fatal_error(format!("{e:#}")),
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let mut stdout = std::io::stdout().lock();
+        for (id, lock) in locks.iter() {
+            let stale = lock.is_stale(now);
+            let printed = match APP.output().is_json() {
+                true => json_line(&mut stdout, &LockLine { id, stale, lock }),
+                false => writeln!(stdout, "{id}\t{lock}{}", if stale { "\tstale" } else { "" }),
+            };
+            if printed.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Unlock {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// IDs of the locks to remove, as shown by `locks list`
+    #[clap(required_unless_present_any = ["stale", "all"])]
+    ids: Vec<String>,
+
+    /// Remove the locks that weren't refreshed recently
+    #[clap(long, conflicts_with_all = ["ids", "all"])]
+    stale: bool,
+
+    /// Remove every lock, even if its holder may still be running
+    #[clap(long, conflicts_with = "ids")]
+    all: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Unlock {
+    async fn run(&self) {
+        self.stash.require_write();
+        let stash = self.stash.open();
+        report_reconstructed(legacy::load_lock_key(&stash));
+
+        let Some(locks) =
+            zerostash_files::Locks::open(&stash).unwrap_or_else(|e| fatal_error(format!("{e:#}")))
+        else {
+            fatal_error("The stash has no locks");
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let removed = locks
+            .unlock(|id, lock| {
+                self.all || (self.stale && lock.is_stale(now)) || self.ids.iter().any(|i| i == id)
+            })
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        for id in self.ids.iter().filter(|id| !removed.contains_key(*id)) {
+            eprintln!("No lock {id} on the stash");
+        }
+        for (id, lock) in removed.iter() {
+            println!("Removed {id}\t{lock}");
+        }
+    }
+}
@@ -1,12 +1,45 @@
 //! `log` subcommand
 
-use crate::prelude::*;
-use chrono::{DateTime, Utc};
+use crate::{
+    commands::{json_line, report_reconstructed},
+    prelude::*,
+};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use humansize::{format_size, BINARY};
+use serde::Serialize;
+use zerostash_files::legacy;
 
 #[derive(Command, Debug)]
 pub struct Log {
     #[clap(flatten)]
     stash: StashArgs,
+
+    /// Only show the latest N commits
+    #[clap(short = 'n', long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Only show commits made at or after this time, given as a date,
+    /// a local time like `2024-03-01 18:00`, or in RFC 3339
+    #[clap(long, value_name = "TIME", value_parser = parse_since)]
+    since: Option<DateTime<Utc>>,
+
+    /// Print the commits as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// A commit as `log --json` prints it. Commits made by older versions,
+/// or not by `commit`, don't have an author and changes.
+#[derive(Serialize)]
+struct LogEntry {
+    id: String,
+    time: DateTime<Local>,
+    message: Option<String>,
+    author: Option<String>,
+    hostname: Option<String>,
+    files_added: Option<u64>,
+    files_changed: Option<u64>,
+    bytes_written: Option<u64>,
 }
 
 #[async_trait]
@@ -14,28 +47,96 @@ impl AsyncRunnable for Log {
     /// Start the application.
     async fn run(&self) {
         let stash = self.stash.open();
-        let mut stdout = std::io::stdout().lock();
+        report_reconstructed(legacy::load_commit_info(&stash));
 
+        let mut parent = None;
+        let mut entries = vec![];
         for commit in stash.commit_list().iter() {
+            let info = stash.index().info_after(parent);
+            parent = Some(commit.id);
+
+            if info.as_ref().is_some_and(|i| i.checkpoint) {
+                continue;
+            }
+
             let time: DateTime<Utc> = commit.metadata.time.into();
-            let local_time = time.with_timezone(&chrono::Local);
-            let formatted_time = local_time.format("%Y %b %e %H:%M:%S").to_string();
-
-            if writeln!(
-                stdout,
-                "{:?}\t{}\t{}",
-                commit.id,
-                formatted_time,
-                commit
-                    .metadata
-                    .message
-                    .as_ref()
-                    .unwrap_or(&"No commit message".to_string())
-            )
-            .is_err()
-            {
+            if self.since.is_some_and(|since| time < since) {
+                continue;
+            }
+
+            entries.push(LogEntry {
+                id: format!("{:?}", commit.id),
+                time: time.with_timezone(&Local),
+                message: commit.metadata.message.clone(),
+                author: info.as_ref().map(|i| i.author.clone()),
+                hostname: info.as_ref().map(|i| i.hostname.clone()),
+                files_added: info.as_ref().map(|i| i.files_added),
+                files_changed: info.as_ref().map(|i| i.files_changed),
+                bytes_written: info.as_ref().map(|i| i.bytes_written),
+            });
+        }
+
+        if let Some(limit) = self.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+            return;
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        for entry in entries.iter() {
+            let printed = match APP.output().is_json() {
+                true => json_line(&mut stdout, entry),
+                false => print(&mut stdout, entry),
+            };
+            if printed.is_err() {
                 break;
             }
         }
     }
 }
+
+fn print(out: &mut impl Write, entry: &LogEntry) -> std::io::Result<()> {
+    let origin = match (&entry.author, &entry.hostname) {
+        (Some(author), Some(hostname)) => format!("{author}@{hostname}"),
+        _ => "-".to_string(),
+    };
+    let changes = match (entry.files_added, entry.files_changed, entry.bytes_written) {
+        (Some(added), Some(changed), Some(bytes)) => {
+            format!("+{added} ~{changed} {}", format_size(bytes, BINARY))
+        }
+        _ => "-".to_string(),
+    };
+
+    writeln!(
+        out,
+        "{}\t{}\t{origin}\t{changes}\t{}",
+        entry.id,
+        entry.time.format("%Y %b %e %H:%M:%S"),
+        entry.message.as_deref().unwrap_or("No commit message")
+    )
+}
+
+/// A day means the start of it, in local time
+fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let local = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| format!("invalid time `{s}`, expected a date"))?;
+
+    Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("invalid time `{s}`"))
+}
@@ -1,10 +1,14 @@
 //! `ls` subcommand
 
-use crate::prelude::*;
+use crate::{
+    commands::{json_line, load_tree, report_reconstructed, CommitSelector},
+    prelude::*,
+};
 use abscissa_core::terminal::{stderr, stdout};
 use chrono::{DateTime, Utc};
 use humansize::{format_size, BINARY};
-use std::{io::Write, sync::Arc, writeln};
+use serde::Serialize;
+use std::{io::Write, path::PathBuf, sync::Arc, writeln};
 use termcolor::{Color, ColorSpec, StandardStreamLock, WriteColor};
 use zerostash_files::*;
 
@@ -15,6 +19,11 @@ pub struct Ls {
     #[clap(flatten)]
     stash: StashArgs,
 
+    /// List the files as of a commit ID or tag, or the last commit before a
+    /// date like `2024-03-01` or `2024-03-01 18:00`
+    #[clap(long, value_name = "COMMIT|TIME", conflicts_with = "commit_id")]
+    at: Option<CommitSelector>,
+
     #[clap(short = 'l', long)]
     list: bool,
 
@@ -29,16 +38,27 @@ pub struct Ls {
 impl AsyncRunnable for Ls {
     /// Start the application.
     async fn run(&self) {
+        let options = zerostash_files::restore::Options {
+            scope: self.stash.token_scope(),
+            ..self.options.clone()
+        };
+
         let stash = self.stash.open();
-        stash.load(stash.index().tree()).unwrap();
+        if let Some(at) = &self.at {
+            let commit = at.resolve(&stash).unwrap_or_else(|e| fatal_error(e));
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
+        }
+        load_tree(&stash);
+        report_reconstructed(legacy::load_tags(&stash));
         let printer = match self.list {
+            _ if APP.output().is_json() => self.print_json(),
             false => self.print_simple(),
             true => self.print_list(),
         };
 
         let mut stdout = stdout().lock();
         let mut count = 0;
-        for item in self.options.list(&stash) {
+        for item in options.list(&stash) {
             let (path, entry) = (item.0, item.1);
             count += 1;
 
@@ -51,7 +71,45 @@ impl AsyncRunnable for Ls {
     }
 }
 
+/// A file as `--output json` prints it
+#[derive(Serialize)]
+struct LsEntry {
+    path: String,
+    #[serde(rename = "type")]
+    file_type: &'static str,
+    /// Target of a symlink, or the other name of a hardlink
+    target: Option<PathBuf>,
+    size: u64,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: DateTime<Utc>,
+}
+
 impl Ls {
+    fn print_json(&self) -> Printer {
+        Box::new(|stdout, path, entry| {
+            // the same names as `type` in filter expressions
+            let (file_type, target) = match &entry.file_type {
+                FileType::Symlink(target) => ("symlink", Some(target.clone())),
+                FileType::Hardlink(other) => ("hardlink", Some(other.into())),
+                other => (query::type_name(other), None),
+            };
+
+            let entry = LsEntry {
+                path,
+                file_type,
+                target,
+                size: entry.size,
+                mode: entry.unix_perm,
+                uid: entry.unix_uid,
+                gid: entry.unix_gid,
+                mtime: entry.as_ref().into(),
+            };
+            json_line(stdout, &entry)
+        })
+    }
+
     fn print_simple(&self) -> Printer {
         Box::new(|stdout, path, _| writeln!(stdout, "{}", path))
     }
@@ -81,7 +139,7 @@ impl Ls {
             let group = get_gid(entry.unix_gid);
 
             let file_color = match entry.file_type {
-                FileType::File => ColorSpec::new(),
+                FileType::File | FileType::Hardlink(_) => ColorSpec::new(),
                 FileType::Directory => ColorSpec::new()
                     .set_fg(Some(Color::Red))
                     .set_bold(true)
@@ -90,6 +148,10 @@ impl Ls {
                     .set_fg(Some(Color::Blue))
                     .set_bold(true)
                     .clone(),
+                FileType::Fifo
+                | FileType::Socket
+                | FileType::CharDevice(_)
+                | FileType::BlockDevice(_) => ColorSpec::new().set_fg(Some(Color::Yellow)).clone(),
             };
 
             print(stdout, ColorSpec::new(), mode)?;
@@ -100,10 +162,10 @@ impl Ls {
             print(
                 stdout,
                 file_color,
-                if let FileType::Symlink(ref target) = entry.file_type {
-                    format!("{} -> {:?}", path, target)
-                } else {
-                    path
+                match entry.file_type {
+                    FileType::Symlink(ref target) => format!("{} -> {:?}", path, target),
+                    FileType::Hardlink(ref other) => format!("{} => {}", path, other),
+                    _ => path,
                 },
             )?;
 
@@ -1,6 +1,11 @@
 //! `mount` subcommand
 
-use crate::{migration::migration, prelude::*};
+use crate::{
+    commands::{load_tree, CommitSelector},
+    migration::migration,
+    prelude::*,
+};
+use std::num::NonZeroUsize;
 
 #[derive(Command, Debug)]
 pub struct Mount {
@@ -17,6 +22,39 @@ pub struct Mount {
     /// Mounts the filesystem read-write
     #[clap(short = 'w', long = "read-write")]
     read_write: bool,
+
+    /// Keep the stash read-only, and write all changes to this
+    /// directory instead. Use `overlay commit` to save them.
+    #[clap(long, value_name = "PATH", conflicts_with = "read_write")]
+    overlay: Option<std::path::PathBuf>,
+
+    /// Mount the files as of a commit ID or tag, or the last commit before a
+    /// date like `2024-03-01` or `2024-03-01 18:00`. Always read-only
+    #[clap(
+        long,
+        alias = "before",
+        value_name = "COMMIT|TIME",
+        conflicts_with_all = ["commit_id", "read_write", "overlay"]
+    )]
+    at: Option<CommitSelector>,
+
+    /// Show every commit in a read-only directory named after its
+    /// time at the root of the mount
+    #[clap(long, conflicts_with_all = ["at", "commit_id", "read_write", "overlay"])]
+    history: bool,
+
+    /// Keep the objects read from a remote stash in this directory,
+    /// so the next mount doesn't download them again
+    #[clap(long, value_name = "DIR")]
+    cache_dir: Option<std::path::PathBuf>,
+
+    /// Size of the object cache in MiB
+    #[clap(long, value_name = "MIB", default_value = "1024")]
+    cache_size: NonZeroUsize,
+
+    /// Don't cache objects on disk
+    #[clap(long, conflicts_with = "cache_dir")]
+    no_cache: bool,
 }
 
 #[cfg(unix)]
@@ -24,16 +62,123 @@ pub struct Mount {
 impl AsyncRunnable for Mount {
     /// Start the application.
     async fn run(&self) {
-        let mut stash = self.stash.open();
+        if !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+        if self
+            .stash
+            .token_scope()
+            .is_some_and(|scope| scope.prefix.is_some())
+        {
+            fatal_error("Tokens limited to a path prefix can't mount the stash");
+        }
+
+        if self.history {
+            return self.mount_history().await;
+        }
+
+        let (mut stash, _lock) = if self.read_write {
+            let (stash, lock) = self.open_locked();
+            (stash, Some(lock))
+        } else {
+            (self.open(), None)
+        };
+        if let Some(at) = &self.at {
+            let commit = at.resolve(&stash).unwrap_or_else(|e| fatal_error(e));
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
+        }
         let threads = APP.get_worker_threads();
-        stash.load(stash.index().tree()).unwrap();
-        stash.load(stash.index().files()).unwrap();
-        migration(&mut stash);
+        load_tree(&stash);
+        if self.read_write {
+            migration(&mut stash);
+        }
+
+        crate::systemd::spawn_watchdog();
+        let ready = || {
+            _ = crate::systemd::notify("READY=1");
+        };
+
+        let result = match &self.overlay {
+            Some(upper) => {
+                zerostash_fuse::overlay::mount(
+                    stash,
+                    &self.mount_point,
+                    upper.clone(),
+                    threads,
+                    ready,
+                )
+                .await
+            }
+            None => {
+                zerostash_fuse::mount::mount(
+                    stash,
+                    &self.mount_point,
+                    threads,
+                    self.read_write,
+                    ready,
+                )
+                .await
+            }
+        };
+
+        if let Err(e) = result {
+            panic!("Error = {}", e)
+        }
 
+        _ = crate::systemd::notify("STOPPING=1");
+    }
+}
+
+#[cfg(unix)]
+impl Mount {
+    /// Open the stash with a persistent object cache, unless its
+    /// objects are already on a local disk
+    fn open(&self) -> Stash {
+        self.stash.open_config(self.config(), self.stash.key())
+    }
+
+    /// Open the stash like [`Self::open`], locked while it's mounted
+    /// read-write
+    fn open_locked(&self) -> (Stash, zerostash_files::LockGuard) {
+        self.stash
+            .open_config_locked(self.config(), self.stash.key(), "mount")
+    }
+
+    fn config(&self) -> crate::config::Stash {
+        let mut config = self.stash.parse_stash();
+        if self.no_cache {
+            return config;
+        }
+
+        let dir = self
+            .cache_dir
+            .clone()
+            .unwrap_or_else(|| config.mount_cache_dir());
+
+        config.backend = config
+            .backend
+            .with_cache(dir.to_string_lossy().into_owned(), self.cache_size);
+        config
+    }
+
+    async fn mount_history(&self) {
+        let open = self
+            .config()
+            .opener(self.stash.key())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        crate::systemd::spawn_watchdog();
+        let ready = || {
+            _ = crate::systemd::notify("READY=1");
+        };
+
+        let threads = APP.get_worker_threads();
         if let Err(e) =
-            zerostash_fuse::mount::mount(stash, &self.mount_point, threads, self.read_write).await
+            zerostash_fuse::history::mount(open, &self.mount_point, threads, ready).await
         {
-            panic!("Error = {}", e)
+            fatal_error(format!("{e:#}"));
         }
+
+        _ = crate::systemd::notify("STOPPING=1");
     }
 }
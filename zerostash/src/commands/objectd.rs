@@ -0,0 +1,39 @@
+//! `objectd` subcommand
+
+use crate::prelude::*;
+use std::{path::PathBuf, str::FromStr};
+use zerostash_files::backends::socket;
+
+/// Serve the objects of a stash to other `0s` processes over a unix socket
+///
+/// The server only handles encrypted objects, and never needs the key
+/// to the stash.
+#[derive(Command, Debug)]
+pub struct Objectd {
+    /// Stash path or alias
+    stash: String,
+
+    /// Path of the socket to listen on
+    #[clap(short, long, value_name = "PATH")]
+    socket: PathBuf,
+}
+
+#[async_trait]
+impl AsyncRunnable for Objectd {
+    async fn run(&self) {
+        let stash = crate::config::Stash::from_str(&self.stash).unwrap();
+        let backend = stash
+            .backend
+            .to_infinitree()
+            .unwrap_or_else(|e| fatal_error(e));
+
+        let listener = socket::bind(&self.socket).expect("Can't listen on socket");
+        println!("Serving objects on {}", self.socket.display());
+
+        crate::systemd::spawn_watchdog();
+        _ = crate::systemd::notify("READY=1");
+
+        abscissa_tokio::tokio::task::block_in_place(|| socket::serve(listener, backend))
+            .expect("Object server failed");
+    }
+}
@@ -0,0 +1,78 @@
+//! `overlay` subcommand
+
+use crate::{migration::migration, prelude::*};
+use std::path::PathBuf;
+use tracing::{info_span, Instrument};
+
+#[derive(Command, Debug)]
+pub enum Overlay {
+    /// Fold the changes made in an overlay mount into a new commit
+    Commit(OverlayCommit),
+}
+
+#[async_trait]
+impl AsyncRunnable for Overlay {
+    async fn run(&self) {
+        use Overlay::*;
+        match self {
+            Commit(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct OverlayCommit {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// The directory used as `--overlay` for the mount
+    overlay: PathBuf,
+
+    #[clap(flatten)]
+    preserve: zerostash_files::PreserveMetadata,
+
+    /// Commit message to include in the changeset
+    #[clap(short = 'm', long)]
+    message: Option<String>,
+
+    /// Empty the overlay directory after a successful commit
+    #[clap(long)]
+    clear: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for OverlayCommit {
+    async fn run(&self) {
+        self.stash.require_write();
+        let (mut stash, _lock) = self.stash.open_locked("overlay commit");
+        stash.load_all().unwrap();
+        migration(&mut stash);
+
+        zerostash_files::store::add_overlay(
+            &stash,
+            &self.overlay,
+            &self.preserve,
+            APP.get_worker_threads(),
+        )
+        .instrument(info_span!("overlay", stash = %self.stash.stash))
+        .await
+        .unwrap();
+
+        stash
+            .commit(self.message.clone())
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+
+        if self.clear {
+            for entry in std::fs::read_dir(&self.overlay).expect("Can't read overlay") {
+                let path = entry.expect("Can't read overlay").path();
+                if path.is_dir() {
+                    std::fs::remove_dir_all(path)
+                } else {
+                    std::fs::remove_file(path)
+                }
+                .expect("Failed to clear overlay");
+            }
+        }
+    }
+}
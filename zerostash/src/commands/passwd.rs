@@ -0,0 +1,170 @@
+//! `passwd` subcommand
+
+use crate::config::{KdfParams, Key, SymmetricKey};
+use crate::prelude::*;
+
+#[derive(Command, Debug)]
+pub struct Passwd {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// New username. Asked for interactively if not given
+    #[clap(long, value_name = "USER")]
+    new_user: Option<String>,
+
+    /// Stretch the new password with Argon2id, using the `--kdf-*`
+    /// parameters. Without it, the parameters of the current key are
+    /// kept.
+    #[clap(long)]
+    kdf: bool,
+
+    #[clap(flatten)]
+    kdf_params: KdfParams,
+}
+
+#[async_trait]
+impl AsyncRunnable for Passwd {
+    /// Re-wrap the master key of the stash with new credentials.
+    ///
+    /// Data objects are encrypted with keys derived from the master
+    /// key, so only the root object needs to be rewritten.
+    async fn run(&self) {
+        self.stash.require_write();
+        let mut stash_cfg = self.stash.parse_stash();
+        let stash_name = stash_cfg.alias.clone();
+
+        // ask for the current credentials before the new ones
+        let old_key = match self.stash.key().unwrap_or_else(|| stash_cfg.key.clone()) {
+            Key::Interactive => current_credentials(SymmetricKey::default(), &stash_name),
+            Key::Userpass(k) if k.password.is_none() => current_credentials(k, &stash_name),
+            key => key,
+        };
+        // stashes whose parameters are only configured get them
+        // stored with the new password
+        let old_key = stash_cfg
+            .with_stored_kdf(old_key)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        let keychain_user = match &old_key {
+            Key::Keychain(k) => Some(k.user.clone()),
+            _ => None,
+        };
+
+        let kdf = match &old_key {
+            _ if self.kdf => Some(self.kdf_params),
+            Key::Userpass(k) => k.kdf,
+            _ => None,
+        };
+        if kdf.is_some() && keychain_user.is_some() {
+            fatal_error("Stretching the password is not supported for keychain keys");
+        }
+
+        println!("New credentials:\n");
+        let user = self
+            .new_user
+            .clone()
+            .or_else(|| keychain_user.clone())
+            .unwrap_or_else(|| {
+                rprompt::prompt_reply("Username: ").unwrap_or_else(|e| fatal_error(e))
+            });
+        let password = new_password();
+
+        let new_key = Key::Userpass(SymmetricKey {
+            user: Some(user.clone().into()),
+            password: Some(password.clone().into()),
+            keychain: false,
+            kdf,
+        });
+
+        // users of a shared stash only have their own key slot
+        // replaced
+        let shared = stash_cfg
+            .key_slots(old_key.clone())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        if !shared.is_empty() {
+            let slot = stash_cfg
+                .change_key_slot(old_key, new_key)
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+            println!("Password changed for the key slot {slot}");
+            print_kdf_hint(&stash_cfg, kdf);
+            return;
+        }
+
+        // the root object is replaced in place, so make sure a crash
+        // leaves either the old or the new one behind
+        stash_cfg.backend = stash_cfg.backend.with_atomic_writes();
+
+        let stash = stash_cfg
+            .try_open(Some(old_key.change_to(new_key.clone())))
+            .and_then(|outcome| outcome.into_stash(&stash_name))
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if let Err(e) = stash.reseal() {
+            fatal_error(format!("Failed to change password: {e}"));
+        }
+        stash_cfg
+            .store_kdf(&new_key)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!("Password changed");
+        print_kdf_hint(&stash_cfg, kdf);
+
+        if keychain_user.as_ref() == Some(&user) {
+            crate::config::store_password(&stash_name, &user, &password.into())
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+            println!("Password for {user} updated in the keychain");
+        } else if let Key::Userpass(SymmetricKey {
+            password: Some(_), ..
+        }) = &stash_cfg.key
+        {
+            println!("The configuration of {stash_name} still holds the old password, update it to keep using the stash");
+        }
+    }
+}
+
+/// The KDF parameters are stored in the stash, but configured ones
+/// take precedence, so they have to match
+fn print_kdf_hint(stash_cfg: &crate::config::Stash, kdf: Option<KdfParams>) {
+    let configured = match &stash_cfg.key {
+        Key::Userpass(k) => k.kdf,
+        _ => None,
+    };
+
+    if configured.is_some() && configured != kdf {
+        println!(
+            "Remove `kdf` from the key of {} in the configuration to open it, the parameters are stored in the stash",
+            stash_cfg.alias
+        );
+    }
+}
+
+fn current_credentials(key: SymmetricKey, stash: &str) -> Key {
+    println!("Current credentials for the stash:\n");
+    let kdf = key.kdf;
+    let (user, password) = key
+        .interactive_credentials(stash)
+        .unwrap_or_else(|e| fatal_error(e));
+    println!();
+
+    Key::Userpass(SymmetricKey {
+        user: Some(user),
+        password: Some(password),
+        keychain: false,
+        kdf,
+    })
+}
+
+fn new_password() -> String {
+    let password = rpassword::prompt_password("Password: ").unwrap_or_else(|e| fatal_error(e));
+    let confirm =
+        rpassword::prompt_password("Repeat password: ").unwrap_or_else(|e| fatal_error(e));
+
+    if password != confirm {
+        fatal_error("Passwords don't match");
+    }
+    if password.is_empty() {
+        fatal_error("The password can't be empty");
+    }
+
+    password
+}
@@ -0,0 +1,210 @@
+//! `prune` subcommand
+
+use crate::prelude::*;
+use humansize::{format_size, BINARY};
+use infinitree::{object::ObjectId, ChunkPointer};
+use std::{collections::HashSet, sync::Arc};
+use zerostash_files::{
+    backends::{Relocation, Relocations},
+    gc, RefLogEntry, OBJECT_SIZE,
+};
+
+#[derive(Command, Debug)]
+pub struct Prune {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Only print what would be deleted
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Prune {
+    /// Start the application.
+    async fn run(&self) {
+        self.stash.require_delete();
+        let (stash, _lock) = if self.dry_run {
+            (self.stash.open(), None)
+        } else {
+            let (stash, lock) = self.stash.open_locked("prune");
+            (stash, Some(lock))
+        };
+        stash.load_all().unwrap();
+
+        prune(&self.stash, &stash, self.dry_run);
+    }
+}
+
+/// Delete the objects that only forgotten commits use
+pub(crate) fn prune(args: &StashArgs, stash: &Stash, dry_run: bool) {
+    let forgotten = stash
+        .index()
+        .forgotten
+        .read()
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>();
+    if forgotten.is_empty() {
+        println!("No commits were forgotten, there's nothing to prune");
+        return;
+    }
+
+    let open = args
+        .parse_stash()
+        .opener(args.key())
+        .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+    let plan = gc::prune_plan(open, &forgotten).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+    println!(
+        "{} chunks are only used by forgotten commits",
+        plan.dead_chunks.len()
+    );
+    println!(
+        "Deleting {} objects reclaims {} in {} chunks",
+        plan.dead_objects.len(),
+        format_size(plan.reclaimed.bytes, BINARY),
+        plan.reclaimed.chunks
+    );
+    if !plan.partial_objects.is_empty() {
+        let stranded = plan
+            .partial_objects
+            .iter()
+            .map(|(_, u)| u.bytes)
+            .sum::<u64>();
+        println!(
+            "{} objects also hold live chunks, and keep {} of dead ones",
+            plan.partial_objects.len(),
+            format_size(stranded, BINARY)
+        );
+    }
+
+    if dry_run {
+        return;
+    }
+
+    // drop the dead chunks from the index first, so nothing is
+    // deduplicated against objects that are about to go away
+    let mut entry = RefLogEntry::new(stash, "prune");
+    entry.deleted_objects = plan.dead_objects.len();
+    stash.index().record(entry);
+
+    for digest in plan.dead_chunks {
+        stash.index().chunks.remove(digest);
+    }
+    stash.index().rebuild_digest_filter();
+    stash
+        .commit(Some("Prune forgotten commits".into()))
+        .expect("Failed to write metadata");
+    stash.backend().sync().expect("Failed to write to storage");
+
+    let config = args.parse_stash();
+    let mut relocations = config
+        .relocations()
+        .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+    let before = relocations.targets();
+
+    // objects that were repacked before are gone already, only their
+    // relocation has to go
+    let mut delete = plan
+        .dead_objects
+        .iter()
+        .filter(|id| relocations.remove(id).is_none())
+        .copied()
+        .collect::<Vec<_>>();
+
+    let repacked = if stash.index().params.read().needs_upgrade() {
+        if !plan.live_chunks.is_empty() {
+            println!("Run `0s upgrade-format` to also repack the objects that hold live chunks");
+        }
+        vec![]
+    } else {
+        repack(stash, &plan.live_chunks, &relocations)
+    };
+    for relocation in repacked {
+        let source = relocation.source;
+        if relocations.insert(relocation).is_none() {
+            delete.push(source);
+        }
+    }
+
+    // objects that only held the live chunks of the objects above
+    let after = relocations.targets();
+    delete.extend(before.difference(&after));
+
+    config
+        .store_relocations(&relocations)
+        .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+    stash
+        .backend()
+        .delete(&delete)
+        .and_then(|_| stash.backend().sync())
+        .unwrap_or_else(|e| fatal_error(e));
+
+    let retained = APP.retained_objects().take();
+    if !retained.is_empty() {
+        println!(
+            "{} objects are locked by S3 Object Lock, and have to be deleted from the bucket after their retention expires:",
+            retained.len()
+        );
+        for id in retained {
+            println!("  {id}");
+        }
+    }
+}
+
+/// Move the live chunks of partially dead objects into new objects,
+/// and check that every chunk can be read from its new place before
+/// the old objects are deleted
+fn repack(
+    stash: &Stash,
+    live_chunks: &[(ObjectId, Vec<Arc<ChunkPointer>>)],
+    relocations: &Relocations,
+) -> Vec<Relocation> {
+    let backend = stash.backend();
+    let repacked = gc::repack(backend.as_ref(), live_chunks)
+        .unwrap_or_else(|e| fatal_error(format!("Failed to repack objects: {e:#}")));
+    if repacked.is_empty() {
+        return repacked;
+    }
+
+    let targets = repacked
+        .iter()
+        .map(|r| r.target)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    if !relocations.fits(&repacked) {
+        _ = backend.delete(&targets);
+        println!("Too many objects were repacked already, not repacking more");
+        return vec![];
+    }
+
+    let shared = APP.relocations();
+    for relocation in &repacked {
+        shared.write().unwrap().insert(relocation.clone());
+    }
+
+    let mut reader = stash.storage_reader().unwrap();
+    let mut buf = vec![0; OBJECT_SIZE as usize];
+    let unreadable = live_chunks
+        .iter()
+        .flat_map(|(_, chunks)| chunks)
+        .find_map(|cp| reader.read_chunk(cp, &mut buf).err());
+    if let Some(error) = unreadable {
+        for relocation in &repacked {
+            shared.write().unwrap().remove(&relocation.source);
+        }
+        _ = backend.delete(&targets);
+        fatal_error(format!(
+            "A repacked chunk can't be read, so nothing was repacked: {error}"
+        ));
+    }
+
+    println!(
+        "Repacked the live chunks of {} objects into {}",
+        repacked.len(),
+        targets.len()
+    );
+    repacked
+}
@@ -0,0 +1,35 @@
+//! `rebuild-filter` subcommand
+
+use crate::prelude::*;
+
+#[derive(Command, Debug)]
+pub struct RebuildFilter {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for RebuildFilter {
+    /// Start the application.
+    async fn run(&self) {
+        self.stash.require_write();
+        let (stash, _lock) = self.stash.open_locked("rebuild-filter");
+        stash.load_all().unwrap();
+
+        let index = stash.index();
+        index.rebuild_digest_filter();
+
+        let filter = index.digest_filter.read();
+        println!(
+            "Chunk filter rebuilt with {} of {} digests",
+            filter.len(),
+            filter.capacity()
+        );
+        drop(filter);
+
+        stash
+            .commit(Some("Rebuild chunk digest filter".into()))
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+    }
+}
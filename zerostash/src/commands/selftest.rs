@@ -0,0 +1,256 @@
+//! `selftest` subcommand
+
+use crate::{
+    config::{Key, Stash as StashConfig},
+    prelude::*,
+};
+use anyhow::Context;
+use humansize::{format_size, BINARY};
+use rand::RngCore;
+use std::{
+    fs,
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
+use zerostash_files::{check, gc, restore, store, PreserveMetadata};
+
+/// Size of the generated file that's split like large files are
+const LARGE_FILE_SIZE: usize = 20 * 1024 * 1024;
+
+#[derive(Command, Debug)]
+pub struct Selftest {
+    /// Stash name or location whose backend to test. A temporary
+    /// directory by default
+    #[clap(long, value_name = "STASH")]
+    backend: Option<String>,
+
+    /// Keep the generated and restored files
+    #[clap(long)]
+    keep_files: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Selftest {
+    /// Start the application.
+    async fn run(&self) {
+        let work = std::env::temp_dir().join(format!("0s-selftest-{}", rand::random::<u64>()));
+        let result = self.exercise(&work).await;
+
+        if !self.keep_files {
+            _ = fs::remove_dir_all(&work);
+        } else {
+            println!("Files kept in {}", work.display());
+        }
+
+        match result {
+            Ok(problems) if problems.is_empty() => println!("Selftest passed"),
+            Ok(problems) => {
+                for problem in problems.iter() {
+                    println!("{problem}");
+                }
+                fatal_error(format!("Selftest found {} problems", problems.len()));
+            }
+            Err(e) => fatal_error(format!("Selftest failed: {e:#}")),
+        }
+    }
+}
+
+impl Selftest {
+    /// Store generated files in a new stash, restore them, and
+    /// compare them with the originals. Returns the differences.
+    async fn exercise(&self, work: &Path) -> anyhow::Result<Vec<String>> {
+        let data = work.join("data");
+        let restored = work.join("restored");
+        fs::create_dir_all(&restored)?;
+        let (files, bytes) = generate(&data).context("Failed to generate the test files")?;
+        let data = fs::canonicalize(data)?;
+        println!(
+            "Generated:     {files} files, {}",
+            format_size(bytes, BINARY)
+        );
+
+        // a random key gives the stash its own root object, so it
+        // doesn't interfere with stashes on the same backend
+        let location = match &self.backend {
+            Some(backend) => backend.clone(),
+            None => work.join("stash").to_string_lossy().into_owned(),
+        };
+        let mut config = StashConfig::from_str(&location)?;
+        config.key = Key::Raw(rand::random::<[u8; 32]>().into());
+        println!("Backend:       {location}");
+
+        let threads = APP.get_worker_threads();
+        let stash = config.open_or_create(None, true)?.into_stash(&location)?;
+        let start = Instant::now();
+        let options = store::Options {
+            paths: vec![data.clone()],
+            preserve: preserve(),
+            ..Default::default()
+        };
+        options.add_recursive(&stash, threads).await?;
+        stash.commit(Some("Selftest".to_string()))?;
+        stash.backend().sync()?;
+        println!("Committed in   {:.1?}", start.elapsed());
+
+        let result = self.restore(&config, &location, &data, &restored).await;
+
+        // a temporary directory goes away with the rest of the files
+        if self.backend.is_some() {
+            let start = Instant::now();
+            cleanup(&config, &location).context("Failed to remove the test stash")?;
+            println!("Cleaned up in  {:.1?}", start.elapsed());
+        }
+
+        result
+    }
+
+    async fn restore(
+        &self,
+        config: &StashConfig,
+        location: &str,
+        data: &Path,
+        restored: &Path,
+    ) -> anyhow::Result<Vec<String>> {
+        let stash = config.open_or_create(None, false)?.into_stash(location)?;
+        stash.load_all()?;
+
+        let start = Instant::now();
+        let options = restore::Options {
+            globs: vec!["*".to_string()],
+            preserve: preserve(),
+            chdir: Some(restored.to_owned()),
+            ..Default::default()
+        };
+        options.from_iter(&stash, APP.get_worker_threads()).await?;
+        println!("Restored in    {:.1?}", start.elapsed());
+
+        let start = Instant::now();
+        let report = check::check(
+            || config.open_or_create(None, false)?.into_stash(location),
+            check::ReadData::All,
+            None,
+            None,
+        )?;
+        println!(
+            "Checked:       {} chunks in {:.1?}",
+            report.chunks_read,
+            start.elapsed()
+        );
+
+        let mut problems = report.problems;
+        let relative = data.strip_prefix("/").unwrap_or(data);
+        compare(data, &restored.join(relative), &mut problems)?;
+
+        Ok(problems)
+    }
+}
+
+fn preserve() -> PreserveMetadata {
+    PreserveMetadata {
+        permissions: true,
+        ownership: true,
+        times: true,
+    }
+}
+
+/// Create files with the edge cases backups tend to trip over, and
+/// return how many there are, and their total size
+fn generate(dir: &Path) -> std::io::Result<(usize, u64)> {
+    let mut rng = rand::thread_rng();
+    let mut large = vec![0; LARGE_FILE_SIZE];
+    rng.fill_bytes(&mut large);
+
+    let nested = dir.join("nested").join("deeper");
+    fs::create_dir_all(&nested)?;
+
+    let mut files = vec![
+        (dir.join("empty"), vec![]),
+        (dir.join("small.txt"), b"zerostash selftest\n".to_vec()),
+        (
+            dir.join("ünïcødé 日本語 🦀.txt"),
+            "ünïcødé\n".as_bytes().to_vec(),
+        ),
+        (dir.join("with spaces & 'quotes'"), b"quoted\n".to_vec()),
+        (nested.join("large.bin"), large.clone()),
+    ];
+    // the same contents again, to be deduplicated
+    files.push((nested.join("large copy.bin"), large));
+
+    let mut bytes = 0;
+    for (path, contents) in files.iter() {
+        fs::write(path, contents)?;
+        bytes += contents.len() as u64;
+    }
+
+    // mostly a hole, with data at the end
+    let mut sparse = fs::File::create(dir.join("sparse"))?;
+    sparse.seek(SeekFrom::Start(8 * 1024 * 1024))?;
+    sparse.write_all(b"end of a sparse file\n")?;
+    bytes += sparse.metadata()?.len();
+
+    #[cfg(unix)]
+    let links = {
+        use std::os::unix::fs::symlink;
+
+        symlink("small.txt", dir.join("link"))?;
+        symlink("nested/deeper", dir.join("link to a directory"))?;
+        symlink("missing", dir.join("dangling link"))?;
+        3
+    };
+    #[cfg(not(unix))]
+    let links = 0;
+
+    Ok((files.len() + 1 + links, bytes))
+}
+
+/// Compare the restored files with the originals
+fn compare(original: &Path, restored: &Path, problems: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in fs::read_dir(original)? {
+        let entry = entry?;
+        let path = entry.path();
+        let copy = restored.join(entry.file_name());
+        let name = path.display();
+
+        let metadata = entry.metadata()?;
+        let Ok(copy_metadata) = fs::symlink_metadata(&copy) else {
+            problems.push(format!("{name}: not restored"));
+            continue;
+        };
+
+        if metadata.file_type().is_symlink() {
+            if fs::read_link(&path)? != fs::read_link(&copy).unwrap_or_default() {
+                problems.push(format!("{name}: the link points elsewhere"));
+            }
+        } else if metadata.is_dir() {
+            compare(&path, &copy, problems)?;
+        } else {
+            if fs::read(&path)? != fs::read(&copy)? {
+                problems.push(format!("{name}: the contents differ"));
+            }
+            if metadata.permissions() != copy_metadata.permissions() {
+                problems.push(format!("{name}: the permissions differ"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete everything the test stored on the backend
+fn cleanup(config: &StashConfig, location: &str) -> anyhow::Result<()> {
+    let open = config.opener(None)?;
+    let data = gc::data_objects(open)?;
+
+    let stash = config.open_or_create(None, false)?.into_stash(location)?;
+    stash.load_all()?;
+    let index = stash.index_object_ids().collect::<Vec<_>>();
+
+    let backend = stash.backend();
+    backend.delete(&data.into_iter().collect::<Vec<_>>())?;
+    backend.delete(&index)?;
+    backend.sync()?;
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! `stash` subcommand
+
+use crate::config::{Backend, Key, Stash};
+use crate::prelude::*;
+use std::path::PathBuf;
+
+#[derive(Command, Debug)]
+pub enum Stashes {
+    /// List the stashes in the config file
+    List(ListStashes),
+    /// Add a stash to the config file
+    Add(AddStash),
+    /// Remove a stash from the config file, without touching its data
+    Remove(RemoveStash),
+    /// Change the alias of a stash in the config file
+    Rename(RenameStash),
+}
+
+#[async_trait]
+impl AsyncRunnable for Stashes {
+    async fn run(&self) {
+        use Stashes::*;
+        match self {
+            List(c) => c.run().await,
+            Add(c) => c.run().await,
+            Remove(c) => c.run().await,
+            Rename(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ListStashes {
+    /// Only list the stashes in this group
+    #[clap(short, long)]
+    group: Option<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for ListStashes {
+    async fn run(&self) {
+        let config = APP.config();
+        for alias in config.stashes_in(self.group.as_deref()) {
+            let stash = config.resolve_stash(&alias).unwrap();
+            println!(
+                "{alias}\t{}\t{}",
+                location(&stash.backend),
+                stash.groups.join(",")
+            );
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct AddStash {
+    /// Name to refer to the stash by
+    alias: String,
+
+    /// Path or S3 URL of the stash
+    location: String,
+
+    /// Use a keyfile for the stash
+    #[clap(short, long, value_name = "PATH")]
+    keyfile: Option<PathBuf>,
+
+    /// Use a key specification TOML. Eg: '{ source = "yubikey" }'
+    #[clap(short = 'K', value_name = "TOML", long, conflicts_with = "keyfile")]
+    keystring: Option<String>,
+
+    /// Add the stash to a group for `0s all`
+    #[clap(short, long = "group")]
+    groups: Vec<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for AddStash {
+    async fn run(&self) {
+        let key = if let Some(path) = self.keyfile.clone() {
+            Key::KeyFile { path }
+        } else if let Some(s) = &self.keystring {
+            toml::from_str(s).unwrap_or_else(|e| fatal_error(e))
+        } else {
+            Key::default()
+        };
+
+        let stash = Stash {
+            key,
+            backend: self
+                .location
+                .parse()
+                .unwrap_or_else(|e: anyhow::Error| fatal_error(format!("{e:#}"))),
+            groups: self.groups.clone(),
+            bandwidth: None,
+            hooks: Default::default(),
+            alias: self.alias.clone(),
+        };
+
+        update_config(|config| config.add_stash(&self.alias, stash));
+        println!("Added {}", self.alias);
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct RemoveStash {
+    /// Alias of the stash
+    alias: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for RemoveStash {
+    async fn run(&self) {
+        update_config(|config| config.remove_stash(&self.alias).map(|_| ()));
+        println!(
+            "Removed {} from the config. Its data is still in the backend, use `0s wipe` to delete it.",
+            self.alias
+        );
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct RenameStash {
+    /// Current alias of the stash
+    alias: String,
+
+    /// New alias of the stash
+    new_alias: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for RenameStash {
+    async fn run(&self) {
+        update_config(|config| config.rename_stash(&self.alias, &self.new_alias));
+        println!("Renamed {} to {}", self.alias, self.new_alias);
+    }
+}
+
+pub(crate) fn update_config(change: impl FnOnce(&mut ZerostashConfig) -> anyhow::Result<()>) {
+    let mut config = ZerostashConfig::clone(&APP.config());
+    change(&mut config)
+        .and_then(|_| config.write())
+        .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+}
+
+fn location(backend: &Backend) -> String {
+    match backend {
+        Backend::Filesystem { path, .. } => path.clone(),
+        Backend::S3 { bucket, region, .. } => format!("s3://{}/{bucket}", region.name()),
+        Backend::FsCache { upstream, .. } => format!("{} (cached)", location(upstream)),
+        Backend::Verify { upstream } => format!("{} (verified)", location(upstream)),
+        #[cfg(unix)]
+        Backend::Socket { path } => format!("socket {path}"),
+    }
+}
@@ -0,0 +1,167 @@
+//! `stats` subcommand
+
+use crate::{
+    commands::{json_line, load_tree},
+    prelude::*,
+};
+use chrono::{DateTime, Utc};
+use humansize::{format_size, BINARY};
+use serde::Serialize;
+use zerostash_files::stats;
+
+#[derive(Command, Debug)]
+pub struct Stats {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Add up the size of the files in the latest commit per
+    /// directory, like `du`
+    #[clap(long)]
+    tree: bool,
+
+    /// Number of directory levels to show with `--tree`
+    #[clap(long, default_value_t = 1, requires = "tree")]
+    depth: usize,
+}
+
+#[async_trait]
+impl AsyncRunnable for Stats {
+    /// Start the application.
+    async fn run(&self) {
+        let mut stdout = std::io::stdout().lock();
+
+        if self.tree {
+            let stash = self.stash.open();
+            load_tree(&stash);
+
+            for (dir, usage) in stats::tree_usage(&stash, self.depth) {
+                let printed = if APP.output().is_json() {
+                    json_line(
+                        &mut stdout,
+                        &DirLine {
+                            dir,
+                            files: usage.files,
+                            bytes: usage.bytes,
+                        },
+                    )
+                } else {
+                    let size = format_size(usage.bytes, BINARY);
+                    writeln!(stdout, "{size:<12}{:<10}{dir}", usage.files)
+                };
+                if printed.is_err() {
+                    return;
+                }
+            }
+            return;
+        }
+
+        let open = self
+            .stash
+            .parse_stash()
+            .opener(self.stash.key())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        let stats = stats::stats(open).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if APP.output().is_json() {
+            _ = json_line(&mut stdout, &StatsLine::from(&stats));
+        } else {
+            _ = print(&mut stdout, &stats);
+        }
+    }
+}
+
+/// A directory as `stats --tree --output json` prints it
+#[derive(Serialize)]
+struct DirLine {
+    dir: String,
+    files: usize,
+    bytes: u64,
+}
+
+/// What `stats --output json` prints
+#[derive(Serialize)]
+struct StatsLine {
+    commits: Vec<CommitLine>,
+    files: usize,
+    logical_bytes: u64,
+    chunks: usize,
+    deduplicated_bytes: u64,
+    objects: usize,
+    stored_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct CommitLine {
+    id: String,
+    time: DateTime<Utc>,
+    message: Option<String>,
+    files: usize,
+    logical_bytes: u64,
+    added_chunks: usize,
+    added_bytes: u64,
+}
+
+impl From<&stats::Stats> for StatsLine {
+    fn from(stats: &stats::Stats) -> Self {
+        let commits = stats
+            .commits
+            .iter()
+            .map(|c| CommitLine {
+                id: format!("{:?}", c.id),
+                time: c.time,
+                message: c.message.clone(),
+                files: c.files,
+                logical_bytes: c.logical_bytes,
+                added_chunks: c.added_chunks,
+                added_bytes: c.added_bytes,
+            })
+            .collect();
+
+        Self {
+            commits,
+            files: stats.files,
+            logical_bytes: stats.logical_bytes,
+            chunks: stats.chunks,
+            deduplicated_bytes: stats.deduplicated_bytes,
+            objects: stats.objects,
+            stored_bytes: stats.stored_bytes,
+        }
+    }
+}
+
+fn print(out: &mut impl Write, stats: &stats::Stats) -> std::io::Result<()> {
+    writeln!(out, "Added by each commit:")?;
+    for commit in stats.commits.iter() {
+        let local_time = commit.time.with_timezone(&chrono::Local);
+        writeln!(
+            out,
+            "{:?}\t{}\t{}\t{}\t{}",
+            commit.id,
+            local_time.format("%Y %b %e %H:%M:%S"),
+            format_size(commit.added_bytes, BINARY),
+            commit.added_chunks,
+            commit.message.as_deref().unwrap_or("No commit message")
+        )?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Commits:       {}", stats.commits.len())?;
+    writeln!(out, "Files:         {}", stats.files)?;
+    writeln!(
+        out,
+        "Logical size:  {}",
+        format_size(stats.logical_bytes, BINARY)
+    )?;
+    writeln!(
+        out,
+        "Deduplicated:  {} in {} chunks",
+        format_size(stats.deduplicated_bytes, BINARY),
+        stats.chunks
+    )?;
+    writeln!(
+        out,
+        "Stored:        {} in {} objects",
+        format_size(stats.stored_bytes, BINARY),
+        stats.objects
+    )
+}
@@ -0,0 +1,31 @@
+use crate::prelude::AsyncRunnable;
+use async_trait::async_trait;
+use clap::Parser;
+
+mod commit;
+mod ls;
+mod restore;
+
+#[derive(Debug, Parser)]
+pub enum Stream {
+    /// Store the data piped to stdin under a name
+    Commit(commit::StreamCommit),
+
+    /// List the streams in the stash
+    Ls(ls::StreamLs),
+
+    /// Write a stream to stdout
+    Restore(restore::StreamRestore),
+}
+
+#[async_trait]
+impl AsyncRunnable for Stream {
+    async fn run(&self) {
+        use Stream::*;
+        match self {
+            Commit(c) => c.run().await,
+            Ls(l) => l.run().await,
+            Restore(r) => r.run().await,
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! `stream commit` subcommand
+
+use crate::{migration::migration, prelude::*};
+use infinitree::object::Pool;
+use std::num::NonZeroUsize;
+use zerostash_files::ZfsSnapshot;
+
+#[derive(Command, Debug)]
+pub struct StreamCommit {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Commit message to include in the changeset
+    #[clap(short = 'm', long)]
+    message: Option<String>,
+
+    /// Name to store the stream under. Earlier commits keep the
+    /// previous versions of a stream with the same name
+    #[clap(short = 'n', long)]
+    name: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for StreamCommit {
+    /// Start the application.
+    async fn run(&self) {
+        self.stash.require_write();
+        let (mut stash, _lock) = self.stash.open_locked("stream commit");
+        // everything is written back on commit
+        stash.load_all().unwrap();
+        migration(&mut stash);
+
+        let threads = APP.get_worker_threads();
+        let writer = Pool::new(
+            NonZeroUsize::new(threads).unwrap(),
+            stash.storage_writer().unwrap(),
+        )
+        .unwrap();
+        let hasher = stash.hasher().unwrap();
+
+        let stream = abscissa_tokio::tokio::task::block_in_place(|| {
+            ZfsSnapshot::from_stdout(writer, hasher, &mut std::io::stdin().lock(), threads)
+        })
+        .unwrap_or_else(|e| fatal_error(format!("Failed to store the stream: {e}")));
+
+        stash.index().streams.insert(self.name.clone(), stream);
+
+        stash
+            .commit(self.message.clone())
+            .expect("failed to write metadata");
+        stash.backend().sync().expect("failed to write to storage");
+    }
+}
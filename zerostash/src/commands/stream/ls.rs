@@ -0,0 +1,44 @@
+//! `stream ls` subcommand
+
+use crate::{commands::report_reconstructed, prelude::*};
+use chrono::{DateTime, Utc};
+use humansize::{format_size, BINARY};
+use std::io::Write;
+use zerostash_files::legacy;
+
+#[derive(Command, Debug)]
+pub struct StreamLs {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for StreamLs {
+    /// Start the application.
+    async fn run(&self) {
+        let stash = self.stash.open();
+        report_reconstructed(legacy::load_streams(&stash));
+
+        let mut streams = vec![];
+        stash.index().streams.for_each(|name, stream| {
+            streams.push((name.clone(), DateTime::<Utc>::from(stream), stream.size));
+        });
+        streams.sort();
+
+        let mut stdout = std::io::stdout().lock();
+        for (name, time, size) in streams {
+            let local_time = time.with_timezone(&chrono::Local);
+            if writeln!(
+                stdout,
+                "{:<25} {:<12} {}",
+                name,
+                format_size(size, BINARY),
+                local_time.format("%Y %b %e %H:%M:%S")
+            )
+            .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! `stream restore` subcommand
+
+use crate::{
+    commands::{report_reconstructed, CommitSelector},
+    prelude::*,
+};
+use zerostash_files::legacy;
+
+#[derive(Command, Debug)]
+pub struct StreamRestore {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the stored stream
+    #[clap(short = 'n', long)]
+    name: String,
+
+    /// Read the stream as of a commit ID or tag, or the last commit before a
+    /// date like `2024-03-01` or `2024-03-01 18:00`
+    #[clap(long, value_name = "COMMIT|TIME", conflicts_with = "commit_id")]
+    at: Option<CommitSelector>,
+}
+
+#[async_trait]
+impl AsyncRunnable for StreamRestore {
+    /// Start the application.
+    async fn run(&self) {
+        if !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+        if self
+            .stash
+            .token_scope()
+            .is_some_and(|scope| scope.prefix.is_some())
+        {
+            fatal_error("Tokens limited to a path prefix can't read streams");
+        }
+
+        let stash = self.stash.open();
+        if let Some(at) = &self.at {
+            let commit = at.resolve(&stash).unwrap_or_else(|e| fatal_error(e));
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
+        }
+        report_reconstructed(legacy::load_streams(&stash));
+
+        let Some(stream) = stash.index().streams.get(&self.name) else {
+            fatal_error(format!("No stream named '{}' in the stash", self.name));
+        };
+
+        let reader = stash.storage_reader().unwrap();
+        abscissa_tokio::tokio::task::block_in_place(|| {
+            stream.to_stdin(reader, &mut std::io::stdout().lock())
+        })
+        .unwrap_or_else(|e| fatal_error(format!("Failed to write the stream: {e}")));
+    }
+}
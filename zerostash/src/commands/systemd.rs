@@ -0,0 +1,119 @@
+//! `systemd` subcommand
+
+use crate::{
+    config::{Backend, Key},
+    prelude::*,
+    systemd::BackupJob,
+};
+use std::path::PathBuf;
+
+#[derive(Command, Debug)]
+pub enum Systemd {
+    /// Generate and install a service and timer for a configured stash
+    Install(Install),
+}
+
+#[async_trait]
+impl AsyncRunnable for Systemd {
+    async fn run(&self) {
+        use Systemd::*;
+        match self {
+            Install(i) => i.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Install {
+    /// Stash alias from the config file
+    stash: String,
+
+    /// The paths to back up
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// When to run the backup, in `systemd.time` calendar syntax
+    #[clap(short = 's', long, default_value = "daily")]
+    on_calendar: String,
+
+    /// Install as a user service instead of a system service
+    #[clap(long)]
+    user: bool,
+
+    /// Write the units into this directory instead of the default
+    #[clap(short = 'o', long, value_name = "PATH")]
+    output_dir: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Install {
+    async fn run(&self) {
+        let Some(stash) = APP.config().resolve_stash(&self.stash) else {
+            fatal_error(format!(
+                "Stash '{}' is not defined in the config file",
+                self.stash
+            ));
+        };
+
+        if matches!(stash.key, Key::Interactive) {
+            fatal_error("Scheduled backups need credentials stored in the config file");
+        }
+
+        let executable = std::env::current_exe().expect("Can't find the path of 0s");
+        let config = ZerostashConfig::path();
+        let job = BackupJob {
+            stash: &self.stash,
+            paths: &self.paths,
+            schedule: &self.on_calendar,
+            executable: &executable,
+            config: &config,
+            writable: writable_paths(&stash.backend),
+        };
+
+        let dir = self
+            .output_dir
+            .clone()
+            .unwrap_or_else(|| self.default_dir());
+        std::fs::create_dir_all(&dir).expect("Can't create unit directory");
+
+        let name = job.unit_name();
+        for (suffix, contents) in [("service", job.service()), ("timer", job.timer())] {
+            let path = dir.join(format!("{name}.{suffix}"));
+            std::fs::write(&path, contents).expect("Can't write unit file");
+            println!("Wrote {}", path.display());
+        }
+
+        let scope = if self.user { "--user " } else { "" };
+        println!("\nTo activate, run:\n");
+        println!("  systemctl {scope}daemon-reload");
+        println!("  systemctl {scope}enable --now {name}.timer");
+    }
+}
+
+impl Install {
+    fn default_dir(&self) -> PathBuf {
+        if !self.user {
+            return "/etc/systemd/system".into();
+        }
+
+        let mut dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+            .expect("Can't find the user configuration directory");
+        dir.push("systemd/user");
+        dir
+    }
+}
+
+fn writable_paths(backend: &Backend) -> Vec<String> {
+    match backend {
+        Backend::Filesystem { path, .. } => vec![path.clone()],
+        Backend::FsCache { upstream, .. } => {
+            let mut paths = writable_paths(upstream);
+            paths.extend(backend.cache_paths().into_iter().map(String::from));
+            paths
+        }
+        Backend::Verify { upstream } => writable_paths(upstream),
+        _ => vec![],
+    }
+}
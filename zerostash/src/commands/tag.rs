@@ -0,0 +1,158 @@
+//! `tag` subcommand
+
+use crate::{
+    commands::{report_reconstructed, CommitSelector},
+    prelude::*,
+};
+use zerostash_files::legacy;
+
+#[derive(Command, Debug)]
+pub enum Tag {
+    /// Give a name to a commit, to use instead of its ID
+    Add(AddTag),
+    /// Remove the name of a commit
+    Remove(RemoveTag),
+    /// List the named commits
+    List(ListTags),
+}
+
+#[async_trait]
+impl AsyncRunnable for Tag {
+    async fn run(&self) {
+        use Tag::*;
+        match self {
+            Add(c) => c.run().await,
+            Remove(c) => c.run().await,
+            List(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct AddTag {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the tag, eg. `v1.0`
+    name: String,
+
+    /// Commit to tag, by ID, tag or date. Defaults to the latest commit
+    #[clap(value_name = "COMMIT|TIME")]
+    commit: Option<CommitSelector>,
+
+    /// Move the tag if it's already on another commit
+    #[clap(short, long)]
+    force: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for AddTag {
+    async fn run(&self) {
+        self.stash.require_write();
+        if !matches!(self.name.parse(), Ok(CommitSelector::Tag(_))) {
+            fatal_error(format!(
+                "`{}` looks like a commit ID or a date, and can't be a tag",
+                self.name
+            ));
+        }
+
+        let (stash, _lock) = self.stash.open_locked("tag add");
+        stash.load_all().unwrap();
+
+        let id = match &self.commit {
+            Some(commit) => commit.resolve(&stash).unwrap_or_else(|e| fatal_error(e)),
+            None => stash
+                .commit_list()
+                .iter()
+                .last()
+                .map(|c| c.id)
+                .unwrap_or_else(|| fatal_error("The stash has no commits to tag")),
+        };
+
+        let tags = &stash.index().commit_tags;
+        match tags.read().get(&self.name) {
+            Some(previous) if *previous == id => return,
+            Some(previous) if !self.force => fatal_error(format!(
+                "{} is already on commit {previous:?}, use --force to move it",
+                self.name
+            )),
+            _ => {}
+        }
+        tags.write().insert(self.name.clone(), id);
+
+        stash
+            .commit(Some(format!("Tag {id:?} as {}", self.name)))
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+
+        println!("{}\t{id:?}", self.name);
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct RemoveTag {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the tag to remove
+    name: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for RemoveTag {
+    async fn run(&self) {
+        self.stash.require_write();
+        let (stash, _lock) = self.stash.open_locked("tag rm");
+        stash.load_all().unwrap();
+
+        if stash
+            .index()
+            .commit_tags
+            .write()
+            .remove(&self.name)
+            .is_none()
+        {
+            fatal_error(format!("No tag {} in the stash", self.name));
+        }
+
+        stash
+            .commit(Some(format!("Remove tag {}", self.name)))
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ListTags {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for ListTags {
+    async fn run(&self) {
+        let stash = self.stash.open();
+        report_reconstructed(legacy::load_commit_tags(&stash));
+
+        let times = stash
+            .commit_list()
+            .iter()
+            .map(|c| (c.id, chrono::DateTime::<chrono::Utc>::from(c.metadata.time)))
+            .collect::<std::collections::HashMap<_, _>>();
+
+        let mut stdout = std::io::stdout().lock();
+        for (name, id) in stash.index().commit_tags.read().iter() {
+            let time = match times.get(id) {
+                Some(time) => time
+                    .with_timezone(&chrono::Local)
+                    .format("%Y %b %e %H:%M:%S")
+                    .to_string(),
+                None => "missing".to_string(),
+            };
+
+            if writeln!(stdout, "{name}\t{id:?}\t{time}").is_err() {
+                break;
+            }
+        }
+    }
+}
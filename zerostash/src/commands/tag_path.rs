@@ -0,0 +1,55 @@
+//! `tag-path` subcommand
+
+use crate::prelude::*;
+
+#[derive(Command, Debug)]
+pub struct TagPath {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// File or directory in the stash
+    path: String,
+
+    /// Tags to add as `key=value`
+    #[clap(value_name = "KEY=VALUE", value_parser = zerostash_files::parse_tag)]
+    tags: Vec<(String, String)>,
+
+    /// Remove the tag with this key. Can be repeated.
+    #[clap(long, value_name = "KEY")]
+    remove: Vec<String>,
+
+    /// Commit message to include in the changeset
+    #[clap(short = 'm', long)]
+    message: Option<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for TagPath {
+    /// Start the application.
+    async fn run(&self) {
+        self.stash.require_write();
+        let (stash, _lock) = self.stash.open_locked("tag-path");
+        stash.load_all().unwrap();
+
+        let path = self.path.trim_matches('/');
+        let index = stash.index();
+        if !path.is_empty() && !matches!(index.tree.node_by_path(path), Ok(Some(_))) {
+            fatal_error(format!("{} is not in the stash", self.path));
+        }
+
+        index.tag_path(path, self.tags.clone(), &self.remove);
+
+        for (key, value) in index.tags_of(path) {
+            println!("{key}={value}");
+        }
+
+        stash
+            .commit(
+                self.message
+                    .clone()
+                    .or_else(|| Some(format!("Tag {}", self.path))),
+            )
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+    }
+}
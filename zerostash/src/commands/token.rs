@@ -0,0 +1,161 @@
+//! `token` subcommand
+
+use super::user::current_key;
+use crate::prelude::*;
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use zerostash_files::TokenScope;
+
+#[derive(Command, Debug)]
+pub enum Token {
+    /// Issue a read-only token to hand out for restores
+    Issue(IssueToken),
+    /// Revoke a token
+    Revoke(RevokeToken),
+    /// List the tokens issued for a stash
+    List(ListTokens),
+}
+
+#[async_trait]
+impl AsyncRunnable for Token {
+    async fn run(&self) {
+        use Token::*;
+        match self {
+            Issue(c) => c.run().await,
+            Revoke(c) => c.run().await,
+            List(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct IssueToken {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the token
+    name: String,
+
+    /// Only allow access to paths below this prefix
+    #[clap(long, value_name = "PATH")]
+    prefix: Option<String>,
+
+    /// Refuse the token after this date. Eg: 2024-12-31, or
+    /// 2024-12-31T18:00:00Z
+    #[clap(long, value_name = "DATE", value_parser = parse_date)]
+    until: Option<DateTime<Utc>>,
+}
+
+#[async_trait]
+impl AsyncRunnable for IssueToken {
+    async fn run(&self) {
+        self.stash.require_write();
+        let stash_cfg = self.stash.parse_stash();
+        let (current, _) = current_key(&self.stash);
+
+        let scope = TokenScope {
+            prefix: self.prefix.clone(),
+            expires: self.until.map(|until| until.timestamp()),
+            issued: Utc::now().timestamp(),
+        };
+
+        let token = stash_cfg
+            .issue_token(current, &self.name, scope)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!("Token {} for {}:\n", self.name, stash_cfg.alias);
+        println!("{token}\n");
+        println!("Use it with:\n");
+        println!(
+            "    0s checkout -K '{{ source = \"token\", token = \"{token}\" }}' {}",
+            self.stash.stash
+        );
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct RevokeToken {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the token to revoke
+    name: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for RevokeToken {
+    async fn run(&self) {
+        self.stash.require_delete();
+        let stash_cfg = self.stash.parse_stash();
+        let (current, _) = current_key(&self.stash);
+
+        stash_cfg
+            .revoke_token(current, &self.name)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!("Revoked token {} for {}", self.name, stash_cfg.alias);
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ListTokens {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for ListTokens {
+    async fn run(&self) {
+        let stash_cfg = self.stash.parse_stash();
+        let (current, _) = current_key(&self.stash);
+
+        let tokens = stash_cfg
+            .tokens(current)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if tokens.is_empty() {
+            println!("No tokens issued for {}", stash_cfg.alias);
+        }
+
+        let now = Utc::now().timestamp();
+        for (name, scope) in tokens {
+            let expires = match scope.expires {
+                Some(_) if scope.is_expired(now) => "expired".to_string(),
+                Some(expires) => format!("until {}", format_time(expires)),
+                None => "no expiry".to_string(),
+            };
+
+            println!(
+                "{name}\t{}\tissued {}\t{expires}",
+                scope.prefix.as_deref().unwrap_or("/"),
+                format_time(scope.issued),
+            );
+        }
+    }
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    // a day means until the end of it, in local time
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("invalid date `{date}`, expected YYYY-MM-DD"))?;
+    let end = day.and_hms_opt(23, 59, 59).unwrap();
+
+    Local
+        .from_local_datetime(&end)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("invalid date `{date}`"))
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&Local)
+                .format("%Y %b %e %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,124 @@
+//! `top` subcommand
+
+use crate::{control::Status, prelude::*};
+use humansize::{format_size, BINARY};
+use std::{fmt::Write, path::PathBuf, time::Duration};
+
+#[derive(Command, Debug)]
+pub struct Top {
+    /// Control socket of the running commit, as given to
+    /// `--control-socket`
+    socket: PathBuf,
+
+    /// Seconds between updates
+    #[clap(short = 'n', long, default_value = "1")]
+    interval: u64,
+}
+
+#[async_trait]
+impl AsyncRunnable for Top {
+    /// Start the application.
+    async fn run(&self) {
+        self.watch()
+            .await
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+    }
+}
+
+impl Top {
+    #[cfg(unix)]
+    async fn watch(&self) -> anyhow::Result<()> {
+        use anyhow::Context;
+        use std::time::Instant;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let stream = tokio::net::UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.socket.display()))?;
+        let (read, mut write) = stream.into_split();
+        let mut replies = BufReader::new(read).lines();
+        let mut previous: Option<(Instant, Status)> = None;
+
+        loop {
+            // the socket goes away when the commit finishes
+            if write.write_all(b"activity\n").await.is_err() {
+                break;
+            }
+            let Ok(Some(reply)) = replies.next_line().await else {
+                break;
+            };
+
+            let status: Status =
+                serde_json::from_str(&reply).context("Unexpected reply from the socket")?;
+            let now = Instant::now();
+            let since = previous.as_ref().map(|(t, p)| (now - *t, p));
+            print!("\x1b[2J\x1b[H{}", render(&status, since));
+
+            previous = Some((now, status));
+            tokio::time::sleep(Duration::from_secs(self.interval)).await;
+        }
+
+        println!("The operation has finished");
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    async fn watch(&self) -> anyhow::Result<()> {
+        anyhow::bail!("Control sockets are only supported on Unix")
+    }
+}
+
+fn render(status: &Status, since: Option<(Duration, &Status)>) -> String {
+    let rate = |now: u64, before: Option<u64>| match (since, before) {
+        (Some((elapsed, _)), Some(before)) if !elapsed.is_zero() => {
+            let bytes = now.saturating_sub(before) as f64 / elapsed.as_secs_f64();
+            format!("{}/s", format_size(bytes as u64, BINARY))
+        }
+        _ => "-".to_string(),
+    };
+    let previous = since.map(|(_, p)| p);
+
+    let mut out = String::new();
+    _ = writeln!(
+        out,
+        "{}\n",
+        if status.paused { "Paused" } else { "Running" }
+    );
+    _ = writeln!(
+        out,
+        "Storage:  {} written ({}), {} read ({})",
+        format_size(status.bytes_written, BINARY),
+        rate(status.bytes_written, previous.map(|p| p.bytes_written)),
+        format_size(status.bytes_read, BINARY),
+        rate(status.bytes_read, previous.map(|p| p.bytes_read)),
+    );
+    _ = writeln!(out, "Queue:    {} files", status.activity.queued);
+    _ = writeln!(
+        out,
+        "Chunks:   {} seen, {} stored, {:.1}% deduplicated\n",
+        status.activity.chunks,
+        status.activity.chunks_stored,
+        status.activity.dedup_ratio() * 100.0
+    );
+
+    _ = writeln!(
+        out,
+        "{:>6} {:>8} {:>10} {:>12}  FILE",
+        "WORKER", "FILES", "READ", "RATE"
+    );
+    for (i, worker) in status.activity.workers.iter().enumerate() {
+        let before = previous
+            .and_then(|p| p.activity.workers.get(i))
+            .map(|w| w.bytes);
+        _ = writeln!(
+            out,
+            "{i:>6} {:>8} {:>10} {:>12}  {}",
+            worker.files,
+            format_size(worker.bytes, BINARY),
+            rate(worker.bytes, before),
+            worker.current.as_deref().unwrap_or("-")
+        );
+    }
+
+    out
+}
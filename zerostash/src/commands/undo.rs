@@ -0,0 +1,80 @@
+//! `undo` subcommand
+
+use crate::prelude::*;
+use chrono::{Local, TimeZone};
+
+#[derive(Command, Debug)]
+pub struct Undo {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// List the operations that can be undone, newest first
+    #[clap(long)]
+    list: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Undo {
+    /// Start the application.
+    async fn run(&self) {
+        self.stash.require_write();
+        let (stash, _lock) = self.stash.open_locked("undo");
+        stash.load_all().unwrap();
+
+        let now = chrono::Utc::now().timestamp();
+        let window = stash.index().params.read().undo_window;
+
+        if self.list {
+            for entry in stash.index().reflog.read().iter().rev() {
+                let time = Local.timestamp_opt(entry.time, 0).unwrap();
+                let state = if entry.deleted_objects > 0 {
+                    "data deleted"
+                } else if entry.is_undoable(window, now) {
+                    "undoable"
+                } else {
+                    "expired"
+                };
+
+                println!(
+                    "{}\t{}\t{state}\t{}",
+                    time.format("%Y %b %e %H:%M:%S"),
+                    entry.operation,
+                    entry.head.map(|id| format!("{id:?}")).unwrap_or_default()
+                );
+            }
+            return;
+        }
+
+        let Some(entry) = stash.index().reflog.read().last().cloned() else {
+            fatal_error("There's nothing to undo");
+        };
+        if entry.deleted_objects > 0 {
+            fatal_error(format!(
+                "`{}` deleted {} objects from the backend, and can't be undone",
+                entry.operation, entry.deleted_objects
+            ));
+        }
+        if !entry.is_undoable(window, now) {
+            fatal_error(format!(
+                "`{}` is older than the undo window of {window}",
+                entry.operation
+            ));
+        }
+
+        *stash.index().forgotten.write() = entry.forgotten.clone();
+        for (name, snapshot) in entry.zfs_snapshots.iter() {
+            stash
+                .index()
+                .zfs_snapshots
+                .insert(name.clone(), snapshot.clone());
+        }
+        stash.index().reflog.write().pop();
+
+        stash
+            .commit(Some(format!("Undo {}", entry.operation)))
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+
+        println!("Undid `{}`", entry.operation);
+    }
+}
@@ -0,0 +1,38 @@
+//! `upgrade-format` subcommand
+
+use crate::{migration::migration, prelude::*};
+use zerostash_files::FORMAT_VERSION;
+
+#[derive(Command, Debug)]
+pub struct UpgradeFormat {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for UpgradeFormat {
+    /// Rewrite the index of the stash in the current format
+    async fn run(&self) {
+        self.stash.require_write();
+        let (mut stash, _lock) = self.stash.open_locked("upgrade-format");
+
+        let from = stash.index().params.read().format_version;
+        if !stash.index().params.read().needs_upgrade() {
+            println!("{} is already in format version {from}", self.stash.stash);
+            return;
+        }
+
+        stash.load_all().unwrap();
+        migration(&mut stash);
+
+        stash
+            .commit(Some(format!("Upgrade format to version {FORMAT_VERSION}")))
+            .expect("Failed to write metadata");
+        stash.backend().sync().expect("Failed to write to storage");
+
+        println!(
+            "Upgraded {} from format version {from} to {FORMAT_VERSION}",
+            self.stash.stash
+        );
+    }
+}
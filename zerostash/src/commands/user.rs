@@ -0,0 +1,165 @@
+//! `user` subcommand
+
+use crate::config::{Key, SymmetricKey};
+use crate::prelude::*;
+use tracing::warn;
+
+#[derive(Command, Debug)]
+pub enum User {
+    /// Give another user access to the stash with their own password
+    Add(AddUser),
+    /// Delete the key slot of a user. This doesn't revoke access to
+    /// anything the user could read before.
+    RemoveSlot(RemoveSlot),
+    /// List the users of a shared stash
+    List(ListUsers),
+}
+
+#[async_trait]
+impl AsyncRunnable for User {
+    async fn run(&self) {
+        use User::*;
+        match self {
+            Add(c) => c.run().await,
+            RemoveSlot(c) => c.run().await,
+            List(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct AddUser {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the new user
+    name: String,
+
+    /// Name of the slot that keeps the current credentials working if
+    /// the stash isn't shared yet. Defaults to the current username.
+    #[clap(long)]
+    owner: Option<String>,
+}
+
+#[async_trait]
+impl AsyncRunnable for AddUser {
+    async fn run(&self) {
+        self.stash.require_write();
+        let stash_cfg = self.stash.parse_stash();
+        let (current, current_user) = current_key(&self.stash);
+
+        println!("Password for {}:\n", self.name);
+        let password = rpassword::prompt_password("Password: ").unwrap_or_else(|e| fatal_error(e));
+        let confirm =
+            rpassword::prompt_password("Repeat password: ").unwrap_or_else(|e| fatal_error(e));
+        if password != confirm {
+            fatal_error("Passwords don't match");
+        }
+
+        let user = Key::Userpass(SymmetricKey {
+            user: Some(self.name.clone().into()),
+            password: Some(password.into()),
+            keychain: false,
+            kdf: None,
+        });
+
+        let owner = self
+            .owner
+            .clone()
+            .or(current_user)
+            .unwrap_or_else(|| "owner".into());
+
+        stash_cfg
+            .add_key_slot(current, &owner, user, &self.name)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!("Added {} to {}", self.name, stash_cfg.alias);
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct RemoveSlot {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the user whose key slot to delete
+    name: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for RemoveSlot {
+    async fn run(&self) {
+        self.stash.require_delete();
+        let stash_cfg = self.stash.parse_stash();
+        let (current, _) = current_key(&self.stash);
+
+        stash_cfg
+            .remove_key_slot(current, &self.name)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!(
+            "Removed the key slot of {} from {}",
+            self.name, stash_cfg.alias
+        );
+        warn!(
+            "{} can no longer open the stash with their password, but every slot holds the same master key. \
+             If they kept it, they can still read the stash, including later commits. \
+             Move the data to a new stash to revoke their access.",
+            self.name
+        );
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ListUsers {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for ListUsers {
+    async fn run(&self) {
+        let stash_cfg = self.stash.parse_stash();
+        let (current, _) = current_key(&self.stash);
+
+        let users = stash_cfg
+            .key_slots(current)
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        if users.is_empty() {
+            println!("{} is not shared", stash_cfg.alias);
+        }
+        for user in users {
+            println!("{user}");
+        }
+    }
+}
+
+/// Resolve interactive credentials once, since they are used several
+/// times while managing key slots
+pub(super) fn current_key(args: &StashArgs) -> (Key, Option<String>) {
+    let stash_cfg = args.parse_stash();
+    let key = args.key().unwrap_or_else(|| stash_cfg.key.clone());
+
+    let credentials = match key {
+        Key::Interactive => SymmetricKey::default(),
+        Key::Userpass(k) if k.password.is_none() => k,
+        key => return (key, None),
+    };
+
+    let kdf = credentials.kdf;
+    let (user, password) = credentials
+        .interactive_credentials(&stash_cfg.alias)
+        .unwrap_or_else(|e| fatal_error(e));
+    let name = secrecy::ExposeSecret::expose_secret(&user).to_string();
+
+    (
+        Key::Userpass(SymmetricKey {
+            user: Some(user),
+            password: Some(password),
+            keychain: false,
+            kdf,
+        }),
+        Some(name),
+    )
+}
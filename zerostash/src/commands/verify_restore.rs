@@ -0,0 +1,53 @@
+//! `verify-restore` subcommand
+
+use crate::prelude::*;
+use humansize::{format_size, BINARY};
+use std::path::PathBuf;
+use zerostash_files::{restore, verify_restore};
+
+#[derive(Command, Debug)]
+pub struct VerifyRestore {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// List of globs to match in the database
+    globs: Vec<String>,
+
+    /// Compare the files with the ones in this directory
+    #[clap(long, value_name = "DIR")]
+    local: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AsyncRunnable for VerifyRestore {
+    /// Start the application.
+    async fn run(&self) {
+        if !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+
+        let stash = self.stash.open();
+        stash.load_all().unwrap();
+
+        let options = restore::Options {
+            globs: self.globs.clone(),
+            scope: self.stash.token_scope(),
+            ..Default::default()
+        };
+        let report = verify_restore::verify(&stash, options.list(&stash), self.local.as_deref())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        println!("Files:         {}", report.files);
+        println!("Verified:      {}", format_size(report.bytes, BINARY));
+
+        if report.problems.is_empty() {
+            println!("No problems found");
+            return;
+        }
+
+        for problem in report.problems.iter() {
+            println!("{problem}");
+        }
+        fatal_error(format!("Found {} problems", report.problems.len()));
+    }
+}
@@ -1,30 +1,121 @@
 //! `wipe` subcommand
 
 use crate::prelude::*;
+use zerostash_files::{backends::manifest_id, gc};
 
 #[derive(Command, Debug)]
 pub struct Wipe {
-    stash: String,
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Don't ask for confirmation
+    #[clap(long)]
+    yes: bool,
+
+    /// Also remove the stash from the config file
+    #[clap(long)]
+    forget: bool,
 }
 
 #[async_trait]
 impl AsyncRunnable for Wipe {
     /// Start the application.
     async fn run(&self) {
-        use crate::config::Backend::*;
-
-        let config = APP.config();
-        let path = match config.resolve_stash(&self.stash) {
-            None => self.stash.clone(),
-            Some(stash) => match &stash.backend {
-                Filesystem { path } => path.clone(),
-                _ => {
-                    println!("Wipe: Non-local backend found, skipping...");
-                    return;
-                }
-            },
-        };
-
-        std::fs::remove_dir_all(path).expect("Error while wiping stash...");
+        let config = self.stash.parse_stash();
+        self.stash.require_delete();
+
+        if !self.yes && !confirm(&self.stash.stash) {
+            println!("Wipe: Aborted");
+            return;
+        }
+
+        match config.backend.local_dir() {
+            Some(path) => {
+                std::fs::remove_dir_all(path).unwrap_or_else(|e| fatal_error(e));
+            }
+            None => self.delete_objects(&config),
+        }
+
+        for path in config.backend.cache_paths() {
+            remove_cache(path);
+        }
+        // objects cached by `mount`
+        #[cfg(unix)]
+        remove_cache(config.mount_cache_dir());
+
+        if self.forget {
+            let mut stashes = ZerostashConfig::clone(&APP.config());
+            stashes
+                .remove_stash(&config.alias)
+                .and_then(|_| stashes.write())
+                .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        }
+
+        let retained = APP.retained_objects().take();
+        if retained.is_empty() {
+            println!("Wiped {}", self.stash.stash);
+        } else {
+            println!(
+                "Wiped {}, except {} objects that S3 Object Lock keeps until their retention expires",
+                self.stash.stash,
+                retained.len()
+            );
+        }
+    }
+}
+
+impl Wipe {
+    /// Delete the objects of a remote stash one by one, file contents
+    /// first, so an interrupted wipe can be run again
+    fn delete_objects(&self, config: &crate::config::Stash) {
+        let open = config
+            .opener(self.stash.key())
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        let mut data = gc::data_objects(open).unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+
+        // repacked objects are gone, the objects that hold their live
+        // chunks are not
+        let relocations = config
+            .relocations()
+            .unwrap_or_else(|e| fatal_error(format!("{e:#}")));
+        data.retain(|id| relocations.get(id).is_none());
+        data.extend(relocations.targets());
+
+        let stash = self.stash.open();
+        stash.load_all().unwrap();
+
+        let mut index = stash.index_object_ids().collect::<Vec<_>>();
+        if !relocations.is_empty() {
+            index.push(manifest_id());
+        }
+        stash.index().key_slots.for_each(|_, slot| {
+            index.extend(slot.objects.iter().copied());
+        });
+        stash.index().tokens.for_each(|_, token| {
+            index.extend(token.slot.objects.iter().copied());
+        });
+
+        let backend = stash.backend();
+        backend
+            .delete(&data.into_iter().collect::<Vec<_>>())
+            .and_then(|_| backend.delete(&index))
+            .and_then(|_| backend.sync())
+            .unwrap_or_else(|e| fatal_error(e));
+    }
+}
+
+fn remove_cache(path: impl AsRef<std::path::Path>) {
+    match std::fs::remove_dir_all(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => fatal_error(e),
+        _ => {}
     }
 }
+
+fn confirm(name: &str) -> bool {
+    let reply = rprompt::prompt_reply(format!(
+        "This deletes every commit and file in the stash. Type `{name}` to confirm: "
+    ))
+    .unwrap_or_default();
+
+    reply.trim() == name
+}
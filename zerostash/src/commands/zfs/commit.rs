@@ -2,13 +2,14 @@
 
 use std::{
     io::Read,
+    num::NonZeroUsize,
     process::{Child, ChildStdout, Stdio},
 };
 
-use infinitree::Infinitree;
+use infinitree::{object::Pool, Infinitree};
 use zerostash_files::{Files, ZfsSnapshot};
 
-use crate::prelude::*;
+use crate::{migration::migration, prelude::*};
 
 #[derive(Command, Debug)]
 pub struct ZfsCommit {
@@ -33,8 +34,11 @@ pub struct ZfsCommit {
 impl AsyncRunnable for ZfsCommit {
     /// Start the application.
     async fn run(&self) {
-        let stash = self.stash.open();
-        stash.load(stash.index().zfs_snapshots()).unwrap();
+        self.stash.require_write();
+        let (mut stash, _lock) = self.stash.open_locked("zfs commit");
+        // everything is written back on commit
+        stash.load_all().unwrap();
+        migration(&mut stash);
 
         let args = {
             let mut args = self.arguments.to_vec();
@@ -84,9 +88,17 @@ async fn store_stream_from_stdout(
         panic!("cannot overwrite existing snapshot");
     }
 
-    let writer = stash.storage_writer().unwrap();
+    let threads = APP.get_worker_threads();
+    let writer = Pool::new(
+        NonZeroUsize::new(threads).unwrap(),
+        stash.storage_writer().unwrap(),
+    )
+    .unwrap();
+    let hasher = stash.hasher().unwrap();
+
     let stream = abscissa_tokio::tokio::task::block_in_place(|| {
-        ZfsSnapshot::from_stdout(writer, stdout).expect("failed to capture snapshot")
+        ZfsSnapshot::from_stdout(writer, hasher, stdout, threads)
+            .expect("failed to capture snapshot")
     });
 
     snapshots.insert(snapshot, stream);
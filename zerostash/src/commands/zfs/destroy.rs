@@ -1,6 +1,7 @@
 //! `zfs destroy` subcommand
 
 use crate::prelude::*;
+use zerostash_files::RefLogEntry;
 
 #[derive(Command, Debug)]
 pub struct ZfsDestroy {
@@ -16,9 +17,19 @@ pub struct ZfsDestroy {
 impl AsyncRunnable for ZfsDestroy {
     /// Start the application.
     async fn run(&self) {
-        let stash = self.stash.open();
+        self.stash.require_delete();
+        let (stash, _lock) = self.stash.open_locked("zfs destroy");
         stash.load_all().unwrap();
 
+        let Some(snapshot) = stash.index().zfs_snapshots.get(&self.name) else {
+            fatal_error(format!("No snapshot named '{}' in the stash", self.name));
+        };
+
+        let mut entry = RefLogEntry::new(&stash, "zfs destroy");
+        entry
+            .zfs_snapshots
+            .push((self.name.clone(), snapshot.as_ref().clone()));
+        stash.index().record(entry);
         stash.index().zfs_snapshots.remove(self.name.clone());
 
         stash
@@ -6,9 +6,9 @@ use std::{
 };
 
 use infinitree::Infinitree;
-use zerostash_files::Files;
+use zerostash_files::{legacy, Files};
 
-use crate::prelude::*;
+use crate::{commands::report_reconstructed, prelude::*};
 
 #[derive(Command, Debug)]
 pub struct ZfsExtract {
@@ -29,8 +29,19 @@ pub struct ZfsExtract {
 impl AsyncRunnable for ZfsExtract {
     /// Start the application.
     async fn run(&self) {
+        if !self.stash.role().can_read_data() {
+            fatal_error("The key can't read file contents from the stash");
+        }
+        if self
+            .stash
+            .token_scope()
+            .is_some_and(|scope| scope.prefix.is_some())
+        {
+            fatal_error("Tokens limited to a path prefix can't read ZFS snapshots");
+        }
+
         let stash = self.stash.open();
-        stash.load(stash.index().zfs_snapshots()).unwrap();
+        report_reconstructed(legacy::load_zfs_snapshots(&stash));
 
         let mut child = execute_command(&self.arguments);
         let stdin = child.stdin.as_mut().expect("failed to open stdin");
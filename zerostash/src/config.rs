@@ -6,21 +6,40 @@
 
 use crate::{application::APP, prelude::Stash as InfiniStash};
 use abscissa_core::Application;
-use anyhow::Result;
+use anyhow::{Context, Result};
+use secrecy::ExposeSecret;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+use zerostash_files::{
+    backends::{BandwidthSchedule, Observed, Relocated, Relocations, Throttled, Traced},
+    KeyPolicy, KeyType, StashParams, FORMAT_VERSION,
+};
 
 mod crypto_box_keys;
 pub use crypto_box_keys::*;
+mod keychain;
+pub use keychain::*;
 mod symmetric_key;
 pub use symmetric_key::*;
 mod yubikey;
 pub use yubikey::*;
+mod fido2;
+pub use fido2::*;
+mod kdf;
+pub use kdf::*;
 
 mod key;
 pub use key::*;
 mod backend;
 pub use backend::*;
+pub mod bundle;
+pub mod hooks;
+mod imds;
+mod secret;
+pub use secret::Secret;
+mod key_slots;
+pub mod raw_key;
+pub mod shamir;
 
 pub trait KeyToSource {
     type Target;
@@ -34,6 +53,39 @@ pub struct ZerostashConfig {
     /// An example configuration section
     #[serde(rename = "stash", default)]
     stashes: HashMap<String, Stash>,
+
+    /// Restrict the key types used to open stashes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_policy: Option<KeyPolicy>,
+
+    /// The file the configuration was loaded from
+    #[serde(skip)]
+    pub(crate) file: Option<PathBuf>,
+}
+
+/// What opening a stash without creating it found
+pub enum OpenOutcome {
+    Opened(InfiniStash),
+    /// There's no stash at the location
+    NotFound,
+    /// The location holds data, but the key doesn't open it
+    AuthFailed(anyhow::Error),
+}
+
+impl OpenOutcome {
+    /// Return the stash, or an error that explains why it couldn't
+    /// be opened
+    pub fn into_stash(self, alias: &str) -> Result<InfiniStash> {
+        match self {
+            Self::Opened(stash) => Ok(stash),
+            Self::NotFound => anyhow::bail!(
+                "No stash found at {alias}. Check the location, or create a stash with `0s init` or --create"
+            ),
+            Self::AuthFailed(error) => Err(error.context(format!(
+                "Authentication failed for {alias}: the key doesn't open this stash"
+            ))),
+        }
+    }
 }
 
 /// Describe the configuration for a named stash
@@ -44,6 +96,16 @@ pub struct Stash {
     pub key: Key,
     /// Backend configuration for the stash
     pub backend: Backend,
+    /// Groups for running batch operations with `0s all`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Limit the bandwidth used by the backend, depending on the
+    /// time of day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<BandwidthSchedule>,
+    /// Shell commands to run before and after `commit`
+    #[serde(default, skip_serializing_if = "hooks::Hooks::is_empty")]
+    pub hooks: hooks::Hooks,
 
     /// Name as referenced by the user. We can't deserialize this.
     /// However, when reading the config, `resolve_stash` will populate it.
@@ -61,10 +123,13 @@ impl FromStr for Stash {
                 backend: name.parse()?,
                 alias: name.to_string(),
                 key: Default::default(),
+                groups: vec![],
+                bandwidth: None,
+                hooks: Default::default(),
             },
         };
 
-        if let Backend::Filesystem { path } = &stash.backend {
+        if let Backend::Filesystem { path, .. } = &stash.backend {
             stash.alias = path.clone();
         };
 
@@ -73,35 +138,333 @@ impl FromStr for Stash {
 }
 
 impl Stash {
+    fn open_backend(&self) -> Result<Arc<dyn infinitree::backends::Backend>> {
+        // reads of repacked objects go to the objects that hold their
+        // chunks now
+        match self.relocations() {
+            Ok(relocations) => APP.relocations().write().unwrap().extend(relocations),
+            Err(error) => tracing::warn!("{error:#}"),
+        }
+
+        let backend: Arc<dyn infinitree::backends::Backend> = Traced::new(Observed::new(
+            Relocated::new(self.backend.to_infinitree()?, APP.relocations()),
+            APP.transfer_stats(),
+        ));
+
+        Ok(match &self.bandwidth {
+            Some(schedule) if !schedule.is_unlimited() => Throttled::new(backend, schedule.clone()),
+            _ => backend,
+        })
+    }
+
+    /// Objects that `prune` repacked into others. They're read from the
+    /// long-term backend, as a cache could hold an outdated list.
+    pub(crate) fn relocations(&self) -> Result<Relocations> {
+        let backend = self.backend.long_term().to_infinitree()?;
+        Relocations::load(backend.as_ref()).context("Failed to read the list of repacked objects")
+    }
+
+    /// Replace the list of repacked objects
+    pub(crate) fn store_relocations(&self, relocations: &Relocations) -> Result<()> {
+        let backend = self.backend.long_term().to_infinitree()?;
+        relocations
+            .store(backend.as_ref())
+            .context("Failed to write the list of repacked objects")
+    }
+
     fn get_locators(
         &self,
         override_key: Option<Key>,
-    ) -> Result<(Arc<dyn infinitree::backends::Backend>, infinitree::Key)> {
-        let backend = self.backend.to_infinitree()?;
+    ) -> Result<(
+        Arc<dyn infinitree::backends::Backend>,
+        infinitree::Key,
+        CryptoSuite,
+    )> {
+        let backend = self.open_backend()?;
 
-        // This is to use absolute paths in the FS.
-        let keysource = match override_key {
+        let key = match override_key {
             Some(key) => key,
             None => self.key.clone(),
         }
-        .to_keysource(&self.alias)?;
+        .with_stored_kdf(&backend)?;
+
+        let key_type = key.key_type()?;
+        if let Some(policy) = APP.config().key_policy() {
+            policy.check(key_type)?;
+        }
+
+        let backend: Arc<dyn infinitree::backends::Backend> = match key.role()? {
+            Role::Full => backend,
+            Role::WriteOnly => zerostash_files::backends::AppendOnly::new(backend),
+            Role::ReadOnly | Role::ListOnly => zerostash_files::backends::ReadOnly::new(backend),
+        };
+
+        let is_token = key.is_token()?;
+
+        // This is to use absolute paths in the FS.
+        let keysource = key.to_keysource(&self.alias)?;
+
+        if is_token {
+            match key_slots::token_scope(&backend, keysource.clone()) {
+                None => anyhow::bail!(
+                    "The token was revoked, or it doesn't belong to {}",
+                    self.alias
+                ),
+                Some(scope) if scope.is_expired(chrono::Utc::now().timestamp()) => {
+                    anyhow::bail!("The token for {} has expired", self.alias)
+                }
+                Some(_) => {}
+            }
+        }
+
+        // users of a shared stash unlock the master key through their
+        // key slot
+        let keysource = match key_slots::unlock(&backend, keysource.clone()) {
+            Some(master) => Arc::new(raw_key::to_keysource(master)?),
+            None => keysource,
+        };
+
+        Ok((backend, keysource, key_type))
+    }
+
+    /// `key` with the KDF parameters stored in the stash filled in
+    pub fn with_stored_kdf(&self, key: Key) -> Result<Key> {
+        key.with_stored_kdf(&self.open_backend()?)
+    }
+
+    /// Store the KDF parameters of a password key in the stash, so
+    /// they don't have to be configured to open it
+    pub fn store_kdf(&self, key: &Key) -> Result<()> {
+        let Key::Userpass(SymmetricKey {
+            user: Some(user),
+            kdf,
+            ..
+        }) = key
+        else {
+            return Ok(());
+        };
+
+        kdf::store(&self.open_backend()?, user.expose_secret(), *kdf)
+    }
+
+    /// Store the KDF parameters of the key a new stash is created
+    /// with, if it has any
+    fn store_new_kdf(&self, override_key: Option<&Key>) -> Result<()> {
+        match override_key.unwrap_or(&self.key) {
+            key @ Key::Userpass(SymmetricKey { kdf: Some(_), .. }) => self.store_kdf(key),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse to open the stash if it was created with a key policy
+    /// that doesn't allow `key_type`
+    fn enforce_key_policy(stash: &InfiniStash, key_type: KeyType) -> Result<()> {
+        if stash.commit_list().iter().next().is_some() {
+            stash.load(stash.index().key_policy())?;
+        }
+
+        if let Some(policy) = stash.index().key_policy.read().as_ref() {
+            policy.check(key_type)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load the parameters the stash was created with, so they are
+    /// carried over to new commits.
+    ///
+    /// Fails if the stash was written in a newer format.
+    fn load_params(stash: &InfiniStash) -> Result<()> {
+        if stash.commit_list().iter().next().is_some() {
+            stash.load(stash.index().params())?;
+            stash.index().params.read().check_format()?;
+        }
+
+        Ok(())
+    }
+
+    fn open_with(
+        &self,
+        backend: Arc<dyn infinitree::backends::Backend>,
+        key: infinitree::Key,
+        key_type: KeyType,
+    ) -> Result<OpenOutcome> {
+        let error = match InfiniStash::open(backend, key) {
+            Ok(stash) => {
+                Self::enforce_key_policy(&stash, key_type)?;
+                Self::load_params(&stash)?;
+                return Ok(OpenOutcome::Opened(stash));
+            }
+            Err(error) => anyhow::Error::from(error),
+        };
 
-        Ok((backend, keysource))
+        // If we can tell that there's something at the location, the
+        // key is wrong. Otherwise, only a missing root object means
+        // there's no stash.
+        let missing = match self.backend.holds_objects() {
+            Some(holds_objects) => !holds_objects,
+            None => error.chain().any(|e| {
+                e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+            }),
+        };
+
+        Ok(if missing {
+            OpenOutcome::NotFound
+        } else {
+            OpenOutcome::AuthFailed(error)
+        })
+    }
+
+    fn new_with(
+        backend: Arc<dyn infinitree::backends::Backend>,
+        key: infinitree::Key,
+        key_type: KeyType,
+        params: StashParams,
+    ) -> Result<InfiniStash> {
+        let stash = InfiniStash::empty(backend, key)?;
+
+        // only new stashes record the local key policy, so opening an
+        // existing stash never changes the policy it is bound by
+        *stash.index().key_policy.write() = APP.config().key_policy();
+        *stash.index().params.write() = StashParams {
+            key_type: Some(key_type),
+            format_version: FORMAT_VERSION,
+            ..params
+        };
+
+        // the lock key is part of the first commit, so no client ever
+        // sees the stash without locks
+        zerostash_files::Locks::setup(&stash)?;
+
+        Ok(stash)
+    }
+
+    /// Try to open an existing stash with the config-stored credentials
+    pub fn try_open(&self, override_key: Option<Key>) -> Result<OpenOutcome> {
+        let (backend, key, key_type) = self.get_locators(override_key)?;
+        self.open_with(backend, key, key_type)
+    }
+
+    /// Open the stash, or create it with default parameters if
+    /// `create` is set and it doesn't exist yet
+    pub fn open_or_create(&self, override_key: Option<Key>, create: bool) -> Result<OpenOutcome> {
+        let (backend, key, key_type) = self.get_locators(override_key.clone())?;
+        match self.open_with(backend.clone(), key.clone(), key_type)? {
+            OpenOutcome::NotFound if create => {
+                let stash = Self::new_with(backend, key, key_type, StashParams::default())?;
+                self.store_new_kdf(override_key.as_ref())?;
+                Ok(OpenOutcome::Opened(stash))
+            }
+            outcome => Ok(outcome),
+        }
     }
 
-    /// Try to open a stash with the config-stored credentials
-    pub fn try_open(&self, override_key: Option<Key>) -> Result<InfiniStash> {
-        let (backend, key) = self.get_locators(override_key)?;
-        InfiniStash::open(backend, key)
+    /// Open the stash like [`Self::open_or_create`], and return a
+    /// function that opens it again with the same key, eg. once a lock
+    /// is held
+    pub fn open_reopenable(
+        &self,
+        override_key: Option<Key>,
+        create: bool,
+    ) -> Result<(InfiniStash, impl FnMut() -> Result<InfiniStash>)> {
+        let (backend, key, key_type) = self.get_locators(override_key.clone())?;
+        let stash = match self.open_with(backend.clone(), key.clone(), key_type)? {
+            OpenOutcome::NotFound if create => {
+                let stash = Self::new_with(
+                    backend.clone(),
+                    key.clone(),
+                    key_type,
+                    StashParams::default(),
+                )?;
+                self.store_new_kdf(override_key.as_ref())?;
+                stash
+            }
+            outcome => outcome.into_stash(&self.alias)?,
+        };
+
+        // reopening checks the key policy and loads the parameters
+        // again, as the stash may have changed in the meantime
+        let this = self.clone();
+        Ok((stash, move || {
+            this.open_with(backend.clone(), key.clone(), key_type)?
+                .into_stash(&this.alias)
+        }))
     }
 
-    pub fn open_or_new(&self, override_key: Option<Key>) -> Result<InfiniStash> {
-        let (backend, key) = self.get_locators(override_key)?;
-        let stash = InfiniStash::open(backend.clone(), key.clone())
-            .or_else(|_| InfiniStash::empty(backend, key))?;
+    /// Create a new, empty stash with the given parameters.
+    ///
+    /// Fails if the key already opens a stash on the backend.
+    pub fn create(&self, override_key: Option<Key>, params: StashParams) -> Result<InfiniStash> {
+        let (backend, key, key_type) = self.get_locators(override_key.clone())?;
+        if InfiniStash::open(backend.clone(), key.clone()).is_ok() {
+            anyhow::bail!("A stash already exists at {}", self.alias);
+        }
 
+        let stash = Self::new_with(backend, key, key_type, params)?;
+        self.store_new_kdf(override_key.as_ref())?;
         Ok(stash)
     }
+
+    /// What the configured key, or `override_key`, may do with the
+    /// stash
+    pub fn role(&self, override_key: Option<&Key>) -> Result<Role> {
+        override_key.unwrap_or(&self.key).role()
+    }
+
+    /// Directory for the objects read through a mount of the stash,
+    /// so the next mount doesn't start with a cold cache
+    #[cfg(unix)]
+    pub fn mount_cache_dir(&self) -> PathBuf {
+        let name = self
+            .alias
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+
+        xdg::BaseDirectories::with_prefix("zerostash")
+            .unwrap()
+            .get_cache_home()
+            .join("mount")
+            .join(name)
+    }
+
+    /// Directory for the journals of interrupted operations on the
+    /// stash, so they can be resumed
+    #[cfg(unix)]
+    pub fn state_dir(&self) -> PathBuf {
+        xdg::BaseDirectories::with_prefix("zerostash")
+            .unwrap()
+            .get_state_home()
+            .join("journals")
+    }
+
+    /// Directory for the journals of interrupted operations on the
+    /// stash, so they can be resumed
+    #[cfg(windows)]
+    pub fn state_dir(&self) -> PathBuf {
+        dirs::home_dir()
+            .expect("cannot find home directory")
+            .join(".zerostash")
+            .join("journals")
+    }
+
+    /// Return a function that opens a fresh instance of the stash on
+    /// every call, resolving the key only once.
+    ///
+    /// Fails like [`OpenOutcome::into_stash`] if the key doesn't open
+    /// a stash at the location.
+    pub fn opener(&self, override_key: Option<Key>) -> Result<impl FnMut() -> Result<InfiniStash>> {
+        let (backend, key, key_type) = self.get_locators(override_key)?;
+        self.open_with(backend.clone(), key.clone(), key_type)?
+            .into_stash(&self.alias)?;
+
+        let this = self.clone();
+        Ok(move || {
+            this.open_with(backend.clone(), key.clone(), key_type)?
+                .into_stash(&this.alias)
+        })
+    }
 }
 
 impl ZerostashConfig {
@@ -126,13 +489,126 @@ impl ZerostashConfig {
         p
     }
 
-    /// Write the config file to the file system
+    /// Write the config file to the file system.
+    ///
+    /// Only the stashes that changed are rewritten, so the comments and
+    /// layout of the rest of the file are kept.
     pub fn write(&self) -> Result<()> {
-        unimplemented!()
+        let path = self.file.clone().unwrap_or_else(Self::path);
+        let mut document = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.parse::<toml_edit::DocumentMut>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let on_disk: ZerostashConfig = toml::from_str(&document.to_string())?;
+
+        let stashes = document
+            .entry("stash")
+            .or_insert_with(|| {
+                let mut table = toml_edit::Table::new();
+                table.set_implicit(true);
+                table.into()
+            })
+            .as_table_like_mut()
+            .context("`stash` is not a table in the config file")?;
+
+        for (alias, _) in on_disk.stashes.iter() {
+            if !self.stashes.contains_key(alias) {
+                stashes.remove(alias);
+            }
+        }
+
+        let mut aliases = self.stashes.keys().collect::<Vec<_>>();
+        aliases.sort();
+        for alias in aliases {
+            let stash = toml::to_string(&self.stashes[alias])?;
+            let unchanged = on_disk
+                .stashes
+                .get(alias)
+                .is_some_and(|old| toml::to_string(old).is_ok_and(|old| old == stash));
+
+            if !unchanged {
+                // keep `key` and `backend` on a line each, like the
+                // example config does
+                let mut table = toml_edit::Table::new();
+                for (key, item) in stash.parse::<toml_edit::DocumentMut>()?.iter() {
+                    if let Ok(value) = item.clone().into_value() {
+                        table.insert(key, value.into());
+                    }
+                }
+                stashes.insert(alias, table.into());
+            }
+        }
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        options
+            .open(&path)
+            .and_then(|mut file| {
+                std::io::Write::write_all(&mut file, document.to_string().as_bytes())
+            })
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Add a stash to the config under `alias`
+    pub fn add_stash(&mut self, alias: &str, mut stash: Stash) -> Result<()> {
+        if self.stashes.contains_key(alias) {
+            anyhow::bail!("{alias} is already configured");
+        }
+
+        stash.alias = alias.to_string();
+        self.stashes.insert(alias.to_string(), stash);
+        Ok(())
+    }
+
+    /// Remove the stash `alias` from the config
+    pub fn remove_stash(&mut self, alias: &str) -> Result<Stash> {
+        self.stashes
+            .remove(alias)
+            .with_context(|| format!("{alias} is not configured"))
+    }
+
+    /// Change the alias of a configured stash
+    pub fn rename_stash(&mut self, alias: &str, new_alias: &str) -> Result<()> {
+        if self.stashes.contains_key(new_alias) {
+            anyhow::bail!("{new_alias} is already configured");
+        }
+
+        let stash = self.remove_stash(alias)?;
+        self.add_stash(new_alias, stash)
+    }
+
+    /// The key policy in effect, if any.
+    ///
+    /// Builds with the `key-policy` feature always enforce a policy.
+    pub fn key_policy(&self) -> Option<KeyPolicy> {
+        if cfg!(feature = "key-policy") {
+            return Some(self.key_policy.clone().unwrap_or_default());
+        }
+
+        self.key_policy.clone()
     }
 
     /// Find a stash by name in the config, and return a read-only
     /// reference if found
+    /// Aliases of the configured stashes in `group`, or all of them
+    pub fn stashes_in(&self, group: Option<&str>) -> Vec<String> {
+        let mut aliases = self
+            .stashes
+            .iter()
+            .filter(|(_, stash)| match group {
+                Some(group) => stash.groups.iter().any(|g| g == group),
+                None => true,
+            })
+            .map(|(alias, _)| alias.clone())
+            .collect::<Vec<_>>();
+        aliases.sort();
+        aliases
+    }
+
     pub fn resolve_stash(&self, alias: impl AsRef<str>) -> Option<Stash> {
         match self.stashes.get(alias.as_ref()).cloned() {
             Some(mut stash) => {
@@ -170,6 +646,10 @@ backend = { type = "fs", path = "/path/to/stash" }
 key = { source = "yubikey", user = "123", password = "123" }
 backend = { type = "fs", path = "/path/to/stash" }
 
+[stash.fido2]
+key = { source = "fido2", user = "123", password = "123", credential = "00ff", salt = "00ff", pin = true }
+backend = { type = "fs", path = "/path/to/stash" }
+
 [stash.writeonly]
 key = { source = "split_key", user = "123", password = "123", write = "p0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venq9sue6u"}
 backend = { type = "fs", path = "/path/to/stash" }
@@ -183,10 +663,59 @@ password = "123"
 write = "p0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venq9sue6u"
 read = "s0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venqn52utr"
 
+[stash.split_operator]
+backend = { type = "fs", path = "/path/to/stash" }
+[stash.split_operator.key]
+source = "split_key"
+user = "123"
+password = "123"
+write = "p0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venq9sue6u"
+read_from = { source = "file", path = "/media/usb/backup.secret" }
+
+[stash.split_list_only]
+backend = { type = "fs", path = "/path/to/stash" }
+[stash.split_list_only.key]
+source = "read_only"
+key = { source = "split_key", user = "123", password = "123", write = "p0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venq9sue6u" }
+
+[stash.split_sealed]
+backend = { type = "fs", path = "/path/to/stash" }
+[stash.split_sealed.key]
+source = "split_key"
+user = "123"
+password = "123"
+write = "p0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venq9sue6u"
+read_from = { source = "fido2", sealed = "00ff", credential = "00ff", salt = "00ff" }
+
+[stash.stretched]
+key = { source = "plaintext", user = "123", password = "123", kdf = { memory_mib = 512, iterations = 4, parallelism = 4 } }
+backend = { type = "fs", path = "/path/to/stash" }
+
+[stash.os_keychain]
+key = { source = "keychain", user = "123" }
+backend = { type = "fs", path = "/path/to/stash" }
+
 [stash.keyfile]
 key = { source = "file", path = "./example_keyfile.toml" }
 backend = { type = "fs", path = "/path/to/stash" }
 
+[stash.auditor]
+key = { source = "read_only", key = { source = "file", path = "./example_keyfile.toml" } }
+backend = { type = "fs", path = "/path/to/stash" }
+
+[stash.delegated]
+key = { source = "token", token = "t0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venq9sue6u" }
+backend = { type = "fs", path = "/path/to/stash" }
+
+[stash.durable]
+key = { source = "ask" }
+backend = { type = "fs", path = "/path/to/stash", fsync = true, tmpfile_rename = true }
+
+[stash.grouped]
+key = { source = "ask" }
+backend = { type = "fs", path = "/path/to/stash" }
+groups = ["nightly", "offsite"]
+
 
 [stash.s3]
 key = { source = "ask" }
@@ -208,9 +737,112 @@ max_size_mb = 1024
 type = "s3"
 bucket = "test_bucket"
 region = { name = "custom", details = { endpoint = "https://127.0.0.1:8080/", "region" = "" }}
+
+[stash.s3_spilled]
+key = { source = "ask" }
+
+[stash.s3_spilled.backend]
+type = "fs_cache"
+path = "/path_to_cache"
+max_size_mb = 1024
+min_free_percent = 10
+spill = [{ path = "/path_to_scratch", max_size_mb = 4096 }]
+
+[stash.s3_spilled.backend.upstream]
+type = "s3"
+bucket = "test_bucket"
+region = { name = "us-east-1" }
+
+[stash.s3_verified]
+key = { source = "ask" }
+
+[stash.s3_verified.backend]
+type = "verify"
+
+[stash.s3_verified.backend.upstream]
+type = "s3"
+bucket = "test_bucket"
+region = { name = "us-east-1" }
+
+[stash.shared]
+key = { source = "ask" }
+backend = { type = "socket", path = "/run/zerostash/objectd.sock" }
+
+[stash.s3_ipv6]
+key = { source = "ask" }
+[stash.s3_ipv6.backend]
+type = "s3"
+bucket = "test_bucket"
+region = { name = "custom", details = { endpoint = "https://[2001:db8::1]:9443/", "region" = "" }}
+path_style = true
+ca_bundle = "/etc/zerostash/ca.pem"
+
+[stash.s3_sts]
+key = { source = "ask" }
+backend = { type = "s3", bucket = "test_bucket", region = { name = "us-east-1" }, keys = ["ASIA...", "secret_key"], session_token = "token" }
+
+[stash.s3_ec2]
+key = { source = "ask" }
+backend = { type = "s3", bucket = "test_bucket", region = { name = "us-east-1" }, instance_metadata = true }
+
+[stash.s3_locked]
+key = { source = "ask" }
+backend = { type = "s3", bucket = "test_bucket", region = { name = "us-east-1" }, immutable_days = 30 }
+
+[key_policy]
+allowed = ["symmetric", "crypto_box"]
+"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn write_keeps_unchanged_stashes() {
+        use super::{Stash, ZerostashConfig};
+        use abscissa_core::Config;
+
+        let path = std::env::temp_dir().join(format!("0s-config-{}.toml", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            r#"# my stashes
+[stash.home]
+key = { source = "ask" }
+backend = { type = "fs", path = "/home/backup" } # external drive
 "#,
         )
         .unwrap();
+
+        let mut config =
+            ZerostashConfig::load_toml(std::fs::read_to_string(&path).unwrap()).unwrap();
+        config.file = Some(path.clone());
+
+        let server = Stash {
+            key: Default::default(),
+            backend: "/srv/backup".parse().unwrap(),
+            groups: vec!["nightly".into()],
+            bandwidth: None,
+            hooks: Default::default(),
+            alias: String::new(),
+        };
+        config.add_stash("server", server).unwrap();
+        config.write().unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("# my stashes"));
+        assert!(written.contains("# external drive"));
+
+        let mut config = ZerostashConfig::load_toml(&written).unwrap();
+        config.file = Some(path.clone());
+        assert_eq!(config.stashes_in(Some("nightly")), vec!["server"]);
+
+        config.rename_stash("home", "drive").unwrap();
+        config.remove_stash("server").unwrap();
+        config.write().unwrap();
+
+        let config = ZerostashConfig::load_toml(std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(config.stashes_in(None), vec!["drive"]);
+
+        std::fs::remove_file(path).unwrap();
     }
 
     #[test]
@@ -277,7 +909,12 @@ backend = { type = "fs", path = "/path/to/stash" }
             Backend::S3 {
                 bucket: "bucket/path".into(),
                 region: Region::UsEast1,
-                keys: Some(("access".into(), "secret".into()))
+                keys: Some(("access".into(), "secret".into())),
+                session_token: None,
+                instance_metadata: false,
+                path_style: false,
+                ca_bundle: None,
+                immutable_days: None,
             }
         );
 
@@ -286,7 +923,12 @@ backend = { type = "fs", path = "/path/to/stash" }
             Backend::S3 {
                 bucket: "bucket/path".into(),
                 region: Region::UsEast1,
-                keys: None
+                keys: None,
+                session_token: None,
+                instance_metadata: false,
+                path_style: false,
+                ca_bundle: None,
+                immutable_days: None,
             }
         );
 
@@ -300,7 +942,12 @@ backend = { type = "fs", path = "/path/to/stash" }
                     region: "us-east-1".into(),
                     endpoint: "server.com".into()
                 },
-                keys: None
+                keys: None,
+                session_token: None,
+                instance_metadata: false,
+                path_style: false,
+                ca_bundle: None,
+                immutable_days: None,
             }
         );
 
@@ -314,7 +961,12 @@ backend = { type = "fs", path = "/path/to/stash" }
                     region: "".into(),
                     endpoint: "server.com".into()
                 },
-                keys: Some(("access".into(), "secret-".into()))
+                keys: Some(("access".into(), "secret-".into())),
+                session_token: None,
+                instance_metadata: false,
+                path_style: false,
+                ca_bundle: None,
+                immutable_days: None,
             }
         );
 
@@ -328,11 +980,70 @@ backend = { type = "fs", path = "/path/to/stash" }
                     region: "us-east-1".into(),
                     endpoint: "server.com".into()
                 },
-                keys: Some(("accesskey".into(), "secret+key/=".into()))
+                keys: Some(("accesskey".into(), "secret+key/=".into())),
+                session_token: None,
+                instance_metadata: false,
+                path_style: false,
+                ca_bundle: None,
+                immutable_days: None,
+            }
+        );
+
+        assert_eq!(
+            "s3://us-east-1#[2001:db8::1]:9000/bucket"
+                .parse::<Backend>()
+                .unwrap(),
+            Backend::S3 {
+                bucket: "bucket".into(),
+                region: Region::Custom {
+                    region: "us-east-1".into(),
+                    endpoint: "[2001:db8::1]:9000".into()
+                },
+                keys: None,
+                session_token: None,
+                instance_metadata: false,
+                path_style: false,
+                ca_bundle: None,
+                immutable_days: None,
+            }
+        );
+
+        assert_eq!(
+            "s3://[::1]:9000/bucket".parse::<Backend>().unwrap(),
+            Backend::S3 {
+                bucket: "bucket".into(),
+                region: Region::Custom {
+                    region: "".into(),
+                    endpoint: "[::1]:9000".into()
+                },
+                keys: None,
+                session_token: None,
+                instance_metadata: false,
+                path_style: false,
+                ca_bundle: None,
+                immutable_days: None,
             }
         )
     }
 
+    #[test]
+    fn read_only_split_key_is_list_only() {
+        use super::{Key, Role};
+
+        let key: Key = toml::from_str(
+            r#"
+source = "read_only"
+key = { source = "split_key", user = "123", password = "123", write = "p0s-1xqcrzvfjxgenxdp5x56nvd3hxuurswfev9skycnrvdjxget9venq9sue6u" }
+"#,
+        )
+        .unwrap();
+
+        let role = key.role().unwrap();
+        assert_eq!(role, Role::ListOnly);
+        assert!(!role.can_read_data());
+        assert!(!role.can_write());
+    }
+
     #[test]
     fn no_scheme_gets_file_backend() {
         use super::Backend;
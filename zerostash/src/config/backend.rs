@@ -1,9 +1,11 @@
-use super::Result;
+use super::{Result, Role, Secret};
+use crate::application::APP;
 use anyhow::Context;
 use infinitree_backends::Region;
+use secrecy::ExposeSecret;
 use serde::{Deserialize, Serialize};
 use std::{
-    num::NonZeroUsize,
+    num::{NonZeroU32, NonZeroUsize},
     path::{Component, Path, PathBuf},
     str::FromStr,
     sync::Arc,
@@ -18,8 +20,19 @@ use std::{
 pub enum Backend {
     /// Use a directory on a local filesystem
     #[serde(rename = "fs")]
-    #[allow(missing_docs)]
-    Filesystem { path: String },
+    Filesystem {
+        /// Directory to store objects in
+        path: String,
+
+        /// Flush every object to the disk before moving on
+        #[serde(default, skip_serializing_if = "is_false")]
+        fsync: bool,
+
+        /// Write objects to a temporary file and rename them into
+        /// place, so interrupted writes don't leave partial objects
+        #[serde(default, skip_serializing_if = "is_false")]
+        tmpfile_rename: bool,
+    },
 
     /// Descriptor for S3 connection.
     #[serde(rename = "s3")]
@@ -32,7 +45,31 @@ pub enum Backend {
         region: Region,
 
         /// ("access_key_id", "secret_access_key")
-        keys: Option<(String, String)>,
+        keys: Option<(String, Secret)>,
+
+        /// Session token of temporary credentials issued by AWS STS
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_token: Option<Secret>,
+
+        /// Fetch temporary credentials from the EC2 instance metadata
+        /// service, and refresh them before they expire
+        #[serde(default, skip_serializing_if = "is_false")]
+        instance_metadata: bool,
+
+        /// Address objects as `endpoint/bucket/key` instead of
+        /// `bucket.endpoint/key`. Most self-hosted servers need this.
+        #[serde(default, skip_serializing_if = "is_false")]
+        path_style: bool,
+
+        /// PEM file with additional CA certificates to trust
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ca_bundle: Option<PathBuf>,
+
+        /// Lock uploaded objects in compliance mode for this many days
+        /// using S3 Object Lock. The bucket needs to have Object Lock
+        /// enabled.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        immutable_days: Option<NonZeroU32>,
     },
 
     /// Cache files in a local directory, up to `max_size` in size
@@ -43,21 +80,334 @@ pub enum Backend {
         max_size_mb: NonZeroUsize,
         /// Where to store local files
         path: String,
+        /// Stop caching in `path` if less than this percent of the
+        /// disk would be free
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_free_percent: Option<u8>,
+        /// More directories to cache in once `path` is full
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        spill: Vec<CacheLocation>,
         /// Long-term backend
         upstream: Box<Backend>,
     },
+
+    /// Read back and verify every object written to `upstream`
+    /// before a commit is considered successful
+    #[serde(rename = "verify")]
+    Verify {
+        /// Backend to verify
+        upstream: Box<Backend>,
+    },
+
+    /// Connect to a `0s objectd` object server listening on a unix socket
+    #[cfg(unix)]
+    #[serde(rename = "socket")]
+    Socket {
+        /// Path of the socket
+        path: String,
+    },
+}
+
+/// Additional directory for an `fs_cache` backend
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CacheLocation {
+    /// Where to store local files
+    pub path: String,
+    /// Max size of the cache in this directory
+    pub max_size_mb: NonZeroUsize,
+    /// Stop caching here if less than this percent of the disk would
+    /// be free
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_percent: Option<u8>,
+}
+
+impl CacheLocation {
+    fn to_cache_dir(&self) -> zerostash_files::backends::CacheDir {
+        zerostash_files::backends::CacheDir {
+            path: self.path.clone().into(),
+            max_size: self.max_size_mb.get() as u64 * 1024 * 1024,
+            min_free_percent: self.min_free_percent.unwrap_or(0),
+        }
+    }
+}
+
+fn is_false(v: &bool) -> bool {
+    !v
 }
 
 impl Backend {
-    pub(super) fn to_infinitree(&self) -> Result<Arc<dyn infinitree::backends::Backend>> {
+    /// Whether the backend holds any objects, if that can be checked
+    /// cheaply
+    pub fn holds_objects(&self) -> Option<bool> {
+        use Backend::*;
+
+        match self {
+            Filesystem { path, .. } => Some(
+                std::fs::read_dir(path)
+                    .map(|mut dir| dir.next().is_some())
+                    .unwrap_or(false),
+            ),
+            FsCache { upstream, .. } | Verify { upstream } => upstream.holds_objects(),
+            _ => None,
+        }
+    }
+
+    /// Directory that holds every object of the stash, if the objects
+    /// are stored locally
+    pub fn local_dir(&self) -> Option<&str> {
+        use Backend::*;
+
+        match self {
+            Filesystem { path, .. } => Some(path),
+            FsCache { upstream, .. } | Verify { upstream } => upstream.local_dir(),
+            _ => None,
+        }
+    }
+
+    /// The backend that stores the objects, without the caches and
+    /// checks in front of it
+    pub fn long_term(&self) -> &Backend {
+        use Backend::*;
+
+        match self {
+            FsCache { upstream, .. } | Verify { upstream } => upstream.long_term(),
+            backend => backend,
+        }
+    }
+
+    /// Where the objects of the stash are stored, regardless of
+    /// caching and credentials
+    pub fn location(&self) -> String {
+        use Backend::*;
+
+        match self {
+            Filesystem { path, .. } => format!("fs:{}", normalize_path(Path::new(path)).display()),
+            S3 { bucket, region, .. } => format!("s3:{region}/{bucket}"),
+            FsCache { upstream, .. } | Verify { upstream } => upstream.location(),
+            #[cfg(unix)]
+            Socket { path } => format!("socket:{path}"),
+        }
+    }
+
+    /// IAM policy that allows the storage credentials of a key with
+    /// `role` only what the role needs, if the stash is stored on S3
+    ///
+    /// Commits replace the root object, so overwrites can't be denied.
+    /// Enable versioning on the bucket to keep overwritten objects.
+    pub fn storage_policy(&self, role: Role) -> Option<serde_json::Value> {
+        use Backend::*;
+
+        let bucket = match self {
+            S3 { bucket, .. } => bucket,
+            FsCache { upstream, .. } | Verify { upstream } => return upstream.storage_policy(role),
+            _ => return None,
+        };
+
+        let name = bucket
+            .split_once('/')
+            .map_or(bucket.as_str(), |(name, _)| name);
+        let objects = format!("arn:aws:s3:::{bucket}/*");
+
+        let mut allow = vec!["s3:GetObject"];
+        if role.can_write() {
+            allow.push("s3:PutObject");
+        }
+        if role.can_delete() {
+            allow.push("s3:DeleteObject");
+        }
+
+        let mut statements = vec![
+            serde_json::json!({
+                "Effect": "Allow",
+                "Action": ["s3:ListBucket"],
+                "Resource": format!("arn:aws:s3:::{name}"),
+            }),
+            serde_json::json!({
+                "Effect": "Allow",
+                "Action": allow,
+                "Resource": objects,
+            }),
+        ];
+
+        // explicit denies win over anything else attached to the user
+        if !role.can_delete() {
+            let mut deny = vec!["s3:DeleteObject", "s3:DeleteObjectVersion"];
+            if !role.can_write() {
+                deny.push("s3:PutObject");
+            }
+
+            statements.push(serde_json::json!({
+                "Effect": "Deny",
+                "Action": deny,
+                "Resource": objects,
+            }));
+            statements.push(serde_json::json!({
+                "Effect": "Deny",
+                "Action": [
+                    "s3:PutBucketVersioning",
+                    "s3:PutLifecycleConfiguration",
+                    "s3:PutBucketPolicy",
+                    "s3:DeleteBucketPolicy",
+                ],
+                "Resource": format!("arn:aws:s3:::{name}"),
+            }));
+        }
+
+        Some(serde_json::json!({
+            "Version": "2012-10-17",
+            "Statement": statements,
+        }))
+    }
+
+    /// The same backend with objects cached in `path`, unless they
+    /// are stored or cached locally already
+    pub(crate) fn with_cache(self, path: String, max_size_mb: NonZeroUsize) -> Backend {
+        if self.local_dir().is_some() || !self.cache_paths().is_empty() {
+            return self;
+        }
+
+        Backend::FsCache {
+            max_size_mb,
+            path,
+            min_free_percent: None,
+            spill: vec![],
+            upstream: Box::new(self),
+        }
+    }
+
+    /// The same backend, with objects in local directories written to
+    /// a temporary file, flushed, and renamed into place, so replacing
+    /// an object never leaves a partial one behind
+    pub(crate) fn with_atomic_writes(&self) -> Backend {
+        use Backend::*;
+
+        match self.clone() {
+            Filesystem { path, .. } => Filesystem {
+                path,
+                fsync: true,
+                tmpfile_rename: true,
+            },
+            FsCache {
+                max_size_mb,
+                path,
+                min_free_percent,
+                spill,
+                upstream,
+            } => FsCache {
+                max_size_mb,
+                path,
+                min_free_percent,
+                spill,
+                upstream: Box::new(upstream.with_atomic_writes()),
+            },
+            Verify { upstream } => Verify {
+                upstream: Box::new(upstream.with_atomic_writes()),
+            },
+            other => other,
+        }
+    }
+
+    pub(crate) fn to_infinitree(&self) -> Result<Arc<dyn infinitree::backends::Backend>> {
         use Backend::*;
 
         let backend: Arc<dyn infinitree::backends::Backend> = match self {
-            Filesystem { path } => infinitree::backends::Directory::new(path)?,
+            Filesystem {
+                path,
+                fsync,
+                tmpfile_rename,
+            } => {
+                let directory = infinitree::backends::Directory::new(path)?;
+                if *fsync || *tmpfile_rename {
+                    zerostash_files::backends::DurableDirectory::new(
+                        path,
+                        directory,
+                        zerostash_files::backends::Durability {
+                            fsync: *fsync,
+                            tmpfile_rename: *tmpfile_rename,
+                        },
+                    )
+                } else {
+                    directory
+                }
+            }
+            S3 {
+                bucket,
+                region,
+                keys,
+                session_token,
+                instance_metadata,
+                path_style,
+                ca_bundle,
+                immutable_days,
+            } if session_token.is_some()
+                || *instance_metadata
+                || *path_style
+                || ca_bundle.is_some()
+                || immutable_days.is_some() =>
+            {
+                use rusty_s3::Credentials;
+
+                if *instance_metadata && (keys.is_some() || session_token.is_some()) {
+                    anyhow::bail!("Keys can't be set when using the instance metadata service");
+                }
+
+                let root_certificates = match ca_bundle {
+                    Some(path) => vec![std::fs::read(path)
+                        .with_context(|| format!("Failed to read {}", path.display()))?],
+                    None => vec![],
+                };
+                let (endpoint, region) = s3_endpoint(region)?;
+                let options = zerostash_files::backends::S3Options {
+                    endpoint: endpoint.parse().context("Invalid S3 endpoint")?,
+                    region,
+                    bucket: bucket.clone(),
+                    path_style: *path_style,
+                    root_certificates,
+                    object_lock_days: *immutable_days,
+                    retained: APP.retained_objects(),
+                };
+
+                let connect = move |credentials| -> Result<Arc<dyn infinitree::backends::Backend>> {
+                    Ok(
+                        zerostash_files::backends::S3Backend::new(options.clone(), credentials)
+                            .context("Failed to connect to S3")?,
+                    )
+                };
+
+                if *instance_metadata {
+                    super::imds::Refreshing::new(move |temporary| {
+                        connect(Credentials::new_with_token(
+                            &temporary.access_key_id,
+                            temporary.secret_access_key.expose_secret(),
+                            temporary.token.expose_secret(),
+                        ))
+                    })?
+                } else {
+                    connect(match (keys, session_token) {
+                        (Some((access_key, secret_key)), Some(token)) => {
+                            Credentials::new_with_token(
+                                access_key,
+                                secret_key.expose_secret(),
+                                token.expose_secret(),
+                            )
+                        }
+                        (Some((access_key, secret_key)), None) => {
+                            Credentials::new(access_key, secret_key.expose_secret())
+                        }
+                        (None, Some(_)) => anyhow::bail!("`session_token` needs `keys` to be set"),
+                        // also picks up AWS_SESSION_TOKEN
+                        (None, None) => Credentials::from_env().context(
+                            "Set `keys`, or AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY",
+                        )?,
+                    })?
+                }
+            }
             S3 {
                 bucket,
                 region,
                 keys,
+                ..
             } => {
                 use infinitree_backends::{Credentials, S3};
 
@@ -65,7 +415,7 @@ impl Backend {
                     Some((access_key, secret_key)) => S3::with_credentials(
                         region.clone(),
                         bucket,
-                        Credentials::new(access_key, secret_key),
+                        Credentials::new(access_key, secret_key.expose_secret()),
                     ),
                     None => S3::new(region.clone(), bucket),
                 }
@@ -74,16 +424,93 @@ impl Backend {
             FsCache {
                 max_size_mb,
                 path,
+                min_free_percent,
+                spill,
                 upstream,
+            } if min_free_percent.is_some() || !spill.is_empty() => {
+                let first = CacheLocation {
+                    path: path.clone(),
+                    max_size_mb: *max_size_mb,
+                    min_free_percent: *min_free_percent,
+                };
+
+                zerostash_files::backends::SpillCache::new(
+                    std::iter::once(&first)
+                        .chain(spill.iter())
+                        .map(CacheLocation::to_cache_dir)
+                        .collect(),
+                    upstream.to_infinitree()?,
+                )
+                .context("Failed to set up the cache directories")?
+            }
+            FsCache {
+                max_size_mb,
+                path,
+                upstream,
+                ..
             } => infinitree_backends::Cache::new(
                 path,
                 NonZeroUsize::new(max_size_mb.get() * 1024 * 1024)
                     .expect("Deserialization should have failed if `max_size_mb` is 0"),
                 upstream.to_infinitree()?,
             )?,
+            // read objects back from the long-term backend, not a
+            // local copy
+            Verify { upstream } => match *upstream.clone() {
+                FsCache {
+                    max_size_mb,
+                    path,
+                    min_free_percent,
+                    spill,
+                    upstream,
+                } => FsCache {
+                    max_size_mb,
+                    path,
+                    min_free_percent,
+                    spill,
+                    upstream: Box::new(Verify { upstream }),
+                }
+                .to_infinitree()?,
+                upstream => zerostash_files::backends::VerifyWrites::new(upstream.to_infinitree()?),
+            },
+            #[cfg(unix)]
+            Socket { path } => zerostash_files::backends::SocketBackend::new(path)
+                .context("Failed to connect to the object server")?,
         };
 
-        Ok(backend)
+        match APP.request_log() {
+            Some(log) => Ok(log.wrap(self.type_name(), backend)),
+            None => Ok(backend),
+        }
+    }
+
+    /// Name of the backend type in the config file
+    fn type_name(&self) -> &'static str {
+        use Backend::*;
+
+        match self {
+            Filesystem { .. } => "fs",
+            S3 { .. } => "s3",
+            FsCache { .. } => "fs_cache",
+            Verify { .. } => "verify",
+            #[cfg(unix)]
+            Socket { .. } => "socket",
+        }
+    }
+}
+
+impl Backend {
+    /// Directories of the local cache in front of the backend, if any
+    pub fn cache_paths(&self) -> Vec<&str> {
+        use Backend::*;
+
+        match self {
+            FsCache { path, spill, .. } => std::iter::once(path.as_str())
+                .chain(spill.iter().map(|s| s.path.as_str()))
+                .collect(),
+            Verify { upstream } => upstream.cache_paths(),
+            _ => vec![],
+        }
     }
 }
 
@@ -94,7 +521,7 @@ impl FromStr for Backend {
         match s.split_once("://") {
             Some(("s3", url)) => {
                 let re = regex::Regex::new(
-                    r"^((?P<akey>[a-zA-Z0-9]+):(?P<skey>[a-zA-Z0-9/+=-]+)@)?((?P<region>[0-9a-z-]+)#)?(?P<host>[a-zA-Z0-9.-]+)?/(?P<bucketpath>[a-zA-Z0-9./_-]+)?$",
+                    r"^((?P<akey>[a-zA-Z0-9]+):(?P<skey>[a-zA-Z0-9/+=-]+)@)?((?P<region>[0-9a-z-]+)#)?(?P<host>\[[0-9a-fA-F:.]+\]|[a-zA-Z0-9.-]+)?(:(?P<port>[0-9]+))?/(?P<bucketpath>[a-zA-Z0-9./_-]+)?$",
                 )
                     .expect("syntactically correct");
 
@@ -102,7 +529,12 @@ impl FromStr for Backend {
                 let akey = caps.name("akey");
                 let skey = caps.name("skey");
                 let region_name = caps.name("region");
-                let host = caps.name("host");
+                let host = match (caps.name("host"), caps.name("port")) {
+                    (Some(h), Some(p)) => Some(format!("{}:{}", h.as_str(), p.as_str())),
+                    (Some(h), None) => Some(h.as_str().to_string()),
+                    (None, Some(_)) => anyhow::bail!("invalid url: port without hostname"),
+                    (None, None) => None,
+                };
                 let bucket = caps
                     .name("bucketpath")
                     .context("no s3 bucket provided")?
@@ -112,15 +544,20 @@ impl FromStr for Backend {
                 let region = match (region_name, host) {
                     (Some(r), Some(h)) => Region::Custom {
                         region: r.as_str().into(),
-                        endpoint: h.as_str().into(),
+                        endpoint: h,
                     },
                     (Some(r), None) => r.as_str().parse().context("invalid region name")?,
-                    (None, Some(h)) => h.as_str().parse().context("invalid hostname")?,
+                    // IPv6 literals and ports can only be custom endpoints
+                    (None, Some(h)) if h.contains(':') => Region::Custom {
+                        region: "".into(),
+                        endpoint: h,
+                    },
+                    (None, Some(h)) => h.parse().context("invalid hostname")?,
                     (None, None) => anyhow::bail!("invalid url: no hostname or region"),
                 };
 
                 let keys = match (akey, skey) {
-                    (Some(a), Some(s)) => Some((a.as_str().to_string(), s.as_str().to_string())),
+                    (Some(a), Some(s)) => Some((a.as_str().to_string(), s.as_str().into())),
                     _ => None,
                 };
 
@@ -128,6 +565,11 @@ impl FromStr for Backend {
                     bucket,
                     region,
                     keys,
+                    session_token: None,
+                    instance_metadata: false,
+                    path_style: false,
+                    ca_bundle: None,
+                    immutable_days: None,
                 })
             }
             Some(_) => anyhow::bail!("protocol not supported"),
@@ -139,12 +581,47 @@ impl FromStr for Backend {
                 .to_string_lossy()
                 .to_string();
 
-                Ok(Self::Filesystem { path })
+                Ok(Self::Filesystem {
+                    path,
+                    fsync: false,
+                    tmpfile_rename: false,
+                })
             }
         }
     }
 }
 
+/// Base URL and signing region of the S3 service in `region`
+fn s3_endpoint(region: &Region) -> Result<(String, String)> {
+    let value = serde_json::to_value(region)?;
+    let name = value["name"].as_str().unwrap_or_default();
+
+    if name == "custom" {
+        let details = &value["details"];
+        let endpoint = details["endpoint"].as_str().unwrap_or_default();
+        let endpoint = if endpoint.contains("://") {
+            endpoint.to_string()
+        } else {
+            format!("https://{endpoint}")
+        };
+        let region = match details["region"].as_str() {
+            Some("") | None => "us-east-1",
+            Some(region) => region,
+        };
+
+        return Ok((endpoint, region.to_string()));
+    }
+
+    let endpoint = match name {
+        "nyc3" | "ams3" | "sgp1" | "fra1" => format!("https://{name}.digitaloceanspaces.com"),
+        _ if name.starts_with("cn-") => format!("https://s3.{name}.amazonaws.com.cn"),
+        _ if name.contains('-') => format!("https://s3.{name}.amazonaws.com"),
+        _ => anyhow::bail!("Set a custom endpoint for region {name} to use these S3 options"),
+    };
+
+    Ok((endpoint, name.to_string()))
+}
+
 // originally lifted from
 // https://github.com/rust-lang/cargo/blob/fede83ccf973457de319ba6fa0e36ead454d2e20/src/cargo/util/paths.rs#L61
 pub fn normalize_path(path: &Path) -> PathBuf {
@@ -183,6 +660,7 @@ pub fn normalize_path(path: &Path) -> PathBuf {
 
 #[cfg(test)]
 mod test {
+    use super::{Backend, Role};
     use std::path::PathBuf;
 
     // Ignore this test on Windows because file path prefixes make
@@ -219,4 +697,113 @@ mod test {
             PathBuf::from("/zerostash")
         );
     }
+
+    #[test]
+    fn storage_policy_denies_deletes_to_append_only_keys() {
+        let backend: Backend = "s3://us-east-1#/backups".parse().unwrap();
+        let policy = backend.storage_policy(Role::WriteOnly).unwrap();
+        let statements = policy["Statement"].as_array().unwrap();
+
+        let denied = statements
+            .iter()
+            .filter(|s| s["Effect"] == "Deny")
+            .flat_map(|s| s["Action"].as_array().unwrap())
+            .filter_map(|action| action.as_str())
+            .collect::<Vec<_>>();
+        assert!(denied.contains(&"s3:DeleteObject"));
+        assert!(denied.contains(&"s3:DeleteObjectVersion"));
+        assert!(!denied.contains(&"s3:PutObject"));
+
+        let full = backend.storage_policy(Role::Full).unwrap();
+        assert!(full["Statement"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|s| s["Effect"] == "Allow"));
+
+        let local: Backend = "/tmp/stash".parse().unwrap();
+        assert!(local.storage_policy(Role::ReadOnly).is_none());
+    }
+
+    #[test]
+    fn temporary_s3_credentials() {
+        let backend: Backend = toml::from_str(
+            r#"
+type = "s3"
+bucket = "test_bucket"
+region = { name = "us-east-1" }
+keys = ["ASIAEXAMPLE", "secret"]
+session_token = "token"
+"#,
+        )
+        .unwrap();
+        let Backend::S3 {
+            session_token,
+            instance_metadata,
+            ..
+        } = backend
+        else {
+            unreachable!()
+        };
+        assert_eq!(session_token, Some("token".into()));
+        assert!(!instance_metadata);
+
+        // mixing instance credentials with keys fails before anything
+        // is fetched
+        let backend: Backend = toml::from_str(
+            r#"
+type = "s3"
+bucket = "test_bucket"
+region = { name = "us-east-1" }
+keys = ["AKIAEXAMPLE", "secret"]
+instance_metadata = true
+"#,
+        )
+        .unwrap();
+        let error = backend.to_infinitree().err().unwrap();
+        assert!(error.to_string().contains("instance metadata"));
+    }
+
+    #[test]
+    fn s3_endpoints_come_from_the_region() {
+        use super::s3_endpoint;
+
+        let backend: Backend = toml::from_str(
+            r#"
+type = "s3"
+bucket = "test_bucket"
+region = { name = "custom", details = { endpoint = "[2001:db8::1]:9443", "region" = "" }}
+path_style = true
+ca_bundle = "/etc/zerostash/ca.pem"
+"#,
+        )
+        .unwrap();
+
+        let Backend::S3 {
+            region,
+            path_style,
+            ca_bundle,
+            ..
+        } = backend
+        else {
+            unreachable!()
+        };
+        assert!(path_style);
+        assert_eq!(ca_bundle, Some(PathBuf::from("/etc/zerostash/ca.pem")));
+        assert_eq!(
+            s3_endpoint(&region).unwrap(),
+            ("https://[2001:db8::1]:9443".into(), "us-east-1".into())
+        );
+
+        let Backend::S3 { region, .. } = "s3://eu-west-1#/backups".parse().unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(
+            s3_endpoint(&region).unwrap(),
+            (
+                "https://s3.eu-west-1.amazonaws.com".into(),
+                "eu-west-1".into()
+            )
+        );
+    }
 }
@@ -0,0 +1,129 @@
+//! Passphrase protected key bundles to move keys between machines.
+//!
+//! A bundle holds the contents of a key file, encrypted with
+//! ChaCha20-Poly1305 under a key stretched from the passphrase with
+//! Argon2id. The result is Base64 armored, so it can be pasted into a
+//! password manager.
+use super::{KdfParams, Result};
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand::RngCore;
+use secrecy::{ExposeSecret, SecretString};
+
+const BEGIN: &str = "-----BEGIN ZEROSTASH KEY BUNDLE-----";
+const END: &str = "-----END ZEROSTASH KEY BUNDLE-----";
+
+const MAGIC: &[u8; 4] = b"0skb";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// magic, version, 3 KDF parameters, salt, nonce
+const HEADER_LEN: usize = 4 + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+
+/// Encrypt the contents of a key file with `passphrase`
+pub fn seal(contents: &[u8], passphrase: &SecretString, kdf: &KdfParams) -> Result<String> {
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+
+    let mut bundle = Vec::with_capacity(HEADER_LEN + contents.len() + 16);
+    bundle.extend_from_slice(MAGIC);
+    bundle.push(VERSION);
+    for param in [kdf.memory_mib, kdf.iterations, kdf.parallelism] {
+        bundle.extend_from_slice(&param.to_le_bytes());
+    }
+    bundle.extend_from_slice(&salt);
+    bundle.extend_from_slice(&nonce);
+
+    let cipher = cipher(passphrase, &salt, kdf)?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), contents)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt the key bundle"))?;
+    bundle.extend_from_slice(&ciphertext);
+
+    let encoded = STANDARD.encode(&bundle);
+    let mut armored = format!("{BEGIN}\n");
+    for line in encoded.as_bytes().chunks(64) {
+        armored.push_str(std::str::from_utf8(line).unwrap());
+        armored.push('\n');
+    }
+    armored.push_str(END);
+    armored.push('\n');
+
+    Ok(armored)
+}
+
+/// Decrypt a bundle created by [`seal`]
+pub fn open(armored: &str, passphrase: &SecretString) -> Result<Vec<u8>> {
+    let armored = armored.trim();
+    let Some(body) = armored
+        .strip_prefix(BEGIN)
+        .and_then(|rest| rest.strip_suffix(END))
+    else {
+        bail!("Not a key bundle");
+    };
+
+    let encoded = body.split_whitespace().collect::<String>();
+    let bundle = STANDARD
+        .decode(encoded)
+        .context("The key bundle is corrupted")?;
+
+    if bundle.len() < HEADER_LEN || &bundle[..4] != MAGIC {
+        bail!("The key bundle is corrupted");
+    }
+    if bundle[4] != VERSION {
+        bail!("Unsupported key bundle version {}", bundle[4]);
+    }
+
+    let param = |i: usize| {
+        let start = 5 + i * 4;
+        u32::from_le_bytes(bundle[start..start + 4].try_into().unwrap())
+    };
+    let kdf = KdfParams {
+        memory_mib: param(0),
+        iterations: param(1),
+        parallelism: param(2),
+    };
+
+    let salt_start = HEADER_LEN - NONCE_LEN - SALT_LEN;
+    let salt = &bundle[salt_start..salt_start + SALT_LEN];
+    let nonce = &bundle[salt_start + SALT_LEN..HEADER_LEN];
+
+    cipher(passphrase, salt, &kdf)?
+        .decrypt(Nonce::from_slice(nonce), &bundle[HEADER_LEN..])
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the key bundle is corrupted"))
+}
+
+fn cipher(passphrase: &SecretString, salt: &[u8], kdf: &KdfParams) -> Result<ChaCha20Poly1305> {
+    let mut key = kdf.derive(passphrase.expose_secret().as_bytes(), salt)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    key.fill(0);
+
+    Ok(cipher)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open, seal};
+    use crate::config::KdfParams;
+
+    #[test]
+    fn seal_and_open() {
+        let kdf = KdfParams {
+            memory_mib: 8,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let contents = b"source = \"plaintext\"\nuser = \"me\"\npassword = \"secret\"\n";
+
+        let armored = seal(contents, &"passphrase".to_string().into(), &kdf).unwrap();
+        assert!(armored.starts_with(super::BEGIN));
+
+        let opened = open(&armored, &"passphrase".to_string().into()).unwrap();
+        assert_eq!(opened, contents);
+
+        assert!(open(&armored, &"wrong".to_string().into()).is_err());
+    }
+}
@@ -1,8 +1,10 @@
 use super::*;
+use anyhow::Context;
 use bech32::{Bech32m, Hrp};
 use infinitree::crypto::{cryptobox::*, RawKey};
 use secrecy::ExposeSecret;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SplitKeyStorage {
@@ -16,9 +18,13 @@ pub struct SplitKeyStorage {
 impl KeyToSource for SplitKeyStorage {
     type Target = StorageOnly;
     fn to_keysource(self, stash: &str) -> Result<Self::Target> {
-        let (user, pw) = self.credentials.interactive_credentials(stash)?;
+        let (user, pw) = self.credentials.credentials(stash)?;
+        let read = match (self.keys.read, self.keys.read_from) {
+            (None, Some(source)) => Some(source.secret_key()?),
+            (read, _) => read,
+        };
 
-        Ok(match self.keys.read {
+        Ok(match read {
             Some(sk) => StorageOnly::encrypt_and_decrypt(user, pw, self.keys.write, sk),
             None => StorageOnly::encrypt_only(user, pw, self.keys.write),
         }?)
@@ -38,6 +44,42 @@ pub struct SplitKeys {
         default
     )]
     pub read: Option<RawKey>,
+
+    /// Where to find the secret key if it's not stored in the key
+    /// file itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[clap(skip)]
+    pub read_from: Option<SecretKeySource>,
+}
+
+/// Keeps the secret key of a split key away from the key file, so
+/// the machines making backups never need to hold it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "source")]
+pub enum SecretKeySource {
+    /// A separate file with the armored secret key, eg. on removable
+    /// media
+    #[serde(rename = "file")]
+    File { path: PathBuf },
+
+    /// The secret key, sealed with a FIDO2 token
+    #[serde(rename = "fido2")]
+    Fido2(super::SealedKey),
+}
+
+impl SecretKeySource {
+    pub fn secret_key(self) -> Result<RawKey> {
+        match self {
+            Self::File { path } => {
+                let contents = std::fs::read_to_string(&path).with_context(|| {
+                    format!("Failed to read the secret key from {}", path.display())
+                })?;
+                decode_bech32("s0s-", contents.trim())
+            }
+            Self::Fido2(sealed) => sealed.unseal(),
+        }
+    }
 }
 
 impl SplitKeys {
@@ -45,6 +87,7 @@ impl SplitKeys {
         let rw = self.clone();
         let mut wo = self;
         wo.read = None;
+        wo.read_from = None;
 
         (rw, wo)
     }
@@ -56,6 +99,7 @@ impl Default for SplitKeys {
         SplitKeys {
             read: Some(kp.secret_key),
             write: kp.public_key,
+            read_from: None,
         }
     }
 }
@@ -68,7 +112,7 @@ fn bech32_pk(k: &RawKey) -> String {
     .unwrap()
 }
 
-fn bech32_sk(k: &RawKey) -> String {
+pub(crate) fn bech32_sk(k: &RawKey) -> String {
     bech32::encode::<Bech32m>(
         Hrp::parse("s0s-").unwrap(),
         k.expose_secret(),
@@ -76,7 +120,7 @@ fn bech32_sk(k: &RawKey) -> String {
     .unwrap()
 }
 
-fn decode_bech32(check_hrp: &str, ser: &str) -> Result<RawKey> {
+pub(super) fn decode_bech32(check_hrp: &str, ser: &str) -> Result<RawKey> {
     let (hrp, bytes) = bech32::decode(ser)?;
     if bytes.len() != 32 {
         anyhow::bail!("invalid key length");
@@ -96,6 +140,7 @@ impl std::fmt::Debug for SplitKeys {
         f.debug_struct("SplitKeys")
             .field("write", &bech32_pk(&self.write))
             .field("read", &self.read.is_some())
+            .field("read_from", &self.read_from)
             .finish()
     }
 }
@@ -0,0 +1,256 @@
+use super::{symmetric_key::SymmetricKey, KeyToSource};
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "fido2")]
+use ctap_hid_fido2::{
+    fidokey::{
+        get_assertion::get_assertion_params::Extension as AssertionExtension,
+        make_credential::make_credential_params::Extension as CredentialExtension,
+        GetAssertionArgsBuilder, MakeCredentialArgsBuilder,
+    },
+    verifier, Cfg, FidoKeyHidFactory,
+};
+use infinitree::crypto::{RawKey, UsernamePassword};
+use rand::RngCore;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_RP_ID: &str = "zerostash";
+
+/// Username and password, combined with a secret derived on a FIDO2
+/// token using the `hmac-secret` extension.
+///
+/// The stash can't be opened without the token the credential was
+/// created on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fido2Key {
+    #[serde(flatten)]
+    pub credentials: SymmetricKey,
+
+    /// Hex encoded id of the credential on the token
+    pub credential: String,
+
+    /// Hex encoded salt for the `hmac-secret` extension
+    pub salt: String,
+
+    /// Relying party id the credential was created for
+    #[serde(default = "default_rp_id")]
+    pub rp_id: String,
+
+    /// Ask for the PIN of the token
+    #[serde(default)]
+    pub pin: bool,
+}
+
+fn default_rp_id() -> String {
+    DEFAULT_RP_ID.into()
+}
+
+impl KeyToSource for Fido2Key {
+    type Target = UsernamePassword;
+
+    fn to_keysource(self, stash: &str) -> Result<Self::Target> {
+        let (user, pw) = self.credentials.credentials(stash)?;
+
+        let salt: [u8; 32] = decode_hex(&self.salt)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("The salt of the FIDO2 key must be 32 bytes"))?;
+        let pin = ask_pin(self.pin)?;
+        let secret = hmac_secret(
+            &self.rp_id,
+            &decode_hex(&self.credential)?,
+            &salt,
+            pin.as_ref(),
+        )?;
+
+        // the token's secret is part of the password, so the key
+        // derivation is unchanged
+        let password = format!("{}:{}", pw.expose_secret(), encode_hex(&secret));
+        Ok(UsernamePassword::with_credentials(user, password.into())?)
+    }
+}
+
+impl Fido2Key {
+    /// Create a new `hmac-secret` credential on the token
+    pub fn enroll(credentials: SymmetricKey, rp_id: String, pin: bool) -> Result<Self> {
+        let (credential, _) = make_credential(&rp_id, pin)?;
+
+        let mut salt = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+
+        Ok(Self {
+            credentials,
+            credential: encode_hex(&credential),
+            salt: encode_hex(&salt),
+            rp_id,
+            pin,
+        })
+    }
+}
+
+/// A key encrypted with a secret that only a FIDO2 token can derive.
+///
+/// Every key is sealed with a fresh salt, so XOR with the token's
+/// `hmac-secret` output is enough to protect it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SealedKey {
+    /// Hex encoded key, XOR the token's secret
+    pub sealed: String,
+
+    /// Hex encoded id of the credential on the token
+    pub credential: String,
+
+    /// Hex encoded salt for the `hmac-secret` extension
+    pub salt: String,
+
+    /// Relying party id the credential was created for
+    #[serde(default = "default_rp_id")]
+    pub rp_id: String,
+
+    /// Ask for the PIN of the token
+    #[serde(default)]
+    pub pin: bool,
+}
+
+impl SealedKey {
+    /// Create a new credential on the token, and seal `key` with it
+    pub fn seal(key: &RawKey, rp_id: String, pin: bool) -> Result<Self> {
+        let (credential, pin_code) = make_credential(&rp_id, pin)?;
+
+        let mut salt = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        let secret = hmac_secret(&rp_id, &credential, &salt, pin_code.as_ref())?;
+
+        Ok(Self {
+            sealed: encode_hex(&xor(key.expose_secret(), &secret)),
+            credential: encode_hex(&credential),
+            salt: encode_hex(&salt),
+            rp_id,
+            pin,
+        })
+    }
+
+    pub fn unseal(&self) -> Result<RawKey> {
+        let salt: [u8; 32] = decode_hex(&self.salt)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("The salt of the sealed key must be 32 bytes"))?;
+        let sealed: [u8; 32] = decode_hex(&self.sealed)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("The sealed key must be 32 bytes"))?;
+
+        let pin = ask_pin(self.pin)?;
+        let secret = hmac_secret(
+            &self.rp_id,
+            &decode_hex(&self.credential)?,
+            &salt,
+            pin.as_ref(),
+        )?;
+
+        Ok(xor(&sealed, &secret).into())
+    }
+}
+
+fn xor(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut out = [0; 32];
+    for (o, (a, b)) in out.iter_mut().zip(a.iter().zip(b)) {
+        *o = a ^ b;
+    }
+    out
+}
+
+#[cfg(feature = "fido2")]
+fn make_credential(rp_id: &str, pin: bool) -> Result<(Vec<u8>, Option<SecretString>)> {
+    let device = FidoKeyHidFactory::create(&Cfg::init())
+        .context("No FIDO2 token found. Is it plugged in?")?;
+    let pin_code = ask_pin(pin)?;
+
+    let challenge = verifier::create_challenge();
+    let builder = MakeCredentialArgsBuilder::new(rp_id, &challenge)
+        .extensions(&[CredentialExtension::HmacSecret(Some(true))]);
+    let args = match &pin_code {
+        Some(p) => builder.pin(p.expose_secret()),
+        None => builder.without_pin_and_uv(),
+    }
+    .build();
+
+    println!("Touch your security key to create a credential...");
+    let attestation = device.make_credential_with_args(&args)?;
+
+    Ok((attestation.credential_descriptor.id, pin_code))
+}
+
+#[cfg(not(feature = "fido2"))]
+fn make_credential(_rp_id: &str, _pin: bool) -> Result<(Vec<u8>, Option<SecretString>)> {
+    bail!("FIDO2 keys need zerostash to be built with the `fido2` feature")
+}
+
+fn ask_pin(pin: bool) -> Result<Option<SecretString>> {
+    if !pin {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        rpassword::prompt_password("PIN of the security key: ")?.into(),
+    ))
+}
+
+#[cfg(feature = "fido2")]
+fn hmac_secret(
+    rp_id: &str,
+    credential: &[u8],
+    salt: &[u8; 32],
+    pin: Option<&SecretString>,
+) -> Result<[u8; 32]> {
+    let device = FidoKeyHidFactory::create(&Cfg::init())
+        .context("No FIDO2 token found. Is it plugged in?")?;
+
+    let challenge = verifier::create_challenge();
+    let builder = GetAssertionArgsBuilder::new(rp_id, &challenge)
+        .credential_id(credential)
+        .extensions(&[AssertionExtension::HmacSecret(Some(*salt))]);
+    let args = match pin {
+        Some(p) => builder.pin(p.expose_secret()),
+        None => builder.without_pin_and_uv(),
+    }
+    .build();
+
+    println!("Touch your security key to unlock the stash...");
+    let assertions = device
+        .get_assertion_with_args(&args)
+        .context("The security key refused the request. Is this the right token?")?;
+
+    for assertion in assertions {
+        for extension in assertion.extensions {
+            if let AssertionExtension::HmacSecret(Some(secret)) = extension {
+                return Ok(secret);
+            }
+        }
+    }
+
+    bail!("The security key doesn't support the hmac-secret extension")
+}
+
+#[cfg(not(feature = "fido2"))]
+fn hmac_secret(
+    _rp_id: &str,
+    _credential: &[u8],
+    _salt: &[u8; 32],
+    _pin: Option<&SecretString>,
+) -> Result<[u8; 32]> {
+    bail!("FIDO2 keys need zerostash to be built with the `fido2` feature")
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        bail!("Invalid hex string");
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).context("Invalid hex string"))
+        .collect()
+}
@@ -0,0 +1,85 @@
+//! Shell commands to run around a backup
+//!
+//! The commands run with `sh -c`, or `cmd /C` on Windows, and get the
+//! details of the backup in `ZEROSTASH_*` environment variables.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Runs before the files are read. The backup is cancelled if it
+    /// fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_backup: Option<String>,
+    /// Runs after the commit is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_backup: Option<String>,
+    /// Runs if the backup fails after `pre_backup`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_error: Option<String>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_backup.is_none() && self.post_backup.is_none() && self.on_error.is_none()
+    }
+}
+
+/// Run the hook called `name`, if it's set, with `ZEROSTASH_HOOK`
+/// and `env` in its environment
+pub fn run(name: &str, command: Option<&str>, env: &[(&str, String)]) -> Result<()> {
+    let Some(command) = command else {
+        return Ok(());
+    };
+
+    let mut shell = shell(command);
+    shell.env("ZEROSTASH_HOOK", name);
+    for (key, value) in env {
+        shell.env(key, value);
+    }
+
+    let status = shell
+        .status()
+        .with_context(|| format!("Failed to start the {name} hook"))?;
+    if !status.success() {
+        anyhow::bail!("The {name} hook failed with {status}");
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::run;
+
+    #[test]
+    fn hooks_get_the_environment() {
+        let env = [("ZEROSTASH_STASH", "mystash".to_string())];
+
+        run(
+            "pre_backup",
+            Some(r#"test "$ZEROSTASH_HOOK:$ZEROSTASH_STASH" = pre_backup:mystash"#),
+            &env,
+        )
+        .unwrap();
+        assert!(run("post_backup", Some("exit 3"), &env).is_err());
+        assert!(run("on_error", None, &env).is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+//! Temporary S3 credentials from the EC2 instance metadata service.
+//!
+//! Credentials of the IAM role attached to the instance expire after a
+//! few hours, so the S3 connection is re-established with fresh ones
+//! shortly before that happens.
+use super::{Result, Secret};
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, warn};
+
+const ENDPOINT: &str = "http://169.254.169.254/latest";
+
+/// Refresh the credentials this many minutes before they expire
+const REFRESH_MARGIN_MINS: i64 = 5;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct TemporaryCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: Secret,
+    pub token: Secret,
+    pub expiration: DateTime<Utc>,
+}
+
+/// Fetch the credentials of the IAM role attached to the instance
+pub(crate) fn fetch() -> Result<TemporaryCredentials> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_secs(5))
+        .build();
+
+    // IMDSv2 only answers requests that carry a session token
+    let token = agent
+        .put(&format!("{ENDPOINT}/api/token"))
+        .set("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .call()
+        .context("The instance metadata service is unreachable")?
+        .into_string()?;
+
+    let get = |path: &str| -> Result<String> {
+        Ok(agent
+            .get(&format!(
+                "{ENDPOINT}/meta-data/iam/security-credentials/{path}"
+            ))
+            .set("X-aws-ec2-metadata-token", &token)
+            .call()?
+            .into_string()?)
+    };
+
+    let roles = get("")?;
+    let role = roles
+        .lines()
+        .next()
+        .context("No IAM role is attached to the instance")?;
+
+    serde_json::from_str(&get(role)?)
+        .context("Invalid credentials from the instance metadata service")
+}
+
+type Connect = dyn Fn(&TemporaryCredentials) -> Result<Arc<dyn Backend>> + Send + Sync;
+
+/// Reconnect to the upstream with fresh credentials before the current
+/// ones expire
+pub(crate) struct Refreshing {
+    connect: Box<Connect>,
+    current: RwLock<(Arc<dyn Backend>, DateTime<Utc>)>,
+}
+
+impl Refreshing {
+    pub(crate) fn new(
+        connect: impl Fn(&TemporaryCredentials) -> Result<Arc<dyn Backend>> + Send + Sync + 'static,
+    ) -> Result<Arc<Self>> {
+        let credentials = fetch()?;
+        let upstream = connect(&credentials)?;
+
+        Ok(Arc::new(Self {
+            connect: Box::new(connect),
+            current: RwLock::new((upstream, credentials.expiration)),
+        }))
+    }
+
+    fn upstream(&self) -> Arc<dyn Backend> {
+        let fresh = |expiration: &DateTime<Utc>| {
+            Utc::now() + Duration::minutes(REFRESH_MARGIN_MINS) < *expiration
+        };
+
+        {
+            let current = self.current.read().unwrap();
+            if fresh(&current.1) {
+                return current.0.clone();
+            }
+        }
+
+        let mut current = self.current.write().unwrap();
+        if !fresh(&current.1) {
+            let refreshed = fetch().and_then(|credentials| {
+                Ok(((self.connect)(&credentials)?, credentials.expiration))
+            });
+
+            match refreshed {
+                // writes queued in the old connection would be lost
+                // with it, so it's only replaced once they're flushed
+                Ok(refreshed) => match current.0.sync() {
+                    Ok(()) => {
+                        debug!(expiration = %refreshed.1, "refreshed S3 credentials");
+                        *current = refreshed;
+                    }
+                    Err(error) => {
+                        warn!(%error, "failed to flush writes before refreshing S3 credentials")
+                    }
+                },
+                // the old credentials may still work for a few minutes
+                Err(error) => warn!(%error, "failed to refresh S3 credentials"),
+            }
+        }
+
+        current.0.clone()
+    }
+}
+
+impl Backend for Refreshing {
+    fn write_object(&self, object: &WriteObject) -> std::result::Result<(), BackendError> {
+        self.upstream().write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> std::result::Result<Arc<ReadObject>, BackendError> {
+        self.upstream().read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> std::result::Result<(), BackendError> {
+        self.upstream().preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> std::result::Result<(), BackendError> {
+        self.upstream().delete(objects)
+    }
+
+    fn sync(&self) -> std::result::Result<(), BackendError> {
+        self.upstream().sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> std::result::Result<(), BackendError> {
+        self.upstream().keep_warm(objects)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TemporaryCredentials;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn parse_credentials() {
+        let credentials: TemporaryCredentials = serde_json::from_str(
+            r#"{
+  "Code" : "Success",
+  "LastUpdated" : "2024-05-01T10:00:00Z",
+  "Type" : "AWS-HMAC",
+  "AccessKeyId" : "ASIAEXAMPLE",
+  "SecretAccessKey" : "secret",
+  "Token" : "token",
+  "Expiration" : "2024-05-01T16:00:00Z"
+}"#,
+        )
+        .unwrap();
+
+        assert_eq!(credentials.access_key_id, "ASIAEXAMPLE");
+        assert_eq!(credentials.token.expose_secret(), "token");
+        assert_eq!(
+            credentials.expiration.to_rfc3339(),
+            "2024-05-01T16:00:00+00:00"
+        );
+    }
+}
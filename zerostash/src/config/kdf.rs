@@ -0,0 +1,120 @@
+use super::Result;
+use infinitree::{
+    backends::Backend,
+    object::{ObjectId, WriteObject},
+    Hasher,
+};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Starts the object that holds the KDF parameters of a user
+const RECORD_MAGIC: &[u8] = b"0s-kdf\x01";
+
+/// Argon2id parameters to stretch the password with before it's used
+/// to derive the stash key.
+///
+/// This is on top of the key derivation of the stash, so changing
+/// them needs a new password, eg. through `0s passwd --kdf`.
+///
+/// The parameters of each user are stored in the stash in an
+/// unencrypted object, so the password can be stretched before the
+/// stash is opened. Parameters in the configuration take precedence.
+#[derive(clap::Args, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct KdfParams {
+    /// Memory used by the key derivation, in MiB
+    #[clap(long = "kdf-memory", value_name = "MIB", default_value_t = 256)]
+    pub memory_mib: u32,
+
+    /// Number of passes over the memory
+    #[clap(long = "kdf-iterations", default_value_t = 3)]
+    pub iterations: u32,
+
+    /// Number of lanes that can be computed in parallel
+    #[clap(long = "kdf-parallelism", default_value_t = 4)]
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    pub fn stretch(&self, user: &SecretString, password: &SecretString) -> Result<SecretString> {
+        // the salt only needs to be unique per user, the stash key
+        // derivation adds its own
+        let salt = format!("zerostash-kdf:{}", user.expose_secret());
+        let hash = self.derive(password.expose_secret().as_bytes(), salt.as_bytes())?;
+
+        Ok(hash
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+            .into())
+    }
+
+    /// Derive a 32 byte key from `secret` with Argon2id
+    pub(super) fn derive(&self, secret: &[u8], salt: &[u8]) -> Result<Vec<u8>> {
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.memory_mib.saturating_mul(1024),
+            time_cost: self.iterations,
+            lanes: self.parallelism,
+            hash_length: 32,
+            ..argon2::Config::default()
+        };
+
+        Ok(argon2::hash_raw(secret, salt, &config)?)
+    }
+}
+
+/// KDF parameters of a user, as stored in the stash
+#[derive(Deserialize, Serialize)]
+struct Record {
+    kdf: Option<KdfParams>,
+}
+
+fn record_id(user: &str) -> ObjectId {
+    let hash = Hasher::new()
+        .update(format!("zerostash-kdf-params:{user}").as_bytes())
+        .finalize();
+    ObjectId::from_bytes(hash.as_bytes())
+}
+
+/// The KDF parameters stored for `user`, or `None` if there's no
+/// record of them, eg. in stashes made before they were stored
+pub(super) fn read_stored(
+    backend: &Arc<dyn Backend>,
+    user: &str,
+) -> Result<Option<Option<KdfParams>>> {
+    let Ok(object) = backend.read_object(&record_id(user)) else {
+        return Ok(None);
+    };
+
+    let data = object
+        .as_inner()
+        .strip_prefix(RECORD_MAGIC)
+        .filter(|data| data.len() >= 4)
+        .ok_or_else(|| anyhow::anyhow!("Invalid KDF parameters stored for {user}"))?;
+    let (len, data) = data.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    let record: Record = serde_json::from_slice(data.get(..len).unwrap_or_default())?;
+
+    Ok(Some(record.kdf))
+}
+
+/// Store the KDF parameters of `user`, replacing the previous ones
+pub(super) fn store(backend: &Arc<dyn Backend>, user: &str, kdf: Option<KdfParams>) -> Result<()> {
+    let record = serde_json::to_vec(&Record { kdf })?;
+
+    let mut object = WriteObject::default();
+    object.set_id(record_id(user));
+    let buf = object.as_inner_mut();
+    buf[..RECORD_MAGIC.len()].copy_from_slice(RECORD_MAGIC);
+    let buf = &mut buf[RECORD_MAGIC.len()..];
+    buf[..4].copy_from_slice(&(record.len() as u32).to_le_bytes());
+    buf[4..4 + record.len()].copy_from_slice(&record);
+
+    backend.write_object(&object)?;
+    backend.sync()?;
+
+    Ok(())
+}
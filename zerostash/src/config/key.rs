@@ -1,7 +1,9 @@
 use super::{KeyToSource, Result};
 use anyhow::bail;
+use secrecy::ExposeSecret;
 use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, sync::Arc};
+use zerostash_files::KeyType;
 
 /// Credentials for a stash
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -17,26 +19,168 @@ pub enum Key {
     #[serde(rename = "yubikey")]
     Yubikey(super::YubikeyCRKey),
 
+    /// 2 factor authentication with the hmac-secret extension of a
+    /// FIDO2 token
+    #[serde(rename = "fido2")]
+    Fido2(super::Fido2Key),
+
     /// Use a different key for reading archives and appending
     #[serde(rename = "split_key")]
     SplitKeyStorage(super::SplitKeyStorage),
 
+    /// Username with the password stored in the OS keychain
+    #[serde(rename = "keychain")]
+    Keychain(super::KeychainKey),
+
     /// Get credentials through other interactive/command line methods
     #[serde(rename = "ask")]
     #[default]
     Interactive,
 
-    /// Plain text username/password pair
+    /// Read the key from a file. The file contains either a raw
+    /// 32 byte key, an armored key, or a TOML key specification.
     #[serde(rename = "file")]
     #[allow(missing_docs)]
     KeyFile { path: PathBuf },
 
+    /// Open the stash with `key`, but refuse to modify it, see
+    /// [`Role`].
+    ///
+    /// This is only a guard in the client: whoever holds the config
+    /// holds `key`, which can write to the stash. Delegation tokens
+    /// are credentials that can't.
+    #[serde(rename = "read_only")]
+    ReadOnly { key: Box<Key> },
+
+    /// Delegation token issued with `0s token issue`. Only allows
+    /// reading the stash, within the limits of the token.
+    #[serde(rename = "token")]
+    Token { token: super::Secret },
+
+    /// Master key of a stash, unlocked through a key slot
+    #[serde(skip)]
+    Raw(infinitree::crypto::RawKey),
+
     /// Creates a `ChangeKey` structure
     #[serde(skip)]
     ChangeTo { old: Box<Key>, new: Box<Key> },
 }
 
+/// What a key is allowed to do with a stash
+///
+/// Reading file contents needs a key that can decrypt them, but
+/// writing and deleting objects is only refused by the client, see
+/// [`Backend::storage_policy`](super::Backend::storage_policy).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Read and modify everything
+    Full,
+    /// Append new commits, but not read file contents or delete
+    /// anything
+    WriteOnly,
+    /// List and restore files, but not modify the stash
+    ReadOnly,
+    /// List files and their metadata, but not read file contents or
+    /// modify the stash
+    ListOnly,
+}
+
+impl Role {
+    pub fn can_read_data(self) -> bool {
+        matches!(self, Role::Full | Role::ReadOnly)
+    }
+
+    pub fn can_write(self) -> bool {
+        matches!(self, Role::Full | Role::WriteOnly)
+    }
+
+    pub fn can_delete(self) -> bool {
+        self == Role::Full
+    }
+}
+
 impl Key {
+    /// The type of key that opens a stash with this key
+    pub(crate) fn key_type(&self) -> Result<KeyType> {
+        Ok(match self {
+            Self::KeyFile { path } => {
+                let contents = std::fs::read(path)?;
+                match super::raw_key::parse(&contents) {
+                    Some(_) => KeyType::Symmetric,
+                    None => toml::from_str::<Key>(std::str::from_utf8(&contents)?)?.key_type()?,
+                }
+            }
+            Self::Interactive
+            | Self::Userpass(_)
+            | Self::Keychain(_)
+            | Self::Token { .. }
+            | Self::Raw(_) => KeyType::Symmetric,
+            Self::Yubikey(_) => KeyType::YubikeyHmacSha1,
+            Self::Fido2(_) => KeyType::Fido2HmacSecret,
+            Self::SplitKeyStorage(_) => KeyType::CryptoBox,
+            Self::ReadOnly { key } => key.key_type()?,
+            Self::ChangeTo { new, .. } => new.key_type()?,
+        })
+    }
+
+    pub(crate) fn role(&self) -> Result<Role> {
+        Ok(match self {
+            Self::KeyFile { path } => {
+                let contents = std::fs::read(path)?;
+                match super::raw_key::parse(&contents) {
+                    Some(_) => Role::Full,
+                    None => toml::from_str::<Key>(std::str::from_utf8(&contents)?)?.role()?,
+                }
+            }
+            Self::SplitKeyStorage(k) if k.keys.read.is_none() && k.keys.read_from.is_none() => {
+                Role::WriteOnly
+            }
+            // the index of split key stashes is readable without the
+            // secret key, but file contents aren't
+            Self::ReadOnly { key } if key.role()? == Role::WriteOnly => Role::ListOnly,
+            Self::ReadOnly { .. } | Self::Token { .. } => Role::ReadOnly,
+            Self::ChangeTo { new, .. } => new.role()?,
+            _ => Role::Full,
+        })
+    }
+
+    /// Whether this is a delegation token
+    pub(crate) fn is_token(&self) -> Result<bool> {
+        Ok(match self {
+            Self::KeyFile { path } => {
+                let contents = std::fs::read(path)?;
+                match super::raw_key::parse(&contents) {
+                    Some(_) => false,
+                    None => toml::from_str::<Key>(std::str::from_utf8(&contents)?)?.is_token()?,
+                }
+            }
+            Self::Token { .. } => true,
+            _ => false,
+        })
+    }
+
+    /// Fill in the KDF parameters stored in the stash for password
+    /// keys that don't configure them
+    pub(crate) fn with_stored_kdf(
+        self,
+        backend: &Arc<dyn infinitree::backends::Backend>,
+    ) -> Result<Key> {
+        Ok(match self {
+            Self::Interactive => {
+                Self::Userpass(super::SymmetricKey::default().with_stored_kdf(backend)?)
+            }
+            Self::Userpass(k) => Self::Userpass(k.with_stored_kdf(backend)?),
+            Self::ReadOnly { key } => Self::ReadOnly {
+                key: Box::new(key.with_stored_kdf(backend)?),
+            },
+            Self::ChangeTo { old, new } => Self::ChangeTo {
+                old: Box::new(old.with_stored_kdf(backend)?),
+                new,
+            },
+            key => key,
+        })
+    }
+
     pub(crate) fn change_to(self, new: Key) -> Key {
         Key::ChangeTo {
             old: Box::new(self),
@@ -74,15 +218,26 @@ impl KeyToSource for Key {
     fn to_keysource(self, stash: &str) -> Result<infinitree::Key> {
         Ok(match self {
             Self::KeyFile { path } => {
-                let contents = std::fs::read_to_string(path)?;
-                let keys: Key = toml::from_str(&contents)?;
+                let contents = std::fs::read(path)?;
+                if let Some(key) = super::raw_key::parse(&contents) {
+                    return Ok(Arc::new(super::raw_key::to_keysource(key?)?));
+                }
+
+                let keys: Key = toml::from_str(std::str::from_utf8(&contents)?)?;
 
                 // this is technically recursion, it may be an ouroboros
                 keys.to_keysource(stash)?
             }
             Self::Interactive => Arc::new(super::SymmetricKey::default().to_keysource(stash)?),
             Self::Userpass(k) => Arc::new(k.to_keysource(stash)?),
+            Self::Keychain(k) => Arc::new(k.to_keysource(stash)?),
+            Self::Raw(k) => Arc::new(k.to_keysource(stash)?),
+            Self::ReadOnly { key } => key.to_keysource(stash)?,
+            Self::Token { token } => Arc::new(super::raw_key::to_keysource(
+                super::key_slots::parse_token(token.expose_secret())?,
+            )?),
             Self::Yubikey(k) => Arc::new(k.to_keysource(stash)?),
+            Self::Fido2(k) => Arc::new(k.to_keysource(stash)?),
             Self::SplitKeyStorage(k) => Arc::new(k.to_keysource(stash)?),
 
             Self::ChangeTo { old, new } => match (*old, *new) {
@@ -91,15 +246,30 @@ impl KeyToSource for Key {
                 }
                 (Key::Interactive, Key::Userpass(new)) => change_key!(stash, old!(), new),
                 (Key::Interactive, Key::Yubikey(new)) => change_key!(stash, old!(), new),
+                (Key::Interactive, Key::Fido2(new)) => change_key!(stash, old!(), new),
 
                 (Key::Userpass(old), Key::Interactive) => change_key!(stash, old, new!()),
                 (Key::Userpass(old), Key::Userpass(new)) => change_key!(stash, old, new),
                 (Key::Userpass(old), Key::Yubikey(new)) => change_key!(stash, old, new),
+                (Key::Userpass(old), Key::Fido2(new)) => change_key!(stash, old, new),
+
+                (Key::Keychain(old), Key::Interactive) => change_key!(stash, old, new!()),
+                (Key::Keychain(old), Key::Userpass(new)) => change_key!(stash, old, new),
 
                 (Key::Yubikey(old), Key::Interactive) => change_key!(stash, old, new!()),
                 (Key::Yubikey(old), Key::Userpass(new)) => change_key!(stash, old, new),
                 (Key::Yubikey(old), Key::Yubikey(new)) => change_key!(stash, old, new),
 
+                (Key::Fido2(old), Key::Interactive) => change_key!(stash, old, new!()),
+                (Key::Fido2(old), Key::Userpass(new)) => change_key!(stash, old, new),
+                (Key::Fido2(old), Key::Fido2(new)) => change_key!(stash, old, new),
+
+                (Key::Interactive, Key::Raw(new)) => change_key!(stash, old!(), new),
+                (Key::Userpass(old), Key::Raw(new)) => change_key!(stash, old, new),
+                (Key::Keychain(old), Key::Raw(new)) => change_key!(stash, old, new),
+                (Key::Yubikey(old), Key::Raw(new)) => change_key!(stash, old, new),
+                (Key::Fido2(old), Key::Raw(new)) => change_key!(stash, old, new),
+
                 (Key::SplitKeyStorage(old), Key::SplitKeyStorage(new)) => {
                     change_key!(stash, old, new)
                 }
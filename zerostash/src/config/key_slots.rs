@@ -0,0 +1,494 @@
+//! LUKS-style key slots for stashes shared by several users.
+//!
+//! A shared stash is sealed with a random master key. Every user gets
+//! a small tree of their own on the same backend, opened by their own
+//! credentials, which holds the master key. The stash itself records
+//! which objects make up each slot, so any user can delete the slot of
+//! another one. That doesn't revoke access: every slot holds the same
+//! master key, and anyone who has seen it can keep using it.
+//!
+//! Delegation tokens are key slots opened by a random key. Instead of
+//! the master key, they hold the key of a view: a separate index on
+//! the same backend, with a copy of what the token may read as of when
+//! it was issued. The data objects are shared with the stash, but the
+//! chunk keys of files outside the view aren't in it.
+use super::{decode_bech32, raw_key, Key, KeyToSource, Result, Stash};
+use crate::prelude::Stash as InfiniStash;
+use infinitree::{
+    backends::{Backend, BackendError},
+    crypto::RawKey,
+    fields,
+    object::{ObjectId, ReadObject, WriteObject},
+    Infinitree,
+};
+use rand::RngCore;
+use std::sync::{Arc, Mutex};
+use zerostash_files::{Files, KeySlot, Token, TokenScope};
+
+/// Human readable prefix of delegation tokens
+const TOKEN_HRP: &str = "t0s-";
+
+#[derive(Clone, Default, infinitree::Index)]
+struct SlotIndex {
+    /// Armored master key of the stash, or the key of the view of a
+    /// delegation token
+    master: fields::Serialized<Option<String>>,
+    /// Restrictions of a delegation token
+    scope: fields::Serialized<Option<TokenScope>>,
+}
+
+/// Remembers the objects written and read through it
+struct Recorder {
+    upstream: Arc<dyn Backend>,
+    written: Mutex<Vec<ObjectId>>,
+    read: Mutex<Vec<ObjectId>>,
+}
+
+impl Recorder {
+    fn new(upstream: &Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self {
+            upstream: upstream.clone(),
+            written: Mutex::default(),
+            read: Mutex::default(),
+        })
+    }
+
+    fn written(&self) -> Vec<ObjectId> {
+        self.written.lock().unwrap().clone()
+    }
+
+    fn read(&self) -> Vec<ObjectId> {
+        self.read.lock().unwrap().clone()
+    }
+}
+
+impl Backend for Recorder {
+    fn write_object(&self, object: &WriteObject) -> Result<(), BackendError> {
+        self.written.lock().unwrap().push(*object.id());
+        self.upstream.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>, BackendError> {
+        self.read.lock().unwrap().push(*id);
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<(), BackendError> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<(), BackendError> {
+        self.upstream.delete(objects)
+    }
+
+    fn sync(&self) -> Result<(), BackendError> {
+        self.upstream.sync()
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<(), BackendError> {
+        self.upstream.keep_warm(objects)
+    }
+}
+
+/// Return the key a key slot holds if `key` opens one on the backend
+pub(super) fn unlock(backend: &Arc<dyn Backend>, key: infinitree::Key) -> Option<RawKey> {
+    let slot = Infinitree::<SlotIndex>::open(backend.clone(), key).ok()?;
+    slot.load(slot.index().master()).ok()?;
+
+    let armored = slot.index().master.read().clone()?;
+    raw_key::parse(armored.as_bytes())?.ok()
+}
+
+/// Return the name of the key slot `key` opens, if it's recorded in
+/// `stash`
+fn slot_name(
+    backend: &Arc<dyn Backend>,
+    stash: &InfiniStash,
+    key: infinitree::Key,
+) -> Option<String> {
+    let recorder = Recorder::new(backend);
+    unlock(&(recorder.clone() as Arc<dyn Backend>), key)?;
+    let read = recorder.read();
+
+    let mut found = None;
+    stash.index().key_slots.for_each(|name, slot| {
+        if slot.objects.iter().any(|id| read.contains(id)) {
+            found = Some(name.clone());
+        }
+    });
+
+    found
+}
+
+/// Return the restrictions of the token slot `key` opens, or `None` if
+/// it doesn't open one
+pub(super) fn token_scope(backend: &Arc<dyn Backend>, key: infinitree::Key) -> Option<TokenScope> {
+    let slot = Infinitree::<SlotIndex>::open(backend.clone(), key).ok()?;
+    slot.load(slot.index().scope()).ok()?;
+
+    let scope = slot.index().scope.read().clone();
+    scope
+}
+
+/// Encode a token key as text that's safe to hand out
+pub(super) fn armor_token(key: &RawKey) -> String {
+    bech32::encode::<bech32::Bech32m>(
+        bech32::Hrp::parse(TOKEN_HRP).unwrap(),
+        secrecy::ExposeSecret::expose_secret(key),
+    )
+    .unwrap()
+}
+
+pub(super) fn parse_token(token: &str) -> Result<RawKey> {
+    decode_bech32(TOKEN_HRP, token.trim())
+}
+
+/// Write a new key slot, and return the objects it's made of
+fn write_slot(
+    backend: &Arc<dyn Backend>,
+    key: infinitree::Key,
+    master: &RawKey,
+    scope: Option<TokenScope>,
+) -> Result<KeySlot> {
+    let recorder = Recorder::new(backend);
+
+    let slot = Infinitree::<SlotIndex>::empty(recorder.clone(), key)?;
+    *slot.index().master.write() = Some(raw_key::armor(master));
+    *slot.index().scope.write() = scope;
+    slot.commit(Some("Key slot".into()))?;
+    slot.backend().sync()?;
+
+    Ok(KeySlot {
+        objects: recorder.written(),
+    })
+}
+
+/// Write the view of a delegation token limited to `scope` under
+/// `key`, and return the objects it's made of
+fn write_view(
+    backend: &Arc<dyn Backend>,
+    stash: &InfiniStash,
+    key: &RawKey,
+    scope: &TokenScope,
+) -> Result<Vec<ObjectId>> {
+    let recorder = Recorder::new(backend);
+
+    let view = Infinitree::<Files>::empty(
+        recorder.clone(),
+        Arc::new(raw_key::to_keysource(key.clone())?),
+    )?;
+    stash.index().copy_to_view(view.index(), scope);
+    view.commit(Some("Token view".into()))?;
+    view.backend().sync()?;
+
+    Ok(recorder.written())
+}
+
+fn random_key() -> RawKey {
+    let mut bytes = [0; raw_key::RAW_KEY_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes.into()
+}
+
+impl Stash {
+    fn open_shared(backend: &Arc<dyn Backend>, master: &RawKey) -> Result<InfiniStash> {
+        let stash = InfiniStash::open(
+            backend.clone(),
+            Arc::new(raw_key::to_keysource(master.clone())?),
+        )?;
+        stash.load_all()?;
+
+        Ok(stash)
+    }
+
+    /// Open the stash with `current`, through its key slot if the stash
+    /// is shared
+    fn open_current(&self, backend: &Arc<dyn Backend>, current: Key) -> Result<InfiniStash> {
+        let key = current.to_keysource(&self.alias)?;
+        if let Some(master) = unlock(backend, key.clone()) {
+            return Self::open_shared(backend, &master);
+        }
+
+        let stash = InfiniStash::open(backend.clone(), key)?;
+        stash.load_all()?;
+
+        Ok(stash)
+    }
+
+    /// Names of the users with a key slot, if the stash is shared
+    pub fn key_slots(&self, current: Key) -> Result<Vec<String>> {
+        let backend = self.open_backend()?;
+        let current = current.with_stored_kdf(&backend)?;
+        let Some(master) = unlock(&backend, current.to_keysource(&self.alias)?) else {
+            return Ok(vec![]);
+        };
+
+        let stash = Self::open_shared(&backend, &master)?;
+        let mut users = vec![];
+        stash
+            .index()
+            .key_slots
+            .for_each(|user, _| users.push(user.clone()));
+        users.sort();
+
+        Ok(users)
+    }
+
+    /// Unlock the master key with `current`.
+    ///
+    /// If the stash isn't shared yet, it's moved under a random master
+    /// key first, and the `current` credentials keep working through a
+    /// slot named `owner`.
+    fn share(&self, backend: &Arc<dyn Backend>, current: Key, owner: &str) -> Result<RawKey> {
+        if let Some(master) = unlock(backend, current.clone().to_keysource(&self.alias)?) {
+            return Ok(master);
+        }
+
+        let master = random_key();
+        let stash = InfiniStash::open(
+            backend.clone(),
+            current
+                .clone()
+                .change_to(Key::Raw(master.clone()))
+                .to_keysource(&self.alias)?,
+        )?;
+        stash.reseal()?;
+        stash.backend().sync()?;
+
+        // the old root object is overwritten by the owner's slot
+        let slot = write_slot(
+            backend,
+            current.clone().to_keysource(&self.alias)?,
+            &master,
+            None,
+        )?;
+        let stash = Self::open_shared(backend, &master)?;
+        stash.index().key_slots.insert(owner.to_string(), slot);
+        stash.commit(Some(format!("Add key slot for {owner}")))?;
+        stash.backend().sync()?;
+        self.store_kdf(&current)?;
+
+        Ok(master)
+    }
+
+    /// Give `user` access to the stash with their own password, or
+    /// change the password of an existing slot.
+    ///
+    /// The first slot moves the stash under a random master key. The
+    /// `current` credentials keep working through a slot named
+    /// `owner`.
+    pub fn add_key_slot(
+        &self,
+        current: Key,
+        owner: &str,
+        user: Key,
+        user_name: &str,
+    ) -> Result<()> {
+        // replacing the root object must not leave a broken one behind
+        let backend = Stash {
+            backend: self.backend.with_atomic_writes(),
+            ..self.clone()
+        }
+        .open_backend()?;
+        let current = current.with_stored_kdf(&backend)?;
+
+        let master = self.share(&backend, current, owner)?;
+        self.replace_key_slot(&backend, &master, user, user_name)
+    }
+
+    /// Replace the key slot `current` opens with one for `new` under
+    /// the same name, and return the name
+    pub fn change_key_slot(&self, current: Key, new: Key) -> Result<String> {
+        let backend = Stash {
+            backend: self.backend.with_atomic_writes(),
+            ..self.clone()
+        }
+        .open_backend()?;
+        let current = current.with_stored_kdf(&backend)?;
+
+        let current = current.to_keysource(&self.alias)?;
+        let Some(master) = unlock(&backend, current.clone()) else {
+            anyhow::bail!("{} is not shared through key slots", self.alias);
+        };
+
+        let stash = Self::open_shared(&backend, &master)?;
+        let Some(name) = slot_name(&backend, &stash, current) else {
+            anyhow::bail!("The key slot of the current credentials isn't recorded in the stash");
+        };
+
+        self.replace_key_slot(&backend, &master, new, &name)?;
+        Ok(name)
+    }
+
+    /// Write a key slot for `user` named `user_name`, and delete the
+    /// slot that had the name before
+    fn replace_key_slot(
+        &self,
+        backend: &Arc<dyn Backend>,
+        master: &RawKey,
+        user: Key,
+        user_name: &str,
+    ) -> Result<()> {
+        let slot = write_slot(
+            backend,
+            user.clone().to_keysource(&self.alias)?,
+            master,
+            None,
+        )?;
+        let stash = Self::open_shared(backend, master)?;
+
+        let mut replaced = None;
+        stash.index().key_slots.for_each(|name, old| {
+            if name == user_name {
+                replaced = Some(old.clone());
+            }
+        });
+
+        stash.index().key_slots.retain(|name, _| name != user_name);
+        stash
+            .index()
+            .key_slots
+            .insert(user_name.to_string(), slot.clone());
+        stash.commit(Some(format!("Add key slot for {user_name}")))?;
+        stash.backend().sync()?;
+
+        if let Some(old) = replaced {
+            let stale = old
+                .objects
+                .into_iter()
+                .filter(|id| !slot.objects.contains(id))
+                .collect::<Vec<_>>();
+            backend.delete(&stale)?;
+        }
+
+        self.store_kdf(&user)
+    }
+
+    /// Delete the key slot of `user`, so their credentials no longer
+    /// unlock the master key
+    pub fn remove_key_slot(&self, current: Key, user: &str) -> Result<()> {
+        let backend = self.open_backend()?;
+        let current = current.with_stored_kdf(&backend)?;
+        let Some(master) = unlock(&backend, current.to_keysource(&self.alias)?) else {
+            anyhow::bail!("{} is not shared through key slots", self.alias);
+        };
+
+        let stash = Self::open_shared(&backend, &master)?;
+        let mut slots = 0;
+        let mut removed = None;
+        stash.index().key_slots.for_each(|name, slot| {
+            slots += 1;
+            if name == user {
+                removed = Some(slot.clone());
+            }
+        });
+
+        let Some(removed) = removed else {
+            anyhow::bail!("No key slot for {user}");
+        };
+        if slots == 1 {
+            anyhow::bail!("Refusing to remove the last key slot");
+        }
+
+        stash.index().key_slots.retain(|name, _| name != user);
+        stash.commit(Some(format!("Remove key slot for {user}")))?;
+        stash.backend().sync()?;
+
+        backend.delete(&removed.objects)?;
+
+        Ok(())
+    }
+
+    /// Issue a read-only delegation token restricted by `scope`, and
+    /// return it.
+    ///
+    /// The token opens a view of the stash as it is now, and never
+    /// holds the master key, so it only gives access to the file
+    /// contents within its prefix.
+    pub fn issue_token(&self, current: Key, name: &str, scope: TokenScope) -> Result<String> {
+        let backend = Stash {
+            backend: self.backend.with_atomic_writes(),
+            ..self.clone()
+        }
+        .open_backend()?;
+        let current = current.with_stored_kdf(&backend)?;
+
+        let stash = self.open_current(&backend, current)?;
+        if stash.index().tokens.contains(name) {
+            anyhow::bail!("A token named {name} already exists");
+        }
+        stash.index().tree_from_flat_index();
+
+        let view = random_key();
+        let mut objects = write_view(&backend, &stash, &view, &scope)?;
+
+        let token = random_key();
+        let slot = write_slot(
+            &backend,
+            Arc::new(raw_key::to_keysource(token.clone())?),
+            &view,
+            Some(scope.clone()),
+        )?;
+        objects.extend(slot.objects);
+
+        stash.index().tokens.insert(
+            name.to_string(),
+            Token {
+                slot: KeySlot { objects },
+                scope,
+            },
+        );
+        stash.commit(Some(format!("Issue token {name}")))?;
+        stash.backend().sync()?;
+
+        Ok(armor_token(&token))
+    }
+
+    /// Revoke the token `name` by deleting its key slot and view
+    pub fn revoke_token(&self, current: Key, name: &str) -> Result<()> {
+        let backend = self.open_backend()?;
+        let current = current.with_stored_kdf(&backend)?;
+        let stash = self.open_current(&backend, current)?;
+        let Some(token) = stash.index().tokens.get(name) else {
+            anyhow::bail!("No token named {name}");
+        };
+
+        stash.index().tokens.remove(name.to_string());
+        stash.commit(Some(format!("Revoke token {name}")))?;
+        stash.backend().sync()?;
+
+        backend.delete(&token.slot.objects)?;
+
+        Ok(())
+    }
+
+    /// Names and restrictions of the tokens issued for the stash
+    pub fn tokens(&self, current: Key) -> Result<Vec<(String, TokenScope)>> {
+        let backend = self.open_backend()?;
+        let current = current.with_stored_kdf(&backend)?;
+        let stash = self.open_current(&backend, current)?;
+        let mut tokens = vec![];
+        stash
+            .index()
+            .tokens
+            .for_each(|name, token| tokens.push((name.clone(), token.scope.clone())));
+        tokens.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(tokens)
+    }
+
+    /// Restrictions of the configured key, or `override_key`, if it's a
+    /// delegation token
+    pub fn token_scope(&self, override_key: Option<&Key>) -> Result<Option<TokenScope>> {
+        let key = override_key.unwrap_or(&self.key);
+        if !key.is_token()? {
+            return Ok(None);
+        }
+
+        let backend = self.open_backend()?;
+        Ok(token_scope(
+            &backend,
+            key.clone().to_keysource(&self.alias)?,
+        ))
+    }
+}
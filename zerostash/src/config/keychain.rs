@@ -0,0 +1,48 @@
+use super::{symmetric_key::SERVICE_NAME, KeyToSource};
+use anyhow::Result;
+use infinitree::crypto::UsernamePassword;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+
+/// Read the password for the stash from the operating system's
+/// credential store: the macOS Keychain, the Secret Service (GNOME
+/// Keyring, KWallet), or the Windows Credential Manager.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeychainKey {
+    /// Username
+    pub user: String,
+}
+
+impl KeyToSource for KeychainKey {
+    type Target = UsernamePassword;
+
+    fn to_keysource(self, stash: &str) -> Result<Self::Target> {
+        let password = get_password(stash, &self.user)?;
+        Ok(UsernamePassword::with_credentials(
+            SecretString::from(self.user),
+            password,
+        )?)
+    }
+}
+
+fn entry(stash: &str, user: &str) -> Result<keyring::Entry> {
+    // same naming as the macOS `keychain = true` option, so passwords
+    // stored by either are found by both
+    let account_name = format!("{stash}#:0s:#{user}");
+    Ok(keyring::Entry::new(SERVICE_NAME, &account_name)?)
+}
+
+pub fn get_password(stash: &str, user: &str) -> Result<SecretString> {
+    match entry(stash, user)?.get_password() {
+        Ok(password) => Ok(password.into()),
+        Err(keyring::Error::NoEntry) => anyhow::bail!(
+            "No password for user {user} in the keychain. Save it with `0s keys store {stash}`"
+        ),
+        Err(error) => Err(error.into()),
+    }
+}
+
+pub fn store_password(stash: &str, user: &str, password: &SecretString) -> Result<()> {
+    Ok(entry(stash, user)?.set_password(password.expose_secret())?)
+}
@@ -0,0 +1,77 @@
+use super::{decode_bech32, KeyToSource, Result};
+use bech32::{Bech32m, Hrp};
+use infinitree::crypto::{RawKey, UsernamePassword};
+use secrecy::{ExposeSecret, SecretString};
+
+/// Size of a raw key file in bytes
+pub const RAW_KEY_LEN: usize = 32;
+
+/// Human readable prefix of armored keys
+const ARMOR_HRP: &str = "k0s-";
+
+/// Raw keys are stretched like a password under this username
+const RAW_KEY_USER: &str = "zerostash-raw-key";
+
+/// Encode a key as text that's safe to copy & paste
+pub fn armor(key: &RawKey) -> String {
+    bech32::encode::<Bech32m>(Hrp::parse(ARMOR_HRP).unwrap(), key.expose_secret()).unwrap()
+}
+
+/// Read a key file that holds either exactly [`RAW_KEY_LEN`] bytes, or
+/// an armored key.
+///
+/// Returns `None` if the file looks like a TOML key specification.
+pub fn parse(contents: &[u8]) -> Option<Result<RawKey>> {
+    if let Ok(text) = std::str::from_utf8(contents) {
+        let text = text.trim();
+        if text.starts_with(ARMOR_HRP) {
+            return Some(decode_bech32(ARMOR_HRP, text));
+        }
+
+        if toml::from_str::<super::Key>(text).is_ok() {
+            return None;
+        }
+    }
+
+    if contents.len() != RAW_KEY_LEN {
+        return None;
+    }
+
+    let mut key = [0; RAW_KEY_LEN];
+    key.copy_from_slice(contents);
+    Some(Ok(key.into()))
+}
+
+pub fn to_keysource(key: RawKey) -> Result<UsernamePassword> {
+    let user: SecretString = RAW_KEY_USER.to_string().into();
+    let password: SecretString = armor(&key).into();
+
+    Ok(UsernamePassword::with_credentials(user, password)?)
+}
+
+impl KeyToSource for RawKey {
+    type Target = UsernamePassword;
+
+    fn to_keysource(self, _stash: &str) -> Result<Self::Target> {
+        to_keysource(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn parse_key_files() {
+        let key = [7u8; super::RAW_KEY_LEN];
+
+        let raw = super::parse(&key).unwrap().unwrap();
+        assert_eq!(raw.expose_secret(), &key);
+
+        let armored = format!("{}\n", super::armor(&raw));
+        let parsed = super::parse(armored.as_bytes()).unwrap().unwrap();
+        assert_eq!(parsed.expose_secret(), &key);
+
+        assert!(super::parse(b"source = \"ask\"").is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// A credential stored in the config, like an S3 secret key.
+///
+/// It never shows up in `Debug` output, so configs can be logged and
+/// put in error messages freely. Saving the config still writes it out.
+#[derive(Clone)]
+pub struct Secret(SecretString);
+
+impl ExposeSecret<str> for Secret {
+    fn expose_secret(&self) -> &str {
+        self.0.expose_secret()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        value.to_string().into()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.expose_secret() == other.expose_secret()
+    }
+}
+
+impl Eq for Secret {}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        ser.serialize_str(self.expose_secret())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        String::deserialize(de).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{Backend, Key};
+
+    #[test]
+    fn secrets_stay_out_of_debug_output() {
+        let backend: Backend = "s3://access:hunter2@us-east-1#server.com/bucket"
+            .parse()
+            .unwrap();
+        let debug = format!("{backend:?}");
+        assert!(debug.contains("access"));
+        assert!(!debug.contains("hunter2"));
+
+        let key: Key = toml::from_str(
+            r#"source = "token"
+token = "t0s-1secret""#,
+        )
+        .unwrap();
+        assert!(!format!("{key:?}").contains("t0s-1secret"));
+
+        // the config file still has to hold the secret
+        assert!(toml::to_string(&key).unwrap().contains("t0s-1secret"));
+    }
+}
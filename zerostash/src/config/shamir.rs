@@ -0,0 +1,194 @@
+//! Shamir's secret sharing of raw keys over GF(256).
+//!
+//! Every byte of the key is the constant term of a random polynomial
+//! of degree `threshold - 1`, and a share is the value of all
+//! polynomials at the share's index.
+use super::{raw_key::RAW_KEY_LEN, Result};
+use anyhow::bail;
+use bech32::{Bech32m, Hrp};
+use infinitree::crypto::RawKey;
+use rand::RngCore;
+use secrecy::ExposeSecret;
+
+/// Human readable prefix of armored shares
+const SHARE_HRP: &str = "r0s-";
+
+/// A piece of a split key
+#[derive(Clone)]
+pub struct Share {
+    threshold: u8,
+    index: u8,
+    data: [u8; RAW_KEY_LEN],
+}
+
+impl Share {
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Encode the share as text that's easy to print or put in a QR
+    /// code
+    pub fn armor(&self) -> String {
+        let mut bytes = vec![self.threshold, self.index];
+        bytes.extend_from_slice(&self.data);
+
+        bech32::encode_upper::<Bech32m>(Hrp::parse(SHARE_HRP).unwrap(), &bytes).unwrap()
+    }
+
+    pub fn parse(armored: &str) -> Result<Self> {
+        let (hrp, bytes) = bech32::decode(armored.trim())?;
+        if !hrp.as_str().eq_ignore_ascii_case(SHARE_HRP) {
+            bail!("Not a key share");
+        }
+        if bytes.len() != RAW_KEY_LEN + 2 || bytes[0] < 2 || bytes[1] == 0 {
+            bail!("Invalid key share");
+        }
+
+        let mut data = [0; RAW_KEY_LEN];
+        data.copy_from_slice(&bytes[2..]);
+
+        Ok(Self {
+            threshold: bytes[0],
+            index: bytes[1],
+            data,
+        })
+    }
+}
+
+/// Split `key` into `shares` pieces, any `threshold` of which recover
+/// it
+pub fn split(key: &RawKey, shares: u8, threshold: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > shares {
+        bail!("The threshold must be between 2 and the number of shares");
+    }
+
+    let mut coefficients = vec![[0u8; RAW_KEY_LEN]; threshold as usize];
+    coefficients[0].copy_from_slice(key.expose_secret());
+    for c in coefficients.iter_mut().skip(1) {
+        rand::rngs::OsRng.fill_bytes(c);
+    }
+
+    let shares = (1..=shares)
+        .map(|x| {
+            let mut data = [0; RAW_KEY_LEN];
+            for (i, y) in data.iter_mut().enumerate() {
+                // Horner's method, highest degree first
+                *y = coefficients
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, c| mul(acc, x) ^ c[i]);
+            }
+
+            Share {
+                threshold,
+                index: x,
+                data,
+            }
+        })
+        .collect();
+
+    for c in coefficients.iter_mut() {
+        c.fill(0);
+    }
+
+    Ok(shares)
+}
+
+/// Recover the key from at least `threshold` different shares
+pub fn combine(shares: &[Share]) -> Result<RawKey> {
+    let Some(first) = shares.first() else {
+        bail!("No shares given");
+    };
+    if shares.iter().any(|s| s.threshold != first.threshold) {
+        bail!("The shares belong to different keys");
+    }
+
+    let mut used: Vec<&Share> = vec![];
+    for share in shares {
+        if !used.iter().any(|s| s.index == share.index) {
+            used.push(share);
+        }
+    }
+    if used.len() < first.threshold as usize {
+        bail!(
+            "{} different shares are needed, only {} given",
+            first.threshold,
+            used.len()
+        );
+    }
+    used.truncate(first.threshold as usize);
+
+    let mut key = [0; RAW_KEY_LEN];
+    for (i, share) in used.iter().enumerate() {
+        // Lagrange basis polynomial of the share, evaluated at 0
+        let mut basis = 1;
+        for (j, other) in used.iter().enumerate() {
+            if i != j {
+                basis = mul(basis, div(other.index, other.index ^ share.index));
+            }
+        }
+
+        for (k, byte) in key.iter_mut().enumerate() {
+            *byte ^= mul(share.data[k], basis);
+        }
+    }
+
+    Ok(key.into())
+}
+
+/// Multiplication in GF(256) with the AES polynomial
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+
+    product
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    // b^254 is the inverse of b
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = mul(inverse, b);
+    }
+
+    mul(a, inverse)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{combine, split, Share};
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn split_and_combine() {
+        let key = [42u8; super::RAW_KEY_LEN].into();
+        let shares = split(&key, 5, 3).unwrap();
+
+        let armored = shares.iter().map(Share::armor).collect::<Vec<_>>();
+        let parsed = armored
+            .iter()
+            .map(|s| Share::parse(s).unwrap())
+            .collect::<Vec<_>>();
+
+        let recovered = combine(&[parsed[4].clone(), parsed[0].clone(), parsed[2].clone()]);
+        assert_eq!(recovered.unwrap().expose_secret(), key.expose_secret());
+
+        assert!(combine(&[parsed[1].clone(), parsed[1].clone(), parsed[3].clone()]).is_err());
+        assert!(split(&key, 2, 3).is_err());
+    }
+}
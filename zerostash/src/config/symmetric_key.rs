@@ -2,8 +2,9 @@ use super::{KeyToSource, Result};
 use infinitree::crypto::UsernamePassword;
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
-const SERVICE_NAME: &str = "dev.symmetree.zerostash";
+pub(super) const SERVICE_NAME: &str = "dev.symmetree.zerostash";
 
 /// Username + Password information
 #[derive(clap::Args, Default, Clone, Debug, Deserialize, Serialize)]
@@ -25,6 +26,11 @@ pub struct SymmetricKey {
     #[serde(default, skip_serializing_if = "is_false")]
     #[clap(short = 'e', long = "keychain")]
     pub keychain: bool,
+
+    /// Stretch the password with Argon2id before use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[clap(skip)]
+    pub kdf: Option<super::KdfParams>,
 }
 
 fn is_false(v: &bool) -> bool {
@@ -35,7 +41,7 @@ impl KeyToSource for SymmetricKey {
     type Target = UsernamePassword;
 
     fn to_keysource(self, stash: &str) -> Result<Self::Target> {
-        let (user, pw) = self.interactive_credentials(stash)?;
+        let (user, pw) = self.credentials(stash)?;
         Ok(UsernamePassword::with_credentials(user, pw)?)
     }
 }
@@ -73,20 +79,47 @@ impl SymmetricKey {
         Ok(self)
     }
 
+    /// Use the KDF parameters stored in the stash for the user, unless
+    /// they're configured. Asks for the username if it's not
+    /// configured.
+    pub(super) fn with_stored_kdf(
+        mut self,
+        backend: &Arc<dyn infinitree::backends::Backend>,
+    ) -> Result<Self> {
+        if self.kdf.is_some() {
+            return Ok(self);
+        }
+
+        let user = match self.user.take() {
+            Some(user) => user,
+            None => ask_user()?,
+        };
+        self.kdf = super::kdf::read_stored(backend, user.expose_secret())?.flatten();
+        self.user = Some(user);
+
+        Ok(self)
+    }
+
+    /// Ask for the credentials that are not configured, and stretch
+    /// the password if a KDF is configured
+    pub fn credentials(self, stash: &str) -> Result<(SecretString, SecretString)> {
+        let kdf = self.kdf;
+        let (user, pw) = self.interactive_credentials(stash)?;
+
+        match kdf {
+            Some(kdf) => {
+                let stretched = kdf.stretch(&user, &pw)?;
+                Ok((user, stretched))
+            }
+            None => Ok((user, pw)),
+        }
+    }
+
     /// Ask for credentials on the standard input using [rpassword]
     pub fn interactive_credentials(self, stash: &str) -> Result<(SecretString, SecretString)> {
         let user = match self.user {
             Some(ref u) => u.clone(),
-            None => {
-                let stdin = std::io::stdin();
-                let stderr = std::io::stdout();
-                rprompt::prompt_reply_from_bufread(
-                    &mut stdin.lock(),
-                    &mut stderr.lock(),
-                    "Username: ",
-                )?
-                .into()
-            }
+            None => ask_user()?,
         };
 
         #[cfg(target_os = "macos")]
@@ -109,6 +142,15 @@ impl SymmetricKey {
     }
 }
 
+fn ask_user() -> Result<SecretString> {
+    let stdin = std::io::stdin();
+    let stderr = std::io::stdout();
+    Ok(
+        rprompt::prompt_reply_from_bufread(&mut stdin.lock(), &mut stderr.lock(), "Username: ")?
+            .into(),
+    )
+}
+
 #[cfg(target_os = "macos")]
 fn ask_keychain_pass(stash: &str, user: &SecretString) -> Result<SecretString> {
     let pw = get_keychain_pw(stash, user.expose_secret());
@@ -161,4 +203,4 @@ where
     S: serde::Serializer,
 {
     ser.serialize_str(val.as_ref().unwrap().expose_secret())
-}
\ No newline at end of file
+}
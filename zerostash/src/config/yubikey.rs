@@ -39,7 +39,7 @@ impl KeyToSource for YubikeyCRKey {
             });
         }
 
-        let (user, pw) = self.credentials.interactive_credentials(stash)?;
+        let (user, pw) = self.credentials.credentials(stash)?;
         Ok(YubikeyCR::with_credentials(user, pw, ykconfig)?)
     }
 }
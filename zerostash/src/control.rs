@@ -0,0 +1,140 @@
+//! Pausing a running commit from the outside
+//!
+//! `SIGUSR1` pauses and `SIGUSR2` resumes the commit. The same is
+//! available through an optional control socket, which accepts the
+//! `pause`, `resume`, and `status` commands, one per line.
+//!
+//! The `activity` command replies with a [`Status`] on a single JSON
+//! line, which is what `0s top` polls.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::info;
+use zerostash_files::{
+    activity::{Activity, Snapshot},
+    pause::Pause,
+};
+
+/// Reply to the `activity` command
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Status {
+    pub paused: bool,
+    /// Bytes sent to the storage backend so far
+    pub bytes_written: u64,
+    /// Bytes read from the storage backend so far
+    pub bytes_read: u64,
+    #[serde(flatten)]
+    pub activity: Snapshot,
+}
+
+impl Status {
+    fn current(pause: &Pause, activity: &Activity) -> Self {
+        use std::sync::atomic::Ordering;
+
+        let transfer = crate::application::APP.transfer_stats();
+        Self {
+            paused: pause.is_paused(),
+            bytes_written: transfer.bytes_written.load(Ordering::Relaxed),
+            bytes_read: transfer.bytes_read.load(Ordering::Relaxed),
+            activity: activity.snapshot(),
+        }
+    }
+}
+
+/// Pause on `SIGUSR1`, and resume on `SIGUSR2`
+#[cfg(unix)]
+pub fn handle_signals(pause: &Pause) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    let pause = pause.clone();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = usr1.recv() => {
+                    info!("pause requested");
+                    pause.pause();
+                }
+                Some(()) = usr2.recv() => {
+                    info!("resume requested");
+                    pause.resume();
+                }
+                else => break,
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals(_pause: &Pause) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Accept control commands on a Unix socket at `path`
+#[cfg(unix)]
+pub fn listen(path: &Path, pause: &Pause, activity: &Activity) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    // a socket left behind by a previous run would fail the bind
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    let pause = pause.clone();
+    let activity = activity.clone();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let pause = pause.clone();
+            let activity = activity.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = command(&pause, &activity, line.trim());
+                    if write.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path, _pause: &Pause, _activity: &Activity) -> anyhow::Result<()> {
+    anyhow::bail!("Control sockets are only supported on Unix")
+}
+
+#[cfg(unix)]
+fn command(pause: &Pause, activity: &Activity, command: &str) -> String {
+    match command {
+        "pause" => {
+            info!("pause requested");
+            pause.pause();
+            "ok\n".into()
+        }
+        "resume" => {
+            info!("resume requested");
+            pause.resume();
+            "ok\n".into()
+        }
+        "status" if pause.is_paused() => "paused\n".into(),
+        "status" => "running\n".into(),
+        "activity" => {
+            let status = Status::current(pause, activity);
+            format!("{}\n", serde_json::to_string(&status).unwrap())
+        }
+        _ => "unknown command\n".into(),
+    }
+}
@@ -68,3 +68,30 @@ impl From<io::Error> for Error {
         ErrorKind::Io.context(err).into()
     }
 }
+
+/// Redact credentials from panic messages before they are printed
+pub fn redact_panics() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| payload.downcast_ref::<&str>().copied());
+
+        match message.map(|m| (m, zerostash_files::redact(m))) {
+            Some((message, redacted)) if message != redacted => {
+                let thread = std::thread::current();
+                let location = info
+                    .location()
+                    .map(|l| format!(" at {}:{}", l.file(), l.line()))
+                    .unwrap_or_default();
+                eprintln!(
+                    "thread '{}' panicked{location}:\n{redacted}",
+                    thread.name().unwrap_or("<unnamed>")
+                );
+            }
+            _ => default(info),
+        }
+    }));
+}
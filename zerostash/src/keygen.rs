@@ -1,5 +1,5 @@
 use crate::{
-    config::{Key, YubikeyCRConfig},
+    config::{Key, SecretKeySource, YubikeyCRConfig},
     prelude::*,
 };
 use anyhow::Result;
@@ -59,6 +59,10 @@ pub enum GenKeyCmd {
     /// Generate a username/password pair with 2 factor authentication using a Yubikey
     Yubikey(YubikeyCRKey),
 
+    /// Generate a username/password pair with 2 factor authentication
+    /// using the hmac-secret extension of a FIDO2 token
+    Fido2(Fido2Key),
+
     /// Generate split keys with read/write and write-only permissions.
     /// Changing the read/write keys is NOT supported!
     #[clap(name = "split_key")]
@@ -70,6 +74,7 @@ impl GenerateKey for GenKeyCmd {
         match self {
             Self::Userpass(k) => k.generate(gen),
             Self::Yubikey(k) => k.generate(gen),
+            Self::Fido2(k) => k.generate(gen),
             Self::SplitKeyStorage(k) => k.generate(gen),
         }
     }
@@ -147,6 +152,32 @@ impl GenerateKey for YubikeyCRKey {
     }
 }
 
+#[derive(clap::Args, Debug, Clone)]
+pub struct Fido2Key {
+    #[clap(flatten)]
+    pub credentials: SymmetricKey,
+
+    /// Relying party id of the credential on the token
+    #[clap(long, default_value = "zerostash")]
+    pub rp_id: String,
+
+    /// Ask for the PIN of the token on every use
+    #[clap(long)]
+    pub pin: bool,
+}
+
+impl GenerateKey for Fido2Key {
+    fn generate(self, gen: &Generate) -> Result<Vec<WriteToFile<Key>>> {
+        let file = self.credentials.keyfile.clone();
+        let key = self.credentials.fill_random(&gen.stash)?;
+
+        Ok(vec![WriteToFile {
+            file,
+            obj: Key::Fido2(crate::config::Fido2Key::enroll(key, self.rp_id, self.pin)?),
+        }])
+    }
+}
+
 #[derive(clap::Args, Debug, Clone)]
 pub struct SplitKeyStorage {
     /// Username
@@ -163,11 +194,47 @@ pub struct SplitKeyStorage {
 
     #[clap(short = 'p', long)]
     write_keyfile: PathBuf,
+
+    /// Store the armored secret key in this file instead of the
+    /// read/write key file, eg. on removable media
+    #[clap(long, value_name = "PATH", conflicts_with = "secret_fido2")]
+    secret_keyfile: Option<PathBuf>,
+
+    /// Seal the secret key in the read/write key file with a FIDO2
+    /// token, so restores need the token
+    #[clap(long)]
+    secret_fido2: bool,
+
+    /// Ask for the PIN of the FIDO2 token
+    #[clap(long, requires = "secret_fido2")]
+    pin: bool,
+
+    /// Also write a list-only key, which can read file names and
+    /// metadata, but not file contents, eg. for audit tools. See `0s
+    /// keys policy` for its storage credentials
+    #[clap(long, value_name = "PATH")]
+    list_keyfile: Option<PathBuf>,
 }
 
 impl GenerateKey for SplitKeyStorage {
     fn generate(self, gen: &Generate) -> Result<Vec<WriteToFile<Key>>> {
-        let (rw, wo) = crate::config::SplitKeys::default().split();
+        let (mut rw, wo) = crate::config::SplitKeys::default().split();
+
+        // the secret key never touches the backup client's key file,
+        // only the one used for restores
+        if let Some(path) = &self.secret_keyfile {
+            let secret = rw.read.take().expect("generated keys have a secret key");
+            let armored = format!("{}\n", crate::config::bech32_sk(&secret));
+            crate::commands::write_key_file(path, armored.as_bytes());
+            rw.read_from = Some(SecretKeySource::File { path: path.clone() });
+        } else if self.secret_fido2 {
+            let secret = rw.read.take().expect("generated keys have a secret key");
+            rw.read_from = Some(SecretKeySource::Fido2(crate::config::SealedKey::seal(
+                &secret,
+                "zerostash".into(),
+                self.pin,
+            )?));
+        }
 
         #[cfg(target_os = "macos")]
         let key: crate::config::SymmetricKey = SymmetricKey {
@@ -184,15 +251,32 @@ impl GenerateKey for SplitKeyStorage {
         }
         .fill_random(&gen.stash)?;
 
-        Ok(vec![(wo, self.write_keyfile), (rw, self.read_keyfile)]
-            .into_iter()
-            .map(|(k, file)| WriteToFile {
+        let split_key = |keys| {
+            Key::SplitKeyStorage(crate::config::SplitKeyStorage {
+                credentials: key.clone(),
+                keys,
+            })
+        };
+
+        let mut files = vec![];
+        if let Some(file) = self.list_keyfile {
+            files.push(WriteToFile {
                 file,
-                obj: Key::SplitKeyStorage(crate::config::SplitKeyStorage {
-                    credentials: key.clone(),
-                    keys: k,
+                obj: Key::ReadOnly {
+                    key: Box::new(split_key(wo.clone())),
+                },
+            });
+        }
+
+        files.extend(
+            vec![(wo, self.write_keyfile), (rw, self.read_keyfile)]
+                .into_iter()
+                .map(|(k, file)| WriteToFile {
+                    file,
+                    obj: split_key(k),
                 }),
-            })
-            .collect())
+        );
+
+        Ok(files)
     }
 }
@@ -19,12 +19,22 @@
 )]
 
 pub mod migration;
+#[cfg(target_os = "macos")]
+pub mod apfs;
 pub mod application;
 pub mod commands;
 pub mod config;
+pub mod control;
 pub mod error;
 pub mod keygen;
 pub mod prelude;
+pub mod progress;
+#[cfg(target_os = "linux")]
+pub mod snapshot;
+pub mod systemd;
+pub mod telemetry;
+#[cfg(windows)]
+pub mod vss;
 #[cfg(feature = "fuse")]
 pub use zerostash_fuse;
 
@@ -32,10 +42,6 @@ pub use zerostash_fuse;
 #[cfg(test)]
 use tokio as _;
 #[cfg(test)]
-use tracing as _;
-#[cfg(test)]
-use tracing_subscriber as _;
-#[cfg(test)]
 use walkdir as _;
 
 #[cfg(unix)]
@@ -1,6 +1,9 @@
 use infinitree::Infinitree;
-use zerostash_files::Files;
+use zerostash_files::{Files, FORMAT_VERSION};
 
+/// Bring the index up to the current format.
+///
+/// The stash has to be fully loaded.
 pub fn migration(stash: &mut Infinitree<Files>) {
     let mut count = 0;
 
@@ -17,6 +20,9 @@ pub fn migration(stash: &mut Infinitree<Files>) {
         stash.index().files.retain(|_, _| false);
         println!("Migrated {} files", count);
     }
+
+    // whatever is committed next is in the current format
+    stash.index().params.write().format_version = FORMAT_VERSION;
 }
 
 fn path_to_filename(path: &str) -> String {
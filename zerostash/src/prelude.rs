@@ -18,6 +18,7 @@ pub trait AsyncRunnable {
 }
 
 pub fn fatal_error(err: impl Into<Box<dyn std::error::Error>>) -> ! {
-    status_err!("{} fatal error: {}", APP.name(), err.into());
+    let message = zerostash_files::redact(&err.into().to_string());
+    status_err!("{} fatal error: {}", APP.name(), message);
     std::process::exit(1)
 }
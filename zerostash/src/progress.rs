@@ -0,0 +1,157 @@
+//! Progress of long running commits and restores
+//!
+//! The workers count the files and bytes they're done with in an
+//! [`Activity`], which is shown as a progress bar on a terminal, or
+//! as JSON lines with `--json-progress`.
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+use zerostash_files::activity::{Activity, Snapshot};
+
+const INTERVAL: Duration = Duration::from_millis(500);
+const JSON_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ProgressArgs {
+    /// Don't show the progress
+    #[clap(short = 'q', long)]
+    pub quiet: bool,
+
+    /// Print the progress as a JSON line to stderr every few seconds,
+    /// instead of a progress bar
+    #[clap(long, conflicts_with = "quiet")]
+    pub json_progress: bool,
+}
+
+/// A line printed by `--json-progress`
+#[derive(Serialize)]
+struct ProgressLine {
+    files_done: u64,
+    files_found: u64,
+    bytes_done: u64,
+    bytes_found: u64,
+    files_per_sec: f64,
+    bytes_per_sec: f64,
+    dedup_ratio: f64,
+    eta_secs: Option<u64>,
+}
+
+/// Shows the progress until it's dropped
+pub struct Progress {
+    task: Option<JoinHandle<()>>,
+    bar: Option<ProgressBar>,
+}
+
+impl ProgressArgs {
+    /// Start showing the progress of `activity`
+    pub fn start(&self, activity: &Activity) -> Progress {
+        if self.quiet {
+            return Progress {
+                task: None,
+                bar: None,
+            };
+        }
+
+        let activity = activity.clone();
+        let start = Instant::now();
+
+        if self.json_progress {
+            let task = tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(JSON_INTERVAL).await;
+                    let line = json_line(&activity.snapshot(), start.elapsed());
+                    _ = writeln!(
+                        std::io::stderr().lock(),
+                        "{}",
+                        serde_json::to_string(&line).unwrap()
+                    );
+                }
+            });
+
+            return Progress {
+                task: Some(task),
+                bar: None,
+            };
+        }
+
+        // hidden when stderr isn't a terminal, eg. in a systemd unit
+        let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {wide_bar} {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, {msg})",
+            )
+            .unwrap(),
+        );
+
+        let task = tokio::spawn({
+            let bar = bar.clone();
+            async move {
+                loop {
+                    let snapshot = activity.snapshot();
+                    update(&bar, &snapshot, start.elapsed());
+                    tokio::time::sleep(INTERVAL).await;
+                }
+            }
+        });
+
+        Progress {
+            task: Some(task),
+            bar: Some(bar),
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+fn update(bar: &ProgressBar, snapshot: &Snapshot, elapsed: Duration) {
+    if snapshot.listed {
+        bar.set_length(snapshot.bytes_found);
+    }
+    bar.set_position(snapshot.bytes_done);
+
+    let eta = match snapshot.eta(elapsed) {
+        Some(eta) => format!("{} left", indicatif::HumanDuration(eta)),
+        None => "scanning".to_string(),
+    };
+    bar.set_message(format!(
+        "{}/{} files, {:.0} files/s, {:.1}% deduplicated, {eta}",
+        snapshot.files_done,
+        snapshot.files_found,
+        per_sec(snapshot.files_done, elapsed),
+        snapshot.dedup_ratio() * 100.0
+    ));
+}
+
+fn json_line(snapshot: &Snapshot, elapsed: Duration) -> ProgressLine {
+    ProgressLine {
+        files_done: snapshot.files_done,
+        files_found: snapshot.files_found,
+        bytes_done: snapshot.bytes_done,
+        bytes_found: snapshot.bytes_found,
+        files_per_sec: per_sec(snapshot.files_done, elapsed),
+        bytes_per_sec: per_sec(snapshot.bytes_done, elapsed),
+        dedup_ratio: snapshot.dedup_ratio(),
+        eta_secs: snapshot.eta(elapsed).map(|eta| eta.as_secs()),
+    }
+}
+
+fn per_sec(count: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    count as f64 / elapsed.as_secs_f64()
+}
@@ -0,0 +1,288 @@
+//! LVM and btrfs snapshots
+//!
+//! Temporary snapshots are taken with `lvcreate` or `btrfs subvolume
+//! snapshot`, both of which need root.
+
+use anyhow::{bail, Context};
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tracing::{info, warn};
+use zerostash_files::store::Snapshot;
+
+/// Snapshots are created with this prefix, so they can be skipped when
+/// they show up inside a btrfs snapshot
+pub const PREFIX: &str = ".0s-snapshot-";
+
+/// The inode number of the root of every btrfs subvolume
+const BTRFS_SUBVOLUME_ROOT: u64 = 256;
+
+/// Temporary snapshots that are removed when dropped
+#[derive(Debug, Default)]
+pub struct VolumeSnapshots {
+    cleanup: Vec<Cleanup>,
+    pub snapshots: Vec<Snapshot>,
+}
+
+#[derive(Debug)]
+enum Cleanup {
+    Btrfs(PathBuf),
+    Lvm { mount: PathBuf, volume: String },
+}
+
+/// A line of `/proc/self/mountinfo`
+#[derive(Debug, PartialEq, Eq)]
+struct Mount {
+    mount_point: PathBuf,
+    fs_type: String,
+    source: String,
+}
+
+impl VolumeSnapshots {
+    /// Snapshot the volumes that hold `paths`, which need to be
+    /// absolute, if they're on LVM or btrfs.
+    ///
+    /// Paths on other file systems are read as they are, with a
+    /// warning.
+    pub fn create(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+            .context("Failed to read the mount table")?;
+        let mounts = mountinfo
+            .lines()
+            .filter_map(parse_mount)
+            .collect::<Vec<_>>();
+
+        // clean up from here on, even if a snapshot fails
+        let mut snapshots = Self::default();
+        let mut roots = BTreeMap::new();
+
+        for path in paths {
+            let Some(mount) = mounts
+                .iter()
+                .filter(|m| path.starts_with(&m.mount_point))
+                .max_by_key(|m| m.mount_point.as_os_str().len())
+            else {
+                bail!("Failed to find the file system of {}", path.display());
+            };
+
+            let root = match mount.fs_type.as_str() {
+                "btrfs" => subvolume_of(path, &mount.mount_point)?,
+                _ if is_logical_volume(&mount.source) => mount.mount_point.clone(),
+                _ => {
+                    warn!(
+                        path = %path.display(),
+                        fs = %mount.fs_type,
+                        "not on LVM or btrfs, reading without a snapshot"
+                    );
+                    continue;
+                }
+            };
+
+            let snapshot = match roots.get(&root) {
+                Some(snapshot) => PathBuf::clone(snapshot),
+                None => {
+                    let snapshot = if mount.fs_type == "btrfs" {
+                        snapshots.btrfs(&root)?
+                    } else {
+                        snapshots.lvm(mount)?
+                    };
+                    roots.insert(root.clone(), snapshot.clone());
+                    snapshot
+                }
+            };
+
+            snapshots.snapshots.push(Snapshot {
+                original: path.clone(),
+                mounted: snapshot.join(path.strip_prefix(&root)?),
+            });
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Snapshot a subvolume into itself, as subvolumes below it aren't
+    /// part of the snapshot
+    fn btrfs(&mut self, subvolume: &Path) -> anyhow::Result<PathBuf> {
+        let target = subvolume.join(format!("{PREFIX}{}", rand::random::<u64>()));
+        run(Command::new("btrfs")
+            .args(["subvolume", "snapshot", "-r"])
+            .arg(subvolume)
+            .arg(&target))
+        .with_context(|| format!("Failed to snapshot {}", subvolume.display()))?;
+
+        info!(subvolume = %subvolume.display(), target = %target.display(), "snapshot created");
+        self.cleanup.push(Cleanup::Btrfs(target.clone()));
+        Ok(target)
+    }
+
+    fn lvm(&mut self, mount: &Mount) -> anyhow::Result<PathBuf> {
+        let fields = run(Command::new("lvs")
+            .args(["--noheadings", "-o", "vg_name,lv_name,pool_lv"])
+            .arg(&mount.source))
+        .with_context(|| format!("Failed to find the logical volume of {}", mount.source))?;
+
+        let fields = fields.split_whitespace().collect::<Vec<_>>();
+        let (vg, lv, thin) = match fields[..] {
+            [vg, lv] => (vg, lv, false),
+            [vg, lv, _pool] => (vg, lv, true),
+            _ => bail!("Unexpected output from lvs: {}", fields.join(" ")),
+        };
+
+        // thin snapshots take space from the pool, others need their own
+        let name = format!(
+            "{}{}",
+            PREFIX.trim_start_matches('.'),
+            rand::random::<u64>()
+        );
+        let mut lvcreate = Command::new("lvcreate");
+        lvcreate.args(["--snapshot", "--name", name.as_str()]);
+        if thin {
+            lvcreate.args(["--setactivationskip", "n"]);
+        } else {
+            lvcreate.args(["--extents", "10%ORIGIN"]);
+        }
+        run(lvcreate.arg(format!("{vg}/{lv}")))
+            .with_context(|| format!("Failed to snapshot {vg}/{lv}"))?;
+
+        let volume = format!("{vg}/{name}");
+        info!(origin = %format!("{vg}/{lv}"), %volume, "snapshot created");
+
+        let target = std::env::temp_dir().join(format!("0s-mount-{}", rand::random::<u64>()));
+        let mounted = std::fs::create_dir(&target)
+            .with_context(|| format!("Failed to create {}", target.display()))
+            .and_then(|_| {
+                // xfs refuses to mount two file systems with the same uuid
+                let options = match mount.fs_type.as_str() {
+                    "xfs" => "ro,nouuid",
+                    _ => "ro",
+                };
+                run(Command::new("mount")
+                    .args(["-o", options])
+                    .arg(format!("/dev/{volume}"))
+                    .arg(&target))
+                .with_context(|| format!("Failed to mount {volume}"))
+            });
+
+        self.cleanup.push(Cleanup::Lvm {
+            mount: target.clone(),
+            volume,
+        });
+        mounted.map(|_| target)
+    }
+}
+
+impl Drop for VolumeSnapshots {
+    fn drop(&mut self) {
+        for cleanup in self.cleanup.iter().rev() {
+            let result = match cleanup {
+                Cleanup::Btrfs(snapshot) => run(Command::new("btrfs")
+                    .args(["subvolume", "delete"])
+                    .arg(snapshot)),
+                Cleanup::Lvm { mount, volume } => {
+                    if mount.exists() {
+                        _ = run(Command::new("umount").arg(mount));
+                        _ = std::fs::remove_dir(mount);
+                    }
+                    run(Command::new("lvremove").args(["--yes", volume.as_str()]))
+                }
+            };
+
+            if let Err(error) = result {
+                warn!(?cleanup, "failed to remove snapshot: {error:#}");
+            }
+        }
+    }
+}
+
+fn parse_mount(line: &str) -> Option<Mount> {
+    // 36 35 98:0 / /mnt/data rw,noatime master:1 - ext4 /dev/sda1 rw
+    let (mount, fs) = line.split_once(" - ")?;
+    let mount_point = mount.split(' ').nth(4)?;
+    let mut fs = fs.split(' ');
+
+    Some(Mount {
+        mount_point: unescape(mount_point).into(),
+        fs_type: fs.next()?.to_string(),
+        source: unescape(fs.next()?),
+    })
+}
+
+/// Spaces and such are escaped as octal, like `\040`
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        match rest
+            .get(i + 1..i + 4)
+            .and_then(|o| u8::from_str_radix(o, 8).ok())
+        {
+            Some(c) => {
+                unescaped.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// The root of the subvolume that holds `path`
+fn subvolume_of(path: &Path, mount_point: &Path) -> anyhow::Result<PathBuf> {
+    for dir in path.ancestors() {
+        let metadata =
+            std::fs::metadata(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+        if (metadata.is_dir() && metadata.ino() == BTRFS_SUBVOLUME_ROOT) || dir == mount_point {
+            return Ok(dir.to_owned());
+        }
+    }
+
+    Ok(mount_point.to_owned())
+}
+
+fn is_logical_volume(source: &str) -> bool {
+    source.starts_with("/dev/mapper/")
+        && Command::new("lvs")
+            .arg(source)
+            .output()
+            .is_ok_and(|o| o.status.success())
+}
+
+fn run(command: &mut Command) -> anyhow::Result<String> {
+    let output = command.output().context("Failed to run command")?;
+
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mount, Mount};
+
+    #[test]
+    fn mountinfo_is_parsed() {
+        assert_eq!(
+            parse_mount(
+                r"36 35 253:1 / /mnt/my\040data rw,noatime shared:1 - ext4 /dev/mapper/vg-data rw"
+            ),
+            Some(Mount {
+                mount_point: "/mnt/my data".into(),
+                fs_type: "ext4".into(),
+                source: "/dev/mapper/vg-data".into(),
+            })
+        );
+        assert_eq!(parse_mount("36 35 253:1 /"), None);
+    }
+}
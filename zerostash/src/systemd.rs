@@ -0,0 +1,218 @@
+//! systemd integration
+//!
+//! Generates service and timer units for scheduled backups, and
+//! implements the `sd_notify` protocol for long-running commands.
+
+use std::{env, io, path::Path, thread, time::Duration};
+
+/// Send a state update to the service manager.
+///
+/// Returns `Ok(false)` if the process is not supervised by systemd.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<bool> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+
+    let socket = UnixDatagram::unbound()?;
+
+    // sockets in the abstract namespace are prefixed with '@'
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.to_str().and_then(|p| p.strip_prefix('@')) {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let addr = SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(true);
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(true)
+}
+
+#[cfg(windows)]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// The interval at which the watchdog needs to be notified, if
+/// `WatchdogSec=` is configured for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+
+    // ping twice per period so a slow tick doesn't kill the service
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Keep pinging the watchdog in a background thread for the
+/// lifetime of the process.
+pub fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+
+    thread::spawn(move || loop {
+        if notify("WATCHDOG=1").is_err() {
+            break;
+        }
+        thread::sleep(interval);
+    });
+}
+
+/// Description of a scheduled backup job
+pub struct BackupJob<'a> {
+    /// Stash alias as defined in the config
+    pub stash: &'a str,
+    /// Paths to back up
+    pub paths: &'a [std::path::PathBuf],
+    /// `OnCalendar=` expression
+    pub schedule: &'a str,
+    /// Path to the `0s` executable
+    pub executable: &'a Path,
+    /// Config file to use in the service
+    pub config: &'a Path,
+    /// Paths that need to be writable for the backend to work
+    pub writable: Vec<String>,
+}
+
+impl BackupJob<'_> {
+    /// Name of the units, without the `.service` or `.timer` suffix
+    pub fn unit_name(&self) -> String {
+        let name = self
+            .stash
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect::<String>();
+
+        format!("zerostash-{name}")
+    }
+
+    pub fn service(&self) -> String {
+        let mut command = vec![
+            escape_arg(&self.executable.to_string_lossy()),
+            "--config".into(),
+            escape_arg(&self.config.to_string_lossy()),
+            "commit".into(),
+            escape_arg(self.stash),
+        ];
+        command.extend(self.paths.iter().map(|p| escape_arg(&p.to_string_lossy())));
+
+        let mut unit = format!(
+            "[Unit]
+Description=Zerostash backup of {stash}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart={command}
+Nice=10
+IOSchedulingClass=idle
+NoNewPrivileges=yes
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectSystem=strict
+ProtectHome=read-only
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectKernelLogs=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+RestrictSUIDSGID=yes
+RestrictRealtime=yes
+RestrictNamespaces=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+",
+            stash = self.stash,
+            command = command.join(" "),
+        );
+
+        for path in self.writable.iter() {
+            unit.push_str(&format!("ReadWritePaths={}\n", escape_arg(path)));
+        }
+
+        unit
+    }
+
+    pub fn timer(&self) -> String {
+        format!(
+            "[Unit]
+Description=Scheduled zerostash backup of {stash}
+
+[Timer]
+OnCalendar={schedule}
+Persistent=true
+RandomizedDelaySec=5min
+
+[Install]
+WantedBy=timers.target
+",
+            stash = self.stash,
+            schedule = self.schedule,
+        )
+    }
+}
+
+/// Quote an argument for `ExecStart=`, and escape `%` specifiers
+fn escape_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%");
+
+    if escaped
+        .chars()
+        .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+    {
+        format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_arg, BackupJob};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn exec_args_are_escaped() {
+        assert_eq!(escape_arg("/home/user"), "/home/user");
+        assert_eq!(escape_arg("/home/my user"), "\"/home/my user\"");
+        assert_eq!(escape_arg("100%"), "100%%");
+        assert_eq!(escape_arg("a\"b"), "\"a\\\"b\"");
+    }
+
+    #[test]
+    fn units_reference_the_stash() {
+        let paths = [PathBuf::from("/home")];
+        let job = BackupJob {
+            stash: "nightly s3",
+            paths: &paths,
+            schedule: "daily",
+            executable: Path::new("/usr/bin/0s"),
+            config: Path::new("/etc/zerostash/config.toml"),
+            writable: vec!["/archive".into()],
+        };
+
+        assert_eq!(job.unit_name(), "zerostash-nightly-s3");
+        assert!(job.service().contains(
+            "ExecStart=/usr/bin/0s --config /etc/zerostash/config.toml commit \"nightly s3\" /home\n"
+        ));
+        assert!(job.service().contains("ReadWritePaths=/archive\n"));
+        assert!(job.timer().contains("OnCalendar=daily\n"));
+    }
+}
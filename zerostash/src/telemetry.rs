@@ -0,0 +1,295 @@
+//! OpenTelemetry trace export
+//!
+//! Spans are collected by a `tracing` layer, and shipped in batches
+//! to an OTLP/HTTP collector using the JSON encoding.
+
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+const BATCH_SIZE: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+/// Install a global subscriber that logs to the terminal at
+/// `log_level`, and exports spans to the OTLP `endpoint`.
+pub fn init(endpoint: &str, log_level: &str) -> anyhow::Result<()> {
+    use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
+
+    let (sender, receiver) = mpsc::channel();
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let handle = thread::spawn(move || export_loop(url, receiver));
+
+    _ = EXPORTER.set(Exporter {
+        sender: sender.clone(),
+        handle: Mutex::new(Some(handle)),
+    });
+
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(EnvFilter::new(log_level)))
+        .with(OtlpLayer { sender }.with_filter(LevelFilter::DEBUG))
+        .try_init()?;
+
+    Ok(())
+}
+
+/// Send all buffered spans to the collector, and stop exporting.
+pub fn shutdown() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+
+    _ = exporter.sender.send(Message::Shutdown);
+    if let Some(handle) = exporter.handle.lock().unwrap().take() {
+        _ = handle.join();
+    }
+}
+
+struct Exporter {
+    sender: mpsc::Sender<Message>,
+    handle: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+enum Message {
+    Span(FinishedSpan),
+    Shutdown,
+}
+
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    start: u128,
+    attributes: Vec<(String, Value)>,
+}
+
+struct FinishedSpan {
+    name: &'static str,
+    data: SpanData,
+    end: u128,
+}
+
+impl FinishedSpan {
+    fn to_json(&self) -> Value {
+        json!({
+            "traceId": format!("{:032x}", self.data.trace_id),
+            "spanId": format!("{:016x}", self.data.span_id),
+            "parentSpanId": self.data.parent_id.map(|id| format!("{id:016x}")).unwrap_or_default(),
+            "name": self.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": self.data.start.to_string(),
+            "endTimeUnixNano": self.end.to_string(),
+            "attributes": self.data.attributes.iter().map(|(key, value)| json!({
+                "key": key,
+                "value": value,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// `tracing` layer that forwards closed spans to the exporter
+struct OtlpLayer {
+    sender: mpsc::Sender<Message>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+
+        let (trace_id, parent_id) = match parent {
+            Some((trace_id, parent_id)) => (trace_id, Some(parent_id)),
+            None => (rand::random(), None),
+        };
+
+        let mut data = SpanData {
+            trace_id,
+            span_id: rand::random(),
+            parent_id,
+            start: now(),
+            attributes: vec![],
+        };
+        attrs.record(&mut Attributes(&mut data.attributes));
+
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut Attributes(&mut data.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+
+        _ = self.sender.send(Message::Span(FinishedSpan {
+            name: span.name(),
+            data,
+            end: now(),
+        }));
+    }
+}
+
+struct Attributes<'a>(&'a mut Vec<(String, Value)>);
+
+impl Attributes<'_> {
+    fn push(&mut self, field: &Field, value: Value) {
+        self.0.push((field.name().to_string(), value));
+    }
+}
+
+impl Visit for Attributes<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = zerostash_files::redact(&format!("{value:?}"));
+        self.push(field, json!({ "stringValue": value }));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = zerostash_files::redact(value);
+        self.push(field, json!({ "stringValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, json!({ "boolValue": value }));
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+fn export_loop(url: String, receiver: mpsc::Receiver<Message>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    loop {
+        match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Message::Span(span)) => {
+                batch.push(span.to_json());
+                if batch.len() < BATCH_SIZE {
+                    continue;
+                }
+            }
+            Ok(Message::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                send(&url, &mut batch);
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        send(&url, &mut batch);
+    }
+}
+
+fn send(url: &str, batch: &mut Vec<Value>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": "zerostash" },
+                }],
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": "zerostash",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "spans": batch.drain(..).collect::<Vec<_>>(),
+            }],
+        }],
+    });
+
+    // logging through `tracing` here would feed back into the exporter
+    if let Err(error) = ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string())
+    {
+        eprintln!("Failed to export traces: {error}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, OtlpLayer};
+    use std::sync::mpsc;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn child_spans_share_the_trace() {
+        let (sender, receiver) = mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer { sender });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _outer = tracing::info_span!("outer", files = 10).entered();
+            let _inner = tracing::info_span!("inner").entered();
+        });
+
+        let spans = receiver
+            .try_iter()
+            .map(|m| match m {
+                Message::Span(s) => s,
+                Message::Shutdown => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+
+        let (inner, outer) = (&spans[0], &spans[1]);
+        assert_eq!(inner.name, "inner");
+        assert_eq!(outer.name, "outer");
+        assert_eq!(inner.data.trace_id, outer.data.trace_id);
+        assert_eq!(inner.data.parent_id, Some(outer.data.span_id));
+        assert_eq!(outer.to_json()["attributes"][0]["key"], "files");
+    }
+}
@@ -0,0 +1,101 @@
+//! Volume Shadow Copy support
+//!
+//! Shadow copies are created and removed through the `Win32_ShadowCopy`
+//! WMI class, which needs an elevated prompt.
+
+use anyhow::{bail, Context};
+use std::{
+    collections::BTreeSet,
+    path::{Component, Path, PathBuf, Prefix},
+    process::Command,
+};
+use tracing::{info, warn};
+use zerostash_files::store::Snapshot;
+
+/// A shadow copy that's deleted when dropped
+#[derive(Debug)]
+pub struct ShadowCopy {
+    id: String,
+    pub snapshot: Snapshot,
+}
+
+impl ShadowCopy {
+    /// Create a shadow copy of the volume mounted at `volume`, eg. `C:\`
+    pub fn create(volume: &Path) -> anyhow::Result<Self> {
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             $r = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create \
+               -Arguments @{{ Volume = '{}'; Context = 'ClientAccessible' }}; \
+             if ($r.ReturnValue -ne 0) {{ throw \"error $($r.ReturnValue)\" }}; \
+             $s = Get-CimInstance Win32_ShadowCopy -Filter \"ID = '$($r.ShadowID)'\"; \
+             Write-Output $s.ID; Write-Output $s.DeviceObject",
+            volume.display()
+        );
+        let output = powershell(&script)
+            .with_context(|| format!("Failed to create a shadow copy of {}", volume.display()))?;
+
+        let mut lines = output.lines().map(str::trim);
+        let (Some(id), Some(device)) = (lines.next(), lines.next()) else {
+            bail!("Unexpected output when creating a shadow copy: {output}");
+        };
+        info!(volume = %volume.display(), %id, "shadow copy created");
+
+        Ok(Self {
+            id: id.to_string(),
+            snapshot: Snapshot {
+                original: volume.to_owned(),
+                mounted: PathBuf::from(format!("{device}\\")),
+            },
+        })
+    }
+}
+
+impl Drop for ShadowCopy {
+    fn drop(&mut self) {
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             Get-CimInstance Win32_ShadowCopy -Filter \"ID = '{}'\" | Remove-CimInstance",
+            self.id
+        );
+        if let Err(error) = powershell(&script) {
+            warn!(id = %self.id, "failed to delete shadow copy: {error:#}");
+        }
+    }
+}
+
+/// Create a shadow copy of each volume that holds one of `paths`,
+/// which need to be absolute
+pub fn shadow_copies(paths: &[PathBuf]) -> anyhow::Result<Vec<ShadowCopy>> {
+    let volumes = paths
+        .iter()
+        .map(|p| volume_of(p))
+        .collect::<anyhow::Result<BTreeSet<_>>>()?;
+
+    volumes.iter().map(|v| ShadowCopy::create(v)).collect()
+}
+
+/// The drive root of `path`
+fn volume_of(path: &Path) -> anyhow::Result<PathBuf> {
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(drive) | Prefix::Disk(drive) => {
+                Ok(PathBuf::from(format!("{}:\\", drive as char)))
+            }
+            _ => bail!("Shadow copies need a local drive: {}", path.display()),
+        },
+        _ => bail!("Path has no drive: {}", path.display()),
+    }
+}
+
+fn powershell(script: &str) -> anyhow::Result<String> {
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()
+        .context("Failed to run powershell")?;
+
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}